@@ -136,11 +136,16 @@ async function sendInfo(vm: TauriVM, eid: string) {
 	const ei = vm.endpointsInfo.find((el) => el.id === eid);
 	if (!ei || !ei.ip || !ei.port) return;
 
+	// IPv6 addresses need to be bracketed before appending the port
+	const host = ei.ip.includes(':') ? '[' + ei.ip + ']' : ei.ip;
 	const msg: SendInfo = {
 		id: ei.id,
 		name: ei.name ?? 'Unknown',
-		addr: ei.ip + ":" + ei.port,
+		addr: host + ":" + ei.port,
 		ob: vm.outboundPayload,
+		note: null,
+		chunk_size: null,
+		introduction_only: false,
 	};
 
 	await vm.invoke('send_payload', { message: msg });
@@ -151,9 +156,11 @@ async function sendCmd(vm: TauriVM, id: string, action: ChannelAction) {
 		id: id,
 		direction: 'FrontToLib',
 		action: action,
+		accepted_files: null,
 		meta: null,
 		state: null,
 		rtype: null,
+		rejection: null,
 	};
 	console.log("js2rs:", cm);
 
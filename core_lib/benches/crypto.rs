@@ -0,0 +1,147 @@
+// Hot paths of a transfer, run by an actual OutboundRequest over an in-memory
+// stream so that only the CPU cost is measured and not the network. Run with:
+//   cargo bench --features bench
+
+use std::fs::File;
+use std::io::Read;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use rqs_lib::bench::BenchSender;
+use rqs_lib::location_nearby_connections::payload_transfer_frame::{
+    payload_header, PacketType, PayloadChunk, PayloadHeader,
+};
+use rqs_lib::location_nearby_connections::{
+    offline_frame, v1_frame, OfflineFrame, PayloadTransferFrame, V1Frame,
+};
+use sha2::{Digest, Sha256};
+use tokio::runtime::Runtime;
+
+// Same as what the sender reads from the file for each chunk
+const CHUNK_SIZE: usize = 512 * 1024;
+const TRANSFER_SIZE: usize = 100 * 1024 * 1024;
+
+fn chunk_frame(body: &[u8]) -> OfflineFrame {
+    OfflineFrame {
+        version: Some(offline_frame::Version::V1.into()),
+        v1: Some(V1Frame {
+            r#type: Some(v1_frame::FrameType::PayloadTransfer.into()),
+            payload_transfer: Some(PayloadTransferFrame {
+                packet_type: Some(PacketType::Data.into()),
+                payload_header: Some(PayloadHeader {
+                    id: Some(42),
+                    r#type: Some(payload_header::PayloadType::File.into()),
+                    total_size: Some(body.len() as i64),
+                    is_sensitive: Some(false),
+                    file_name: Some(String::from("bench.bin")),
+                    ..Default::default()
+                }),
+                payload_chunk: Some(PayloadChunk {
+                    offset: Some(0),
+                    flags: Some(0),
+                    body: Some(body.to_vec()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }),
+    }
+}
+
+// A file of TRANSFER_SIZE bytes, removed by the caller
+fn transfer_file(name: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("rqs_bench_{name}_{}", std::process::id()));
+    std::fs::write(&path, vec![0x5A; TRANSFER_SIZE]).unwrap();
+    path
+}
+
+fn bench_chunk(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let _guard = rt.enter();
+    let mut sender = BenchSender::new(CHUNK_SIZE).unwrap();
+    let frame = chunk_frame(&vec![0xAB; CHUNK_SIZE]);
+
+    let mut group = c.benchmark_group("encrypt_and_send");
+    group.throughput(Throughput::Bytes(CHUNK_SIZE as u64));
+    group.bench_function("chunk", |b| {
+        b.iter(|| {
+            rt.block_on(sender.encrypt_and_send(black_box(&frame)))
+                .unwrap()
+        })
+    });
+    group.finish();
+}
+
+// Baseline of a whole 100 MB transfer: reading, hashing, encrypting and
+// framing every chunk of the file
+fn bench_transfer(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let _guard = rt.enter();
+    let mut sender = BenchSender::new(CHUNK_SIZE).unwrap();
+    let path = transfer_file("transfer");
+
+    let mut group = c.benchmark_group("transfer");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(TRANSFER_SIZE as u64));
+    group.bench_function("100MB", |b| {
+        b.iter(|| rt.block_on(sender.send_file(&path)).unwrap())
+    });
+    group.finish();
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+// Hashing the chunks as they're sent, against the extra pass over the file
+// it would take to hash it up front
+fn bench_digest(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let _guard = rt.enter();
+    let mut sender = BenchSender::new(CHUNK_SIZE).unwrap();
+    let path = transfer_file("digest");
+
+    let mut group = c.benchmark_group("digest");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(TRANSFER_SIZE as u64));
+    group.bench_function("single_pass", |b| {
+        b.iter(|| rt.block_on(sender.send_file(&path)).unwrap())
+    });
+    group.bench_function("two_pass", |b| {
+        b.iter(|| {
+            let mut hasher = Sha256::new();
+            let mut file = File::open(&path).unwrap();
+            let mut buffer = vec![0u8; CHUNK_SIZE];
+            loop {
+                let bytes_read = file.read(&mut buffer).unwrap();
+                if bytes_read == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..bytes_read]);
+            }
+            let digest = hasher.finalize();
+            (rt.block_on(sender.send_file(&path)).unwrap(), digest)
+        })
+    });
+    group.finish();
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+// From the peer's public key to the session keys: the ECDH, then the HKDF chain
+fn bench_key_derivation(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let _guard = rt.enter();
+    let mut sender = BenchSender::new(CHUNK_SIZE).unwrap();
+
+    c.bench_function("finalize_key_exchange", |b| {
+        b.iter(|| rt.block_on(sender.finalize_key_exchange()).unwrap())
+    });
+}
+
+criterion_group!(
+    benches,
+    bench_chunk,
+    bench_transfer,
+    bench_digest,
+    bench_key_derivation
+);
+criterion_main!(benches);
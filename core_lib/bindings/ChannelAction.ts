@@ -1,3 +1,3 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
 
-export type ChannelAction = "AcceptTransfer" | "RejectTransfer" | "CancelTransfer";
+export type ChannelAction = "AcceptTransfer" | "RejectTransfer" | "CancelTransfer" | "PeerOffline" | "ConfirmPin" | "RejectPin" | "Pause" | "Resume" | "Proceed";
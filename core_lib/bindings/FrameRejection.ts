@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { RejectReason } from "./RejectReason";
+
+export type FrameRejection = { frame_type: string | null, reason: RejectReason, value: string | null, };
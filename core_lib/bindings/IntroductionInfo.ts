@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { OfferedFile } from "./OfferedFile";
+
+export type IntroductionInfo = { files: Array<OfferedFile>, total_bytes: bigint, empty_folders: Array<string>, };
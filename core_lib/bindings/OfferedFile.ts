@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export type OfferedFile = { payload_id: bigint, path: string, name: string, parent_folder: string | null, size: bigint, mime_type: string, };
@@ -1,3 +1,4 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { TextKind } from "./TextKind";
 
-export type OutboundPayload = { "Files": Array<string> };
+export type OutboundPayload = { "Files": Array<string> } | { "Directory": string } | { "DirectoryPart": { files: Array<[string, string]>, empty_folders: Array<string>, } } | { "Text": { kind: TextKind, body: string, } };
@@ -1,5 +1,9 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { CancelReason } from "./CancelReason";
+import type { CancellationKind } from "./CancellationKind";
+import type { FileProgress } from "./FileProgress";
 import type { RemoteDeviceInfo } from "./RemoteDeviceInfo";
 import type { TextPayloadType } from "./TextPayloadType";
+import type { TransferError } from "./TransferError";
 
-export type TransferMetadata = { id: string, source: RemoteDeviceInfo | null, pin_code: string | null, destination: string | null, files: Array<string> | null, text_type: TextPayloadType | null, text_description: string | null, text_payload: string | null, total_bytes: bigint, ack_bytes: bigint, };
+export type TransferMetadata = { id: string, source: RemoteDeviceInfo | null, accepted_by: RemoteDeviceInfo | null, pin_code: string | null, destination: string | null, files: Array<string> | null, skipped_files: Array<string> | null, note: string | null, text_type: TextPayloadType | null, text_description: string | null, text_payload: string | null, total_bytes: bigint, ack_bytes: bigint, file_progress: FileProgress | null, wire_bytes: bigint, goodput: bigint, duration_ms: bigint | null, paused: boolean, handshake_ms: bigint | null, confirmed_files: Array<string> | null, saved_files: Array<string> | null, cancellation: CancellationKind | null, cancel_reason: CancelReason | null, error: TransferError | null, };
@@ -1,13 +1,22 @@
+export * from "./CancelReason"
+export * from "./CancellationKind"
 export * from "./ChannelAction"
 export * from "./ChannelDirection"
 export * from "./ChannelMessage"
 export * from "./DeviceType"
 export * from "./EndpointInfo"
+export * from "./FileProgress"
+export * from "./FrameRejection"
+export * from "./IntroductionInfo"
+export * from "./OfferedFile"
 export * from "./OutboundPayload"
+export * from "./RejectReason"
 export * from "./RemoteDeviceInfo"
 export * from "./SendInfo"
 export * from "./State"
+export * from "./TextKind"
 export * from "./TextPayloadType"
+export * from "./TransferError"
 export * from "./TransferMetadata"
 export * from "./TransferType"
 export * from "./Visibility"
\ No newline at end of file
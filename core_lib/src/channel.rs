@@ -1,7 +1,10 @@
+use futures::Stream;
 use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
 use ts_rs::TS;
 
-use crate::hdl::info::TransferMetadata;
+use crate::hdl::info::{FrameRejection, TransferError, TransferMetadata};
 use crate::hdl::State;
 
 #[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize, TS)]
@@ -18,6 +21,18 @@ pub enum ChannelAction {
     AcceptTransfer,
     RejectTransfer,
     CancelTransfer,
+    // Sent by the discovery when the peer vanished from mDNS
+    PeerOffline,
+    // The PIN of State::AwaitingPinConfirmation matches the peer's
+    ConfirmPin,
+    // It doesn't, someone may be in the middle
+    RejectPin,
+    // Outbound only: stop sending chunks, keeping the session up, until
+    // Resume. The transfer goes on from where it stopped.
+    Pause,
+    Resume,
+    // Outbound only: send the payloads held in State::AwaitingProceed
+    Proceed,
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize, TS)]
@@ -35,9 +50,104 @@ pub struct ChannelMessage {
 
     // Only present when channelDirection is frontToLib
     pub action: Option<ChannelAction>,
+    // Only used with AcceptTransfer: indices into meta.files of the files
+    // to receive, None meaning all of them
+    pub accepted_files: Option<Vec<usize>>,
 
     // Only present when channelDirection is libToFront
     pub rtype: Option<TransferType>,
     pub state: Option<State>,
     pub meta: Option<TransferMetadata>,
+    // Only in the message sent when a frame from the peer was rejected
+    pub rejection: Option<FrameRejection>,
+}
+
+/// What an outbound transfer went through, out of its ChannelMessages. The
+/// stream ends with either Completed or Failed.
+#[derive(Debug, Clone)]
+pub enum TransferEvent {
+    // The handshake is done, both devices show that PIN
+    PinReady(String),
+    // The receiver accepted, the payloads are on their way unless held
+    // until ChannelAction::Proceed
+    Accepted,
+    // Payload bytes sent so far, out of the total
+    Progress {
+        sent: u64,
+        total: u64,
+    },
+    Completed(TransferMetadata),
+    // One of Cancelled, Rejected or Disconnected
+    Failed {
+        state: State,
+        error: Option<TransferError>,
+    },
+}
+
+#[derive(Debug, Default)]
+struct EventFilter {
+    pin_ready: bool,
+    accepted: bool,
+}
+
+impl EventFilter {
+    fn event_for(&mut self, msg: &ChannelMessage) -> Option<TransferEvent> {
+        let meta = msg.meta.as_ref();
+        match msg.state.as_ref()? {
+            State::Finished => Some(TransferEvent::Completed(meta.cloned().unwrap_or_default())),
+            state @ (State::Cancelled | State::Rejected | State::Disconnected) => {
+                Some(TransferEvent::Failed {
+                    state: state.clone(),
+                    error: meta.and_then(|m| m.error.clone()),
+                })
+            }
+            State::SendingFiles | State::AwaitingProceed if !self.accepted => {
+                self.accepted = true;
+                Some(TransferEvent::Accepted)
+            }
+            State::SendingFiles => meta.map(|m| TransferEvent::Progress {
+                sent: m.ack_bytes,
+                total: m.total_bytes,
+            }),
+            _ if !self.pin_ready => {
+                let pin = meta.and_then(|m| m.pin_code.clone())?;
+                self.pin_ready = true;
+                Some(TransferEvent::PinReady(pin))
+            }
+            _ => None,
+        }
+    }
+}
+
+// The events of the transfer id among what the library sends to the front
+pub(crate) fn transfer_events(
+    receiver: Receiver<ChannelMessage>,
+    id: String,
+) -> impl Stream<Item = TransferEvent> {
+    let state = Some((receiver, EventFilter::default()));
+    futures::stream::unfold(state, move |state| {
+        let id = id.clone();
+        async move {
+            let (mut receiver, mut filter) = state?;
+            loop {
+                let msg = match receiver.recv().await {
+                    Ok(msg) => msg,
+                    // Missed some, only progress is ever worth skipping
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                };
+                if msg.direction != ChannelDirection::LibToFront || msg.id != id {
+                    continue;
+                }
+
+                if let Some(event) = filter.event_for(&msg) {
+                    let ended = matches!(
+                        event,
+                        TransferEvent::Completed(_) | TransferEvent::Failed { .. }
+                    );
+                    return Some((event, (!ended).then_some((receiver, filter))));
+                }
+            }
+        }
+    })
 }
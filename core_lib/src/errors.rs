@@ -1,12 +1,268 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::hdl::info::{FrameRejection, TransferError};
+use crate::hdl::State;
+use crate::securegcm::ukey2_alert::AlertType;
+
 #[derive(Debug)]
 pub enum AppError {
     NotAnError,
+    SelfConnection,
+    EncryptionNotEstablished,
+    DuplicatePayloadId(i64),
+    // The peer stopped reading what we send, see stream_write_all
+    WriteStalled,
+    // No ack to a keepalive in time, see OutboundRequest::probe_liveness
+    PeerUnresponsive,
+    // The peer didn't get through the UKEY2 handshake in time
+    HandshakeTimedOut(Duration),
+    // The peer turned the UKEY2 handshake down with an alert
+    PeerAlert {
+        alert: AlertType,
+        message: Option<String>,
+    },
+    // More secure messages per second than the limit, see set_frame_rate_limit
+    ProcessingRateExceeded(u32),
+    // The peer sent something we won't go on with, see hdl::frame_rejection
+    FrameRejected(FrameRejection),
+    // A received file doesn't match the digest the sender computed for it
+    DigestMismatch(i64),
+    // The UKEY2 ClientFinish doesn't hash to the commitment of the ClientInit
+    CommitmentMismatch,
+    // The introduction's total size isn't the sum of its file sizes
+    InconsistentIntroduction {
+        declared: i64,
+        actual: Option<i64>,
+    },
+    // Our own introduction is over what receivers accept, see send_introduction
+    IntroductionTooLarge {
+        size: usize,
+        max: usize,
+    },
+    // A file we introduced can't be opened anymore by the time it's sent
+    FileUnreadable(PathBuf),
 }
 
 impl std::fmt::Display for AppError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::NotAnError => write!(f, "not an error"),
+            Self::SelfConnection => write!(f, "peer presented our own endpoint id"),
+            Self::EncryptionNotEstablished => {
+                write!(f, "tried to send an encrypted frame before the handshake")
+            }
+            Self::DuplicatePayloadId(id) => write!(f, "payload id {id} is already in use"),
+            Self::WriteStalled => write!(f, "peer stopped reading, giving up on writing"),
+            Self::PeerUnresponsive => write!(f, "peer didn't ack the keepalive in time"),
+            Self::HandshakeTimedOut(timeout) => {
+                write!(f, "peer didn't complete the handshake within {timeout:?}")
+            }
+            Self::PeerAlert { alert, message } => match message {
+                Some(message) => write!(f, "peer rejected the handshake: {alert:?} ({message})"),
+                None => write!(f, "peer rejected the handshake: {alert:?}"),
+            },
+            Self::ProcessingRateExceeded(limit) => {
+                write!(f, "peer sent more than {limit} secure messages in a second")
+            }
+            Self::DigestMismatch(id) => {
+                write!(f, "payload {id} doesn't match its digest, discarded it")
+            }
+            Self::CommitmentMismatch => {
+                write!(
+                    f,
+                    "UKEY2 ClientFinish doesn't match the ClientInit commitment"
+                )
+            }
+            Self::FrameRejected(r) => write!(
+                f,
+                "rejected {} frame: {:?} ({})",
+                r.frame_type.as_deref().unwrap_or("unknown"),
+                r.reason,
+                r.value.as_deref().unwrap_or("-")
+            ),
+            Self::InconsistentIntroduction { declared, actual } => match actual {
+                Some(actual) => write!(
+                    f,
+                    "introduction declares {declared} bytes but its files add up to {actual}"
+                ),
+                None => write!(
+                    f,
+                    "introduction declares {declared} bytes but its file sizes are invalid"
+                ),
+            },
+            Self::IntroductionTooLarge { size, max } => write!(
+                f,
+                "introduction of {size} bytes is over the {max} receivers accept, send fewer files"
+            ),
+            Self::FileUnreadable(path) => write!(f, "can't open {} to send it", path.display()),
+        }
+    }
+}
+
+/// Why an outbound transfer failed, reported to the frontend through
+/// TransferMetadata.error along with what the user can do about it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutboundError {
+    Declined,
+    // With the number of bytes the receiver would have needed
+    NotEnoughSpace(u64),
+    UnsupportedAttachment,
+    ConsentTimedOut,
+    HandshakeFailed,
+    // The receiver sent an alert during the handshake, see AppError::PeerAlert
+    HandshakeRejected(AlertType),
+    ConnectionLost,
+    // One of the files was moved or deleted after the introduction
+    FileUnreadable,
+}
+
+impl OutboundError {
+    // The session broke down while in the given state
+    pub fn interrupted_in(state: &State) -> Self {
+        match state {
+            State::Initial
+            | State::SentUkeyClientInit
+            | State::SentUkeyClientFinish
+            | State::SentPairedKeyEncryption
+            | State::SentPairedKeyResult => Self::HandshakeFailed,
+            _ => Self::ConnectionLost,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Declined => "peer_declined",
+            Self::NotEnoughSpace(_) => "peer_out_of_space",
+            Self::UnsupportedAttachment => "unsupported_attachment",
+            Self::ConsentTimedOut => "consent_timed_out",
+            Self::HandshakeFailed => "handshake_failed",
+            Self::HandshakeRejected(_) => "handshake_rejected",
+            Self::ConnectionLost => "connection_lost",
+            Self::FileUnreadable => "file_unreadable",
+        }
+    }
+
+    pub fn hint(&self) -> String {
+        match self {
+            Self::Declined => String::from("Peer declined, ask them to tap Accept"),
+            Self::NotEnoughSpace(bytes) => format!(
+                "Peer is out of disk space, they need to free up {} MB",
+                bytes.div_ceil(1024 * 1024).max(1)
+            ),
+            Self::UnsupportedAttachment => {
+                String::from("Peer can't receive this kind of file, try sending it another way")
+            }
+            Self::ConsentTimedOut => {
+                String::from("Peer didn't answer in time, make sure their screen is on and retry")
+            }
+            Self::HandshakeFailed => String::from(
+                "Couldn't establish a secure connection, ensure both devices are on the same network",
+            ),
+            Self::HandshakeRejected(alert) => format!(
+                "Peer rejected the secure connection ({alert:?}), make sure both devices are up to date"
+            ),
+            Self::ConnectionLost => {
+                String::from("Connection lost, keep both devices close and on the same network")
+            }
+            Self::FileUnreadable => {
+                String::from("A file was moved or deleted since it was picked, pick it again")
+            }
         }
     }
 }
+
+impl std::fmt::Display for OutboundError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.code(), self.hint())
+    }
+}
+
+impl From<OutboundError> for TransferError {
+    fn from(e: OutboundError) -> Self {
+        Self {
+            code: e.code().to_owned(),
+            hint: e.hint(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outbound_error_hints() {
+        let cases = [
+            (
+                OutboundError::Declined,
+                "peer_declined",
+                "Peer declined, ask them to tap Accept",
+            ),
+            (
+                OutboundError::NotEnoughSpace(300 * 1024 * 1024 + 1),
+                "peer_out_of_space",
+                "Peer is out of disk space, they need to free up 301 MB",
+            ),
+            (
+                OutboundError::UnsupportedAttachment,
+                "unsupported_attachment",
+                "Peer can't receive this kind of file, try sending it another way",
+            ),
+            (
+                OutboundError::ConsentTimedOut,
+                "consent_timed_out",
+                "Peer didn't answer in time, make sure their screen is on and retry",
+            ),
+            (
+                OutboundError::HandshakeFailed,
+                "handshake_failed",
+                "Couldn't establish a secure connection, ensure both devices are on the same network",
+            ),
+            (
+                OutboundError::HandshakeRejected(AlertType::BadHandshakeCipher),
+                "handshake_rejected",
+                "Peer rejected the secure connection (BadHandshakeCipher), make sure both devices are up to date",
+            ),
+            (
+                OutboundError::ConnectionLost,
+                "connection_lost",
+                "Connection lost, keep both devices close and on the same network",
+            ),
+            (
+                OutboundError::FileUnreadable,
+                "file_unreadable",
+                "A file was moved or deleted since it was picked, pick it again",
+            ),
+        ];
+
+        for (error, code, hint) in cases {
+            let reported = TransferError::from(error);
+            assert_eq!(reported.code, code);
+            assert_eq!(reported.hint, hint);
+        }
+
+        assert_eq!(
+            AppError::PeerAlert {
+                alert: AlertType::BadHandshakeCipher,
+                message: Some(String::from("no cipher in common")),
+            }
+            .to_string(),
+            "peer rejected the handshake: BadHandshakeCipher (no cipher in common)"
+        );
+
+        // Never less than a MB, even for a few bytes short
+        assert_eq!(
+            OutboundError::NotEnoughSpace(10).hint(),
+            "Peer is out of disk space, they need to free up 1 MB"
+        );
+        assert_eq!(
+            OutboundError::interrupted_in(&State::SentUkeyClientInit),
+            OutboundError::HandshakeFailed
+        );
+        assert_eq!(
+            OutboundError::interrupted_in(&State::SendingFiles),
+            OutboundError::ConnectionLost
+        );
+    }
+}
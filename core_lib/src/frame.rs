@@ -0,0 +1,141 @@
+use anyhow::anyhow;
+use prost::Message;
+
+use crate::location_nearby_connections::{offline_frame, v1_frame, OfflineFrame};
+use crate::securegcm::{ukey2_message, Ukey2Message};
+use crate::securemessage::SecureMessage;
+
+/// What a raw frame turned out to be, see try_parse_frame.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParsedFrame {
+    // Offline frames, the connection request included
+    Offline(OfflineFrame),
+    Ukey2(Ukey2Message),
+    // Encrypted, so nothing more can be said about it without the keys
+    Secure(SecureMessage),
+}
+
+/// Interpret the bytes of a single frame (without its 4-byte length
+/// prefix) without touching any session or doing any crypto. Meant for
+/// inspection, tests and fuzzing: garbage only ever yields an error.
+pub fn try_parse_frame(data: &[u8]) -> Result<ParsedFrame, anyhow::Error> {
+    if data.is_empty() {
+        return Err(anyhow!("Empty frame"));
+    }
+
+    // Protobuf happily decodes about anything, so each candidate must also
+    // be consistent with itself before being picked.
+    if let Ok(frame) = OfflineFrame::decode(data) {
+        if is_consistent_offline_frame(&frame) {
+            return Ok(ParsedFrame::Offline(frame));
+        }
+    }
+
+    if let Ok(msg) = Ukey2Message::decode(data) {
+        if msg.message_type() != ukey2_message::Type::UnknownDoNotUse && msg.message_data.is_some()
+        {
+            return Ok(ParsedFrame::Ukey2(msg));
+        }
+    }
+
+    if let Ok(smsg) = SecureMessage::decode(data) {
+        if !smsg.header_and_body.is_empty() && !smsg.signature.is_empty() {
+            return Ok(ParsedFrame::Secure(smsg));
+        }
+    }
+
+    Err(anyhow!("Not a known frame ({} bytes)", data.len()))
+}
+
+fn is_consistent_offline_frame(frame: &OfflineFrame) -> bool {
+    if frame.version() != offline_frame::Version::V1 {
+        return false;
+    }
+
+    let Some(v1) = frame.v1.as_ref() else {
+        return false;
+    };
+
+    match v1.r#type() {
+        v1_frame::FrameType::UnknownFrameType => false,
+        v1_frame::FrameType::ConnectionRequest => v1.connection_request.is_some(),
+        v1_frame::FrameType::ConnectionResponse => v1.connection_response.is_some(),
+        v1_frame::FrameType::PayloadTransfer => v1.payload_transfer.is_some(),
+        v1_frame::FrameType::BandwidthUpgradeNegotiation => {
+            v1.bandwidth_upgrade_negotiation.is_some()
+        }
+        v1_frame::FrameType::KeepAlive => v1.keep_alive.is_some(),
+        v1_frame::FrameType::Disconnection => v1.disconnection.is_some(),
+        v1_frame::FrameType::PairedKeyEncryption => v1.paired_key_encryption.is_some(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, RngCore};
+
+    use super::*;
+    use crate::location_nearby_connections::{KeepAliveFrame, V1Frame};
+
+    #[test]
+    fn test_parse_known_frames() {
+        let keepalive = OfflineFrame {
+            version: Some(offline_frame::Version::V1.into()),
+            v1: Some(V1Frame {
+                r#type: Some(v1_frame::FrameType::KeepAlive.into()),
+                keep_alive: Some(KeepAliveFrame { ack: Some(true) }),
+                ..Default::default()
+            }),
+        };
+        assert_eq!(
+            try_parse_frame(&keepalive.encode_to_vec()).unwrap(),
+            ParsedFrame::Offline(keepalive)
+        );
+
+        let client_init = Ukey2Message {
+            message_type: Some(ukey2_message::Type::ClientInit.into()),
+            message_data: Some(vec![0x08, 0x01]),
+        };
+        assert_eq!(
+            try_parse_frame(&client_init.encode_to_vec()).unwrap(),
+            ParsedFrame::Ukey2(client_init)
+        );
+
+        let secure = SecureMessage {
+            header_and_body: vec![1, 2, 3],
+            signature: vec![4, 5, 6],
+        };
+        assert_eq!(
+            try_parse_frame(&secure.encode_to_vec()).unwrap(),
+            ParsedFrame::Secure(secure)
+        );
+
+        assert!(try_parse_frame(&[]).is_err());
+    }
+
+    #[test]
+    fn test_parse_garbage() {
+        let mut rng = rand::thread_rng();
+
+        for _ in 0..10_000 {
+            let mut data = vec![0u8; rng.gen_range(0..256)];
+            rng.fill_bytes(&mut data);
+            // Only asserting it doesn't panic, random bytes may well be valid
+            let _ = try_parse_frame(&data);
+        }
+
+        // Every truncation of a valid frame must be handled as well
+        let keepalive = OfflineFrame {
+            version: Some(offline_frame::Version::V1.into()),
+            v1: Some(V1Frame {
+                r#type: Some(v1_frame::FrameType::KeepAlive.into()),
+                keep_alive: Some(KeepAliveFrame { ack: Some(false) }),
+                ..Default::default()
+            }),
+        }
+        .encode_to_vec();
+        for len in 0..keepalive.len() {
+            let _ = try_parse_frame(&keepalive[..len]);
+        }
+    }
+}
@@ -1,5 +1,7 @@
-use std::fs::File;
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
 use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
 use std::time::Duration;
 
 use anyhow::anyhow;
@@ -12,52 +14,121 @@ use p256::{EncodedPoint, PublicKey};
 use prost::Message;
 use rand::Rng;
 use sha2::{Digest, Sha256, Sha512};
-use tokio::io::AsyncWriteExt;
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::time::Instant;
 
-use super::{InnerState, State};
+use super::{
+    build_upgrade_failure, frame_rejection, keepalive_at, our_capabilities, peer_alert,
+    reject_frame, seal_frame, FilenameRewriter, InnerState, IntroductionLimits, PayloadKind,
+    PeerCapabilities, State, CANCEL_GRACE_PERIOD,
+};
 use crate::channel::{ChannelAction, ChannelDirection, ChannelMessage};
-use crate::hdl::info::{InternalFileInfo, TransferMetadata};
+use crate::frame::{try_parse_frame, ParsedFrame};
+use crate::hdl::info::{
+    CancelReason, CancellationKind, FrameRejection, InternalFileInfo, RejectReason,
+    TransferMetadata,
+};
 use crate::hdl::{TextPayloadInfo, TextPayloadType};
+use crate::location_nearby_connections::bandwidth_upgrade_negotiation_frame;
+use crate::location_nearby_connections::bandwidth_upgrade_negotiation_frame::upgrade_path_info::Medium;
 use crate::location_nearby_connections::payload_transfer_frame::{
     payload_header, PacketType, PayloadChunk, PayloadHeader,
 };
 use crate::location_nearby_connections::{KeepAliveFrame, OfflineFrame, PayloadTransferFrame};
 use crate::securegcm::ukey2_alert::AlertType;
 use crate::securegcm::{
-    ukey2_message, DeviceToDeviceMessage, GcmMetadata, Type, Ukey2Alert, Ukey2ClientFinished,
-    Ukey2ClientInit, Ukey2HandshakeCipher, Ukey2Message, Ukey2ServerInit,
+    ukey2_message, DeviceToDeviceMessage, Ukey2Alert, Ukey2ClientFinished, Ukey2ClientInit,
+    Ukey2HandshakeCipher, Ukey2Message, Ukey2ServerInit,
 };
 use crate::securemessage::{
-    EcP256PublicKey, EncScheme, GenericPublicKey, Header, HeaderAndBody, PublicKeyType,
-    SecureMessage, SigScheme,
+    EcP256PublicKey, GenericPublicKey, HeaderAndBody, PublicKeyType, SecureMessage,
 };
-use crate::sharing_nearby::{paired_key_result_frame, text_metadata};
+use crate::sharing_nearby::{paired_key_result_frame, text_metadata, IntroductionFrame};
 use crate::utils::{
-    encode_point, gen_ecdsa_keypair, gen_random, get_download_dir, hkdf_extract_expand,
-    stream_read_exact, to_four_digit_string, DeviceType, RemoteDeviceInfo,
+    bytes_per_second, decode_point, encode_point, gen_ecdsa_keypair, gen_random,
+    get_auto_accept_policy, get_consent_timeout, get_download_dir, get_filename_rewriter,
+    get_frame_rate_limit, get_introduction_limits, get_keepalive_interval, get_max_frame_length,
+    get_temp_dir, hash_prefix, hkdf_extract_expand, is_safe_relative_path, local_device_info,
+    move_file, preallocate, sanitize_file_name, sanitize_note, stream_read_exact,
+    to_four_digit_string, unique_path, RemoteDeviceInfo,
 };
 use crate::{location_nearby_connections, sharing_nearby};
 
 type HmacSha256 = Hmac<Sha256>;
 
-const SANE_FRAME_LENGTH: i32 = 5 * 1024 * 1024;
+// Text payloads above this are written to disk as they come instead of being buffered
+const BYTES_STREAM_THRESHOLD: i64 = 512 * 1024;
 const SANITY_DURATION: Duration = Duration::from_micros(10);
 
+// Generic over the transport like OutboundRequest
 #[derive(Debug)]
-pub struct InboundRequest {
-    socket: TcpStream,
+pub struct InboundRequest<S = TcpStream> {
+    endpoint_id: [u8; 4],
+    socket: S,
     pub state: InnerState,
     sender: Sender<ChannelMessage>,
     receiver: Receiver<ChannelMessage>,
+    filename_rewriter: Option<FilenameRewriter>,
+    // How long the user gets to accept or reject the transfer
+    consent_timeout: Duration,
+    // See RQS::set_max_frame_length
+    max_frame_length: usize,
+    // See RQS::set_introduction_limits
+    introduction_limits: IntroductionLimits,
+    // Where the files of this transfer go, see set_download_dir
+    download_dir: PathBuf,
+    // Set while waiting for that decision
+    consent_deadline: Option<Instant>,
+    // Only with a limit set, see RQS::set_frame_rate_limit
+    frame_limiter: Option<FrameRateLimiter>,
+    // See RQS::set_keepalive_interval
+    keepalive_interval: Option<Duration>,
+    // When the last frame went out
+    last_sent: Instant,
+}
+
+// Counts secure messages in one-second windows
+#[derive(Debug)]
+struct FrameRateLimiter {
+    limit: u32,
+    window_start: Instant,
+    frames: u32,
+}
+
+impl FrameRateLimiter {
+    fn new(limit: u32) -> Self {
+        Self {
+            limit,
+            window_start: Instant::now(),
+            frames: 0,
+        }
+    }
+
+    fn allow(&mut self) -> bool {
+        if self.window_start.elapsed() >= Duration::from_secs(1) {
+            self.window_start = Instant::now();
+            self.frames = 0;
+        }
+
+        self.frames += 1;
+        self.frames <= self.limit
+    }
 }
 
-impl InboundRequest {
-    pub fn new(socket: TcpStream, id: String, sender: Sender<ChannelMessage>) -> Self {
+impl<S: AsyncRead + AsyncWrite + Unpin> InboundRequest<S> {
+    pub fn new(
+        endpoint_id: [u8; 4],
+        socket: S,
+        id: String,
+        sender: Sender<ChannelMessage>,
+    ) -> Self {
         let receiver = sender.subscribe();
 
         Self {
+            endpoint_id,
             socket,
             state: InnerState {
                 id,
@@ -69,12 +140,29 @@ impl InboundRequest {
             },
             sender,
             receiver,
+            filename_rewriter: get_filename_rewriter(),
+            consent_timeout: get_consent_timeout(),
+            max_frame_length: get_max_frame_length(),
+            introduction_limits: get_introduction_limits(),
+            download_dir: get_download_dir(),
+            consent_deadline: None,
+            frame_limiter: get_frame_rate_limit().map(FrameRateLimiter::new),
+            keepalive_interval: get_keepalive_interval(),
+            last_sent: Instant::now(),
         }
     }
 
+    // Defaults to the one set with RQS::set_download_path when the request
+    // came in, changing that later doesn't move an ongoing transfer
+    pub fn set_download_dir(&mut self, dir: PathBuf) {
+        self.download_dir = dir;
+    }
+
     pub async fn handle(&mut self) -> Result<(), anyhow::Error> {
         // Buffer for the 4-byte length
         let mut length_buf = [0u8; 4];
+        let consent_deadline = self.consent_deadline.unwrap_or_else(Instant::now);
+        let keepalive = keepalive_at(self.keepalive_interval, self.last_sent, &self.state);
 
         tokio::select! {
             i = self.receiver.recv() => {
@@ -91,7 +179,7 @@ impl InboundRequest {
                         debug!("inbound: got: {:?}", channel_msg);
                         match channel_msg.action {
                             Some(ChannelAction::AcceptTransfer) => {
-                                self.accept_transfer().await?;
+                                self.accept_transfer(channel_msg.accepted_files).await?;
                             },
                             Some(ChannelAction::RejectTransfer) => {
                                 self.update_state(
@@ -106,17 +194,34 @@ impl InboundRequest {
                                 )).await?;
                                 return Err(anyhow!(crate::errors::AppError::NotAnError));
                             },
-                            Some(ChannelAction::CancelTransfer) => {
+                            Some(action @ (ChannelAction::CancelTransfer | ChannelAction::PeerOffline)) => {
+                                let reason = match action {
+                                    ChannelAction::PeerOffline => CancelReason::PeerOffline,
+                                    _ => CancelReason::User,
+                                };
+                                info!("Cancelling the transfer: {:?}", reason);
+                                let kind = self.cancel_with_grace().await;
                                 self.update_state(
                                     |e| {
                                         e.state = State::Cancelled;
+                                        if let Some(tmd) = e.transfer_metadata.as_mut() {
+                                            tmd.cancellation = Some(kind);
+                                            tmd.cancel_reason = Some(reason);
+                                        }
                                     },
                                     true,
                                 ).await;
-                                self.disconnection().await?;
                                 return Err(anyhow!(crate::errors::AppError::NotAnError));
                             },
-                            None => {
+                            // Only ever asked for by outbound transfers
+                            Some(
+                                ChannelAction::ConfirmPin
+                                | ChannelAction::RejectPin
+                                | ChannelAction::Pause
+                                | ChannelAction::Resume
+                                | ChannelAction::Proceed,
+                            )
+                            | None => {
                                 trace!("inbound: nothing to do")
                             },
                         }
@@ -129,7 +234,19 @@ impl InboundRequest {
             h = stream_read_exact(&mut self.socket, &mut length_buf) => {
                 h?;
 
-                self._handle(length_buf).await?
+                if let Err(e) = self._handle(length_buf).await {
+                    if let Some(rejection) = frame_rejection(&e, &self.state.state) {
+                        self.report_rejection(rejection);
+                    }
+                    return Err(e);
+                }
+            }
+            _ = tokio::time::sleep_until(consent_deadline), if self.consent_deadline.is_some() => {
+                return self.decline_unanswered().await;
+            }
+            _ = tokio::time::sleep_until(keepalive.unwrap_or_else(Instant::now)), if keepalive.is_some() => {
+                trace!("Idle, sending keepalive");
+                self.send_keepalive(false).await?;
             }
         }
 
@@ -139,14 +256,19 @@ impl InboundRequest {
     pub async fn _handle(&mut self, length_buf: [u8; 4]) -> Result<(), anyhow::Error> {
         let msg_length = u32::from_be_bytes(length_buf) as usize;
         // Ensure the message length is not unreasonably big to avoid allocation attacks
-        if msg_length > SANE_FRAME_LENGTH as usize {
+        if msg_length > self.max_frame_length {
             error!("Message length too big");
-            return Err(anyhow!("value"));
+            return Err(reject_frame(
+                None,
+                RejectReason::Oversized,
+                Some(msg_length.to_string()),
+            ));
         }
 
         // Allocate buffer for the actual message and read it
         let mut frame_data = vec![0u8; msg_length];
         stream_read_exact(&mut self.socket, &mut frame_data).await?;
+        self.state.wire_bytes += (msg_length + length_buf.len()) as u64;
 
         let current_state = &self.state;
         // Now determine what will be the request type based on current state
@@ -162,6 +284,7 @@ impl InboundRequest {
                     |e: &mut InnerState| {
                         e.state = State::ReceivedConnectionRequest;
                         e.remote_device_info = Some(rdi);
+                        e.handshake_started = Some(Instant::now());
                     },
                     false,
                 )
@@ -209,7 +332,22 @@ impl InboundRequest {
             }
             _ => {
                 debug!("Handling SecureMessage frame");
-                let smsg = SecureMessage::decode(&*frame_data)?;
+                if let Some(limiter) = self.frame_limiter.as_mut() {
+                    if !limiter.allow() {
+                        warn!("Too many secure messages, dropping the connection");
+                        return Err(anyhow!(crate::errors::AppError::ProcessingRateExceeded(
+                            limiter.limit
+                        )));
+                    }
+                }
+
+                let smsg = match SecureMessage::decode(&*frame_data) {
+                    Ok(smsg) => smsg,
+                    Err(_) if is_connection_request(&frame_data) => {
+                        return Err(unexpected_connection_request());
+                    }
+                    Err(e) => return Err(e.into()),
+                };
                 self.decrypt_and_process_secure_message(&smsg).await?;
             }
         }
@@ -228,10 +366,11 @@ impl InboundRequest {
 
         if v1_frame.r#type() != location_nearby_connections::v1_frame::FrameType::ConnectionRequest
         {
-            return Err(anyhow!(format!(
-                "Unexpected frame type: {:?}",
-                v1_frame.r#type()
-            )));
+            return Err(reject_frame(
+                Some("ConnectionRequest"),
+                RejectReason::Unexpected,
+                Some(format!("{:?}", v1_frame.r#type())),
+            ));
         }
 
         let connection_request = v1_frame
@@ -239,43 +378,27 @@ impl InboundRequest {
             .as_ref()
             .ok_or_else(|| anyhow!("Missing required fields"))?;
 
+        check_not_self_connection(&self.endpoint_id, connection_request.endpoint_id())?;
+
         let endpoint_info = connection_request
             .endpoint_info
             .as_ref()
             .ok_or_else(|| anyhow!("Missing endpoint info"))?;
 
-        // Check if endpoint info length is greater than 17
-        if endpoint_info.len() <= 17 {
-            return Err(anyhow!("Endpoint info too short"));
-        }
-
-        let device_name_length = endpoint_info[17] as usize;
-        // Validate length including device name
-        if endpoint_info.len() < device_name_length + 18 {
-            return Err(anyhow!(
-                "Endpoint info too short to contain the device name"
-            ));
-        }
-
-        // Extract and validate device name based on length
-        let device_name = std::str::from_utf8(&endpoint_info[18..(18 + device_name_length)])
-            .map_err(|_| anyhow!("Device name is not valid UTF-8"))?;
-
-        // Parsing the device type
-        let raw_device_type = (endpoint_info[0] & 7) >> 1_usize;
-
-        Ok(RemoteDeviceInfo {
-            name: device_name.to_string(),
-            device_type: DeviceType::from_raw_value(raw_device_type),
-        })
+        RemoteDeviceInfo::deserialize(endpoint_info)
     }
 
     async fn process_ukey2_client_init(&mut self, msg: &Ukey2Message) -> Result<(), anyhow::Error> {
+        if let Some(e) = peer_alert(msg) {
+            return Err(e);
+        }
+
         if msg.message_type() != ukey2_message::Type::ClientInit {
             self.send_ukey2_alert(AlertType::BadMessageType).await?;
-            return Err(anyhow!(
-                "UKey2: message_type({:?}) != ClientInit",
-                msg.message_type
+            return Err(reject_frame(
+                Some("Ukey2ClientInit"),
+                RejectReason::Unexpected,
+                Some(format!("{:?}", msg.message_type())),
             ));
         }
 
@@ -375,18 +498,24 @@ impl InboundRequest {
         msg: &Ukey2Message,
         frame_data: &Vec<u8>,
     ) -> Result<(), anyhow::Error> {
+        if let Some(e) = peer_alert(msg) {
+            return Err(e);
+        }
+
         if msg.message_type() != ukey2_message::Type::ClientFinish {
             self.send_ukey2_alert(AlertType::BadMessageType).await?;
-            return Err(anyhow!(
-                "UKey2: message_type({:?}) != ClientFinish",
-                msg.message_type
+            return Err(reject_frame(
+                Some("Ukey2ClientFinish"),
+                RejectReason::Unexpected,
+                Some(format!("{:?}", msg.message_type())),
             ));
         }
 
         let sha512 = Sha512::digest(frame_data);
-        if self.state.cipher_commitment.as_ref().unwrap().commitment() != sha512.as_slice() {
+        let commitment = self.state.cipher_commitment.as_ref().unwrap().commitment();
+        if !bool::from(commitment.ct_eq(sha512.as_slice())) {
             error!("cipher_commitment isn't equals to sha512(frame_data)");
-            return Err(anyhow!("UKey2: cipher_commitment != sha512"));
+            return Err(anyhow!(crate::errors::AppError::CommitmentMismatch));
         }
 
         let client_finish = match Ukey2ClientFinished::decode(msg.message_data()) {
@@ -423,10 +552,11 @@ impl InboundRequest {
 
         if v1_frame.r#type() != location_nearby_connections::v1_frame::FrameType::ConnectionResponse
         {
-            return Err(anyhow!(format!(
-                "Unexpected frame type: {:?}",
-                v1_frame.r#type()
-            )));
+            return Err(reject_frame(
+                Some("ConnectionResponse"),
+                RejectReason::Unexpected,
+                Some(format!("{:?}", v1_frame.r#type())),
+            ));
         }
 
         let response = location_nearby_connections::OfflineFrame {
@@ -470,13 +600,13 @@ impl InboundRequest {
     ) -> Result<(), anyhow::Error> {
         let mut hmac = HmacSha256::new_from_slice(self.state.recv_hmac_key.as_ref().unwrap())?;
         hmac.update(&smsg.header_and_body);
-        if !hmac
-            .finalize()
-            .into_bytes()
-            .as_slice()
-            .eq(smsg.signature.as_slice())
-        {
-            return Err(anyhow!("hmac!=signature"));
+        // verify_slice compares in constant time, unlike a plain slice eq
+        if hmac.verify_slice(&smsg.signature).is_err() {
+            return Err(reject_frame(
+                Some("SecureMessage"),
+                RejectReason::BadHmac,
+                None,
+            ));
         }
 
         let header_and_body = HeaderAndBody::decode(&*smsg.header_and_body)?;
@@ -492,10 +622,10 @@ impl InboundRequest {
 
         let seq = self.get_client_seq_inc().await;
         if d2d_msg.sequence_number() != seq {
-            return Err(anyhow!(
-                "Error d2d_msg.sequence_number invalid ({} vs {})",
-                d2d_msg.sequence_number(),
-                seq
+            return Err(reject_frame(
+                Some("SecureMessage"),
+                RejectReason::BadSequence,
+                Some(format!("{} (expected {})", d2d_msg.sequence_number(), seq)),
             ));
         }
 
@@ -525,15 +655,16 @@ impl InboundRequest {
                     payload_header::PayloadType::Bytes => {
                         info!("Processing PayloadType::Bytes");
                         let payload_id = header.id();
+                        check_payload_id(&self.state, payload_id, false)?;
 
-                        if header.total_size() > SANE_FRAME_LENGTH.into() {
-                            self.state.payload_buffers.remove(&payload_id);
-                            return Err(anyhow!(
-                                "Payload too large: {} bytes",
-                                header.total_size()
-                            ));
+                        if self.state.payload_sinks.contains_key(&payload_id) {
+                            return self.write_to_sink(payload_id, chunk).await;
                         }
 
+                        // Reject it before buffering anything if it's announced too large
+                        self.check_buffered_size(payload_id, header.total_size())
+                            .await?;
+
                         self.state
                             .payload_buffers
                             .entry(payload_id)
@@ -550,13 +681,23 @@ impl InboundRequest {
                             ));
                         }
 
+                        // The announced total_size is only the peer's word, the bytes
+                        // actually piling up are held to the same cap
+                        let body = chunk.body();
+                        self.check_buffered_size(payload_id, (buffer_len + body.len()) as i64)
+                            .await?;
+
                         let buffer = self.state.payload_buffers.get_mut(&payload_id).unwrap();
-                        if let Some(body) = &chunk.body {
-                            buffer.extend(body);
-                        }
+                        buffer.extend(body);
 
                         if (chunk.flags() & 1) == 1 {
                             debug!("Chunk flags & 1 == 1 ?? End of data ??");
+                            let buffer = self
+                                .state
+                                .payload_buffers
+                                .remove(&payload_id)
+                                .unwrap_or_default();
+                            self.state.completed_payloads.insert(payload_id);
 
                             if self.state.text_payload.is_some()
                                 && self.state.text_payload.as_ref().unwrap().get_i64_value()
@@ -626,14 +767,18 @@ impl InboundRequest {
                     payload_header::PayloadType::File => {
                         info!("Processing PayloadType::File");
                         let payload_id = header.id();
+                        check_payload_id(&self.state, payload_id, true)?;
+
+                        let Some(file_internal) = self.state.transferred_files.get_mut(&payload_id)
+                        else {
+                            // Left out by the user, but the sender may not support partial acceptance
+                            if self.state.file_order.contains(&payload_id) {
+                                trace!("Dropping a chunk of skipped file {payload_id}");
+                                return Ok(());
+                            }
 
-                        let file_internal = self
-                            .state
-                            .transferred_files
-                            .get_mut(&payload_id)
-                            .ok_or_else(|| {
-                                anyhow!("File payload ID ({}) is not known", payload_id)
-                            })?;
+                            return Err(anyhow!("File payload ID ({}) is not known", payload_id));
+                        };
 
                         let current_offset = file_internal.bytes_transferred;
                         if chunk.offset() != current_offset {
@@ -658,6 +803,9 @@ impl InboundRequest {
                                 .unwrap()
                                 .write_all_at(chunk.body(), current_offset as u64)?;
                             file_internal.bytes_transferred += chunk_size as i64;
+                            if let Some(digest) = file_internal.digest.as_mut() {
+                                digest.update(chunk.body());
+                            }
 
                             self.update_state(
                                 |e| {
@@ -668,10 +816,44 @@ impl InboundRequest {
                                 true,
                             )
                             .await;
-                        } else if (chunk.flags() & 1) == 1 {
-                            self.state.transferred_files.remove(&payload_id);
+                        }
+
+                        // Small files may come whole, in a single last chunk
+                        if (chunk.flags() & 1) == 1 {
+                            if let Some(fi) = self.state.transferred_files.remove(&payload_id) {
+                                let saved = match finalize_received_file(
+                                    fi,
+                                    chunk.sha256_digest.as_deref(),
+                                ) {
+                                    Ok(saved) => saved,
+                                    Err(e) => {
+                                        if let Some(crate::errors::AppError::DigestMismatch(_)) =
+                                            e.downcast_ref()
+                                        {
+                                            self.send_digest_mismatch(payload_id).await?;
+                                        }
+                                        return Err(e);
+                                    }
+                                };
+                                let saved = std::fs::canonicalize(&saved).unwrap_or(saved);
+                                self.update_state(
+                                    |e| {
+                                        if let Some(tmd) = e.transfer_metadata.as_mut() {
+                                            tmd.saved_files
+                                                .get_or_insert_with(Vec::new)
+                                                .push(saved.to_string_lossy().into_owned());
+                                        }
+                                    },
+                                    false,
+                                )
+                                .await;
+                                self.send_transfer_complete(Some(payload_id)).await?;
+                            }
+                            self.state.completed_payloads.insert(payload_id);
+
                             if self.state.transferred_files.is_empty() {
                                 info!("Transfer finished");
+                                self.send_transfer_complete(None).await?;
                                 self.update_state(
                                     |e| {
                                         e.state = State::Finished;
@@ -696,8 +878,18 @@ impl InboundRequest {
                 }
             }
             location_nearby_connections::v1_frame::FrameType::KeepAlive => {
-                trace!("Sending keepalive");
-                self.send_keepalive(true).await?;
+                // Acking an ack back would ping-pong
+                if !v1_frame.keep_alive.as_ref().is_some_and(|k| k.ack()) {
+                    trace!("Sending keepalive");
+                    self.send_keepalive(true).await?;
+                }
+            }
+            location_nearby_connections::v1_frame::FrameType::BandwidthUpgradeNegotiation => {
+                trace!("Received FrameType::BandwidthUpgradeNegotiation");
+                self.process_bandwidth_upgrade(v1_frame).await?;
+            }
+            location_nearby_connections::v1_frame::FrameType::ConnectionRequest => {
+                return Err(unexpected_connection_request());
             }
             _ => {
                 error!("Unhandled offline frame encrypted: {:?}", offline);
@@ -729,6 +921,17 @@ impl InboundRequest {
             return Err(anyhow!(crate::errors::AppError::NotAnError));
         }
 
+        // Other Android versions send frames we don't know or need, these
+        // mustn't be mistaken for the one the current state is waiting for
+        if matches!(
+            v1_frame.r#type(),
+            sharing_nearby::v1_frame::FrameType::UnknownFrameType
+                | sharing_nearby::v1_frame::FrameType::CertificateInfo
+        ) {
+            debug!("Ignoring frame of type {:?}", v1_frame.r#type);
+            return Ok(());
+        }
+
         match self.state.state {
             State::SentConnectionResponse => {
                 debug!("Processing State::SentConnectionResponse");
@@ -811,42 +1014,60 @@ impl InboundRequest {
             .as_ref()
             .ok_or_else(|| anyhow!("Missing required fields"))?;
 
+        if let Err(e) = validate_introduction_entries(introduction, &self.introduction_limits)
+            .and_then(|_| check_introduction_payload_ids(introduction, &self.state))
+            .and_then(|_| check_introduction_total(introduction))
+        {
+            self.reject_transfer(None).await?;
+            return Err(e);
+        }
+
+        let note = introduction.note.as_deref().and_then(sanitize_note);
+        let auto_accept_policy = get_auto_accept_policy();
+        let peer_capabilities = PeerCapabilities::from_advertised(introduction.capabilities());
+        debug!("Peer capabilities: {:?}", peer_capabilities);
+
         // No need to inform the channel here, we'll do it anyway with files info
         self.update_state(
             |e| {
                 e.state = State::WaitingForUserConsent;
+                e.peer_capabilities = peer_capabilities;
             },
             false,
         )
         .await;
 
-        if !introduction.file_metadata.is_empty() && introduction.text_metadata.is_empty() {
+        // The last part of a split directory may hold nothing but empty folders
+        let has_folders = peer_capabilities.empty_folders && !introduction.empty_folders.is_empty();
+        if (!introduction.file_metadata.is_empty() || has_folders)
+            && introduction.text_metadata.is_empty()
+        {
             trace!("process_introduction: handling file_metadata");
             let mut files_name = Vec::with_capacity(introduction.file_metadata.len());
             let mut total_bytes: u64 = 0;
 
             for file in &introduction.file_metadata {
                 info!("File name: {}", file.name());
+                let name = sanitize_file_name(file.name());
 
-                let mut dest = get_download_dir();
-                dest.push(file.name());
-
+                let saved_name = match (&self.filename_rewriter, &self.state.remote_device_info) {
+                    (Some(rewriter), Some(rdi)) => rewriter.rewrite(&name, rdi),
+                    _ => name.clone(),
+                };
+                // Recreating the tree of a directory sent as a whole, as long
+                // as it stays within the download directory
+                let mut dest = self.download_dir.clone();
+                match file.parent_folder.as_deref() {
+                    Some(folder) if is_safe_relative_path(folder) => dest.push(folder),
+                    Some(folder) => warn!("Ignoring unsafe parent folder {:?}", folder),
+                    None => {}
+                }
+                dest.push(saved_name);
+                let dest = unique_path(dest);
                 info!("Destination: {:?}", dest);
-                if dest.exists() {
-                    let mut counter = 1;
-                    dest.pop();
-
-                    loop {
-                        dest.push(format!("{}_{}", counter, file.name()));
-                        if !dest.exists() {
-                            break;
-                        }
-                        dest.pop();
-                        counter += 1;
-                    }
 
-                    info!("New destination: {:?}", dest);
-                }
+                let mut temp_url = get_temp_dir().unwrap_or_else(|| self.download_dir.clone());
+                temp_url.push(format!("{}.{}.part", file.payload_id(), name));
 
                 let info = InternalFileInfo {
                     payload_id: file.payload_id(),
@@ -854,36 +1075,63 @@ impl InboundRequest {
                     bytes_transferred: 0,
                     total_size: file.size(),
                     file: None,
+                    temp_url: Some(temp_url),
+                    digest: Some(Sha256::new()),
                 };
                 total_bytes += info.total_size as u64;
                 self.state.transferred_files.insert(file.payload_id(), info);
-                files_name.push(file.name().to_owned());
+                self.state.file_order.push(file.payload_id());
+                self.state
+                    .introduced_files
+                    .insert(file.payload_id(), (name.clone(), file.size().max(0) as u64));
+                files_name.push(name);
+            }
+
+            // Only heeded from a peer that says it sends them
+            if self.state.peer_capabilities.empty_folders {
+                for folder in &introduction.empty_folders {
+                    if is_safe_relative_path(folder) {
+                        self.state
+                            .empty_folders
+                            .push(self.download_dir.join(folder));
+                    } else {
+                        warn!("Ignoring unsafe empty folder {:?}", folder);
+                    }
+                }
             }
 
             let metadata = TransferMetadata {
                 id: self.state.id.clone(),
                 destination: Some(
-                    get_download_dir()
+                    self.download_dir
+                        .clone()
                         .into_os_string()
                         .into_string()
                         .map_err(|_| anyhow!("failed to convert PathBuf to String"))?,
                 ),
                 source: self.state.remote_device_info.clone(),
                 files: Some(files_name),
+                note: note.clone(),
                 pin_code: self.state.pin_code.clone(),
                 text_description: None,
                 total_bytes,
                 ..Default::default()
             };
 
+            let auto_accept = auto_accept_policy.should_auto_accept(PayloadKind::File, total_bytes);
             info!("Asking for user consent: {:?}", metadata);
             self.update_state(
                 |e| {
                     e.transfer_metadata = Some(metadata);
                 },
-                true,
+                !auto_accept,
             )
             .await;
+
+            if auto_accept {
+                info!("Auto-accepting the transfer");
+                self.accept_transfer(None).await?;
+            }
         } else if introduction.text_metadata.len() == 1 {
             trace!("process_introduction: handling text_metadata");
             let meta = introduction.text_metadata.first().unwrap();
@@ -895,20 +1143,28 @@ impl InboundRequest {
                         destination: None,
                         source: self.state.remote_device_info.clone(),
                         files: None,
+                        note: note.clone(),
                         pin_code: self.state.pin_code.clone(),
                         text_description: meta.text_title.clone(),
                         ..Default::default()
                     };
 
+                    let auto_accept = auto_accept_policy
+                        .should_auto_accept(PayloadKind::Url, meta.size().max(0) as u64);
                     info!("Asking for user consent: {:?}", metadata);
                     self.update_state(
                         |e| {
                             e.text_payload = Some(TextPayloadInfo::Url(meta.payload_id()));
                             e.transfer_metadata = Some(metadata);
                         },
-                        true,
+                        !auto_accept,
                     )
                     .await;
+
+                    if auto_accept {
+                        info!("Auto-accepting the transfer");
+                        self.accept_transfer(None).await?;
+                    }
                 }
                 text_metadata::Type::PhoneNumber
                 | text_metadata::Type::Address
@@ -918,20 +1174,49 @@ impl InboundRequest {
                         destination: None,
                         source: self.state.remote_device_info.clone(),
                         files: None,
+                        note: note.clone(),
                         pin_code: self.state.pin_code.clone(),
                         text_description: meta.text_title.clone(),
                         ..Default::default()
                     };
 
+                    if meta.size() > BYTES_STREAM_THRESHOLD {
+                        let mut file_url = self.download_dir.clone();
+                        file_url.push(format!("text_{}.txt", meta.payload_id()));
+                        let mut temp_url =
+                            get_temp_dir().unwrap_or_else(|| self.download_dir.clone());
+                        temp_url.push(format!("{}.text.part", meta.payload_id()));
+
+                        self.state.payload_sinks.insert(
+                            meta.payload_id(),
+                            InternalFileInfo {
+                                payload_id: meta.payload_id(),
+                                file_url,
+                                bytes_transferred: 0,
+                                total_size: meta.size(),
+                                file: None,
+                                temp_url: Some(temp_url),
+                                digest: None,
+                            },
+                        );
+                    }
+
+                    let auto_accept = auto_accept_policy
+                        .should_auto_accept(PayloadKind::Text, meta.size().max(0) as u64);
                     info!("Asking for user consent: {:?}", metadata);
                     self.update_state(
                         |e| {
                             e.text_payload = Some(TextPayloadInfo::Text(meta.payload_id()));
                             e.transfer_metadata = Some(metadata);
                         },
-                        true,
+                        !auto_accept,
                     )
                     .await;
+
+                    if auto_accept {
+                        info!("Auto-accepting the transfer");
+                        self.accept_transfer(None).await?;
+                    }
                 }
                 text_metadata::Type::Unknown => {
                     // Reject transfer
@@ -950,11 +1235,13 @@ impl InboundRequest {
                 destination: None,
                 source: self.state.remote_device_info.clone(),
                 files: None,
+                note,
                 pin_code: self.state.pin_code.clone(),
                 text_description: meta.ssid.clone(),
                 ..Default::default()
             };
 
+            let auto_accept = auto_accept_policy.should_auto_accept(PayloadKind::Wifi, 0);
             self.update_state(
                 |e| {
                     e.text_payload = Some(TextPayloadInfo::Wifi((
@@ -963,9 +1250,14 @@ impl InboundRequest {
                     )));
                     e.transfer_metadata = Some(metadata);
                 },
-                true,
+                !auto_accept,
             )
             .await;
+
+            if auto_accept {
+                info!("Auto-accepting the transfer");
+                self.accept_transfer(None).await?;
+            }
         } else {
             // Reject transfer
             self.reject_transfer(Some(
@@ -974,9 +1266,125 @@ impl InboundRequest {
             .await?;
         }
 
+        // Unless auto-accepted, don't leave the sender hanging forever
+        if self.state.state == State::WaitingForUserConsent {
+            self.consent_deadline = Some(Instant::now() + self.consent_timeout);
+        }
+
+        Ok(())
+    }
+
+    // Neither accepted nor rejected within consent_timeout
+    async fn decline_unanswered(&mut self) -> Result<(), anyhow::Error> {
+        info!(
+            "No answer within {:?}, declining the transfer",
+            self.consent_timeout
+        );
+        self.consent_deadline = None;
+        self.update_state(
+            |e| {
+                e.state = State::Rejected;
+                if let Some(tmd) = e.transfer_metadata.as_mut() {
+                    tmd.cancel_reason = Some(CancelReason::ConsentTimeout);
+                }
+            },
+            true,
+        )
+        .await;
+
+        self.reject_transfer(Some(
+            sharing_nearby::connection_response_frame::Status::TimedOut,
+        ))
+        .await?;
+        self.disconnection().await?;
+        Err(anyhow!(crate::errors::AppError::NotAnError))
+    }
+
+    // Bytes payloads are held in memory until complete, so they must fit in
+    // a frame, and in the tighter introduction cap when that's the one
+    // expected. Drops what was buffered for the payload when they don't.
+    async fn check_buffered_size(
+        &mut self,
+        payload_id: i64,
+        size: i64,
+    ) -> Result<(), anyhow::Error> {
+        if size > self.max_frame_length as i64 {
+            self.state.payload_buffers.remove(&payload_id);
+            return Err(reject_frame(
+                Some("PayloadTransfer"),
+                RejectReason::Oversized,
+                Some(size.to_string()),
+            ));
+        }
+
+        // The only bytes payload expected in this state is the introduction
+        if self.state.state == State::ReceivedPairedKeyResult {
+            if let Err(e) = validate_introduction_size(size, &self.introduction_limits) {
+                self.state.payload_buffers.remove(&payload_id);
+                self.reject_transfer(None).await?;
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn process_bandwidth_upgrade(
+        &mut self,
+        v1_frame: &location_nearby_connections::V1Frame,
+    ) -> Result<(), anyhow::Error> {
+        let negotiation = v1_frame
+            .bandwidth_upgrade_negotiation
+            .as_ref()
+            .ok_or_else(|| anyhow!("Missing required fields"))?;
+
+        match negotiation.event_type() {
+            bandwidth_upgrade_negotiation_frame::EventType::UpgradePathAvailable => {
+                let medium = negotiation
+                    .upgrade_path_info
+                    .as_ref()
+                    .map(|i| i.medium())
+                    .unwrap_or(Medium::UnknownMedium);
+
+                // Only the sender follows upgrades, see UpgradePolicy. Tell the
+                // peer right away so it doesn't wait for an upgrade that will
+                // never happen, we keep going on the current medium.
+                info!("Declining bandwidth upgrade to {:?}", medium);
+                self.encrypt_and_send(&build_upgrade_failure(medium))
+                    .await?;
+            }
+            _ => {
+                debug!(
+                    "Ignoring bandwidth upgrade event: {:?}",
+                    negotiation.event_type()
+                );
+            }
+        }
+
         Ok(())
     }
 
+    async fn cancel_with_grace(&mut self) -> CancellationKind {
+        if let Err(e) = self.disconnection().await {
+            warn!("Couldn't send the disconnection frame: {}", e);
+            let _ = self.socket.shutdown().await;
+            return CancellationKind::Forced;
+        }
+
+        // Give the peer a chance to close its side (or answer) before tearing
+        // the socket down ourselves, so that an unresponsive peer can't hold us.
+        let mut buf = [0u8; 1];
+        let kind = match tokio::time::timeout(CANCEL_GRACE_PERIOD, self.socket.read(&mut buf)).await
+        {
+            Ok(Ok(_)) => CancellationKind::Clean,
+            _ => CancellationKind::Forced,
+        };
+        debug!("Cancellation completed: {:?}", kind);
+
+        let _ = self.socket.shutdown().await;
+        kind
+    }
+
     async fn disconnection(&mut self) -> Result<(), anyhow::Error> {
         let frame = location_nearby_connections::OfflineFrame {
             version: Some(location_nearby_connections::offline_frame::Version::V1.into()),
@@ -998,23 +1406,97 @@ impl InboundRequest {
         }
     }
 
-    async fn accept_transfer(&mut self) -> Result<(), anyhow::Error> {
+    async fn accept_transfer(
+        &mut self,
+        selection: Option<Vec<usize>>,
+    ) -> Result<(), anyhow::Error> {
+        self.consent_deadline = None;
+        let mut accepted_payload_ids = vec![];
+        if let Some(selection) = selection.filter(|_| !self.state.file_order.is_empty()) {
+            accepted_payload_ids = selection
+                .iter()
+                .filter_map(|i| self.state.file_order.get(*i).cloned())
+                .collect();
+
+            if accepted_payload_ids.is_empty() {
+                info!("None of the offered files were selected, rejecting");
+                self.update_state(
+                    |e| {
+                        e.state = State::Rejected;
+                    },
+                    true,
+                )
+                .await;
+                self.reject_transfer(None).await?;
+                return Err(anyhow!(crate::errors::AppError::NotAnError));
+            }
+
+            self.skip_unselected_files(&accepted_payload_ids).await;
+
+            if !self.state.peer_capabilities.selective_accept {
+                // Everything will come anyway, the chunks of the skipped
+                // files are just dropped on arrival
+                accepted_payload_ids.clear();
+            }
+        }
+
         let ids: Vec<i64> = self.state.transferred_files.keys().cloned().collect();
+        let resume = self.state.peer_capabilities.resume;
+        let mut resume_offsets = HashMap::new();
 
         for id in ids {
             let mfi = self.state.transferred_files.get_mut(&id).unwrap();
 
-            let file = File::create(&mfi.file_url)?;
+            let file = match resume.then(|| resume_partial_file(mfi)).flatten() {
+                Some(file) => {
+                    info!("Resuming {:?} from {}", mfi.file_url, mfi.bytes_transferred);
+                    resume_offsets.insert(id, mfi.bytes_transferred);
+                    file
+                }
+                None => File::create(mfi.temp_url.as_ref().unwrap_or(&mfi.file_url))?,
+            };
             info!("Created file: {:?}", &file);
+            let reserved = preallocate(&file, mfi.total_size as u64);
             mfi.file = Some(file);
+
+            if let Err(err) = reserved {
+                error!("No space left for {:?}: {err}", mfi.file_url);
+                self.update_state(
+                    |e| {
+                        e.state = State::Rejected;
+                    },
+                    true,
+                )
+                .await;
+                self.reject_transfer(Some(
+                    sharing_nearby::connection_response_frame::Status::NotEnoughSpace,
+                ))
+                .await?;
+                return Err(err.into());
+            }
+        }
+
+        for sink in self.state.payload_sinks.values_mut() {
+            sink.file = Some(File::create(
+                sink.temp_url.as_ref().unwrap_or(&sink.file_url),
+            )?);
+        }
+
+        for folder in &self.state.empty_folders {
+            std::fs::create_dir_all(folder)?;
         }
 
+        let resumed_bytes: i64 = resume_offsets.values().sum();
         let frame = sharing_nearby::Frame {
             version: Some(sharing_nearby::frame::Version::V1.into()),
             v1: Some(sharing_nearby::V1Frame {
                 r#type: Some(sharing_nearby::v1_frame::FrameType::Response.into()),
                 connection_response: Some(sharing_nearby::ConnectionResponseFrame {
                     status: Some(sharing_nearby::connection_response_frame::Status::Accept.into()),
+                    accepted_payload_ids,
+                    capabilities: our_capabilities(),
+                    endpoint_info: Some(local_device_info().serialize()),
+                    resume_offsets,
                 }),
                 ..Default::default()
             }),
@@ -1025,6 +1507,10 @@ impl InboundRequest {
         self.update_state(
             |e| {
                 e.state = State::ReceivingFiles;
+                e.transfer_started = Some(Instant::now());
+                if let Some(tmd) = e.transfer_metadata.as_mut() {
+                    tmd.ack_bytes += resumed_bytes as u64;
+                }
             },
             true,
         )
@@ -1033,6 +1519,160 @@ impl InboundRequest {
         Ok(())
     }
 
+    // Large bytes payloads go straight to disk. Each chunk comes from a frame
+    // whose HMAC was already checked, nothing unverified is ever written.
+    async fn write_to_sink(
+        &mut self,
+        payload_id: i64,
+        chunk: &PayloadChunk,
+    ) -> Result<(), anyhow::Error> {
+        let sink = self.state.payload_sinks.get_mut(&payload_id).unwrap();
+        if chunk.offset() != sink.bytes_transferred {
+            return Err(anyhow!(
+                "Unexpected chunk offset: {}, expected: {}",
+                chunk.offset(),
+                sink.bytes_transferred
+            ));
+        }
+
+        let body = chunk.body();
+        if sink.bytes_transferred + body.len() as i64 > sink.total_size {
+            return Err(anyhow!(
+                "Payload exceeds its announced size: {} vs {}",
+                sink.bytes_transferred + body.len() as i64,
+                sink.total_size
+            ));
+        }
+
+        if !body.is_empty() {
+            sink.file
+                .as_ref()
+                .ok_or_else(|| anyhow!("Payload {} wasn't accepted", payload_id))?
+                .write_all_at(body, sink.bytes_transferred as u64)?;
+            sink.bytes_transferred += body.len() as i64;
+
+            self.update_state(
+                |e| {
+                    if let Some(tmd) = e.transfer_metadata.as_mut() {
+                        tmd.ack_bytes += body.len() as u64;
+                    }
+                },
+                true,
+            )
+            .await;
+        }
+
+        if (chunk.flags() & 1) == 0 {
+            return Ok(());
+        }
+
+        let sink = self.state.payload_sinks.remove(&payload_id).unwrap();
+        let destination = finalize_received_file(sink, None)?
+            .to_string_lossy()
+            .into_owned();
+        self.state.completed_payloads.insert(payload_id);
+        self.send_transfer_complete(Some(payload_id)).await?;
+
+        info!("Transfer finished");
+        self.send_transfer_complete(None).await?;
+        self.update_state(
+            |e| {
+                e.state = State::Finished;
+                if let Some(tmd) = e.transfer_metadata.as_mut() {
+                    tmd.text_type = Some(TextPayloadType::Text);
+                    tmd.destination = Some(destination);
+                }
+            },
+            true,
+        )
+        .await;
+        self.disconnection().await?;
+        Err(anyhow!(crate::errors::AppError::NotAnError))
+    }
+
+    // Tell the sender a payload (or the whole session, when None) really made
+    // it to disk. Only sent to senders that advertised they understand it.
+    async fn send_transfer_complete(
+        &mut self,
+        payload_id: Option<i64>,
+    ) -> Result<(), anyhow::Error> {
+        if !self.state.peer_capabilities.transfer_complete {
+            return Ok(());
+        }
+
+        self.send_completion(sharing_nearby::TransferCompleteFrame {
+            payload_id,
+            session_complete: Some(payload_id.is_none()),
+            ..Default::default()
+        })
+        .await
+    }
+
+    // Lets the sender know which file was corrupted on the way, before the
+    // connection is dropped
+    async fn send_digest_mismatch(&mut self, payload_id: i64) -> Result<(), anyhow::Error> {
+        if !self.state.peer_capabilities.transfer_complete {
+            return Ok(());
+        }
+
+        self.send_completion(sharing_nearby::TransferCompleteFrame {
+            payload_id: Some(payload_id),
+            digest_mismatch: Some(true),
+            ..Default::default()
+        })
+        .await
+    }
+
+    async fn send_completion(
+        &mut self,
+        complete: sharing_nearby::TransferCompleteFrame,
+    ) -> Result<(), anyhow::Error> {
+        let frame = sharing_nearby::Frame {
+            version: Some(sharing_nearby::frame::Version::V1.into()),
+            v1: Some(sharing_nearby::V1Frame {
+                r#type: Some(sharing_nearby::v1_frame::FrameType::TransferComplete.into()),
+                transfer_complete: Some(complete),
+                ..Default::default()
+            }),
+        };
+
+        self.send_encrypted_frame(&frame).await
+    }
+
+    // Forget about the files the user didn't pick. The sender is told through
+    // the accepted payload ids and won't send them, if it supports that.
+    async fn skip_unselected_files(&mut self, accepted: &[i64]) {
+        let skipped: Vec<i64> = self
+            .state
+            .file_order
+            .iter()
+            .filter(|id| !accepted.contains(id))
+            .cloned()
+            .collect();
+
+        self.update_state(
+            |e| {
+                let mut skipped_names = vec![];
+                let mut skipped_bytes = 0;
+                for id in &skipped {
+                    if let Some(fi) = e.transferred_files.remove(id) {
+                        skipped_bytes += fi.total_size as u64;
+                        if let Some(name) = fi.file_url.file_name() {
+                            skipped_names.push(name.to_string_lossy().into_owned());
+                        }
+                    }
+                }
+
+                if let Some(tmd) = e.transfer_metadata.as_mut() {
+                    tmd.total_bytes = tmd.total_bytes.saturating_sub(skipped_bytes);
+                    tmd.skipped_files = Some(skipped_names);
+                }
+            },
+            false,
+        )
+        .await;
+    }
+
     async fn reject_transfer(
         &mut self,
         reason: Option<sharing_nearby::connection_response_frame::Status>,
@@ -1049,6 +1689,7 @@ impl InboundRequest {
                 r#type: Some(sharing_nearby::v1_frame::FrameType::Response.into()),
                 connection_response: Some(sharing_nearby::ConnectionResponseFrame {
                     status: Some(sreason.into()),
+                    ..Default::default()
                 }),
                 ..Default::default()
             }),
@@ -1067,24 +1708,39 @@ impl InboundRequest {
             .ec_p256_public_key
             .ok_or_else(|| anyhow!("Missing required fields"))?;
 
-        let mut bytes = vec![0x04];
-        // Ensure no more than 32 bytes for the keys
-        if peer_p256_key.x.len() > 32 {
-            bytes.extend_from_slice(&peer_p256_key.x[peer_p256_key.x.len() - 32..]);
-        } else {
-            bytes.extend_from_slice(&peer_p256_key.x);
-        }
-        if peer_p256_key.y.len() > 32 {
-            bytes.extend_from_slice(&peer_p256_key.y[peer_p256_key.y.len() - 32..]);
-        } else {
-            bytes.extend_from_slice(&peer_p256_key.y);
-        }
-
-        let encoded_point = EncodedPoint::from_bytes(bytes)?;
-        let peer_key = PublicKey::from_encoded_point(&encoded_point).unwrap();
-        let priv_key = self.state.private_key.as_ref().unwrap();
-
-        let dhs = diffie_hellman(priv_key.to_nonzero_scalar(), peer_key.as_affine());
+        let (Some(x), Some(y)) = (
+            decode_point(&peer_p256_key.x),
+            decode_point(&peer_p256_key.y),
+        ) else {
+            self.send_ukey2_alert(AlertType::BadPublicKey).await?;
+            return Err(reject_frame(
+                Some("Ukey2ClientFinish"),
+                RejectReason::Malformed,
+                Some(format!(
+                    "coordinates of {} and {} bytes",
+                    peer_p256_key.x.len(),
+                    peer_p256_key.y.len()
+                )),
+            ));
+        };
+        let bytes = [&[0x04][..], &x, &y].concat();
+
+        // Off the curve (the identity included) it can't be used, and
+        // from_encoded_point is the one place checking it
+        let peer_key = EncodedPoint::from_bytes(&bytes)
+            .ok()
+            .and_then(|point| Option::from(PublicKey::from_encoded_point(&point)));
+        let Some(peer_key) = peer_key else {
+            self.send_ukey2_alert(AlertType::BadPublicKey).await?;
+            return Err(reject_frame(
+                Some("Ukey2ClientFinish"),
+                RejectReason::Malformed,
+                Some(String::from("public key not on P-256")),
+            ));
+        };
+        let priv_key = self.state.private_key.as_ref().unwrap();
+
+        let dhs = diffie_hellman(priv_key.to_nonzero_scalar(), peer_key.as_affine());
         let derived_secret = Sha256::digest(dhs.raw_secret_bytes());
 
         let mut ukey_info: Vec<u8> = vec![];
@@ -1121,6 +1777,7 @@ impl InboundRequest {
                 e.send_hmac_key = Some(server_hmac_key);
                 e.pin_code = Some(to_four_digit_string(&auth_string));
                 e.encryption_done = true;
+                e.handshake_duration = e.handshake_started.map(|s| s.elapsed());
             },
             false,
         )
@@ -1138,7 +1795,7 @@ impl InboundRequest {
         };
 
         let data = Ukey2Message {
-            message_type: Some(atype.into()),
+            message_type: Some(ukey2_message::Type::Alert.into()),
             message_data: Some(alert.encode_to_vec()),
         };
 
@@ -1166,6 +1823,7 @@ impl InboundRequest {
                 offset: Some(0),
                 flags: Some(0),
                 body: Some(frame_data),
+                ..Default::default()
             }),
             payload_header: Some(payload_header.clone()),
             ..Default::default()
@@ -1192,6 +1850,7 @@ impl InboundRequest {
                 offset: Some(body_size as i64),
                 flags: Some(1), // lastChunk
                 body: Some(vec![]),
+                ..Default::default()
             }),
             payload_header: Some(payload_header),
             ..Default::default()
@@ -1215,46 +1874,17 @@ impl InboundRequest {
     }
 
     async fn encrypt_and_send(&mut self, frame: &OfflineFrame) -> Result<(), anyhow::Error> {
-        let d2d_msg = DeviceToDeviceMessage {
-            sequence_number: Some(self.get_server_seq_inc().await),
-            message: Some(frame.encode_to_vec()),
-        };
+        self.state.ensure_encrypted()?;
 
-        let key = self.state.encrypt_key.as_ref().unwrap();
-        let msg_data = d2d_msg.encode_to_vec();
-        let iv = gen_random(16);
+        let seq = self.get_server_seq_inc().await;
+        let smsg = seal_frame(
+            self.state.encrypt_key.as_ref().unwrap(),
+            self.state.send_hmac_key.as_ref().unwrap(),
+            seq,
+            frame,
+        )?;
 
-        let mut cipher = Cipher::new_256(&key[..AES_256_KEY_LEN].try_into().unwrap());
-        cipher.set_auto_padding(true);
-        let encrypted = cipher.cbc_encrypt(&iv, &msg_data);
-
-        let hb = HeaderAndBody {
-            body: encrypted,
-            header: Header {
-                encryption_scheme: EncScheme::Aes256Cbc.into(),
-                signature_scheme: SigScheme::HmacSha256.into(),
-                iv: Some(iv),
-                public_metadata: Some(
-                    GcmMetadata {
-                        r#type: Type::DeviceToDeviceMessage.into(),
-                        version: Some(1),
-                    }
-                    .encode_to_vec(),
-                ),
-                ..Default::default()
-            },
-        };
-
-        let mut hmac = HmacSha256::new_from_slice(self.state.send_hmac_key.as_ref().unwrap())?;
-        hmac.update(&hb.encode_to_vec());
-        let result = hmac.finalize();
-
-        let smsg = SecureMessage {
-            header_and_body: hb.encode_to_vec(),
-            signature: result.into_bytes().to_vec(),
-        };
-
-        self.send_frame(smsg.encode_to_vec()).await?;
+        self.send_frame(smsg).await?;
 
         Ok(())
     }
@@ -1293,6 +1923,7 @@ impl InboundRequest {
 
         self.socket.write_all(&prefixed_length).await?;
         self.socket.flush().await?;
+        self.last_sent = Instant::now();
 
         Ok(())
     }
@@ -1321,6 +1952,21 @@ impl InboundRequest {
         self.state.client_seq
     }
 
+    // Sent right before the session ends because of the frame, update_state
+    // would hold it until the next state change which won't come
+    fn report_rejection(&self, rejection: FrameRejection) {
+        warn!("Rejected a frame from the peer: {:?}", rejection);
+        let _ = self.sender.send(ChannelMessage {
+            id: self.state.id.clone(),
+            direction: ChannelDirection::LibToFront,
+            rtype: Some(crate::channel::TransferType::Inbound),
+            state: Some(self.state.state.clone()),
+            meta: self.state.transfer_metadata.clone(),
+            rejection: Some(rejection),
+            ..Default::default()
+        });
+    }
+
     async fn update_state<F>(&mut self, f: F, inform: bool)
     where
         F: FnOnce(&mut InnerState),
@@ -1331,6 +1977,16 @@ impl InboundRequest {
             return;
         }
 
+        if let Some(tmd) = self.state.transfer_metadata.as_mut() {
+            tmd.wire_bytes = self.state.wire_bytes;
+            tmd.handshake_ms = self.state.handshake_duration.map(|d| d.as_millis() as u64);
+            if let Some(started) = self.state.transfer_started {
+                let elapsed = started.elapsed();
+                tmd.goodput = bytes_per_second(tmd.ack_bytes, elapsed);
+                tmd.duration_ms = Some(elapsed.as_millis() as u64);
+            }
+        }
+
         trace!("Sending msg into the channel");
         let _ = self.sender.send(ChannelMessage {
             id: self.state.id.clone(),
@@ -1346,3 +2002,1402 @@ impl InboundRequest {
         tokio::time::sleep(SANITY_DURATION).await;
     }
 }
+
+// Reopen what an interrupted transfer left of a file offered again under the
+// same payload id, right where it stopped. None if there's nothing to resume.
+fn resume_partial_file(fi: &mut InternalFileInfo) -> Option<File> {
+    let temp_url = fi.temp_url.as_ref()?;
+    let received = std::fs::metadata(temp_url).ok()?.len() as i64;
+    // A complete one never got its last chunk and digest, start it over
+    if received == 0 || received >= fi.total_size {
+        return None;
+    }
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(temp_url)
+        .ok()?;
+    let mut digest = Sha256::new();
+    if let Err(e) = hash_prefix(&mut file, received as u64, &mut digest) {
+        warn!("Can't resume {:?}: {}", temp_url, e);
+        return None;
+    }
+
+    if fi.digest.is_some() {
+        fi.digest = Some(digest);
+    }
+    fi.bytes_transferred = received;
+    Some(file)
+}
+
+// Returns where the file finally landed, which may differ from fi.file_url if
+// something took that name since the introduction (eg: two files offered with
+// the same name)
+// Once the handshake is done, a peer starting it over is confused at best:
+// the session isn't restarted, whether the request came encrypted or not
+fn is_connection_request(data: &[u8]) -> bool {
+    matches!(
+        try_parse_frame(data),
+        Ok(ParsedFrame::Offline(frame)) if frame.v1.as_ref().is_some_and(|v1| {
+            v1.r#type() == location_nearby_connections::v1_frame::FrameType::ConnectionRequest
+        })
+    )
+}
+
+fn unexpected_connection_request() -> anyhow::Error {
+    reject_frame(
+        Some("SecureMessage"),
+        RejectReason::Unexpected,
+        Some(String::from("ConnectionRequest")),
+    )
+}
+
+fn finalize_received_file(
+    mut fi: InternalFileInfo,
+    expected_digest: Option<&[u8]>,
+) -> Result<PathBuf, anyhow::Error> {
+    // Make sure everything hit the disk before moving it around
+    if let Some(file) = fi.file.take() {
+        file.sync_all()?;
+    }
+
+    // Older peers (and every non-rqs one) don't send any digest
+    if let (Some(expected), Some(digest)) = (expected_digest, fi.digest.take()) {
+        if !bool::from(digest.finalize().as_slice().ct_eq(expected)) {
+            if let Some(temp_url) = &fi.temp_url {
+                let _ = std::fs::remove_file(temp_url);
+            }
+
+            return Err(anyhow!(crate::errors::AppError::DigestMismatch(
+                fi.payload_id
+            )));
+        }
+    }
+
+    let Some(temp_url) = &fi.temp_url else {
+        return Ok(fi.file_url);
+    };
+
+    // A rewritten name may well point to a subdirectory
+    if let Some(parent) = fi.file_url.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let dest = unique_path(fi.file_url.clone());
+    move_file(temp_url, &dest)?;
+    info!("Moved {:?} to {:?}", temp_url, dest);
+
+    Ok(dest)
+}
+
+fn check_not_self_connection(
+    endpoint_id: &[u8; 4],
+    peer_endpoint_id: &str,
+) -> Result<(), anyhow::Error> {
+    if peer_endpoint_id.as_bytes() == endpoint_id {
+        return Err(anyhow!(crate::errors::AppError::SelfConnection));
+    }
+
+    Ok(())
+}
+
+fn validate_introduction_size(size: i64, limits: &IntroductionLimits) -> Result<(), anyhow::Error> {
+    if size < 0 || size > limits.max_frame_size as i64 {
+        return Err(anyhow!(
+            "Introduction too large: {} bytes (max: {})",
+            size,
+            limits.max_frame_size
+        ));
+    }
+
+    Ok(())
+}
+
+fn validate_introduction_entries(
+    introduction: &IntroductionFrame,
+    limits: &IntroductionLimits,
+) -> Result<(), anyhow::Error> {
+    let entries = introduction.file_metadata.len()
+        + introduction.text_metadata.len()
+        + introduction.wifi_credentials_metadata.len()
+        + introduction.empty_folders.len();
+    if entries > limits.max_entries {
+        return Err(anyhow!(
+            "Introduction has too many entries: {} (max: {})",
+            entries,
+            limits.max_entries
+        ));
+    }
+
+    Ok(())
+}
+
+// Only checked when declared, which stock senders never do. Anything off
+// would skew the free-space check and the progress, so it's refused.
+fn check_introduction_total(introduction: &IntroductionFrame) -> Result<(), anyhow::Error> {
+    let Some(declared) = introduction.total_size else {
+        return Ok(());
+    };
+
+    let actual = introduction
+        .file_metadata
+        .iter()
+        .try_fold(0i64, |sum, file| match file.size() {
+            size if size < 0 => None,
+            size => sum.checked_add(size),
+        });
+    if actual != Some(declared) {
+        return Err(anyhow!(crate::errors::AppError::InconsistentIntroduction {
+            declared,
+            actual
+        }));
+    }
+
+    Ok(())
+}
+
+// A payload id designates a single payload for the whole session, otherwise
+// the chunks of one would end up written into the other.
+fn check_payload_id(
+    state: &InnerState,
+    payload_id: i64,
+    is_file: bool,
+) -> Result<(), anyhow::Error> {
+    let reused = state.completed_payloads.contains(&payload_id)
+        || if is_file {
+            state.payload_buffers.contains_key(&payload_id)
+                || state.payload_sinks.contains_key(&payload_id)
+        } else {
+            state.file_order.contains(&payload_id)
+        };
+
+    if reused {
+        return Err(anyhow!(crate::errors::AppError::DuplicatePayloadId(
+            payload_id
+        )));
+    }
+
+    Ok(())
+}
+
+fn check_introduction_payload_ids(
+    introduction: &IntroductionFrame,
+    state: &InnerState,
+) -> Result<(), anyhow::Error> {
+    let ids = introduction
+        .file_metadata
+        .iter()
+        .map(|m| m.payload_id())
+        .chain(introduction.text_metadata.iter().map(|m| m.payload_id()))
+        .chain(
+            introduction
+                .wifi_credentials_metadata
+                .iter()
+                .map(|m| m.payload_id()),
+        );
+
+    let mut seen = HashSet::new();
+    for id in ids {
+        if !seen.insert(id)
+            || state.completed_payloads.contains(&id)
+            || state.payload_buffers.contains_key(&id)
+        {
+            return Err(anyhow!(crate::errors::AppError::DuplicatePayloadId(id)));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use tokio::sync::broadcast;
+
+    use super::*;
+    use crate::errors::AppError;
+    use crate::location_nearby_connections::V1Frame;
+    use crate::sharing_nearby::FileMetadata;
+    use crate::utils::{DeviceType, DEFAULT_MAX_FRAME_LENGTH};
+
+    // Along with the peer's end of the socket, which must be kept open
+    async fn new_request() -> (InboundRequest, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (peer, _) = listener.accept().await.unwrap();
+        let (sender, _) = broadcast::channel(10);
+
+        let mut ir = InboundRequest::new(*b"ABCD", socket, String::from("127.0.0.1"), sender);
+        ir.state.decrypt_key = Some(vec![0x42; 32]);
+        ir.state.recv_hmac_key = Some(vec![0x24; 32]);
+        ir.state.encrypt_key = Some(vec![0x43; 32]);
+        ir.state.send_hmac_key = Some(vec![0x25; 32]);
+        (ir, peer)
+    }
+
+    // Encrypt a frame the way the peer would, with the keys of the request
+    fn seal(ir: &InboundRequest, seq: i32, frame: &OfflineFrame) -> SecureMessage {
+        let data = seal_frame(
+            ir.state.decrypt_key.as_ref().unwrap(),
+            ir.state.recv_hmac_key.as_ref().unwrap(),
+            seq,
+            frame,
+        )
+        .unwrap();
+
+        SecureMessage::decode(data.as_slice()).unwrap()
+    }
+
+    // Read and decrypt the next frame the request sent to the peer
+    async fn open(ir: &InboundRequest, peer: &mut TcpStream) -> OfflineFrame {
+        let len = peer.read_u32().await.unwrap() as usize;
+        let mut data = vec![0u8; len];
+        peer.read_exact(&mut data).await.unwrap();
+
+        let smsg = SecureMessage::decode(data.as_slice()).unwrap();
+        let header_and_body = HeaderAndBody::decode(&*smsg.header_and_body).unwrap();
+        let key = ir.state.encrypt_key.as_ref().unwrap();
+        let mut cipher = Cipher::new_256(key[..AES_256_KEY_LEN].try_into().unwrap());
+        cipher.set_auto_padding(true);
+        let decrypted = cipher.cbc_decrypt(header_and_body.header.iv(), &header_and_body.body);
+
+        let d2d_msg = DeviceToDeviceMessage::decode(&*decrypted).unwrap();
+        OfflineFrame::decode(d2d_msg.message()).unwrap()
+    }
+
+    fn payload_frame(
+        id: i64,
+        ptype: payload_header::PayloadType,
+        total_size: i64,
+        offset: i64,
+        body: &[u8],
+        last: bool,
+    ) -> OfflineFrame {
+        OfflineFrame {
+            version: Some(location_nearby_connections::offline_frame::Version::V1.into()),
+            v1: Some(V1Frame {
+                r#type: Some(
+                    location_nearby_connections::v1_frame::FrameType::PayloadTransfer.into(),
+                ),
+                payload_transfer: Some(PayloadTransferFrame {
+                    packet_type: Some(PacketType::Data.into()),
+                    payload_header: Some(PayloadHeader {
+                        id: Some(id),
+                        r#type: Some(ptype.into()),
+                        total_size: Some(total_size),
+                        ..Default::default()
+                    }),
+                    payload_chunk: Some(PayloadChunk {
+                        offset: Some(offset),
+                        flags: Some(last as i32),
+                        body: Some(body.to_vec()),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+        }
+    }
+
+    // An introduction as the sender sends it, in a bytes payload of its own
+    fn introduction_frame(id: i64, files: Vec<FileMetadata>) -> OfflineFrame {
+        let frame = sharing_nearby::Frame {
+            version: Some(sharing_nearby::frame::Version::V1.into()),
+            v1: Some(sharing_nearby::V1Frame {
+                r#type: Some(sharing_nearby::v1_frame::FrameType::Introduction.into()),
+                introduction: Some(IntroductionFrame {
+                    file_metadata: files,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+        }
+        .encode_to_vec();
+
+        payload_frame(
+            id,
+            payload_header::PayloadType::Bytes,
+            frame.len() as i64,
+            0,
+            &frame,
+            true,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_payload_id() {
+        let (mut ir, _peer) = new_request().await;
+        let dir = std::env::temp_dir().join(format!("rqs_duplicate_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        ir.set_download_dir(dir.clone());
+        ir.state.state = State::ReceivedPairedKeyResult;
+
+        let file = |id| FileMetadata {
+            payload_id: Some(id),
+            name: Some(format!("file_{id}")),
+            size: Some(4),
+            ..Default::default()
+        };
+
+        // Payload 3 introduces files 7 and 8, the first one then comes whole
+        let smsg = seal(&ir, 1, &introduction_frame(3, vec![file(7), file(8)]));
+        ir.decrypt_and_process_secure_message(&smsg).await.unwrap();
+        assert_eq!(ir.state.state, State::WaitingForUserConsent);
+        ir.accept_transfer(None).await.unwrap();
+        let frame = payload_frame(7, payload_header::PayloadType::File, 4, 0, b"data", true);
+        ir.decrypt_and_process_secure_message(&seal(&ir, 2, &frame))
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(dir.join("file_7")).unwrap(), b"data");
+
+        // Neither a finished payload, be it the file or the introduction, nor
+        // one still expected can come again under another type
+        let reused = [
+            (7, payload_header::PayloadType::Bytes),
+            (3, payload_header::PayloadType::File),
+            (8, payload_header::PayloadType::Bytes),
+        ];
+        for (seq, (id, ptype)) in (3..).zip(reused) {
+            let frame = payload_frame(id, ptype, 4, 0, b"oops", false);
+            let e = ir
+                .decrypt_and_process_secure_message(&seal(&ir, seq, &frame))
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                e.downcast_ref(),
+                Some(AppError::DuplicatePayloadId(reused_id)) if *reused_id == id
+            ));
+        }
+
+        // Nor can a file chunk reuse the id of bytes still being received
+        let frame = payload_frame(
+            20,
+            payload_header::PayloadType::Bytes,
+            64,
+            0,
+            b"partial",
+            false,
+        );
+        ir.decrypt_and_process_secure_message(&seal(&ir, 6, &frame))
+            .await
+            .unwrap();
+        let frame = payload_frame(20, payload_header::PayloadType::File, 64, 0, b"data", false);
+        let e = ir
+            .decrypt_and_process_secure_message(&seal(&ir, 7, &frame))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(AppError::DuplicatePayloadId(20))
+        ));
+
+        // The rest of the transfer is unaffected
+        let frame = payload_frame(8, payload_header::PayloadType::File, 4, 0, b"more", true);
+        let e = ir
+            .decrypt_and_process_secure_message(&seal(&ir, 8, &frame))
+            .await
+            .unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(AppError::NotAnError)));
+        assert_eq!(ir.state.state, State::Finished);
+        assert_eq!(std::fs::read(dir.join("file_8")).unwrap(), b"more");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_introduced_id() {
+        let file = |id| FileMetadata {
+            payload_id: Some(id),
+            name: Some(format!("file_{id}")),
+            size: Some(4),
+            ..Default::default()
+        };
+
+        // The introduction's own payload id, and an id listed twice
+        for (intro_id, files, reused) in [
+            (3, vec![file(3), file(4)], 3),
+            (5, vec![file(10), file(10)], 10),
+        ] {
+            let (mut ir, mut peer) = new_request().await;
+            ir.state.state = State::ReceivedPairedKeyResult;
+            let smsg = seal(&ir, 1, &introduction_frame(intro_id, files));
+            let e = ir
+                .decrypt_and_process_secure_message(&smsg)
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                e.downcast_ref(),
+                Some(AppError::DuplicatePayloadId(id)) if *id == reused
+            ));
+            assert!(ir.state.transfer_metadata.is_none());
+
+            // Turned down rather than left waiting
+            let body = open(&ir, &mut peer)
+                .await
+                .v1
+                .and_then(|v1| v1.payload_transfer)
+                .and_then(|transfer| transfer.payload_chunk)
+                .and_then(|chunk| chunk.body)
+                .unwrap();
+            let response = sharing_nearby::Frame::decode(body.as_slice())
+                .unwrap()
+                .v1
+                .and_then(|v1| v1.connection_response)
+                .unwrap();
+            assert_eq!(
+                response.status(),
+                sharing_nearby::connection_response_frame::Status::Reject
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_large_text_streamed_to_disk() {
+        let (mut ir, _peer) = new_request().await;
+        let dir = std::env::temp_dir().join(format!("rqs_sink_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let data: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let (temp_url, file_url) = (dir.join("42.text.part"), dir.join("text_42.txt"));
+        ir.state.text_payload = Some(TextPayloadInfo::Text(42));
+        ir.state.transfer_metadata = Some(TransferMetadata::default());
+        ir.state.payload_sinks.insert(
+            42,
+            InternalFileInfo {
+                payload_id: 42,
+                file_url: file_url.clone(),
+                bytes_transferred: 0,
+                total_size: data.len() as i64,
+                file: Some(File::create(&temp_url).unwrap()),
+                temp_url: Some(temp_url),
+                digest: None,
+            },
+        );
+
+        let mut seq = 0;
+        let mut offset = 0;
+        for chunk in data.chunks(64 * 1024) {
+            seq += 1;
+            let frame = payload_frame(
+                42,
+                payload_header::PayloadType::Bytes,
+                data.len() as i64,
+                offset,
+                chunk,
+                false,
+            );
+            ir.decrypt_and_process_secure_message(&seal(&ir, seq, &frame))
+                .await
+                .unwrap();
+            offset += chunk.len() as i64;
+
+            // Nothing but the current frame is ever held in memory
+            assert!(ir.state.payload_buffers.is_empty());
+        }
+
+        let frame = payload_frame(
+            42,
+            payload_header::PayloadType::Bytes,
+            data.len() as i64,
+            offset,
+            &[],
+            true,
+        );
+        let e = ir
+            .decrypt_and_process_secure_message(&seal(&ir, seq + 1, &frame))
+            .await
+            .unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(AppError::NotAnError)));
+
+        assert_eq!(ir.state.state, State::Finished);
+        assert_eq!(std::fs::read(&file_url).unwrap(), data);
+        let tmd = ir.state.transfer_metadata.as_ref().unwrap();
+        assert_eq!(tmd.ack_bytes, data.len() as u64);
+        assert_eq!(
+            tmd.destination,
+            Some(file_url.to_string_lossy().into_owned())
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_decline_unsupported_upgrade() {
+        let (mut ir, mut peer) = new_request().await;
+        let dest = std::env::temp_dir().join(format!("rqs_upgrade_{}", std::process::id()));
+        ir.state.state = State::ReceivingFiles;
+        ir.state.transferred_files.insert(
+            1,
+            InternalFileInfo {
+                payload_id: 1,
+                file_url: dest.clone(),
+                bytes_transferred: 0,
+                total_size: 4,
+                file: Some(File::create(&dest).unwrap()),
+                temp_url: None,
+                digest: None,
+            },
+        );
+
+        let proposal = OfflineFrame {
+            version: Some(location_nearby_connections::offline_frame::Version::V1.into()),
+            v1: Some(V1Frame {
+                r#type: Some(
+                    location_nearby_connections::v1_frame::FrameType::BandwidthUpgradeNegotiation
+                        .into(),
+                ),
+                bandwidth_upgrade_negotiation: Some(
+                    location_nearby_connections::BandwidthUpgradeNegotiationFrame {
+                        event_type: Some(
+                            bandwidth_upgrade_negotiation_frame::EventType::UpgradePathAvailable
+                                .into(),
+                        ),
+                        upgrade_path_info: Some(
+                            bandwidth_upgrade_negotiation_frame::UpgradePathInfo {
+                                medium: Some(Medium::Bluetooth.into()),
+                                ..Default::default()
+                            },
+                        ),
+                        ..Default::default()
+                    },
+                ),
+                ..Default::default()
+            }),
+        };
+        ir.decrypt_and_process_secure_message(&seal(&ir, 1, &proposal))
+            .await
+            .unwrap();
+
+        // Declined right away, on the same connection
+        let answer = open(&ir, &mut peer).await;
+        let negotiation = answer.v1.unwrap().bandwidth_upgrade_negotiation.unwrap();
+        assert_eq!(
+            negotiation.event_type(),
+            bandwidth_upgrade_negotiation_frame::EventType::UpgradeFailure
+        );
+        assert_eq!(
+            negotiation.upgrade_path_info.unwrap().medium(),
+            Medium::Bluetooth
+        );
+        assert_eq!(ir.state.state, State::ReceivingFiles);
+
+        // And the transfer goes on where it was
+        let frame = payload_frame(1, payload_header::PayloadType::File, 4, 0, b"data", true);
+        let e = ir
+            .decrypt_and_process_secure_message(&seal(&ir, 2, &frame))
+            .await
+            .unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(AppError::NotAnError)));
+        assert_eq!(ir.state.state, State::Finished);
+        assert_eq!(std::fs::read(&dest).unwrap(), b"data");
+        std::fs::remove_file(&dest).unwrap();
+    }
+
+    // A sender that advertises nothing: it ignores the selection and never
+    // expects completion frames, nor does it send anything we don't know
+    #[tokio::test]
+    async fn test_minimal_sender() {
+        let (mut ir, _peer) = new_request().await;
+        let dir = std::env::temp_dir().join(format!("rqs_minimal_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Unknown sharing frames are skipped instead of failing the setup
+        ir.state.state = State::ReceivedPairedKeyResult;
+        let unknown = sharing_nearby::Frame {
+            version: Some(sharing_nearby::frame::Version::V1.into()),
+            v1: Some(sharing_nearby::V1Frame {
+                r#type: Some(42),
+                ..Default::default()
+            }),
+        }
+        .encode_to_vec();
+        let frame = payload_frame(
+            3,
+            payload_header::PayloadType::Bytes,
+            unknown.len() as i64,
+            0,
+            &unknown,
+            true,
+        );
+        ir.decrypt_and_process_secure_message(&seal(&ir, 1, &frame))
+            .await
+            .unwrap();
+        assert_eq!(ir.state.state, State::ReceivedPairedKeyResult);
+
+        let files = [(1, dir.join("wanted.bin")), (2, dir.join("unwanted.bin"))];
+        for (id, file_url) in &files {
+            ir.state.file_order.push(*id);
+            ir.state.transferred_files.insert(
+                *id,
+                InternalFileInfo {
+                    payload_id: *id,
+                    file_url: file_url.clone(),
+                    bytes_transferred: 0,
+                    total_size: 4,
+                    file: None,
+                    temp_url: None,
+                    digest: None,
+                },
+            );
+        }
+        ir.state.transfer_metadata = Some(TransferMetadata::default());
+        assert_eq!(ir.state.peer_capabilities, PeerCapabilities::default());
+
+        ir.accept_transfer(Some(vec![0])).await.unwrap();
+        assert!(!files[1].1.exists());
+
+        // The unselected file comes anyway and is dropped
+        let frame = payload_frame(2, payload_header::PayloadType::File, 4, 0, b"nope", true);
+        ir.decrypt_and_process_secure_message(&seal(&ir, 2, &frame))
+            .await
+            .unwrap();
+
+        let frame = payload_frame(1, payload_header::PayloadType::File, 4, 0, b"data", true);
+        let e = ir
+            .decrypt_and_process_secure_message(&seal(&ir, 3, &frame))
+            .await
+            .unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(AppError::NotAnError)));
+
+        assert_eq!(ir.state.state, State::Finished);
+        assert_eq!(std::fs::read(&files[0].1).unwrap(), b"data");
+        assert!(!files[1].1.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_accept_carries_device_info() {
+        let (mut ir, mut peer) = new_request().await;
+        ir.state.transfer_metadata = Some(TransferMetadata::default());
+        ir.accept_transfer(None).await.unwrap();
+
+        let frame = open(&ir, &mut peer).await;
+        let body = frame
+            .v1
+            .and_then(|v1| v1.payload_transfer)
+            .and_then(|pt| pt.payload_chunk)
+            .and_then(|chunk| chunk.body)
+            .unwrap();
+        let response = sharing_nearby::Frame::decode(body.as_slice())
+            .unwrap()
+            .v1
+            .and_then(|v1| v1.connection_response)
+            .unwrap();
+
+        let rdi = RemoteDeviceInfo::deserialize(response.endpoint_info()).unwrap();
+        assert_eq!(rdi.name, local_device_info().name);
+        assert_eq!(rdi.device_type, DeviceType::Laptop);
+    }
+
+    // Both files are offered as the same name, the second one gets renamed
+    #[tokio::test]
+    async fn test_saved_path_after_rename() {
+        let (mut ir, _peer) = new_request().await;
+        let dir = std::env::temp_dir().join(format!("rqs_saved_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        for id in [1, 2] {
+            ir.state.file_order.push(id);
+            ir.state.transferred_files.insert(
+                id,
+                InternalFileInfo {
+                    payload_id: id,
+                    file_url: dir.join("photo.jpg"),
+                    bytes_transferred: 0,
+                    total_size: 4,
+                    file: None,
+                    temp_url: Some(dir.join(format!("{id}.photo.jpg.part"))),
+                    digest: None,
+                },
+            );
+        }
+        ir.state.transfer_metadata = Some(TransferMetadata::default());
+        ir.accept_transfer(None).await.unwrap();
+
+        let frame = payload_frame(1, payload_header::PayloadType::File, 4, 0, b"1111", true);
+        ir.decrypt_and_process_secure_message(&seal(&ir, 1, &frame))
+            .await
+            .unwrap();
+        let frame = payload_frame(2, payload_header::PayloadType::File, 4, 0, b"2222", true);
+        let e = ir
+            .decrypt_and_process_secure_message(&seal(&ir, 2, &frame))
+            .await
+            .unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(AppError::NotAnError)));
+
+        let dir = std::fs::canonicalize(&dir).unwrap();
+        let (first, second) = (dir.join("photo.jpg"), dir.join("1_photo.jpg"));
+        assert_eq!(std::fs::read(&first).unwrap(), b"1111");
+        assert_eq!(std::fs::read(&second).unwrap(), b"2222");
+        assert_eq!(
+            ir.state.transfer_metadata.unwrap().saved_files,
+            Some(vec![
+                first.to_string_lossy().into_owned(),
+                second.to_string_lossy().into_owned()
+            ])
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_key_derivation_vectors() {
+        // Nothing goes out, the peer's end is only kept open
+        let (socket, _peer) = tokio::io::duplex(64 * 1024);
+        let (sender, _) = broadcast::channel(10);
+        let mut ir = InboundRequest::new(*b"ABCD", socket, String::from("duplex"), sender);
+        ir.state.private_key = Some(p256::SecretKey::from_slice(&[0x11; 32]).unwrap());
+        ir.state.client_init_msg_data = Some(b"client init".to_vec());
+        ir.state.server_init_data = Some(b"server init".to_vec());
+
+        // Computed independently, from the same scalars and transcript as outbound's, with
+        // the ECDH and HKDF of Python's cryptography package
+        let peer_key = GenericPublicKey {
+            r#type: PublicKeyType::EcP256.into(),
+            ec_p256_public_key: Some(EcP256PublicKey {
+                x: hex::decode("d65a93977caa3d1b081852ff57a79e465f1660577304baead505dd3a48589cf3")
+                    .unwrap(),
+                y: hex::decode("50185e895372df6221ea3a137557e473fddb6755f05bd507c3c533fce9c91285")
+                    .unwrap(),
+            }),
+            ..Default::default()
+        };
+        let client_key = "d0c3c694763361cfb4b9292e182328c355a6b76e3c4f128c895034115a834906";
+        let client_hmac_key = "88beb21ef45cdf73b434cb350b3533514b4ca9efc60065ee6438acb7a25c7085";
+        let server_key = "6ff43cfb4241b9f392ab3aa9024f6e350b668e4315ef0dfb6fdb467b06ceeb88";
+        let server_hmac_key = "ecbb4ab3631a001555e9cdb903084f8c380726d8770c9911d723a17e4f64bea1";
+
+        // The mirror image of the client's keys
+        ir.finalize_key_exchange(peer_key).await.unwrap();
+        let hex_of = |key: &Option<Vec<u8>>| hex::encode(key.as_ref().unwrap());
+        assert_eq!(hex_of(&ir.state.decrypt_key), client_key);
+        assert_eq!(hex_of(&ir.state.recv_hmac_key), client_hmac_key);
+        assert_eq!(hex_of(&ir.state.encrypt_key), server_key);
+        assert_eq!(hex_of(&ir.state.send_hmac_key), server_hmac_key);
+        assert_eq!(ir.state.pin_code.as_deref(), Some("0398"));
+    }
+
+    #[tokio::test]
+    async fn test_bad_public_key() {
+        let (mut ir, mut peer) = new_request().await;
+        ir.state.private_key = Some(gen_ecdsa_keypair().0);
+        ir.state.client_init_msg_data = Some(vec![]);
+        ir.state.server_init_data = Some(vec![]);
+
+        let generic_key = |x: Vec<u8>, y: Vec<u8>| GenericPublicKey {
+            r#type: PublicKeyType::EcP256.into(),
+            ec_p256_public_key: Some(EcP256PublicKey { x, y }),
+            ..Default::default()
+        };
+
+        let off_curve = "public key not on P-256";
+        for (key, value) in [
+            (generic_key(vec![1; 32], vec![2; 32]), off_curve),
+            (generic_key(vec![0; 32], vec![0; 32]), off_curve),
+            // Padded as a small value would be, still not on the curve
+            (generic_key(vec![1; 5], vec![2; 5]), off_curve),
+            // Never truncated
+            (
+                generic_key(vec![1; 34], vec![2; 32]),
+                "coordinates of 34 and 32 bytes",
+            ),
+            (
+                generic_key(vec![1; 33], vec![2; 32]),
+                "coordinates of 33 and 32 bytes",
+            ),
+        ] {
+            let e = ir.finalize_key_exchange(key).await.unwrap_err();
+            assert_eq!(
+                frame_rejection(&e, &ir.state.state),
+                Some(FrameRejection {
+                    frame_type: Some(String::from("Ukey2ClientFinish")),
+                    reason: RejectReason::Malformed,
+                    value: Some(String::from(value)),
+                })
+            );
+            assert_eq!(ir.state.pin_code, None);
+
+            let len = peer.read_u32().await.unwrap() as usize;
+            let mut data = vec![0u8; len];
+            peer.read_exact(&mut data).await.unwrap();
+            let alert = Ukey2Message::decode(data.as_slice()).unwrap();
+            assert_eq!(alert.message_type(), ukey2_message::Type::Alert);
+            let alert = Ukey2Alert::decode(alert.message_data()).unwrap();
+            assert_eq!(alert.r#type(), AlertType::BadPublicKey);
+        }
+    }
+
+    // Feed the request raw bytes from the peer, returning what it reported
+    // about the frame before giving up on the session
+    async fn rejection_of(state: State, wire: &[u8]) -> FrameRejection {
+        let (mut ir, mut peer) = new_request().await;
+        ir.state.state = state;
+        let mut receiver = ir.sender.subscribe();
+        peer.write_all(wire).await.unwrap();
+
+        assert!(ir.handle().await.is_err());
+        loop {
+            if let Some(rejection) = receiver.try_recv().unwrap().rejection {
+                return rejection;
+            }
+        }
+    }
+
+    fn framed(data: &[u8]) -> Vec<u8> {
+        let mut wire = (data.len() as u32).to_be_bytes().to_vec();
+        wire.extend_from_slice(data);
+        wire
+    }
+
+    #[tokio::test]
+    async fn test_max_frame_length() {
+        let (mut ir, mut peer) = new_request().await;
+        ir.max_frame_length = 16;
+        peer.write_all(&framed(&[0; 17])).await.unwrap();
+        let e = ir.handle().await.unwrap_err();
+        let rejection = frame_rejection(&e, &ir.state.state).unwrap();
+        assert_eq!(rejection.reason, RejectReason::Oversized);
+        assert_eq!(rejection.value.as_deref(), Some("17"));
+
+        // Raised, what the default refuses makes it to the decoding
+        let (mut ir, mut peer) = new_request().await;
+        ir.max_frame_length = DEFAULT_MAX_FRAME_LENGTH * 2;
+        let wire = framed(&vec![0xff; DEFAULT_MAX_FRAME_LENGTH + 1]);
+        let writer = tokio::spawn(async move {
+            peer.write_all(&wire).await.unwrap();
+            peer
+        });
+        let e = ir.handle().await.unwrap_err();
+        let rejection = frame_rejection(&e, &ir.state.state).unwrap();
+        assert_eq!(rejection.reason, RejectReason::Malformed);
+        drop(writer.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_frame_rejections() {
+        let rejection = |frame_type: Option<&str>, reason, value: Option<&str>| FrameRejection {
+            frame_type: frame_type.map(str::to_owned),
+            reason,
+            value: value.map(str::to_owned),
+        };
+
+        let oversized = (DEFAULT_MAX_FRAME_LENGTH as u32 + 1).to_be_bytes();
+        assert_eq!(
+            rejection_of(State::Initial, &oversized).await,
+            rejection(None, RejectReason::Oversized, Some("5242881"))
+        );
+
+        let malformed = rejection_of(State::Initial, &framed(&[0xff; 8])).await;
+        assert_eq!(malformed.frame_type.as_deref(), Some("ConnectionRequest"));
+        assert_eq!(malformed.reason, RejectReason::Malformed);
+
+        let keepalive = OfflineFrame {
+            version: Some(location_nearby_connections::offline_frame::Version::V1.into()),
+            v1: Some(V1Frame {
+                r#type: Some(location_nearby_connections::v1_frame::FrameType::KeepAlive.into()),
+                keep_alive: Some(KeepAliveFrame { ack: Some(false) }),
+                ..Default::default()
+            }),
+        };
+        assert_eq!(
+            rejection_of(State::Initial, &framed(&keepalive.encode_to_vec())).await,
+            rejection(
+                Some("ConnectionRequest"),
+                RejectReason::Unexpected,
+                Some("KeepAlive")
+            )
+        );
+
+        // Same keys as new_request
+        let sealed = |seq| seal_frame(&[0x42; 32], &[0x24; 32], seq, &keepalive).unwrap();
+
+        let mut tampered = SecureMessage::decode(sealed(1).as_slice()).unwrap();
+        tampered.signature[0] ^= 1;
+        assert_eq!(
+            rejection_of(
+                State::SentConnectionResponse,
+                &framed(&tampered.encode_to_vec())
+            )
+            .await,
+            rejection(Some("SecureMessage"), RejectReason::BadHmac, None)
+        );
+
+        assert_eq!(
+            rejection_of(State::SentConnectionResponse, &framed(&sealed(5))).await,
+            rejection(
+                Some("SecureMessage"),
+                RejectReason::BadSequence,
+                Some("5 (expected 1)")
+            )
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_frame_rate_limit() {
+        let (mut ir, mut peer) = new_request().await;
+        ir.state.state = State::SentConnectionResponse;
+        ir.frame_limiter = Some(FrameRateLimiter::new(10));
+
+        let keepalive = OfflineFrame {
+            version: Some(location_nearby_connections::offline_frame::Version::V1.into()),
+            v1: Some(V1Frame {
+                r#type: Some(location_nearby_connections::v1_frame::FrameType::KeepAlive.into()),
+                keep_alive: Some(KeepAliveFrame { ack: Some(true) }),
+                ..Default::default()
+            }),
+        };
+        for seq in 1..=11 {
+            let sealed = seal(&ir, seq, &keepalive).encode_to_vec();
+            peer.write_all(&framed(&sealed)).await.unwrap();
+        }
+
+        for _ in 0..10 {
+            ir.handle().await.unwrap();
+        }
+        let e = ir.handle().await.unwrap_err();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(AppError::ProcessingRateExceeded(10))
+        ));
+
+        // A fresh allowance every second
+        let limiter = ir.frame_limiter.as_mut().unwrap();
+        assert!(!limiter.allow());
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(limiter.allow());
+    }
+
+    #[tokio::test]
+    async fn test_connection_request_mid_session() {
+        let request = OfflineFrame {
+            version: Some(location_nearby_connections::offline_frame::Version::V1.into()),
+            v1: Some(V1Frame {
+                r#type: Some(
+                    location_nearby_connections::v1_frame::FrameType::ConnectionRequest.into(),
+                ),
+                connection_request: Some(location_nearby_connections::ConnectionRequestFrame {
+                    endpoint_id: Some(String::from("WXYZ")),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+        };
+        let expected = FrameRejection {
+            frame_type: Some(String::from("SecureMessage")),
+            reason: RejectReason::Unexpected,
+            value: Some(String::from("ConnectionRequest")),
+        };
+
+        // Resent in the clear
+        assert_eq!(
+            rejection_of(
+                State::ReceivedPairedKeyResult,
+                &framed(&request.encode_to_vec())
+            )
+            .await,
+            expected
+        );
+
+        // Or through the secure channel, same keys as new_request
+        let sealed = seal_frame(&[0x42; 32], &[0x24; 32], 1, &request).unwrap();
+        assert_eq!(
+            rejection_of(State::ReceivedPairedKeyResult, &framed(&sealed)).await,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_self_connection() {
+        let endpoint_id = *b"AbC1";
+
+        let e = check_not_self_connection(&endpoint_id, "AbC1").unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(AppError::SelfConnection)));
+
+        assert!(check_not_self_connection(&endpoint_id, "XyZ9").is_ok());
+    }
+
+    #[test]
+    fn test_introduction_over_limits() {
+        let limits = IntroductionLimits {
+            max_frame_size: 1024,
+            max_entries: 2,
+        };
+
+        let mut introduction = IntroductionFrame {
+            file_metadata: (0..2)
+                .map(|i| FileMetadata {
+                    payload_id: Some(i),
+                    name: Some(format!("file_{i}")),
+                    size: Some(42),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+        assert!(validate_introduction_entries(&introduction, &limits).is_ok());
+
+        introduction.file_metadata.push(FileMetadata {
+            payload_id: Some(2),
+            name: Some(String::from("file_2")),
+            size: Some(42),
+            ..Default::default()
+        });
+        assert!(validate_introduction_entries(&introduction, &limits).is_err());
+
+        assert!(validate_introduction_size(limits.max_frame_size as i64, &limits).is_ok());
+        assert!(validate_introduction_size(limits.max_frame_size as i64 + 1, &limits).is_err());
+    }
+
+    // A peer announcing a small payload, then sending more than that
+    #[tokio::test]
+    async fn test_buffered_size_over_announced() {
+        let (mut ir, _peer) = new_request().await;
+        ir.state.state = State::ReceivedPairedKeyResult;
+        ir.introduction_limits = IntroductionLimits {
+            max_frame_size: 1024,
+            max_entries: 2,
+        };
+
+        let frame = payload_frame(
+            1,
+            payload_header::PayloadType::Bytes,
+            10,
+            0,
+            &[0; 1000],
+            false,
+        );
+        ir.decrypt_and_process_secure_message(&seal(&ir, 1, &frame))
+            .await
+            .unwrap();
+        let frame = payload_frame(
+            1,
+            payload_header::PayloadType::Bytes,
+            10,
+            1000,
+            &[0; 25],
+            false,
+        );
+        let e = ir
+            .decrypt_and_process_secure_message(&seal(&ir, 2, &frame))
+            .await
+            .unwrap_err();
+        assert!(e
+            .to_string()
+            .starts_with("Introduction too large: 1025 bytes"));
+        assert!(ir.state.payload_buffers.is_empty());
+
+        // Past the introduction, still held to the frame length cap
+        let (mut ir, _peer) = new_request().await;
+        ir.state.state = State::ReceivingFiles;
+        ir.max_frame_length = 16;
+        let frame = payload_frame(
+            2,
+            payload_header::PayloadType::Bytes,
+            10,
+            0,
+            &[0; 17],
+            false,
+        );
+        let e = ir
+            .decrypt_and_process_secure_message(&seal(&ir, 1, &frame))
+            .await
+            .unwrap_err();
+        let rejection = frame_rejection(&e, &ir.state.state).unwrap();
+        assert_eq!(rejection.reason, RejectReason::Oversized);
+        assert_eq!(rejection.value.as_deref(), Some("17"));
+        assert!(ir.state.payload_buffers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_filename_rewriter() {
+        let (mut ir, _peer) = new_request().await;
+        ir.state.state = State::ReceivedPairedKeyResult;
+        ir.state.remote_device_info = Some(RemoteDeviceInfo {
+            name: String::from("pixel"),
+            device_type: DeviceType::Phone,
+        });
+        ir.filename_rewriter = Some(FilenameRewriter::new(|name, peer| {
+            if name.starts_with("evil") {
+                format!("../{name}")
+            } else {
+                format!("{}/{name}", peer.name)
+            }
+        }));
+
+        let v1_frame = sharing_nearby::V1Frame {
+            r#type: Some(sharing_nearby::v1_frame::FrameType::Introduction.into()),
+            introduction: Some(IntroductionFrame {
+                file_metadata: [(1, "photo.jpg"), (2, "../../evil.sh")]
+                    .into_iter()
+                    .map(|(id, name)| FileMetadata {
+                        payload_id: Some(id),
+                        name: Some(name.to_owned()),
+                        size: Some(4),
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        ir.process_introduction(&v1_frame).await.unwrap();
+
+        let photo = &ir.state.transferred_files[&1].file_url;
+        let evil = &ir.state.transferred_files[&2].file_url;
+        assert!(photo.ends_with("pixel/photo.jpg"));
+        // Neither the peer nor the rewriter get out of the download directory
+        assert_eq!(evil.file_name().unwrap(), "evil.sh");
+        assert_eq!(evil.parent(), photo.parent().unwrap().parent());
+
+        // The subdirectory only shows up once there's a file to put in it
+        let dir = std::env::temp_dir().join(format!("rqs_rewritten_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("1.photo.jpg.part"), b"data").unwrap();
+        let fi = InternalFileInfo {
+            payload_id: 1,
+            file_url: dir.join("pixel/photo.jpg"),
+            bytes_transferred: 4,
+            total_size: 4,
+            file: None,
+            temp_url: Some(dir.join("1.photo.jpg.part")),
+            digest: None,
+        };
+        let saved = finalize_received_file(fi, None).unwrap();
+        assert_eq!(saved, dir.join("pixel/photo.jpg"));
+        assert_eq!(std::fs::read(&saved).unwrap(), b"data");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_parent_folder() {
+        let (mut ir, _peer) = new_request().await;
+        ir.state.state = State::ReceivedPairedKeyResult;
+
+        let v1_frame = sharing_nearby::V1Frame {
+            r#type: Some(sharing_nearby::v1_frame::FrameType::Introduction.into()),
+            introduction: Some(IntroductionFrame {
+                file_metadata: [
+                    (1, Some("Photos/2024")),
+                    (2, Some("../../etc")),
+                    (3, Some("/etc")),
+                    (4, None),
+                ]
+                .into_iter()
+                .map(|(id, folder)| FileMetadata {
+                    payload_id: Some(id),
+                    name: Some(format!("file_{id}")),
+                    size: Some(4),
+                    parent_folder: folder.map(str::to_owned),
+                    ..Default::default()
+                })
+                .collect(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        ir.process_introduction(&v1_frame).await.unwrap();
+
+        let download = ir.download_dir.clone();
+        let dest = |id| ir.state.transferred_files[&id].file_url.clone();
+        assert!(dest(1).starts_with(download.join("Photos/2024")));
+        // Folders that would leave the download directory are ignored
+        for id in [2, 3, 4] {
+            assert_eq!(dest(id).parent(), Some(download.as_path()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_empty_folders() {
+        let dir = std::env::temp_dir().join(format!("rqs_empty_folders_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("downloads")).unwrap();
+        std::fs::create_dir_all(dir.join("stock")).unwrap();
+        let introduction = |capabilities: Vec<i32>| sharing_nearby::V1Frame {
+            r#type: Some(sharing_nearby::v1_frame::FrameType::Introduction.into()),
+            introduction: Some(IntroductionFrame {
+                file_metadata: vec![FileMetadata {
+                    payload_id: Some(1),
+                    name: Some(String::from("cover.jpg")),
+                    size: Some(4),
+                    parent_folder: Some(String::from("Photos")),
+                    ..Default::default()
+                }],
+                capabilities,
+                empty_folders: ["Photos/empty", "Photos/2024/summer", "../escaped"]
+                    .map(str::to_owned)
+                    .to_vec(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        // Recreated along with the files, but not outside the download directory
+        let (mut ir, _peer) = new_request().await;
+        ir.set_download_dir(dir.join("downloads"));
+        ir.state.state = State::ReceivedPairedKeyResult;
+        ir.process_introduction(&introduction(our_capabilities()))
+            .await
+            .unwrap();
+        ir.accept_transfer(None).await.unwrap();
+        assert!(dir.join("downloads/Photos/empty").is_dir());
+        assert!(dir.join("downloads/Photos/2024/summer").is_dir());
+        assert!(!dir.join("escaped").exists());
+
+        // Not from a peer that didn't advertise them
+        let (mut ir, _peer) = new_request().await;
+        ir.set_download_dir(dir.join("stock"));
+        ir.state.state = State::ReceivedPairedKeyResult;
+        ir.process_introduction(&introduction(vec![]))
+            .await
+            .unwrap();
+        ir.accept_transfer(None).await.unwrap();
+        assert!(ir.state.empty_folders.is_empty());
+        assert!(!dir.join("stock/Photos/empty").exists());
+
+        // Nothing but folders, as the last part of a split directory can be
+        let mut folders_only = introduction(our_capabilities());
+        if let Some(introduction) = folders_only.introduction.as_mut() {
+            introduction.file_metadata.clear();
+        }
+        let (mut ir, _peer) = new_request().await;
+        ir.set_download_dir(dir.join("folders"));
+        ir.state.state = State::ReceivedPairedKeyResult;
+        ir.process_introduction(&folders_only).await.unwrap();
+        assert_eq!(ir.state.state, State::WaitingForUserConsent);
+        ir.accept_transfer(None).await.unwrap();
+        assert!(dir.join("folders/Photos/empty").is_dir());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_consent_timeout() {
+        let (mut ir, mut peer) = new_request().await;
+        ir.state.state = State::ReceivedPairedKeyResult;
+        ir.consent_timeout = Duration::from_secs(30);
+
+        let v1_frame = sharing_nearby::V1Frame {
+            r#type: Some(sharing_nearby::v1_frame::FrameType::Introduction.into()),
+            introduction: Some(IntroductionFrame {
+                file_metadata: vec![FileMetadata {
+                    payload_id: Some(1),
+                    name: Some(String::from("unanswered.txt")),
+                    size: Some(4),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        ir.process_introduction(&v1_frame).await.unwrap();
+        assert_eq!(ir.state.state, State::WaitingForUserConsent);
+
+        // Nobody ever answers
+        let started = Instant::now();
+        while ir.handle().await.is_ok() {}
+        assert!(started.elapsed() >= Duration::from_secs(30));
+        assert_eq!(ir.state.state, State::Rejected);
+        assert_eq!(
+            ir.state.transfer_metadata.as_ref().unwrap().cancel_reason,
+            Some(CancelReason::ConsentTimeout)
+        );
+
+        // The sender is told, rather than left waiting
+        let body = open(&ir, &mut peer)
+            .await
+            .v1
+            .and_then(|v1| v1.payload_transfer)
+            .and_then(|transfer| transfer.payload_chunk)
+            .and_then(|chunk| chunk.body)
+            .unwrap();
+        let response = sharing_nearby::Frame::decode(body.as_slice())
+            .unwrap()
+            .v1
+            .and_then(|v1| v1.connection_response)
+            .unwrap();
+        assert_eq!(
+            response.status(),
+            sharing_nearby::connection_response_frame::Status::TimedOut
+        );
+    }
+
+    #[tokio::test]
+    async fn test_inconsistent_introduction() {
+        let (mut ir, _peer) = new_request().await;
+        ir.state.state = State::ReceivedPairedKeyResult;
+
+        let mut introduction = IntroductionFrame {
+            file_metadata: (0..2)
+                .map(|i| FileMetadata {
+                    payload_id: Some(i),
+                    name: Some(format!("file_{i}")),
+                    size: Some(10 * (i + 1)),
+                    ..Default::default()
+                })
+                .collect(),
+            total_size: Some(40),
+            ..Default::default()
+        };
+        let v1_frame = sharing_nearby::V1Frame {
+            r#type: Some(sharing_nearby::v1_frame::FrameType::Introduction.into()),
+            introduction: Some(introduction.clone()),
+            ..Default::default()
+        };
+
+        let e = ir.process_introduction(&v1_frame).await.unwrap_err();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(AppError::InconsistentIntroduction {
+                declared: 40,
+                actual: Some(30)
+            })
+        ));
+        // Turned down before anything was asked
+        assert_eq!(ir.state.state, State::ReceivedPairedKeyResult);
+        assert!(ir.state.transfer_metadata.is_none());
+
+        introduction.total_size = Some(30);
+        assert!(check_introduction_total(&introduction).is_ok());
+        introduction.total_size = None;
+        assert!(check_introduction_total(&introduction).is_ok());
+
+        introduction.total_size = Some(i64::MAX);
+        introduction.file_metadata[0].size = Some(i64::MAX);
+        assert!(check_introduction_total(&introduction).is_err());
+    }
+
+    #[test]
+    fn test_streamed_digest() {
+        let data: Vec<u8> = (0..3 * 1024).map(|i| (i % 251) as u8).collect();
+        let reference = Sha256::digest(&data);
+
+        let received = |corrupt: bool| {
+            let mut digest = Sha256::new();
+            for chunk in data.chunks(1000) {
+                digest.update(chunk);
+            }
+            if corrupt {
+                digest.update([0u8]);
+            }
+
+            InternalFileInfo {
+                payload_id: 42,
+                file_url: PathBuf::from("/nonexistent"),
+                bytes_transferred: data.len() as i64,
+                total_size: data.len() as i64,
+                file: None,
+                temp_url: None,
+                digest: Some(digest),
+            }
+        };
+
+        assert!(finalize_received_file(received(false), Some(reference.as_slice())).is_ok());
+        let e = finalize_received_file(received(true), Some(reference.as_slice())).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(AppError::DigestMismatch(42))
+        ));
+        // No digest from the peer, nothing to check against
+        assert!(finalize_received_file(received(true), None).is_ok());
+    }
+}
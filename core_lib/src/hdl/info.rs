@@ -2,6 +2,7 @@ use std::fs::File;
 use std::path::PathBuf;
 
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 use ts_rs::TS;
 
 use crate::utils::RemoteDeviceInfo;
@@ -15,6 +16,10 @@ pub struct InternalFileInfo {
     pub bytes_transferred: i64,
     pub total_size: i64,
     pub file: Option<File>,
+    // Where the data is written while in progress (receive side only)
+    pub temp_url: Option<PathBuf>,
+    // Running digest of the bytes received so far (receive side only)
+    pub digest: Option<Sha256>,
 }
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize, TS)]
@@ -22,15 +27,117 @@ pub struct InternalFileInfo {
 pub struct TransferMetadata {
     pub id: String,
     pub source: Option<RemoteDeviceInfo>,
+    // The receiver as it described itself when accepting (outbound only)
+    pub accepted_by: Option<RemoteDeviceInfo>,
     pub pin_code: Option<String>,
 
     pub destination: Option<String>,
     pub files: Option<Vec<String>>,
+    // Files offered but left out by the receiver
+    pub skipped_files: Option<Vec<String>>,
+    pub note: Option<String>,
 
     pub text_type: Option<TextPayloadType>,
     pub text_description: Option<String>,
     pub text_payload: Option<String>,
 
     pub total_bytes: u64,
+    // Useful payload bytes delivered, excluding any protocol overhead. See
+    // goodput for the rate.
     pub ack_bytes: u64,
+    // The file being sent as of this update (outbound only)
+    pub file_progress: Option<FileProgress>,
+    // Raw bytes on the wire (length prefixes, encryption, HMAC, ...)
+    pub wire_bytes: u64,
+    // Average goodput since the payloads started flowing, in bytes/s
+    pub goodput: u64,
+    // Since the payloads started flowing, in ms. Once Finished, how long the
+    // transfer itself took, the handshake and consent excluded.
+    pub duration_ms: Option<u64>,
+    // Paused by ChannelAction::Pause, file_progress telling where (outbound only)
+    pub paused: bool,
+    // How long the UKEY2 handshake took, in ms
+    pub handshake_ms: Option<u64>,
+    // Sent files the receiver confirmed having written and verified (outbound only)
+    pub confirmed_files: Option<Vec<String>>,
+    // Absolute paths the received files were saved at, after any renaming (inbound only)
+    pub saved_files: Option<Vec<String>>,
+
+    // Only present once the transfer was cancelled on our side. The reason is
+    // also given when it was declined automatically.
+    pub cancellation: Option<CancellationKind>,
+    pub cancel_reason: Option<CancelReason>,
+    // Only present once the transfer failed, see OutboundError
+    pub error: Option<TransferError>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub struct FileProgress {
+    // As introduced, telling apart the files of a transfer
+    pub payload_id: i64,
+    pub bytes_transferred: u64,
+    pub total_bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub struct TransferError {
+    // Stable, meant to be matched on
+    pub code: String,
+    // Meant to be shown as is
+    pub hint: String,
+}
+
+/// Why a frame from the peer was turned down, reported through
+/// ChannelMessage.rejection right before the session ends. Never carries
+/// key material nor the frame's content.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub struct FrameRejection {
+    // What the frame was, or was expected to be, when known
+    pub frame_type: Option<String>,
+    pub reason: RejectReason,
+    // The offending value, e.g. the announced length or sequence number
+    pub value: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub enum RejectReason {
+    // Above what we're willing to allocate for
+    Oversized,
+    // The signature doesn't match, tampered with or wrong keys
+    BadHmac,
+    // Replayed, reordered or dropped frame
+    BadSequence,
+    // Couldn't be decoded as what was expected in this state
+    Malformed,
+    // Well-formed, but not what was expected in this state
+    Unexpected,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub enum CancellationKind {
+    // The peer reacted to our disconnection within the grace period
+    Clean,
+    // The peer stayed silent and the socket was closed by force
+    Forced,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub enum CancelReason {
+    // Explicitly asked for from the frontend
+    User,
+    // The peer disappeared from the discovery mid-transfer
+    PeerOffline,
+    // Nobody confirmed the PIN in time, see State::AwaitingPinConfirmation
+    PinConfirmationTimeout,
+    // The user saw another PIN on the receiver, see ChannelAction::RejectPin
+    PinMismatch,
+    // Nobody accepted nor rejected an inbound transfer in time, it was
+    // declined (State::Rejected) rather than cancelled
+    ConsentTimeout,
 }
@@ -9,7 +9,7 @@ use tokio::time::{interval_at, Instant};
 use tokio_util::sync::CancellationToken;
 use ts_rs::TS;
 
-use crate::utils::{gen_mdns_endpoint_info, gen_mdns_name, DeviceType};
+use crate::utils::{gen_mdns_endpoint_info, gen_mdns_name, local_device_info, RemoteDeviceInfo};
 
 const INNER_NAME: &str = "MDnsServer";
 const TICK_INTERVAL: Duration = Duration::from_secs(60);
@@ -50,7 +50,7 @@ impl MDnsServer {
         visibility_sender: Arc<Mutex<watch::Sender<Visibility>>>,
         visibility_receiver: watch::Receiver<Visibility>,
     ) -> Result<Self, anyhow::Error> {
-        let service_info = Self::build_service(endpoint_id, service_port, DeviceType::Laptop)?;
+        let service_info = Self::build_service(endpoint_id, service_port, local_device_info())?;
 
         Ok(Self {
             daemon: ServiceDaemon::new()?,
@@ -133,12 +133,14 @@ impl MDnsServer {
     fn build_service(
         endpoint_id: [u8; 4],
         service_port: u16,
-        device_type: DeviceType,
+        device_info: RemoteDeviceInfo,
     ) -> Result<ServiceInfo, anyhow::Error> {
         let name = gen_mdns_name(endpoint_id);
+        // Still the actual host the records point to, whatever name we go by
         let hostname = sys_metrics::host::get_hostname()?;
-        info!("Broadcasting with: {hostname}");
-        let endpoint_info = gen_mdns_endpoint_info(device_type as u8, &hostname);
+        info!("Broadcasting with: {}", device_info.name);
+        let endpoint_info =
+            gen_mdns_endpoint_info(device_info.device_type as u8, &device_info.name);
 
         let properties = [("n", endpoint_info)];
         let si = ServiceInfo::new(
@@ -1,13 +1,17 @@
 use std::collections::HashMap;
+use std::net::SocketAddr;
 
 use mdns_sd::{ServiceDaemon, ServiceEvent};
 use serde::{Deserialize, Serialize};
-use tokio::net::TcpStream;
 use tokio::sync::broadcast;
 use tokio_util::sync::CancellationToken;
 use ts_rs::TS;
 
-use crate::utils::{is_not_self_ip, parse_mdns_endpoint_info};
+use crate::channel::{ChannelAction, ChannelDirection, ChannelMessage};
+use crate::utils::{
+    connect_first, get_address_family, get_cancel_on_peer_offline, is_not_self_ip,
+    parse_mdns_endpoint_info, sort_by_family,
+};
 use crate::DeviceType;
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize, TS)]
@@ -25,13 +29,21 @@ pub struct EndpointInfo {
 pub struct MDnsDiscovery {
     daemon: ServiceDaemon,
     sender: broadcast::Sender<EndpointInfo>,
+    message_sender: broadcast::Sender<ChannelMessage>,
 }
 
 impl MDnsDiscovery {
-    pub fn new(sender: broadcast::Sender<EndpointInfo>) -> Result<Self, anyhow::Error> {
+    pub fn new(
+        sender: broadcast::Sender<EndpointInfo>,
+        message_sender: broadcast::Sender<ChannelMessage>,
+    ) -> Result<Self, anyhow::Error> {
         let daemon = ServiceDaemon::new()?;
 
-        Ok(Self { daemon, sender })
+        Ok(Self {
+            daemon,
+            sender,
+            message_sender,
+        })
     }
 
     pub async fn run(self, ctk: CancellationToken) -> Result<(), anyhow::Error> {
@@ -56,20 +68,18 @@ impl MDnsDiscovery {
                                 ServiceEvent::ServiceResolved(info) => {
                                     let port = info.get_port();
 
-                                    let ip_hash = info.get_addresses_v4();
-                                    if ip_hash.is_empty() {
-                                        continue;
-                                    }
-
-                                    let ip = match ip_hash.iter().next() {
-                                        Some(i) => i,
-                                        None => continue,
-                                    };
-
-                                    // Check that the IP is not a "self IP"
-                                    if !is_not_self_ip(ip) {
+                                    // Skip the "self IP", sorted so that IPv4 comes first by default
+                                    let mut addrs: Vec<SocketAddr> = info
+                                        .get_addresses()
+                                        .iter()
+                                        .filter(|ip| is_not_self_ip(ip))
+                                        .map(|ip| SocketAddr::new(*ip, port))
+                                        .collect();
+                                    if addrs.is_empty() {
                                         continue;
                                     }
+                                    addrs.sort();
+                                    sort_by_family(&mut addrs, get_address_family());
 
                                     // Decode the "n" text properties
                                     let n = match info.get_property("n") {
@@ -83,14 +93,13 @@ impl MDnsDiscovery {
                                         Err(_) => continue
                                     };
 
-                                    let ip_port = format!("{ip}:{port}");
                                     let fullname = info.get_fullname().to_string();
-                                    if TcpStream::connect(&ip_port).await.is_ok() {
+                                    if let Ok((_, addr)) = connect_first(&addrs).await {
                                         let ei = EndpointInfo {
                                             fullname: fullname.clone(),
-                                            id: ip_port,
+                                            id: addr.to_string(),
                                             name: Some(dn),
-                                            ip: Some(ip.to_string()),
+                                            ip: Some(addr.ip().to_string()),
                                             port: Some(port.to_string()),
                                             rtype: Some(dt),
                                             present: Some(true),
@@ -108,6 +117,9 @@ impl MDnsDiscovery {
                                     if let Some(id) = should_remove {
                                         info!("ServiceRemoved: Remove a previous service: {}", fullname);
                                         cache.remove(&fullname);
+                                        if get_cancel_on_peer_offline() {
+                                            let _ = self.message_sender.send(peer_offline_message(id.clone()));
+                                        }
                                         let _ = self.sender.send(EndpointInfo {
                                             id,
                                             ..Default::default()
@@ -127,3 +139,13 @@ impl MDnsDiscovery {
         Ok(())
     }
 }
+
+// Outbound transfers use the endpoint id as their own id, so they'll pick this up
+pub(crate) fn peer_offline_message(id: String) -> ChannelMessage {
+    ChannelMessage {
+        id,
+        direction: ChannelDirection::FrontToLib,
+        action: Some(ChannelAction::PeerOffline),
+        ..Default::default()
+    }
+}
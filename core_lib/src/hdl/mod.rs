@@ -1,12 +1,43 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt::Arguments;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
+use hmac::{Hmac, Mac};
+use libaes::{Cipher, AES_256_KEY_LEN};
+use log::{Level, Record};
 use p256::{PublicKey, SecretKey};
+use prost::Message;
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::sync::{AcquireError, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
 use ts_rs::TS;
 
-use self::info::{InternalFileInfo, TransferMetadata};
+use self::info::{FrameRejection, InternalFileInfo, RejectReason, TransferMetadata};
+use crate::location_nearby_connections::bandwidth_upgrade_negotiation_frame::upgrade_path_info::Medium;
+use crate::location_nearby_connections::bandwidth_upgrade_negotiation_frame::{
+    self, UpgradePathInfo,
+};
+use crate::location_nearby_connections::{self, BandwidthUpgradeNegotiationFrame, OfflineFrame};
 use crate::securegcm::ukey2_client_init::CipherCommitment;
-use crate::utils::RemoteDeviceInfo;
+use crate::securegcm::{
+    ukey2_message, DeviceToDeviceMessage, GcmMetadata, Type, Ukey2Alert, Ukey2Message,
+};
+use crate::securemessage::{EncScheme, Header, HeaderAndBody, SecureMessage, SigScheme};
+use crate::sharing_nearby::RqsCapability;
+use crate::utils::{gen_random, get_transfer_log_level, is_safe_relative_path, RemoteDeviceInfo};
+
+// Like the log macros, except that the level can be raised for a single
+// transfer, see RQS::set_transfer_log_level
+macro_rules! tlog {
+    ($id:expr, $lvl:expr, $($arg:tt)+) => {
+        $crate::hdl::transfer_log($id, $lvl, module_path!(), format_args!($($arg)+))
+    };
+}
 
 mod ble;
 pub use ble::*;
@@ -38,7 +69,15 @@ pub enum State {
     ReceivedUkeyClientFinish,
     SentConnectionResponse,
     SentPairedKeyResult,
+    // Outbound only, when enabled: the user must confirm the PIN matches
+    // the one shown by the receiver before the files are introduced
+    AwaitingPinConfirmation {
+        pin: String,
+    },
     SentIntroduction,
+    // Outbound only, when asked for: the receiver accepted, the payloads go
+    // out on ChannelAction::Proceed
+    AwaitingProceed,
     ReceivedPairedKeyResult,
     WaitingForUserConsent,
     ReceivingFiles,
@@ -49,6 +88,18 @@ pub enum State {
     Finished,
 }
 
+impl State {
+    // The same state without the PIN, which only the user is meant to see
+    fn redacted(&self) -> Self {
+        match self {
+            Self::AwaitingPinConfirmation { .. } => {
+                Self::AwaitingPinConfirmation { pin: String::new() }
+            }
+            other => other.clone(),
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct InnerState {
     pub id: String,
@@ -62,6 +113,19 @@ pub struct InnerState {
     pub pin_code: Option<String>,
     pub transfer_metadata: Option<TransferMetadata>,
     pub transferred_files: HashMap<i64, InternalFileInfo>,
+    // Payload ids in the order of the introduction, i.e. of TransferMetadata.files
+    pub file_order: Vec<i64>,
+    // Name and size of every introduced file, kept once done with it unlike
+    // transferred_files (see history)
+    pub introduced_files: HashMap<i64, (String, u64)>,
+    // Bytes written (outbound) or read (inbound) on the socket, framing included
+    pub wire_bytes: u64,
+    pub transfer_started: Option<Instant>,
+    // Outbound only: no chunk goes out until ChannelAction::Resume
+    pub paused: bool,
+    // From the connection request up to the derivation of the keys
+    pub handshake_started: Option<Instant>,
+    pub handshake_duration: Option<Duration>,
 
     // Everything needed for encryption/decryption/verif
     pub cipher_commitment: Option<CipherCommitment>,
@@ -81,6 +145,781 @@ pub struct InnerState {
     // pub text_is_url: bool,
     // pub wifi_ssid: Option<String>,
     pub payload_buffers: HashMap<i64, Vec<u8>>,
+    // Bytes payloads too large to be buffered, written to disk instead
+    pub payload_sinks: HashMap<i64, InternalFileInfo>,
+    // Fully received payloads, their ids can't be reused within the session
+    pub completed_payloads: HashSet<i64>,
+    // What the peer advertised, nothing until its introduction/response
+    pub peer_capabilities: PeerCapabilities,
+    // Of an inbound directory, created along with the files once accepted
+    pub empty_folders: Vec<PathBuf>,
+    // Per outbound file, only sampled when enabled (see Compressibility)
+    pub compressibility: HashMap<i64, Compressibility>,
+    // Once the warm-up of an outbound transfer is over, see ChunkTuner
+    pub chunk_tuning: Option<ChunkTuning>,
+}
+
+impl InnerState {
+    // Never trust encryption_done alone, it starts out as true
+    pub(crate) fn ensure_encrypted(&self) -> Result<(), anyhow::Error> {
+        if !self.encryption_done || self.encrypt_key.is_none() || self.send_hmac_key.is_none() {
+            return Err(anyhow::anyhow!(
+                crate::errors::AppError::EncryptionNotEstablished
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn snapshot(&self) -> StateSnapshot {
+        let metadata = self.transfer_metadata.as_ref();
+
+        StateSnapshot {
+            id: self.id.clone(),
+            state: self.state.redacted(),
+            server_seq: self.server_seq,
+            client_seq: self.client_seq,
+            encryption_done: self.encryption_done,
+            remote_device_info: self.remote_device_info.clone(),
+            handshake_cipher: self
+                .cipher_commitment
+                .as_ref()
+                .map(|c| c.handshake_cipher().as_str_name().to_owned()),
+            total_bytes: metadata.map_or(0, |m| m.total_bytes),
+            ack_bytes: metadata.map_or(0, |m| m.ack_bytes),
+            wire_bytes: self.wire_bytes,
+            compressibility: self.compressibility.clone(),
+            chunk_tuning: self.chunk_tuning,
+        }
+    }
+}
+
+/// DANGEROUS: the keys derived during the handshake of a session, in the
+/// clear. Whoever gets hold of them can read and forge everything sent in
+/// that session. Only meant for cross-checking the key derivation against a
+/// capture of a known-good session, hence the feature it's behind and that
+/// it won't build in release.
+#[cfg(feature = "dangerous-debug-keys")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionKeys {
+    pub decrypt_key: Vec<u8>,
+    pub recv_hmac_key: Vec<u8>,
+    pub encrypt_key: Vec<u8>,
+    pub send_hmac_key: Vec<u8>,
+}
+
+#[cfg(all(feature = "dangerous-debug-keys", not(debug_assertions)))]
+compile_error!("the dangerous-debug-keys feature must never make it into a release build");
+
+#[cfg(feature = "dangerous-debug-keys")]
+impl InnerState {
+    // None until the handshake derived them
+    pub fn session_keys(&self) -> Option<SessionKeys> {
+        Some(SessionKeys {
+            decrypt_key: self.decrypt_key.clone()?,
+            recv_hmac_key: self.recv_hmac_key.clone()?,
+            encrypt_key: self.encrypt_key.clone()?,
+            send_hmac_key: self.send_hmac_key.clone()?,
+        })
+    }
+}
+
+/// Read-only view of an InnerState, meant for diagnostics. Keys, the
+/// handshake material and the PIN are deliberately left out.
+#[derive(Debug, Clone, Serialize)]
+pub struct StateSnapshot {
+    pub id: String,
+    // With an empty PIN when awaiting its confirmation
+    pub state: State,
+    pub server_seq: i32,
+    pub client_seq: i32,
+    pub encryption_done: bool,
+    pub remote_device_info: Option<RemoteDeviceInfo>,
+    pub handshake_cipher: Option<String>,
+    pub total_bytes: u64,
+    pub ack_bytes: u64,
+    pub wire_bytes: u64,
+    pub compressibility: HashMap<i64, Compressibility>,
+    pub chunk_tuning: Option<ChunkTuning>,
+}
+
+/// Bounds applied to an incoming introduction frame, on top of the
+/// generic frame length cap, so that a crafted introduction can't
+/// force us into parsing thousands of entries.
+#[derive(Debug, Clone, Copy)]
+pub struct IntroductionLimits {
+    // Maximum encoded size (in bytes) of the introduction frame
+    pub max_frame_size: usize,
+    // Maximum number of entries (files, texts, wifi) it may contain
+    pub max_entries: usize,
+}
+
+impl Default for IntroductionLimits {
+    fn default() -> Self {
+        Self {
+            max_frame_size: 1024 * 1024,
+            max_entries: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PayloadKind {
+    File,
+    Text,
+    Url,
+    Wifi,
+}
+
+/// Maximum size (in bytes), per payload type, under which an incoming
+/// transfer gets accepted without asking the user. None always prompts.
+#[derive(Debug, Clone, Default)]
+pub struct AutoAcceptPolicy {
+    pub file: Option<u64>,
+    pub text: Option<u64>,
+    pub url: Option<u64>,
+    pub wifi: Option<u64>,
+}
+
+impl AutoAcceptPolicy {
+    pub fn should_auto_accept(&self, kind: PayloadKind, size: u64) -> bool {
+        let max_size = match kind {
+            PayloadKind::File => self.file,
+            PayloadKind::Text => self.text,
+            PayloadKind::Url => self.url,
+            PayloadKind::Wifi => self.wifi,
+        };
+
+        max_size.is_some_and(|max| size <= max)
+    }
+}
+
+/// rquickshare extensions the peer said it understands. Stock Android
+/// peers advertise nothing, so none of them get used with those.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PeerCapabilities {
+    pub selective_accept: bool,
+    pub transfer_complete: bool,
+    pub resume: bool,
+    pub empty_folders: bool,
+}
+
+impl PeerCapabilities {
+    pub fn from_advertised(advertised: impl Iterator<Item = RqsCapability>) -> Self {
+        let mut caps = Self::default();
+        for cap in advertised {
+            match cap {
+                RqsCapability::SelectiveAccept => caps.selective_accept = true,
+                RqsCapability::TransferComplete => caps.transfer_complete = true,
+                RqsCapability::Resume => caps.resume = true,
+                RqsCapability::EmptyFolders => caps.empty_folders = true,
+                // Newer peer, or a value we don't know of
+                RqsCapability::UnknownCapability => {}
+            }
+        }
+
+        caps
+    }
+}
+
+// Advertised in our own introduction and response
+pub(crate) fn our_capabilities() -> Vec<i32> {
+    vec![
+        RqsCapability::SelectiveAccept.into(),
+        RqsCapability::TransferComplete.into(),
+        RqsCapability::Resume.into(),
+        RqsCapability::EmptyFolders.into(),
+    ]
+}
+
+/// Turns the name of an incoming file, once sanitized, into the path it's
+/// saved at relative to the download directory, eg. to sort files by peer.
+/// Given the peer's info as well, see RQS::set_filename_rewriter.
+#[derive(Clone)]
+pub struct FilenameRewriter(Arc<dyn Fn(&str, &RemoteDeviceInfo) -> String + Send + Sync>);
+
+impl FilenameRewriter {
+    pub fn new(
+        rewrite: impl Fn(&str, &RemoteDeviceInfo) -> String + Send + Sync + 'static,
+    ) -> Self {
+        Self(Arc::new(rewrite))
+    }
+
+    // What comes back is just as untrusted as the name from the peer, the
+    // sanitized name is kept when it could land outside the download directory
+    pub(crate) fn rewrite(&self, name: &str, peer: &RemoteDeviceInfo) -> String {
+        let rewritten = (self.0)(name, peer);
+        if is_safe_relative_path(&rewritten) {
+            rewritten
+        } else {
+            warn!(
+                "Ignoring unsafe rewritten name {:?} for {:?}",
+                rewritten, name
+            );
+            name.to_owned()
+        }
+    }
+}
+
+impl std::fmt::Debug for FilenameRewriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("FilenameRewriter")
+    }
+}
+
+// Read from the start of a file to estimate how well it compresses
+const COMPRESSIBILITY_SAMPLE_SIZE: u64 = 64 * 1024;
+// Above this (in bits per byte), compressing isn't worth the CPU
+const MAX_COMPRESSIBLE_ENTROPY: f64 = 7.5;
+
+/// How well a file would compress, going by the Shannon entropy of its
+/// first bytes rather than by its MIME type, which says little for
+/// archives, documents or unknown types.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Compressibility {
+    // In bits per byte, from 0 (a single repeated byte) to 8 (random)
+    pub entropy: f64,
+    pub compress: bool,
+}
+
+impl Compressibility {
+    pub fn from_sample(sample: &[u8]) -> Self {
+        let mut counts = [0usize; 256];
+        for b in sample {
+            counts[*b as usize] += 1;
+        }
+
+        let len = sample.len() as f64;
+        let entropy = counts
+            .iter()
+            .filter(|c| **c > 0)
+            .map(|c| {
+                let p = *c as f64 / len;
+                -p * p.log2()
+            })
+            .sum::<f64>();
+
+        Self {
+            entropy,
+            // Nothing to gain on an empty file
+            compress: !sample.is_empty() && entropy < MAX_COMPRESSIBLE_ENTROPY,
+        }
+    }
+
+    pub(crate) fn of_file(path: &Path) -> Result<Self, std::io::Error> {
+        let mut sample = vec![];
+        std::fs::File::open(path)?
+            .take(COMPRESSIBILITY_SAMPLE_SIZE)
+            .read_to_end(&mut sample)?;
+
+        Ok(Self::from_sample(&sample))
+    }
+}
+
+// How long the first chunks of a transfer are timed before settling on a size
+const WARMUP_DURATION: Duration = Duration::from_secs(2);
+// Each chunk should take about this long to go through at the measured rate
+const CHUNK_TARGET_DURATION: Duration = Duration::from_millis(100);
+const MIN_CHUNK_SIZE: usize = 64 * 1024;
+// Well under the 5 MB receivers accept for a single frame
+const MAX_CHUNK_SIZE: usize = 2 * 1024 * 1024;
+
+/// What the warm-up of a transfer measured and settled on.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ChunkTuning {
+    // Goodput during the warm-up, in bytes/s
+    pub throughput: u64,
+    pub chunk_size: usize,
+}
+
+/// Opt-in adaptive chunk size. The first WARMUP_DURATION of a transfer goes
+/// out in chunks of the initial size while the throughput is measured, the
+/// rest in chunks sized to take about CHUNK_TARGET_DURATION each: fewer
+/// frames (and progress events) on a fast link, smoother progress on a slow
+/// one.
+#[derive(Debug, Clone)]
+pub struct ChunkTuner {
+    chunk_size: usize,
+    sent: u64,
+    tuning: Option<ChunkTuning>,
+}
+
+impl ChunkTuner {
+    pub fn new(initial_chunk_size: usize) -> Self {
+        Self {
+            chunk_size: initial_chunk_size,
+            sent: 0,
+            tuning: None,
+        }
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    // Account for a chunk that went out, elapsed being counted from the start
+    // of the transfer. Returns the tuning the moment the warm-up ends.
+    pub fn record(&mut self, bytes: usize, elapsed: Duration) -> Option<ChunkTuning> {
+        if self.tuning.is_some() {
+            return None;
+        }
+
+        self.sent += bytes as u64;
+        if elapsed < WARMUP_DURATION {
+            return None;
+        }
+
+        let throughput = crate::utils::bytes_per_second(self.sent, elapsed);
+        let target = (throughput as f64 * CHUNK_TARGET_DURATION.as_secs_f64()) as usize;
+        // Rounded down to whole 64 KB blocks
+        let chunk_size =
+            target.clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE) / MIN_CHUNK_SIZE * MIN_CHUNK_SIZE;
+
+        self.chunk_size = chunk_size;
+        self.tuning = Some(ChunkTuning {
+            throughput,
+            chunk_size,
+        });
+        self.tuning
+    }
+}
+
+/// Memory budget shared by the chunk buffers of every outbound transfer, so
+/// that interleaving many files (or sessions) never holds more than the limit
+/// at once. Reading the next chunk waits for enough of it to be freed.
+#[derive(Debug, Clone)]
+pub struct ChunkMemory {
+    limit: usize,
+    available: Arc<Semaphore>,
+}
+
+impl ChunkMemory {
+    pub fn new(limit: usize) -> Self {
+        // One permit per byte, the semaphore can't count past u32
+        let limit = limit.clamp(1, u32::MAX as usize);
+        Self {
+            limit,
+            available: Arc::new(Semaphore::new(limit)),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    // Wait for size bytes of the budget, cut down to the limit so that a chunk
+    // can always go through. Returns the reservation, given back when dropped,
+    // along with the size granted.
+    pub async fn reserve(
+        &self,
+        size: usize,
+    ) -> Result<(OwnedSemaphorePermit, usize), AcquireError> {
+        let size = size.min(self.limit);
+        let permit = self
+            .available
+            .clone()
+            .acquire_many_owned(size as u32)
+            .await?;
+        Ok((permit, size))
+    }
+}
+
+/// How the read slots (see RQS::set_max_concurrent_reads) are shared
+/// between the files of every outbound transfer.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SchedulingPolicy {
+    // A file keeps its slot until it was read entirely, first come first
+    // served
+    #[default]
+    Fifo,
+    // The slot is given back after every chunk and handed out in turn, so
+    // that a huge file doesn't hold up the small ones behind it
+    Fair,
+    // Like Fair, except that the file with the fewest bytes left goes first
+    SmallestFirst,
+}
+
+impl SchedulingPolicy {
+    pub fn per_chunk(self) -> bool {
+        self != Self::Fifo
+    }
+
+    // Lowest first, ties going in order of arrival
+    pub fn rank(self, bytes_left: u64) -> u64 {
+        match self {
+            Self::SmallestFirst => bytes_left,
+            Self::Fifo | Self::Fair => 0,
+        }
+    }
+}
+
+/// A bounded number of slots to read files with, like a semaphore except
+/// that they go to the lowest rank rather than strictly in order of arrival.
+#[derive(Debug, Clone)]
+pub struct ReadSlots {
+    inner: Arc<ReadSlotsInner>,
+}
+
+#[derive(Debug)]
+struct ReadSlotsInner {
+    queue: std::sync::Mutex<SlotQueue>,
+    released: Notify,
+}
+
+#[derive(Debug)]
+struct SlotQueue {
+    free: usize,
+    next_ticket: u64,
+    // Rank and ticket of everyone waiting for a slot
+    waiting: BTreeSet<(u64, u64)>,
+}
+
+/// Given back when dropped.
+#[derive(Debug)]
+pub struct ReadSlot {
+    inner: Arc<ReadSlotsInner>,
+}
+
+impl ReadSlots {
+    pub fn new(slots: usize) -> Self {
+        Self {
+            inner: Arc::new(ReadSlotsInner {
+                queue: std::sync::Mutex::new(SlotQueue {
+                    free: slots.max(1),
+                    next_ticket: 0,
+                    waiting: BTreeSet::new(),
+                }),
+                released: Notify::new(),
+            }),
+        }
+    }
+
+    pub async fn acquire(&self, rank: u64) -> ReadSlot {
+        let waiter = {
+            let mut queue = self.inner.queue.lock().unwrap();
+            let key = (rank, queue.next_ticket);
+            queue.next_ticket += 1;
+            queue.waiting.insert(key);
+            SlotWaiter {
+                inner: &self.inner,
+                key,
+            }
+        };
+
+        loop {
+            // Registered before looking, a release in between isn't missed
+            let released = self.inner.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+
+            if waiter.try_take() {
+                return ReadSlot {
+                    inner: self.inner.clone(),
+                };
+            }
+            released.await;
+        }
+    }
+}
+
+// Leaves the queue when given up on before getting a slot
+struct SlotWaiter<'a> {
+    inner: &'a ReadSlotsInner,
+    key: (u64, u64),
+}
+
+impl SlotWaiter<'_> {
+    fn try_take(&self) -> bool {
+        let mut queue = self.inner.queue.lock().unwrap();
+        if queue.free == 0 || queue.waiting.first() != Some(&self.key) {
+            return false;
+        }
+
+        queue.free -= 1;
+        queue.waiting.remove(&self.key);
+        // The next in line may well get one too
+        if queue.free > 0 && !queue.waiting.is_empty() {
+            self.inner.released.notify_waiters();
+        }
+        true
+    }
+}
+
+impl Drop for SlotWaiter<'_> {
+    fn drop(&mut self) {
+        let mut queue = self.inner.queue.lock().unwrap();
+        if queue.waiting.remove(&self.key) {
+            self.inner.released.notify_waiters();
+        }
+    }
+}
+
+impl Drop for ReadSlot {
+    fn drop(&mut self) {
+        let mut queue = self.inner.queue.lock().unwrap();
+        queue.free += 1;
+        self.inner.released.notify_waiters();
+    }
+}
+
+/// Target of the lines logged for a transfer whose level was raised, for the
+/// logger to let them through (eg: RUST_LOG=info,rqs_lib::transfer=trace)
+/// while the others stay subject to the usual filter.
+pub const TRANSFER_LOG_TARGET: &str = "rqs_lib::transfer";
+
+pub(crate) fn transfer_log(id: &str, level: Level, module_path: &'static str, args: Arguments) {
+    if get_transfer_log_level(id).is_some_and(|max| level <= max) {
+        log::logger().log(
+            &Record::builder()
+                .args(format_args!("[{id}] {args}"))
+                .level(level)
+                .target(TRANSFER_LOG_TARGET)
+                .module_path_static(Some(module_path))
+                .build(),
+        );
+    } else if level <= log::max_level() {
+        log::logger().log(
+            &Record::builder()
+                .args(args)
+                .level(level)
+                .target(module_path)
+                .module_path_static(Some(module_path))
+                .build(),
+        );
+    }
+}
+
+// How long a cancelled transfer waits for the peer to close its side
+pub(crate) const CANCEL_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+// Mediums we're able to migrate a session onto, as the sender. Joining a
+// hotspot or a Wi-Fi Direct group is up to the OS, offers of those are
+// declined and the session stays where it is.
+const SUPPORTED_UPGRADE_MEDIUMS: [Medium; 1] = [Medium::WifiLan];
+
+/// IP family tried first when a peer can be reached over both. The other
+/// one is still used as a fallback if connecting fails.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum AddressFamily {
+    #[default]
+    System,
+    Ipv4,
+    Ipv6,
+}
+
+/// Which bandwidth upgrades proposed by a receiver we're willing to follow
+/// when sending. Anything else gets declined and the session stays on its
+/// current medium, as it always does when receiving.
+#[derive(Debug, Clone)]
+pub struct UpgradePolicy {
+    pub accepted_mediums: Vec<Medium>,
+}
+
+impl Default for UpgradePolicy {
+    fn default() -> Self {
+        Self {
+            accepted_mediums: vec![Medium::WifiLan, Medium::WifiHotspot, Medium::WifiDirect],
+        }
+    }
+}
+
+impl UpgradePolicy {
+    pub fn accepts(&self, medium: Medium) -> bool {
+        self.accepted_mediums.contains(&medium) && SUPPORTED_UPGRADE_MEDIUMS.contains(&medium)
+    }
+}
+
+fn upgrade_frame(negotiation: BandwidthUpgradeNegotiationFrame) -> OfflineFrame {
+    OfflineFrame {
+        version: Some(location_nearby_connections::offline_frame::Version::V1.into()),
+        v1: Some(location_nearby_connections::V1Frame {
+            r#type: Some(
+                location_nearby_connections::v1_frame::FrameType::BandwidthUpgradeNegotiation
+                    .into(),
+            ),
+            bandwidth_upgrade_negotiation: Some(negotiation),
+            ..Default::default()
+        }),
+    }
+}
+
+pub(crate) fn build_upgrade_failure(medium: Medium) -> OfflineFrame {
+    upgrade_frame(BandwidthUpgradeNegotiationFrame {
+        event_type: Some(bandwidth_upgrade_negotiation_frame::EventType::UpgradeFailure.into()),
+        upgrade_path_info: Some(UpgradePathInfo {
+            medium: Some(medium.into()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    })
+}
+
+// LAST_WRITE_TO_PRIOR_CHANNEL, SAFE_TO_CLOSE_PRIOR_CHANNEL and the likes,
+// which carry nothing but their event
+pub(crate) fn build_upgrade_event(
+    event_type: bandwidth_upgrade_negotiation_frame::EventType,
+) -> OfflineFrame {
+    upgrade_frame(BandwidthUpgradeNegotiationFrame {
+        event_type: Some(event_type.into()),
+        ..Default::default()
+    })
+}
+
+// First frame on the upgraded connection, in the clear, so that the peer
+// can tell which session it belongs to
+pub(crate) fn build_client_introduction(endpoint_id: &[u8; 4]) -> OfflineFrame {
+    upgrade_frame(BandwidthUpgradeNegotiationFrame {
+        event_type: Some(bandwidth_upgrade_negotiation_frame::EventType::ClientIntroduction.into()),
+        client_introduction: Some(bandwidth_upgrade_negotiation_frame::ClientIntroduction {
+            endpoint_id: Some(String::from_utf8_lossy(endpoint_id).into_owned()),
+            supports_disabling_encryption: Some(false),
+        }),
+        ..Default::default()
+    })
+}
+
+// Where the peer listens for the upgraded connection, if it's offering one
+// over the network we're already on
+pub(crate) fn upgrade_address(info: &UpgradePathInfo) -> Option<SocketAddr> {
+    let socket = info.wifi_lan_socket.as_ref()?;
+    let ip = match socket.ip_address() {
+        [a, b, c, d] => IpAddr::from([*a, *b, *c, *d]),
+        bytes => IpAddr::from(<[u8; 16]>::try_from(bytes).ok()?),
+    };
+    let port = u16::try_from(socket.wifi_port()).ok()?;
+
+    Some(SocketAddr::new(ip, port))
+}
+
+pub(crate) fn reject_frame(
+    frame_type: Option<&str>,
+    reason: RejectReason,
+    value: Option<String>,
+) -> anyhow::Error {
+    anyhow::anyhow!(crate::errors::AppError::FrameRejected(FrameRejection {
+        frame_type: frame_type.map(str::to_owned),
+        reason,
+        value,
+    }))
+}
+
+// A frame decoded fine but lacks what its type requires
+pub(crate) fn missing_fields(frame_type: &str) -> anyhow::Error {
+    reject_frame(
+        Some(frame_type),
+        RejectReason::Malformed,
+        Some(String::from("missing required fields")),
+    )
+}
+
+// A Ukey2Alert sent instead of the handshake message we were waiting for,
+// as the error to end the request with rather than a type mismatch
+pub(crate) fn peer_alert(msg: &Ukey2Message) -> Option<anyhow::Error> {
+    if msg.message_type() != ukey2_message::Type::Alert {
+        return None;
+    }
+
+    // Still an alert when its content is garbled
+    let alert = Ukey2Alert::decode(msg.message_data()).unwrap_or_default();
+    warn!("The peer sent an alert: {:?}", alert);
+    Some(anyhow::anyhow!(crate::errors::AppError::PeerAlert {
+        alert: alert.r#type(),
+        message: alert.error_message,
+    }))
+}
+
+// What to report about an error out of _handle, if it was the frame's fault.
+// Anything protobuf choked on is malformed, whatever the depth it was at.
+pub(crate) fn frame_rejection(e: &anyhow::Error, state: &State) -> Option<FrameRejection> {
+    if let Some(crate::errors::AppError::FrameRejected(rejection)) = e.downcast_ref() {
+        return Some(rejection.clone());
+    }
+
+    e.downcast_ref::<prost::DecodeError>()
+        .map(|de| FrameRejection {
+            frame_type: Some(expected_frame(state).to_owned()),
+            reason: RejectReason::Malformed,
+            value: Some(de.to_string()),
+        })
+}
+
+// The frame each side waits for in the given state
+fn expected_frame(state: &State) -> &'static str {
+    match state {
+        State::Initial => "ConnectionRequest",
+        State::ReceivedConnectionRequest => "Ukey2ClientInit",
+        State::SentUkeyClientInit => "Ukey2ServerInit",
+        State::SentUkeyServerInit => "Ukey2ClientFinish",
+        State::ReceivedUkeyClientFinish | State::SentUkeyClientFinish => "ConnectionResponse",
+        _ => "SecureMessage",
+    }
+}
+
+// When the next keepalive is due, nothing having been sent since last_sent.
+// Only between the end of the handshake and the end of the transfer.
+pub(crate) fn keepalive_at(
+    interval: Option<Duration>,
+    last_sent: Instant,
+    state: &InnerState,
+) -> Option<Instant> {
+    let ended = matches!(
+        state.state,
+        State::Disconnected | State::Rejected | State::Cancelled | State::Finished
+    );
+    if ended || state.handshake_duration.is_none() {
+        return None;
+    }
+
+    interval.map(|interval| last_sent + interval)
+}
+
+/// Encrypt (AES-256-CBC) and sign (HMAC-SHA256) a frame the way every frame
+/// is once the UKEY2 handshake is over, returning the encoded SecureMessage.
+pub fn seal_frame(
+    encrypt_key: &[u8],
+    hmac_key: &[u8],
+    seq: i32,
+    frame: &OfflineFrame,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let d2d_msg = DeviceToDeviceMessage {
+        sequence_number: Some(seq),
+        message: Some(frame.encode_to_vec()),
+    };
+
+    let msg_data = d2d_msg.encode_to_vec();
+    let iv = gen_random(16);
+
+    let mut cipher = Cipher::new_256(encrypt_key[..AES_256_KEY_LEN].try_into()?);
+    cipher.set_auto_padding(true);
+    let encrypted = cipher.cbc_encrypt(&iv, &msg_data);
+
+    let hb = HeaderAndBody {
+        body: encrypted,
+        header: Header {
+            encryption_scheme: EncScheme::Aes256Cbc.into(),
+            signature_scheme: SigScheme::HmacSha256.into(),
+            iv: Some(iv),
+            public_metadata: Some(
+                GcmMetadata {
+                    r#type: Type::DeviceToDeviceMessage.into(),
+                    version: Some(1),
+                }
+                .encode_to_vec(),
+            ),
+            ..Default::default()
+        },
+    };
+
+    let mut hmac = Hmac::<Sha256>::new_from_slice(hmac_key)?;
+    hmac.update(&hb.encode_to_vec());
+    let result = hmac.finalize();
+
+    let smsg = SecureMessage {
+        header_and_body: hb.encode_to_vec(),
+        signature: result.into_bytes().to_vec(),
+    };
+
+    Ok(smsg.encode_to_vec())
 }
 
 #[derive(Debug, Clone)]
@@ -106,3 +945,322 @@ impl TextPayloadInfo {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "dangerous-debug-keys")]
+    #[test]
+    fn test_session_keys() {
+        let mut state = InnerState {
+            decrypt_key: Some(vec![1; 32]),
+            recv_hmac_key: Some(vec![2; 32]),
+            encrypt_key: Some(vec![3; 32]),
+            ..Default::default()
+        };
+        assert!(state.session_keys().is_none());
+
+        state.send_hmac_key = Some(vec![4; 32]);
+        let keys = state.session_keys().unwrap();
+        assert_eq!(keys.decrypt_key, vec![1; 32]);
+        assert_eq!(keys.send_hmac_key, vec![4; 32]);
+    }
+
+    #[test]
+    fn test_auto_accept_thresholds() {
+        let policy = AutoAcceptPolicy {
+            file: Some(1024 * 1024),
+            text: Some(u64::MAX),
+            ..Default::default()
+        };
+
+        assert!(policy.should_auto_accept(PayloadKind::File, 1024));
+        assert!(policy.should_auto_accept(PayloadKind::File, 1024 * 1024));
+        assert!(!policy.should_auto_accept(PayloadKind::File, 1024 * 1024 + 1));
+        assert!(policy.should_auto_accept(PayloadKind::Text, 42));
+        // No threshold means we always prompt
+        assert!(!policy.should_auto_accept(PayloadKind::Url, 0));
+        assert!(!AutoAcceptPolicy::default().should_auto_accept(PayloadKind::Wifi, 0));
+    }
+
+    #[test]
+    fn test_transfer_log_level() {
+        use std::sync::Mutex;
+
+        use log::{LevelFilter, Log, Metadata};
+
+        // Records every line it's given, leaving the filtering to the callers
+        struct Capture(Mutex<Vec<(String, String)>>);
+
+        impl Log for Capture {
+            fn enabled(&self, _: &Metadata) -> bool {
+                true
+            }
+
+            fn log(&self, record: &Record) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push((record.target().to_owned(), record.args().to_string()));
+            }
+
+            fn flush(&self) {}
+        }
+
+        static CAPTURE: Capture = Capture(Mutex::new(vec![]));
+        log::set_logger(&CAPTURE).unwrap();
+        log::set_max_level(LevelFilter::Info);
+        crate::TRANSFER_LOG_LEVELS
+            .write()
+            .unwrap()
+            .insert(String::from("loud-transfer"), LevelFilter::Trace);
+
+        tlog!("loud-transfer", Level::Trace, "frame {}", 1);
+        tlog!("quiet-transfer", Level::Trace, "frame {}", 2);
+        tlog!("quiet-transfer", Level::Info, "done");
+
+        // Other tests may be logging at the same time
+        let lines: Vec<(String, String)> = CAPTURE
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(target, _)| target == TRANSFER_LOG_TARGET || target == module_path!())
+            .cloned()
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                (
+                    String::from(TRANSFER_LOG_TARGET),
+                    String::from("[loud-transfer] frame 1")
+                ),
+                (String::from(module_path!()), String::from("done")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compressibility() {
+        let text = "the quick brown fox jumps over the lazy dog. ".repeat(1000);
+        let estimate = Compressibility::from_sample(text.as_bytes());
+        assert!(estimate.entropy < 5.0);
+        assert!(estimate.compress);
+
+        let estimate = Compressibility::from_sample(&gen_random(64 * 1024));
+        assert!(estimate.entropy > 7.9);
+        assert!(!estimate.compress);
+
+        assert!(!Compressibility::from_sample(&[]).compress);
+    }
+
+    #[test]
+    fn test_chunk_tuner() {
+        const INITIAL: usize = 512 * 1024;
+
+        // 40 MB/s: 4 MB chunks would take 100ms, but that's over the cap
+        let mut fast = ChunkTuner::new(INITIAL);
+        assert_eq!(fast.record(40 * 1024 * 1024, Duration::from_secs(1)), None);
+        assert_eq!(fast.chunk_size(), INITIAL);
+        let tuning = fast
+            .record(40 * 1024 * 1024, Duration::from_secs(2))
+            .unwrap();
+        assert_eq!(tuning.throughput, 40 * 1024 * 1024);
+        assert_eq!(fast.chunk_size(), MAX_CHUNK_SIZE);
+        // Settled for the rest of the transfer
+        assert_eq!(fast.record(1, Duration::from_secs(3)), None);
+
+        // 1.5 MB/s on a weak WiFi
+        let mut slow = ChunkTuner::new(INITIAL);
+        let tuning = slow
+            .record(3 * 1024 * 1024, Duration::from_secs(2))
+            .unwrap();
+        assert_eq!(tuning.chunk_size, 128 * 1024);
+        assert_eq!(slow.chunk_size(), 128 * 1024);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_read_slot_policies() {
+        const CHUNK: u64 = 512 * 1024;
+
+        // A large file read in four chunks while three small ones show up,
+        // all of them sharing a single slot
+        async fn finish_order(policy: SchedulingPolicy) -> Vec<&'static str> {
+            let slots = ReadSlots::new(1);
+            let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+
+            let (large_slots, large_tx) = (slots.clone(), tx.clone());
+            let large = tokio::spawn(async move {
+                let mut slot = None;
+                for left in (1..=4).rev() {
+                    if slot.is_none() {
+                        slot = Some(large_slots.acquire(policy.rank(left * CHUNK)).await);
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    if policy.per_chunk() {
+                        slot = None;
+                    }
+                }
+                drop(slot);
+                large_tx.send("large").unwrap();
+            });
+            while slots.inner.queue.lock().unwrap().free > 0 {
+                tokio::task::yield_now().await;
+            }
+
+            let small: Vec<_> = (0..3)
+                .map(|_| {
+                    let (slots, tx) = (slots.clone(), tx.clone());
+                    tokio::spawn(async move {
+                        let _slot = slots.acquire(policy.rank(1024)).await;
+                        tokio::time::sleep(Duration::from_millis(1)).await;
+                        tx.send("small").unwrap();
+                    })
+                })
+                .collect();
+            large.await.unwrap();
+            for t in small {
+                t.await.unwrap();
+            }
+            drop(tx);
+
+            let mut order = vec![];
+            while let Some(name) = rx.recv().await {
+                order.push(name);
+            }
+            order
+        }
+
+        assert_eq!(
+            finish_order(SchedulingPolicy::Fifo).await,
+            ["large", "small", "small", "small"]
+        );
+        assert_eq!(
+            finish_order(SchedulingPolicy::Fair).await,
+            ["small", "small", "small", "large"]
+        );
+        assert_eq!(
+            finish_order(SchedulingPolicy::SmallestFirst).await,
+            ["small", "small", "small", "large"]
+        );
+
+        // Whatever the order they queued up in, the smallest goes first
+        let slots = ReadSlots::new(1);
+        let held = slots.acquire(0).await;
+        let policy = SchedulingPolicy::SmallestFirst;
+        let (first, second) = (slots.clone(), slots.clone());
+        let large = tokio::spawn(async move { first.acquire(policy.rank(4 * CHUNK)).await });
+        tokio::task::yield_now().await;
+        let small = tokio::spawn(async move { second.acquire(policy.rank(CHUNK)).await });
+        tokio::task::yield_now().await;
+        assert_eq!(slots.inner.queue.lock().unwrap().waiting.len(), 2);
+
+        drop(held);
+        let small_slot = small.await.unwrap();
+        assert!(!large.is_finished());
+        drop(small_slot);
+        large.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_chunk_memory() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        const LIMIT: usize = 1024 * 1024;
+        let memory = ChunkMemory::new(LIMIT);
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+
+        // Several transfers interleaving chunks of different sizes
+        let tasks: Vec<_> = (0..8)
+            .map(|i| {
+                let memory = memory.clone();
+                let (in_flight, max_in_flight) = (in_flight.clone(), max_in_flight.clone());
+                tokio::spawn(async move {
+                    for _ in 0..4 {
+                        let (_reservation, size) =
+                            memory.reserve((i + 1) * 128 * 1024).await.unwrap();
+                        let buffer = vec![0u8; size];
+                        let now = in_flight.fetch_add(buffer.len(), Ordering::SeqCst) + size;
+                        max_in_flight.fetch_max(now, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(5)).await;
+                        in_flight.fetch_sub(buffer.len(), Ordering::SeqCst);
+                    }
+                })
+            })
+            .collect();
+        for t in tasks {
+            t.await.unwrap();
+        }
+
+        assert!((1..=LIMIT).contains(&max_in_flight.load(Ordering::SeqCst)));
+
+        // A chunk larger than the whole budget is cut down rather than stuck
+        let (_reservation, size) = memory.reserve(4 * LIMIT).await.unwrap();
+        assert_eq!(size, LIMIT);
+    }
+
+    #[test]
+    fn test_upgrade_policy() {
+        let policy = UpgradePolicy::default();
+        assert!(!policy.accepts(Medium::Bluetooth));
+        assert!(!policy.accepts(Medium::UnknownMedium));
+        // Accepted by default, but we can't join those ourselves
+        assert!(!policy.accepts(Medium::WifiHotspot));
+        assert!(!policy.accepts(Medium::WifiDirect));
+        assert!(policy.accepts(Medium::WifiLan));
+
+        let frame = build_upgrade_failure(Medium::Bluetooth);
+        let negotiation = frame
+            .v1
+            .as_ref()
+            .unwrap()
+            .bandwidth_upgrade_negotiation
+            .as_ref()
+            .unwrap();
+        assert_eq!(
+            negotiation.event_type(),
+            bandwidth_upgrade_negotiation_frame::EventType::UpgradeFailure
+        );
+        assert_eq!(
+            negotiation.upgrade_path_info.as_ref().unwrap().medium(),
+            Medium::Bluetooth
+        );
+    }
+
+    #[test]
+    fn test_upgrade_address() {
+        use bandwidth_upgrade_negotiation_frame::upgrade_path_info::WifiLanSocket;
+
+        let info = |ip_address: Vec<u8>, wifi_port| UpgradePathInfo {
+            medium: Some(Medium::WifiLan.into()),
+            wifi_lan_socket: Some(WifiLanSocket {
+                ip_address: Some(ip_address),
+                wifi_port: Some(wifi_port),
+            }),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            upgrade_address(&info(vec![192, 168, 1, 20], 4242)),
+            Some("192.168.1.20:4242".parse().unwrap())
+        );
+        let mut v6 = vec![0; 16];
+        v6[15] = 1;
+        assert_eq!(
+            upgrade_address(&info(v6, 4242)),
+            Some("[::1]:4242".parse().unwrap())
+        );
+        assert_eq!(upgrade_address(&info(vec![10, 0, 0], 4242)), None);
+        assert_eq!(upgrade_address(&info(vec![10, 0, 0, 1], 70000)), None);
+        // A hotspot's credentials are no address on our network
+        assert_eq!(
+            upgrade_address(&UpgradePathInfo {
+                medium: Some(Medium::WifiHotspot.into()),
+                ..Default::default()
+            }),
+            None
+        );
+    }
+}
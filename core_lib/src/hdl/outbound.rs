@@ -1,14 +1,16 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::Read;
+use std::net::SocketAddr;
 use std::os::unix::fs::MetadataExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 use anyhow::anyhow;
 use bytes::Bytes;
 use hmac::{Hmac, Mac};
 use libaes::{Cipher, AES_256_KEY_LEN};
+use log::Level;
 use p256::ecdh::diffie_hellman;
 use p256::elliptic_curve::sec1::{FromEncodedPoint, ToEncodedPoint};
 use p256::{EncodedPoint, PublicKey};
@@ -16,71 +18,416 @@ use prost::Message;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256, Sha512};
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::sync::broadcast::error::TryRecvError;
 use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::time::Instant;
 use ts_rs::TS;
 
-use super::info::{InternalFileInfo, TransferMetadata};
-use super::{InnerState, State};
+use super::info::{
+    CancelReason, CancellationKind, FileProgress, FrameRejection, InternalFileInfo, RejectReason,
+    TransferMetadata,
+};
+use super::{
+    build_client_introduction, build_upgrade_event, build_upgrade_failure, frame_rejection,
+    keepalive_at, missing_fields, our_capabilities, peer_alert, reject_frame, seal_frame,
+    upgrade_address, ChunkTuner, Compressibility, InnerState, IntroductionLimits, PeerCapabilities,
+    SchedulingPolicy, State, StateSnapshot, TextPayloadInfo, TextPayloadType, CANCEL_GRACE_PERIOD,
+};
 use crate::channel::{ChannelAction, ChannelDirection, ChannelMessage};
+use crate::errors::OutboundError;
+use crate::journal::Journal;
+use crate::location_nearby_connections::bandwidth_upgrade_negotiation_frame;
 use crate::location_nearby_connections::bandwidth_upgrade_negotiation_frame::upgrade_path_info::Medium;
 use crate::location_nearby_connections::connection_response_frame::ResponseStatus;
 use crate::location_nearby_connections::payload_transfer_frame::{
-    payload_header, PacketType, PayloadChunk, PayloadHeader,
+    control_message, payload_header, ControlMessage, PacketType, PayloadChunk, PayloadHeader,
 };
 use crate::location_nearby_connections::{KeepAliveFrame, OfflineFrame, PayloadTransferFrame};
 use crate::securegcm::ukey2_alert::AlertType;
 use crate::securegcm::ukey2_client_init::CipherCommitment;
 use crate::securegcm::{
-    ukey2_message, DeviceToDeviceMessage, GcmMetadata, Type, Ukey2Alert, Ukey2ClientFinished,
-    Ukey2ClientInit, Ukey2HandshakeCipher, Ukey2Message, Ukey2ServerInit,
+    ukey2_message, DeviceToDeviceMessage, Ukey2Alert, Ukey2ClientFinished, Ukey2ClientInit,
+    Ukey2HandshakeCipher, Ukey2Message, Ukey2ServerInit,
 };
 use crate::securemessage::{
-    EcP256PublicKey, EncScheme, GenericPublicKey, Header, HeaderAndBody, PublicKeyType,
-    SecureMessage, SigScheme,
+    EcP256PublicKey, GenericPublicKey, HeaderAndBody, PublicKeyType, SecureMessage,
 };
 use crate::sharing_nearby::{
-    file_metadata, paired_key_result_frame, FileMetadata, IntroductionFrame,
+    file_metadata, paired_key_result_frame, text_metadata, FileMetadata, IntroductionFrame,
+    TextMetadata,
 };
 use crate::utils::{
-    encode_point, gen_ecdsa_keypair, gen_random, hkdf_extract_expand, stream_read_exact,
-    to_four_digit_string, DeviceType, RemoteDeviceInfo,
+    bytes_per_second, connect_with_retries, decode_point, encode_point, gen_ecdsa_keypair,
+    gen_random, get_chunk_memory, get_chunk_warmup, get_connect_retries, get_follow_symlinks,
+    get_handshake_timeout, get_keepalive_interval, get_max_frame_length,
+    get_pin_confirmation_timeout, get_read_slots, get_require_pin_confirmation,
+    get_sample_compressibility, get_scheduling_policy, get_single_frame_threshold,
+    get_upgrade_policy, get_write_stall_timeout, hash_prefix, hkdf_extract_expand,
+    local_device_info, sanitize_note, stream_read_exact, stream_write_all, to_four_digit_string,
+    RemoteDeviceInfo, DEFAULT_MAX_FRAME_LENGTH,
 };
 use crate::{location_nearby_connections, sharing_nearby};
 
 type HmacSha256 = Hmac<Sha256>;
 
-const SANE_FRAME_LENGTH: i32 = 5 * 1024 * 1024;
 const SANITY_DURATION: Duration = Duration::from_micros(10);
-
-#[derive(Debug, Deserialize, Serialize, TS)]
+// How much of a file is read and sent at once
+const CHUNK_SIZE: usize = 512 * 1024;
+// Room left in a frame for the chunk's header, file name, padding and HMAC
+const CHUNK_OVERHEAD: usize = 16 * 1024;
+// How long to wait for the receiver to confirm once everything was sent
+const COMPLETION_TIMEOUT: Duration = Duration::from_secs(5);
+// At most one progress update that often, however fast the chunks go out
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+// How much of a text the receiver shows when asking for consent
+const TEXT_TITLE_LENGTH: usize = 100;
+// For the upgraded connection to be up and introduced, else we stay put
+const UPGRADE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Deserialize, Serialize, TS)]
 #[ts(export)]
 pub enum OutboundPayload {
     Files(Vec<String>),
+    // Every file under it, the receiver recreating the tree (see walk_directory).
+    // Folders otherwise exist only as the files' parent_folder, empty ones are
+    // listed on their own, which only rquickshare receivers act on.
+    // Receivers take a single introduction per transfer, so one holding more
+    // than IntroductionLimits' defaults goes over several transfers in a row,
+    // each one asking for consent (see split_directory).
+    Directory(String),
+    // Part of a Directory as split_directory makes it: files along with
+    // their folder, then empty folders
+    DirectoryPart {
+        files: Vec<(String, String)>,
+        empty_folders: Vec<String>,
+    },
+    // Sent as is, without going through a file on either side
+    Text {
+        kind: TextKind,
+        body: String,
+    },
+}
+
+/// What a text is, for the receiver to open it with the right app.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub enum TextKind {
+    Text,
+    Url,
+    Address,
+    PhoneNumber,
+}
+
+impl TextKind {
+    fn metadata_type(self) -> text_metadata::Type {
+        match self {
+            TextKind::Text => text_metadata::Type::Text,
+            TextKind::Url => text_metadata::Type::Url,
+            TextKind::Address => text_metadata::Type::Address,
+            TextKind::PhoneNumber => text_metadata::Type::PhoneNumber,
+        }
+    }
+
+    // Receivers only tell URLs apart from the rest
+    fn payload_type(self) -> TextPayloadType {
+        match self {
+            TextKind::Url => TextPayloadType::Url,
+            _ => TextPayloadType::Text,
+        }
+    }
+
+    fn payload_info(self, payload_id: i64) -> TextPayloadInfo {
+        match self {
+            TextKind::Url => TextPayloadInfo::Url(payload_id),
+            _ => TextPayloadInfo::Text(payload_id),
+        }
+    }
+}
+
+/// What sending a payload offers the receiver, see build_introduction.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub struct IntroductionInfo {
+    pub files: Vec<OfferedFile>,
+    pub total_bytes: u64,
+    // Of a Directory, relative like OfferedFile.parent_folder
+    pub empty_folders: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub struct OfferedFile {
+    pub payload_id: i64,
+    // Where it's read from, only the name is sent
+    pub path: String,
+    pub name: String,
+    // Where the receiver puts it, for a file out of a Directory
+    pub parent_folder: Option<String>,
+    pub size: u64,
+    pub mime_type: String,
+}
+
+/// Transports a session can be moved off by a bandwidth upgrade, onto the
+/// TCP connection the peer offered.
+pub trait UpgradableSocket: Sized {
+    fn from_upgrade(stream: TcpStream) -> Option<Self>;
+}
+
+impl UpgradableSocket for TcpStream {
+    fn from_upgrade(stream: TcpStream) -> Option<Self> {
+        Some(stream)
+    }
+}
+
+impl UpgradableSocket for UnixStream {
+    fn from_upgrade(_: TcpStream) -> Option<Self> {
+        None
+    }
+}
+
+// In-memory, to drive the protocol from tests without any socket
+impl UpgradableSocket for DuplexStream {
+    fn from_upgrade(_: TcpStream) -> Option<Self> {
+        None
+    }
 }
 
+// Generic over the transport so that the protocol can also run over a local
+// socket, TCP being what actually goes between devices
 #[derive(Debug)]
-pub struct OutboundRequest {
+pub struct OutboundRequest<S = TcpStream> {
     endpoint_id: [u8; 4],
-    socket: TcpStream,
+    socket: S,
     pub state: InnerState,
     sender: Sender<ChannelMessage>,
     receiver: Receiver<ChannelMessage>,
     payload: OutboundPayload,
+    note: Option<String>,
+    journal: Option<Journal>,
+    // Sent files the receiver didn't confirm yet, by payload id
+    unconfirmed_files: HashMap<i64, String>,
+    // Set once everything was sent, until the receiver confirmed it
+    completion_deadline: Option<Instant>,
+    // Wait for the user to confirm the PIN before introducing the files
+    require_pin_confirmation: bool,
+    pin_confirmation_timeout: Duration,
+    // Set while waiting for the PIN to be confirmed
+    pin_deadline: Option<Instant>,
+    // Counted from the connection request, see handshake_deadline
+    handshake_timeout: Duration,
+    // See RQS::set_max_frame_length
+    max_frame_length: usize,
+    // CHUNK_SIZE unless set per transfer, see set_chunk_size
+    chunk_size: usize,
+    // Hold the payloads once accepted, see OutboundRequestBuilder::introduction_only
+    introduction_only: bool,
+    // How the file reads are shared with the other transfers
+    scheduling: SchedulingPolicy,
+    // When the keepalive of probe_liveness went out, until it's acked
+    keepalive_sent: Option<Instant>,
+    keepalive_rtt: Option<Duration>,
+    // See RQS::set_keepalive_interval
+    keepalive_interval: Option<Duration>,
+    // When the last frame went out, the link is busy as long as they do
+    last_sent: Instant,
+    // The connection a bandwidth upgrade is moving the session onto. Set once
+    // we sent our last write on socket, everything after goes there.
+    upgrade: Option<S>,
+    // A disconnection frame went either way, nothing left to tell the peer
+    disconnected: bool,
+    // Only with the warm-up enabled, otherwise every chunk is chunk_size
+    chunk_tuner: Option<ChunkTuner>,
+    progress: ProgressThrottle,
+}
+
+// Keeps a fast link from flooding the channel with progress updates. The
+// last chunk of a file is always reported.
+#[derive(Debug, Default)]
+struct ProgressThrottle {
+    last_report: Option<Instant>,
+}
+
+impl ProgressThrottle {
+    fn should_report(&mut self, file_done: bool) -> bool {
+        let due = file_done
+            || self
+                .last_report
+                .map_or(true, |at| at.elapsed() >= PROGRESS_INTERVAL);
+        if due {
+            self.last_report = Some(Instant::now());
+        }
+
+        due
+    }
+}
+
+/// Sets up an OutboundRequest, for what isn't worth a positional argument
+/// of new(). Whatever is left unset follows the RQS-wide setting.
+pub struct OutboundRequestBuilder {
+    endpoint_id: [u8; 4],
+    id: String,
+    sender: Sender<ChannelMessage>,
+    payload: OutboundPayload,
+    rdi: RemoteDeviceInfo,
+    note: Option<String>,
+    chunk_size: Option<usize>,
+    handshake_timeout: Option<Duration>,
+    keepalive_interval: Option<Option<Duration>>,
+    require_pin_confirmation: Option<bool>,
+    journal: Option<Journal>,
+    introduction_only: bool,
+}
+
+impl OutboundRequestBuilder {
+    pub fn new(
+        endpoint_id: [u8; 4],
+        id: String,
+        sender: Sender<ChannelMessage>,
+        payload: OutboundPayload,
+    ) -> Self {
+        // Same as send_to_many does, short of anything better
+        let rdi = RemoteDeviceInfo {
+            name: id.clone(),
+            device_type: crate::DeviceType::Unknown,
+        };
+
+        Self {
+            endpoint_id,
+            id,
+            sender,
+            payload,
+            rdi,
+            note: None,
+            chunk_size: None,
+            introduction_only: false,
+            handshake_timeout: None,
+            keepalive_interval: None,
+            require_pin_confirmation: None,
+            journal: None,
+        }
+    }
+
+    // Who we mean to reach, as shown to the user
+    pub fn remote_device(mut self, rdi: RemoteDeviceInfo) -> Self {
+        self.rdi = rdi;
+        self
+    }
+
+    pub fn note(mut self, note: Option<String>) -> Self {
+        self.note = note;
+        self
+    }
+
+    // See OutboundRequest::set_chunk_size, checked by build()
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = Some(chunk_size);
+        self
+    }
+
+    // See RQS::set_handshake_timeout
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    // See RQS::set_keepalive_interval
+    pub fn keepalive_interval(mut self, interval: Option<Duration>) -> Self {
+        self.keepalive_interval = Some(interval);
+        self
+    }
+
+    // See RQS::set_require_pin_confirmation
+    pub fn require_pin_confirmation(mut self, require: bool) -> Self {
+        self.require_pin_confirmation = Some(require);
+        self
+    }
+
+    // Stop once the receiver accepted, in State::AwaitingProceed, and only
+    // read and send the payloads on ChannelAction::Proceed
+    pub fn introduction_only(mut self, introduction_only: bool) -> Self {
+        self.introduction_only = introduction_only;
+        self
+    }
+
+    pub(crate) fn journal(mut self, journal: Option<Journal>) -> Self {
+        self.journal = journal;
+        self
+    }
+
+    pub fn build<S: AsyncRead + AsyncWrite + Unpin + UpgradableSocket>(
+        self,
+        socket: S,
+    ) -> Result<OutboundRequest<S>, anyhow::Error> {
+        let mut or = OutboundRequest::new(
+            self.endpoint_id,
+            socket,
+            self.id,
+            self.sender,
+            self.payload,
+            self.rdi,
+            self.note,
+        );
+        if let Some(chunk_size) = self.chunk_size {
+            or.set_chunk_size(chunk_size)?;
+        }
+        if let Some(timeout) = self.handshake_timeout {
+            or.handshake_timeout = timeout;
+        }
+        if let Some(interval) = self.keepalive_interval {
+            or.keepalive_interval = interval;
+        }
+        if let Some(require) = self.require_pin_confirmation {
+            or.require_pin_confirmation = require;
+        }
+        or.introduction_only = self.introduction_only;
+        or.journal = self.journal;
+
+        Ok(or)
+    }
+
+    // Connects to the first of addrs that answers, retrying as configured
+    // with RQS::set_connect_retries, then builds the request
+    pub async fn connect(
+        self,
+        addrs: &[SocketAddr],
+    ) -> Result<OutboundRequest<TcpStream>, anyhow::Error> {
+        let (socket, addr) = connect_with_retries(addrs, get_connect_retries()).await?;
+        debug!("Connected to {}", addr);
+
+        self.build(socket)
+    }
 }
 
-impl OutboundRequest {
+// For local IPC, connect with connect_unix and build it with the builder
+pub type UnixOutboundRequest = OutboundRequest<UnixStream>;
+
+impl<S: AsyncRead + AsyncWrite + Unpin + UpgradableSocket> OutboundRequest<S> {
     pub fn new(
         endpoint_id: [u8; 4],
-        socket: TcpStream,
+        socket: S,
         id: String,
         sender: Sender<ChannelMessage>,
         payload: OutboundPayload,
         rdi: RemoteDeviceInfo,
+        note: Option<String>,
     ) -> Self {
         let receiver = sender.subscribe();
-        let OutboundPayload::Files(files) = &payload;
+        let (files, text_type, text_payload) = match &payload {
+            OutboundPayload::Files(files) => (Some(files.to_owned()), None, None),
+            OutboundPayload::Directory(dir) => (Some(vec![dir.clone()]), None, None),
+            OutboundPayload::DirectoryPart { files, .. } => (
+                Some(files.iter().map(|(path, _)| path.clone()).collect()),
+                None,
+                None,
+            ),
+            OutboundPayload::Text { kind, body } => {
+                (None, Some(kind.payload_type()), Some(body.clone()))
+            }
+        };
+        let note = note.as_deref().and_then(sanitize_note);
 
         Self {
             endpoint_id,
@@ -91,10 +438,15 @@ impl OutboundRequest {
                 client_seq: 0,
                 state: State::Initial,
                 encryption_done: true,
+                // Who we meant to reach
+                remote_device_info: Some(rdi.clone()),
                 transfer_metadata: Some(TransferMetadata {
                     id: String::from(""),
                     source: Some(rdi),
-                    files: Some(files.to_owned()),
+                    files,
+                    text_type,
+                    text_payload,
+                    note: note.clone(),
                     ..Default::default()
                 }),
                 ..Default::default()
@@ -102,14 +454,95 @@ impl OutboundRequest {
             sender,
             receiver,
             payload,
+            note,
+            journal: None,
+            unconfirmed_files: HashMap::new(),
+            completion_deadline: None,
+            require_pin_confirmation: get_require_pin_confirmation(),
+            pin_confirmation_timeout: get_pin_confirmation_timeout(),
+            pin_deadline: None,
+            handshake_timeout: get_handshake_timeout(),
+            max_frame_length: get_max_frame_length(),
+            chunk_size: CHUNK_SIZE,
+            introduction_only: false,
+            scheduling: get_scheduling_policy(),
+            keepalive_sent: None,
+            keepalive_rtt: None,
+            keepalive_interval: get_keepalive_interval(),
+            last_sent: Instant::now(),
+            upgrade: None,
+            disconnected: false,
+            chunk_tuner: get_chunk_warmup().then(|| ChunkTuner::new(CHUNK_SIZE)),
+            progress: ProgressThrottle::default(),
+        }
+    }
+
+    // Larger chunks go faster on a good link, smaller ones recover better on
+    // a flaky one. Each has to fit in a frame the receiver accepts once
+    // encrypted, whatever it may have raised its limit to. With the warm-up
+    // enabled, it starts from that size.
+    pub fn set_chunk_size(&mut self, chunk_size: usize) -> Result<(), anyhow::Error> {
+        let max = DEFAULT_MAX_FRAME_LENGTH - CHUNK_OVERHEAD;
+        if chunk_size == 0 || chunk_size > max {
+            return Err(anyhow!(
+                "Chunk size of {chunk_size} bytes isn't within 1..={max}"
+            ));
+        }
+
+        self.chunk_size = chunk_size;
+        if self.chunk_tuner.is_some() {
+            self.chunk_tuner = Some(ChunkTuner::new(chunk_size));
+        }
+        Ok(())
+    }
+
+    pub fn snapshot(&self) -> StateSnapshot {
+        self.state.snapshot()
+    }
+
+    // Check that an idle session is still alive before reusing it. Sends a
+    // keepalive and handles whatever comes in until the peer acks it, giving
+    // the round trip time. Not to be raced with handle(), both read from the
+    // socket, and a session that timed out is to be dropped: the read it was
+    // in the middle of is lost.
+    pub async fn probe_liveness(&mut self, timeout: Duration) -> Result<Duration, anyhow::Error> {
+        self.keepalive_rtt = None;
+        self.keepalive_sent = Some(Instant::now());
+        self.send_keepalive(false).await?;
+
+        match tokio::time::timeout(timeout, self.await_keepalive_ack()).await {
+            Ok(rtt) => rtt,
+            Err(_) => {
+                self.keepalive_sent = None;
+                Err(anyhow!(crate::errors::AppError::PeerUnresponsive))
+            }
+        }
+    }
+
+    async fn await_keepalive_ack(&mut self) -> Result<Duration, anyhow::Error> {
+        let mut length_buf = [0u8; 4];
+        loop {
+            stream_read_exact(&mut self.socket, &mut length_buf).await?;
+            self._handle(length_buf).await?;
+            if let Some(rtt) = self.keepalive_rtt.take() {
+                return Ok(rtt);
+            }
         }
     }
 
     pub async fn handle(&mut self) -> Result<(), anyhow::Error> {
         // Buffer for the 4-byte length
         let mut length_buf = [0u8; 4];
+        let completion_deadline = self.completion_deadline.unwrap_or_else(Instant::now);
+        let pin_deadline = self.pin_deadline.unwrap_or_else(Instant::now);
+        let keepalive = keepalive_at(self.keepalive_interval, self.last_sent, &self.state);
+        let handshake_deadline = self.handshake_deadline();
 
+        // Biased so that a cancel already pending wins over the receiver's
+        // confirmation read in the same poll, see cancel()
         tokio::select! {
+            biased;
+
             i = self.receiver.recv() => {
                 match i {
                     Ok(channel_msg) => {
@@ -121,20 +554,41 @@ impl OutboundRequest {
                             return Ok(());
                         }
 
-                        debug!("outbound: got: {:?}", channel_msg);
+                        tlog!(&self.state.id, Level::Debug, "outbound: got: {:?}", channel_msg);
                         match channel_msg.action {
-                            Some(ChannelAction::CancelTransfer) => {
-                                self.update_state(
-                                    |e| {
-                                        e.state = State::Cancelled;
-                                    },
-                                    true,
-                                ).await;
-                                self.disconnection().await?;
-                                return Err(anyhow!(crate::errors::AppError::NotAnError));
+                            Some(action @ (ChannelAction::CancelTransfer | ChannelAction::PeerOffline)) => {
+                                let reason = match action {
+                                    ChannelAction::PeerOffline => CancelReason::PeerOffline,
+                                    _ => CancelReason::User,
+                                };
+                                return self.cancel(reason).await;
+                            },
+                            Some(ChannelAction::ConfirmPin) => {
+                                if matches!(self.state.state, State::AwaitingPinConfirmation { .. }) {
+                                    info!("PIN confirmed");
+                                    self.pin_deadline = None;
+                                    self.send_introduction().await?;
+                                }
+                            },
+                            Some(ChannelAction::RejectPin) => {
+                                if matches!(self.state.state, State::AwaitingPinConfirmation { .. }) {
+                                    warn!("PIN rejected, not sending anything");
+                                    return self.cancel(CancelReason::PinMismatch).await;
+                                }
+                            },
+                            Some(ChannelAction::Proceed) => {
+                                if self.state.state == State::AwaitingProceed {
+                                    info!("Told to proceed, sending the payloads");
+                                    let accepted_by = self
+                                        .state
+                                        .transfer_metadata
+                                        .as_ref()
+                                        .and_then(|m| m.accepted_by.clone());
+                                    self.send_payloads(accepted_by).await?;
+                                }
                             },
                             None => {
-                                trace!("inbound: nothing to do")
+                                tlog!(&self.state.id, Level::Trace, "inbound: nothing to do")
                             },
                             _ => {}
                         }
@@ -147,19 +601,138 @@ impl OutboundRequest {
             h = stream_read_exact(&mut self.socket, &mut length_buf) => {
                 h?;
 
-                self._handle(length_buf).await?
+                if let Err(e) = self._handle(length_buf).await {
+                    if let Some(rejection) = frame_rejection(&e, &self.state.state) {
+                        self.report_rejection(rejection);
+                    }
+                    return Err(e);
+                }
+            }
+            // Racing the channel, a cancel doesn't wait for this one to elapse
+            _ = tokio::time::sleep_until(completion_deadline), if self.completion_deadline.is_some() => {
+                info!("The receiver didn't confirm: {:?}", self.unconfirmed_files.values());
+                self.finish_transfer().await?;
+            }
+            _ = tokio::time::sleep_until(pin_deadline), if self.pin_deadline.is_some() => {
+                return self.cancel(CancelReason::PinConfirmationTimeout).await;
+            }
+            _ = tokio::time::sleep_until(handshake_deadline.unwrap_or_else(Instant::now)), if handshake_deadline.is_some() => {
+                warn!("The handshake didn't complete in time ({:?})", self.state.state);
+                let _ = self.send_ukey2_alert(AlertType::InternalError).await;
+                return Err(anyhow!(crate::errors::AppError::HandshakeTimedOut(self.handshake_timeout)));
+            }
+            _ = tokio::time::sleep_until(keepalive.unwrap_or_else(Instant::now)), if keepalive.is_some() => {
+                tlog!(&self.state.id, Level::Trace, "Idle, sending keepalive");
+                self.send_keepalive(false).await?;
             }
         }
 
         Ok(())
     }
 
+    // The channel isn't read by handle() while the files are being sent, so
+    // this picks up what came in meanwhile before each chunk. Paused, it only
+    // keeps the connection alive until resumed or cancelled.
+    async fn poll_actions(&mut self) -> Result<(), anyhow::Error> {
+        loop {
+            let channel_msg = if self.state.paused {
+                let keepalive = keepalive_at(self.keepalive_interval, self.last_sent, &self.state);
+                tokio::select! {
+                    msg = self.receiver.recv() => msg.ok(),
+                    _ = tokio::time::sleep_until(keepalive.unwrap_or_else(Instant::now)), if keepalive.is_some() => {
+                        self.send_keepalive(false).await?;
+                        None
+                    }
+                }
+            } else {
+                match self.receiver.try_recv() {
+                    Ok(msg) => Some(msg),
+                    Err(TryRecvError::Lagged(_)) => None,
+                    Err(_) => return Ok(()),
+                }
+            };
+
+            let Some(channel_msg) = channel_msg else {
+                continue;
+            };
+            if channel_msg.direction == ChannelDirection::LibToFront
+                || channel_msg.id != self.state.id
+            {
+                continue;
+            }
+
+            match channel_msg.action {
+                Some(action @ (ChannelAction::CancelTransfer | ChannelAction::PeerOffline)) => {
+                    let reason = match action {
+                        ChannelAction::PeerOffline => CancelReason::PeerOffline,
+                        _ => CancelReason::User,
+                    };
+                    return self.cancel(reason).await;
+                }
+                Some(action @ (ChannelAction::Pause | ChannelAction::Resume)) => {
+                    let paused = action == ChannelAction::Pause;
+                    if paused != self.state.paused {
+                        info!("Transfer {}", if paused { "paused" } else { "resumed" });
+                        self.update_state(|e| e.paused = paused, true).await;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Only while waiting on the peer's half of the handshake
+    fn handshake_deadline(&self) -> Option<Instant> {
+        match self.state.state {
+            State::SentUkeyClientInit | State::SentUkeyClientFinish => self
+                .state
+                .handshake_started
+                .map(|started| started + self.handshake_timeout),
+            _ => None,
+        }
+    }
+
+    // Always ends the request, hence the NotAnError. Finished and Cancelled are
+    // both terminal and only ever one of them is reported: a cancel coming
+    // after the last chunk but before the receiver confirmed the transfer
+    // wins, even though the receiver may well keep the files, while one
+    // coming after that confirmation is ignored.
+    async fn cancel(&mut self, reason: CancelReason) -> Result<(), anyhow::Error> {
+        if self.state.state == State::Finished {
+            info!("Ignoring the cancellation ({:?}), already finished", reason);
+            return Err(anyhow!(crate::errors::AppError::NotAnError));
+        }
+
+        info!("Cancelling the transfer: {:?}", reason);
+        // A late confirmation must not turn it into Finished
+        self.completion_deadline = None;
+        self.unconfirmed_files.clear();
+        let kind = self.cancel_with_grace().await;
+        self.update_state(
+            |e| {
+                e.state = State::Cancelled;
+                if let Some(tmd) = e.transfer_metadata.as_mut() {
+                    tmd.cancellation = Some(kind);
+                    tmd.cancel_reason = Some(reason);
+                }
+            },
+            true,
+        )
+        .await;
+        self.drop_journal();
+        Err(anyhow!(crate::errors::AppError::NotAnError))
+    }
+
     pub async fn _handle(&mut self, length_buf: [u8; 4]) -> Result<(), anyhow::Error> {
         let msg_length = u32::from_be_bytes(length_buf) as usize;
         // Ensure the message length is not unreasonably big to avoid allocation attacks
-        if msg_length > SANE_FRAME_LENGTH as usize {
+        if msg_length > self.max_frame_length {
             error!("Message length too big");
-            return Err(anyhow!("value"));
+            return Err(reject_frame(
+                None,
+                RejectReason::Oversized,
+                Some(msg_length.to_string()),
+            ));
         }
 
         // Allocate buffer for the actual message and read it
@@ -170,7 +743,11 @@ impl OutboundRequest {
         // Now determine what will be the request type based on current state
         match current_state.state {
             State::SentUkeyClientInit => {
-                debug!("Handling State::SentUkeyClientInit frame");
+                tlog!(
+                    &self.state.id,
+                    Level::Debug,
+                    "Handling State::SentUkeyClientInit frame"
+                );
                 let msg = Ukey2Message::decode(&*frame_data)?;
                 self.update_state(
                     |e| {
@@ -192,7 +769,11 @@ impl OutboundRequest {
                 .await;
             }
             State::SentUkeyClientFinish => {
-                debug!("Handling State::SentUkeyClientFinish frame");
+                tlog!(
+                    &self.state.id,
+                    Level::Debug,
+                    "Handling State::SentUkeyClientFinish frame"
+                );
                 let frame = location_nearby_connections::OfflineFrame::decode(&*frame_data)?;
                 self.process_connection_response(&frame).await?;
 
@@ -208,7 +789,7 @@ impl OutboundRequest {
                 .await;
             }
             _ => {
-                debug!("Handling SecureMessage frame");
+                tlog!(&self.state.id, Level::Debug, "Handling SecureMessage frame");
                 let smsg = SecureMessage::decode(&*frame_data)?;
                 self.decrypt_and_process_secure_message(&smsg).await?;
             }
@@ -218,6 +799,7 @@ impl OutboundRequest {
     }
 
     pub async fn send_connection_request(&mut self) -> Result<(), anyhow::Error> {
+        let device_info = local_device_info();
         let request = location_nearby_connections::OfflineFrame {
             version: Some(location_nearby_connections::offline_frame::Version::V1.into()),
             v1: Some(location_nearby_connections::V1Frame {
@@ -225,15 +807,10 @@ impl OutboundRequest {
                     location_nearby_connections::v1_frame::FrameType::ConnectionRequest.into(),
                 ),
                 connection_request: Some(location_nearby_connections::ConnectionRequestFrame {
-                    endpoint_id: Some(String::from_utf8_lossy(&self.endpoint_id).to_string()),
-                    endpoint_name: Some(sys_metrics::host::get_hostname()?),
-                    endpoint_info: Some(
-                        RemoteDeviceInfo {
-                            name: sys_metrics::host::get_hostname()?,
-                            device_type: DeviceType::Laptop,
-                        }
-                        .serialize(),
-                    ),
+                    // ASCII, see gen_endpoint_id
+                    endpoint_id: Some(String::from_utf8_lossy(&self.endpoint_id).into_owned()),
+                    endpoint_name: Some(device_info.name.clone()),
+                    endpoint_info: Some(device_info.serialize()),
                     mediums: vec![Medium::WifiLan.into()],
                     ..Default::default()
                 }),
@@ -241,6 +818,7 @@ impl OutboundRequest {
             }),
         };
 
+        self.state.handshake_started = Some(Instant::now());
         self.send_frame(request.encode_to_vec()).await?;
 
         Ok(())
@@ -294,6 +872,10 @@ impl OutboundRequest {
         self.update_state(
             |e| {
                 e.state = State::SentUkeyClientInit;
+                e.cipher_commitment = Some(CipherCommitment {
+                    handshake_cipher: Some(Ukey2HandshakeCipher::P256Sha512.into()),
+                    commitment: Some(sha512.to_vec()),
+                });
                 e.private_key = Some(secret_key);
                 e.public_key = Some(public_key);
                 e.client_init_msg_data = Some(frame.encode_to_vec());
@@ -307,42 +889,65 @@ impl OutboundRequest {
     }
 
     async fn process_ukey2_server_init(&mut self, msg: &Ukey2Message) -> Result<(), anyhow::Error> {
+        if let Some(e) = peer_alert(msg) {
+            return Err(e);
+        }
+
         if msg.message_type() != ukey2_message::Type::ServerInit {
             self.send_ukey2_alert(AlertType::BadMessageType).await?;
-            return Err(anyhow!(
-                "UKey2: message_type({:?}) != ServerInit",
-                msg.message_type
+            return Err(reject_frame(
+                Some("Ukey2ServerInit"),
+                RejectReason::Unexpected,
+                Some(format!("{:?}", msg.message_type())),
             ));
         }
 
-        let server_init = match Ukey2ServerInit::decode(msg.message_data()) {
-            Ok(uk2si) => uk2si,
-            Err(e) => {
-                return Err(anyhow!("UKey2: Ukey2ClientFinished::decode: {}", e));
-            }
-        };
+        // Reported as a malformed Ukey2ServerInit, see frame_rejection
+        let server_init = Ukey2ServerInit::decode(msg.message_data())?;
 
+        // Fields we don't know of (from a newer peer) were skipped by the
+        // decoding, only what the key exchange relies on is checked here
         if server_init.version() != 1 {
             self.send_ukey2_alert(AlertType::BadVersion).await?;
-            return Err(anyhow!("UKey2: server_init.version != 1"));
+            return Err(reject_frame(
+                Some("Ukey2ServerInit"),
+                RejectReason::Unexpected,
+                Some(format!("version {}", server_init.version())),
+            ));
         }
 
         if server_init.random().len() != 32 {
             self.send_ukey2_alert(AlertType::BadRandom).await?;
-            return Err(anyhow!("UKey2: server_init.random.len != 32"));
+            return Err(reject_frame(
+                Some("Ukey2ServerInit"),
+                RejectReason::Malformed,
+                Some(format!("random of {} bytes", server_init.random().len())),
+            ));
         }
 
         if server_init.handshake_cipher() != Ukey2HandshakeCipher::P256Sha512 {
             self.send_ukey2_alert(AlertType::BadHandshakeCipher).await?;
-            return Err(anyhow!("UKey2: handshake_cipher != P256Sha512"));
+            return Err(reject_frame(
+                Some("Ukey2ServerInit"),
+                RejectReason::Unexpected,
+                Some(format!("{:?}", server_init.handshake_cipher())),
+            ));
         }
 
-        let server_public_key = match GenericPublicKey::decode(server_init.public_key()) {
-            Ok(spk) => spk,
-            Err(e) => {
-                return Err(anyhow!("UKey2: GenericPublicKey::decode: {}", e));
-            }
-        };
+        // Both sides of this come from send_ukey2_client_init, nothing the
+        // peer sends can make it fail. It guards our own state: should the
+        // ClientFinish or the commitment have been replaced since the
+        // ClientInit went out (e.g. a request driven through the handshake
+        // twice), the server would only abort once it gets the ClientFinish,
+        // with no hint as to why. Better stop here and tell it.
+        let finish_hash = Sha512::digest(self.state.ukey_client_finish_msg_data.as_ref().unwrap());
+        let commitment = self.state.cipher_commitment.as_ref().unwrap().commitment();
+        if !bool::from(commitment.ct_eq(finish_hash.as_slice())) {
+            self.send_ukey2_alert(AlertType::BadMessageData).await?;
+            return Err(anyhow!(crate::errors::AppError::CommitmentMismatch));
+        }
+
+        let server_public_key = GenericPublicKey::decode(server_init.public_key())?;
 
         self.finalize_key_exchange(server_public_key).await?;
         self.send_frame(self.state.ukey_client_finish_msg_data.clone().unwrap())
@@ -377,22 +982,27 @@ impl OutboundRequest {
         let v1_frame = frame
             .v1
             .as_ref()
-            .ok_or_else(|| anyhow!("Missing required fields"))?;
+            .ok_or_else(|| missing_fields("ConnectionResponse"))?;
 
         if v1_frame.r#type() != location_nearby_connections::v1_frame::FrameType::ConnectionResponse
         {
-            return Err(anyhow!(format!(
-                "Unexpected frame type: {:?}",
-                v1_frame.r#type()
-            )));
+            return Err(reject_frame(
+                Some("ConnectionResponse"),
+                RejectReason::Unexpected,
+                Some(format!("{:?}", v1_frame.r#type())),
+            ));
         }
 
-        if v1_frame.connection_response.is_none() {
-            return Err(anyhow!(format!("Unexpected None connection_response",)));
-        }
+        let Some(connection_response) = &v1_frame.connection_response else {
+            return Err(missing_fields("ConnectionResponse"));
+        };
 
-        if v1_frame.connection_response.as_ref().unwrap().response() != ResponseStatus::Accept {
-            return Err(anyhow!(format!("Connection rejected by third party",)));
+        if connection_response.response() != ResponseStatus::Accept {
+            return Err(reject_frame(
+                Some("ConnectionResponse"),
+                RejectReason::Unexpected,
+                Some(format!("{:?}", connection_response.response())),
+            ));
         }
 
         let paired_encryption = sharing_nearby::Frame {
@@ -419,13 +1029,13 @@ impl OutboundRequest {
     ) -> Result<(), anyhow::Error> {
         let mut hmac = HmacSha256::new_from_slice(self.state.recv_hmac_key.as_ref().unwrap())?;
         hmac.update(&smsg.header_and_body);
-        if !hmac
-            .finalize()
-            .into_bytes()
-            .as_slice()
-            .eq(smsg.signature.as_slice())
-        {
-            return Err(anyhow!("hmac!=signature"));
+        // verify_slice compares in constant time, unlike a plain slice eq
+        if hmac.verify_slice(&smsg.signature).is_err() {
+            return Err(reject_frame(
+                Some("SecureMessage"),
+                RejectReason::BadHmac,
+                None,
+            ));
         }
 
         let header_and_body = HeaderAndBody::decode(&*smsg.header_and_body)?;
@@ -441,10 +1051,10 @@ impl OutboundRequest {
 
         let seq = self.get_client_seq_inc().await;
         if d2d_msg.sequence_number() != seq {
-            return Err(anyhow!(
-                "Error d2d_msg.sequence_number invalid ({} vs {})",
-                d2d_msg.sequence_number(),
-                seq
+            return Err(reject_frame(
+                Some("SecureMessage"),
+                RejectReason::BadSequence,
+                Some(format!("{} (expected {})", d2d_msg.sequence_number(), seq)),
             ));
         }
 
@@ -452,34 +1062,48 @@ impl OutboundRequest {
         let v1_frame = offline
             .v1
             .as_ref()
-            .ok_or_else(|| anyhow!("Missing required fields"))?;
+            .ok_or_else(|| missing_fields("SecureMessage"))?;
         match v1_frame.r#type() {
             location_nearby_connections::v1_frame::FrameType::PayloadTransfer => {
-                trace!("Received FrameType::PayloadTransfer");
+                tlog!(
+                    &self.state.id,
+                    Level::Trace,
+                    "Received FrameType::PayloadTransfer"
+                );
                 let payload_transfer = v1_frame
                     .payload_transfer
                     .as_ref()
-                    .ok_or_else(|| anyhow!("Missing required fields"))?;
+                    .ok_or_else(|| missing_fields("PayloadTransfer"))?;
 
                 let header = payload_transfer
                     .payload_header
                     .as_ref()
-                    .ok_or_else(|| anyhow!("Missing required fields"))?;
+                    .ok_or_else(|| missing_fields("PayloadTransfer"))?;
+                // Those come without any chunk
+                if payload_transfer.packet_type() == PacketType::Control {
+                    let control = payload_transfer
+                        .control_message
+                        .as_ref()
+                        .ok_or_else(|| missing_fields("PayloadTransfer"))?;
+                    self.process_payload_control(header.id(), control);
+                    return Ok(());
+                }
                 let chunk = payload_transfer
                     .payload_chunk
                     .as_ref()
-                    .ok_or_else(|| anyhow!("Missing required fields"))?;
+                    .ok_or_else(|| missing_fields("PayloadTransfer"))?;
 
                 match header.r#type() {
                     payload_header::PayloadType::Bytes => {
                         info!("Processing PayloadType::Bytes");
                         let payload_id = header.id();
 
-                        if header.total_size() > SANE_FRAME_LENGTH.into() {
+                        if header.total_size() > self.max_frame_length as i64 {
                             self.state.payload_buffers.remove(&payload_id);
-                            return Err(anyhow!(
-                                "Payload too large: {} bytes",
-                                header.total_size()
+                            return Err(reject_frame(
+                                Some("PayloadTransfer"),
+                                RejectReason::Oversized,
+                                Some(header.total_size().to_string()),
                             ));
                         }
 
@@ -492,10 +1116,14 @@ impl OutboundRequest {
                         let buffer_len = self.state.payload_buffers.get(&payload_id).unwrap().len();
                         if chunk.offset() != buffer_len as i64 {
                             self.state.payload_buffers.remove(&payload_id);
-                            return Err(anyhow!(
-                                "Unexpected chunk offset: {}, expected: {}",
-                                chunk.offset(),
-                                buffer_len
+                            return Err(reject_frame(
+                                Some("PayloadTransfer"),
+                                RejectReason::BadSequence,
+                                Some(format!(
+                                    "offset {} instead of {}",
+                                    chunk.offset(),
+                                    buffer_len
+                                )),
                             ));
                         }
 
@@ -505,7 +1133,11 @@ impl OutboundRequest {
                         }
 
                         if (chunk.flags() & 1) == 1 {
-                            debug!("Chunk flags & 1 == 1 ?? End of data ??");
+                            tlog!(
+                                &self.state.id,
+                                Level::Debug,
+                                "Chunk flags & 1 == 1 ?? End of data ??"
+                            );
 
                             let innner_frame = sharing_nearby::Frame::decode(buffer.as_slice())?;
                             self.process_transfer_setup(&innner_frame).await?;
@@ -526,8 +1158,39 @@ impl OutboundRequest {
                 }
             }
             location_nearby_connections::v1_frame::FrameType::KeepAlive => {
-                trace!("Sending keepalive");
-                self.send_keepalive(true).await?;
+                // An ack answers a probe of ours, acking it back would ping-pong
+                if v1_frame.keep_alive.as_ref().is_some_and(|k| k.ack()) {
+                    if let Some(sent) = self.keepalive_sent.take() {
+                        self.keepalive_rtt = Some(sent.elapsed());
+                    }
+                } else {
+                    tlog!(&self.state.id, Level::Trace, "Sending keepalive");
+                    self.send_keepalive(true).await?;
+                }
+            }
+            location_nearby_connections::v1_frame::FrameType::BandwidthUpgradeNegotiation => {
+                tlog!(
+                    &self.state.id,
+                    Level::Trace,
+                    "Received FrameType::BandwidthUpgradeNegotiation"
+                );
+                self.process_bandwidth_upgrade(v1_frame).await?;
+            }
+            location_nearby_connections::v1_frame::FrameType::Disconnection => {
+                tlog!(
+                    &self.state.id,
+                    Level::Trace,
+                    "Received FrameType::Disconnection"
+                );
+                return self.process_disconnection().await;
+            }
+            // We're the one who asked for the connection
+            location_nearby_connections::v1_frame::FrameType::ConnectionRequest => {
+                return Err(reject_frame(
+                    Some("SecureMessage"),
+                    RejectReason::Unexpected,
+                    Some(String::from("ConnectionRequest")),
+                ));
             }
             _ => {
                 error!("Unhandled offline frame encrypted: {:?}", offline);
@@ -537,6 +1200,79 @@ impl OutboundRequest {
         Ok(())
     }
 
+    // Acks only tell how far the receiver got, what's confirmed still comes
+    // with the TransferComplete frames
+    fn process_payload_control(&mut self, payload_id: i64, control: &ControlMessage) {
+        match control.event() {
+            control_message::EventType::PayloadReceivedAck => {
+                tlog!(
+                    &self.state.id,
+                    Level::Trace,
+                    "The receiver acked payload {payload_id} up to {}",
+                    control.offset()
+                );
+            }
+            control_message::EventType::PayloadError
+            | control_message::EventType::PayloadCanceled => {
+                let name = self.unconfirmed_files.remove(&payload_id);
+                tlog!(
+                    &self.state.id,
+                    Level::Warn,
+                    "The receiver dropped {:?}: {:?}",
+                    name.as_deref().unwrap_or("an unknown payload"),
+                    control.event()
+                );
+            }
+            control_message::EventType::UnknownEventType => {
+                error!("Unknown payload control event for {payload_id}");
+            }
+        }
+    }
+
+    // A file was introduced but can't be read anymore. The receiver is
+    // waiting for it, so hang up rather than leave it waiting forever.
+    async fn fail_unreadable(&mut self, path: PathBuf) -> Result<(), anyhow::Error> {
+        self.completion_deadline = None;
+        self.update_state(
+            |e| {
+                e.state = State::Disconnected;
+                if let Some(tmd) = e.transfer_metadata.as_mut() {
+                    tmd.error = Some(OutboundError::FileUnreadable.into());
+                }
+            },
+            true,
+        )
+        .await;
+        self.drop_journal();
+        self.disconnection().await?;
+
+        Err(anyhow!(crate::errors::AppError::FileUnreadable(path)))
+    }
+
+    // The receiver hung up, the same as if the connection dropped, unless the
+    // transfer already ended anyway
+    async fn process_disconnection(&mut self) -> Result<(), anyhow::Error> {
+        self.disconnected = true;
+        if self.state.state != State::Finished && self.state.state != State::Cancelled {
+            info!("The receiver disconnected ({:?})", self.state.state);
+            self.completion_deadline = None;
+            self.pin_deadline = None;
+            let error = OutboundError::interrupted_in(&self.state.state);
+            self.update_state(
+                |e| {
+                    e.state = State::Disconnected;
+                    if let Some(tmd) = e.transfer_metadata.as_mut() {
+                        tmd.error = Some(error.into());
+                    }
+                },
+                true,
+            )
+            .await;
+        }
+
+        Err(anyhow!(crate::errors::AppError::NotAnError))
+    }
+
     async fn process_transfer_setup(
         &mut self,
         frame: &sharing_nearby::Frame,
@@ -544,7 +1280,7 @@ impl OutboundRequest {
         let v1_frame = frame
             .v1
             .as_ref()
-            .ok_or_else(|| anyhow!("Missing required fields"))?;
+            .ok_or_else(|| missing_fields("SharingFrame"))?;
 
         if v1_frame.r#type() == sharing_nearby::v1_frame::FrameType::Cancel {
             info!("Transfer canceled");
@@ -555,13 +1291,34 @@ impl OutboundRequest {
                 true,
             )
             .await;
+            self.drop_journal();
             self.disconnection().await?;
             return Err(anyhow!(crate::errors::AppError::NotAnError));
         }
 
+        // Other Android versions send frames we don't know or need, these
+        // mustn't be mistaken for the one the current state is waiting for
+        if matches!(
+            v1_frame.r#type(),
+            sharing_nearby::v1_frame::FrameType::UnknownFrameType
+                | sharing_nearby::v1_frame::FrameType::CertificateInfo
+        ) {
+            tlog!(
+                &self.state.id,
+                Level::Debug,
+                "Ignoring frame of type {:?}",
+                v1_frame.r#type
+            );
+            return Ok(());
+        }
+
         match self.state.state {
             State::SentPairedKeyEncryption => {
-                debug!("Processing State::SentPairedKeyEncryption");
+                tlog!(
+                    &self.state.id,
+                    Level::Debug,
+                    "Processing State::SentPairedKeyEncryption"
+                );
                 self.process_paired_key_encryption_frame(v1_frame).await?;
                 self.update_state(
                     |e| {
@@ -572,21 +1329,24 @@ impl OutboundRequest {
                 .await;
             }
             State::SentPairedKeyResult => {
-                debug!("Processing State::SentPairedKeyResult");
+                tlog!(
+                    &self.state.id,
+                    Level::Debug,
+                    "Processing State::SentPairedKeyResult"
+                );
                 self.process_paired_key_result(v1_frame).await?;
-                self.update_state(
-                    |e| {
-                        e.state = State::SentIntroduction;
-                    },
-                    true,
-                )
-                .await;
             }
             State::SentIntroduction => {
-                debug!("Processing State::SentIntroduction");
+                tlog!(
+                    &self.state.id,
+                    Level::Debug,
+                    "Processing State::SentIntroduction"
+                );
                 self.process_consent(v1_frame).await?;
             }
-            State::SendingFiles => {}
+            State::SendingFiles => {
+                self.process_transfer_complete(v1_frame).await?;
+            }
             _ => {
                 info!(
                     "Unhandled connection state in process_transfer_setup: {:?}",
@@ -603,7 +1363,7 @@ impl OutboundRequest {
         v1_frame: &sharing_nearby::V1Frame,
     ) -> Result<(), anyhow::Error> {
         if v1_frame.paired_key_encryption.is_none() {
-            return Err(anyhow!("Missing required fields"));
+            return Err(missing_fields("PairedKeyEncryption"));
         }
 
         let paired_result = sharing_nearby::Frame {
@@ -627,79 +1387,92 @@ impl OutboundRequest {
         v1_frame: &sharing_nearby::V1Frame,
     ) -> Result<(), anyhow::Error> {
         if v1_frame.paired_key_result.is_none() {
-            return Err(anyhow!("Missing required fields"));
+            return Err(missing_fields("PairedKeyResult"));
+        }
+
+        if self.require_pin_confirmation {
+            let pin = self.state.pin_code.clone().unwrap_or_default();
+            info!("Waiting for the PIN {pin} to be confirmed");
+            self.pin_deadline = Some(Instant::now() + self.pin_confirmation_timeout);
+            self.update_state(
+                |e| {
+                    e.state = State::AwaitingPinConfirmation { pin };
+                },
+                true,
+            )
+            .await;
+            return Ok(());
         }
 
+        self.send_introduction().await
+    }
+
+    async fn send_introduction(&mut self) -> Result<(), anyhow::Error> {
+        let info = build_introduction(&self.payload, |path| {
+            self.journal
+                .as_ref()
+                .and_then(|journal| journal.payload_id(path))
+        })?;
+
         let mut file_metadata: Vec<FileMetadata> = vec![];
         let mut transferred_files: HashMap<i64, InternalFileInfo> = HashMap::new();
-        let mut total_to_send = 0;
-        // TODO - Handle sending Text
-        match &self.payload {
-            OutboundPayload::Files(files) => {
-                for f in files {
-                    let path = Path::new(f);
-                    if !path.is_file() {
-                        warn!("Path is not a file: {}", f);
-                        continue;
+        let mut file_order = vec![];
+        let mut introduced_files = HashMap::new();
+        let mut compressibility = HashMap::new();
+        let mut total_to_send = info.total_bytes;
+        for file in info.files {
+            let path = Path::new(&file.path);
+            transferred_files.insert(
+                file.payload_id,
+                InternalFileInfo {
+                    payload_id: file.payload_id,
+                    file_url: path.to_path_buf(),
+                    bytes_transferred: 0,
+                    total_size: file.size as i64,
+                    file: None,
+                    temp_url: None,
+                    digest: None,
+                },
+            );
+            if get_sample_compressibility() {
+                match Compressibility::of_file(path) {
+                    Ok(estimate) => {
+                        info!(
+                            "{}: {:.2} bits/byte, compress: {}",
+                            file.path, estimate.entropy, estimate.compress
+                        );
+                        compressibility.insert(file.payload_id, estimate);
                     }
+                    Err(e) => warn!("Failed to sample {}: {:?}", file.path, e),
+                }
+            }
+            file_order.push(file.payload_id);
+            introduced_files.insert(file.payload_id, (file.name.clone(), file.size));
+            file_metadata.push(FileMetadata {
+                payload_id: Some(file.payload_id),
+                r#type: Some(file_type(&file.mime_type, path).into()),
+                name: Some(file.name),
+                size: Some(file.size as i64),
+                mime_type: Some(file.mime_type),
+                parent_folder: file.parent_folder,
+                ..Default::default()
+            });
+        }
 
-                    let file = match File::open(f) {
-                        Ok(_f) => _f,
-                        Err(e) => {
-                            error!("Failed to open file: {f}: {:?}", e);
-                            continue;
-                        }
-                    };
-                    let fmetadata = match file.metadata() {
-                        Ok(_fm) => _fm,
-                        Err(e) => {
-                            error!("Failed to get metadata for: {f}: {:?}", e);
-                            continue;
-                        }
-                    };
-
-                    let ftype = mime_guess::from_path(path)
-                        .first_or_octet_stream()
-                        .to_string();
-
-                    let meta_type = if ftype.starts_with("image/") {
-                        file_metadata::Type::Image
-                    } else if ftype.starts_with("video/") {
-                        file_metadata::Type::Video
-                    } else if ftype.starts_with("audio/") {
-                        file_metadata::Type::Audio
-                    } else if path.extension().unwrap_or_default() == "apk" {
-                        file_metadata::Type::App
-                    } else {
-                        file_metadata::Type::Unknown
-                    };
-
-                    info!("File type to send: {}", ftype);
-                    let fname = path
-                        .file_name()
-                        .ok_or_else(|| anyhow!("Failed to get file_name for {f}"))?;
-                    let fmeta = FileMetadata {
-                        payload_id: Some(rand::thread_rng().gen::<i64>()),
-                        name: Some(fname.to_os_string().into_string().unwrap()),
-                        size: Some(fmetadata.size() as i64),
-                        mime_type: Some(ftype),
-                        r#type: Some(meta_type.into()),
-                        ..Default::default()
-                    };
-                    transferred_files.insert(
-                        fmeta.payload_id(),
-                        InternalFileInfo {
-                            payload_id: fmeta.payload_id(),
-                            file_url: path.to_path_buf(),
-                            bytes_transferred: 0,
-                            total_size: fmeta.size(),
-                            file: Some(file),
-                        },
-                    );
-                    file_metadata.push(fmeta);
-                    total_to_send += fmetadata.size();
-                }
-            }
+        // The body itself goes out once accepted, see send_text
+        let mut text_metadata = vec![];
+        let mut text_payload = None;
+        if let OutboundPayload::Text { kind, body } = &self.payload {
+            let payload_id = rand::thread_rng().gen::<i64>();
+            text_metadata.push(TextMetadata {
+                text_title: Some(body.chars().take(TEXT_TITLE_LENGTH).collect()),
+                r#type: Some(kind.metadata_type().into()),
+                payload_id: Some(payload_id),
+                size: Some(body.len() as i64),
+                ..Default::default()
+            });
+            text_payload = Some(kind.payload_info(payload_id));
+            total_to_send += body.len() as u64;
         }
 
         self.update_state(
@@ -707,205 +1480,303 @@ impl OutboundRequest {
                 if let Some(tmd) = e.transfer_metadata.as_mut() {
                     tmd.total_bytes = total_to_send;
                 }
+                e.text_payload = text_payload;
                 e.transferred_files = transferred_files;
+                e.file_order = file_order;
+                e.introduced_files = introduced_files;
+                e.compressibility = compressibility;
             },
             false,
         )
         .await;
 
+        let entries = file_metadata.len() + text_metadata.len() + info.empty_folders.len();
         let introduction = sharing_nearby::Frame {
             version: Some(sharing_nearby::frame::Version::V1.into()),
             v1: Some(sharing_nearby::V1Frame {
                 r#type: Some(sharing_nearby::v1_frame::FrameType::Introduction.into()),
                 introduction: Some(IntroductionFrame {
                     file_metadata,
+                    text_metadata,
+                    note: self.note.clone(),
+                    capabilities: our_capabilities(),
+                    // Only the files count there
+                    total_size: Some(info.total_bytes as i64),
+                    // Stock receivers skip it, the folders just don't show up
+                    empty_folders: info.empty_folders,
                     ..Default::default()
                 }),
                 ..Default::default()
             }),
         };
 
+        // There's a single introduction per transfer, one bigger than
+        // receivers take would only get the transfer refused by them. A
+        // directory sent through manager::connect is split beforehand.
+        let limits = IntroductionLimits::default();
+        if entries > limits.max_entries {
+            return Err(anyhow!(
+                "{entries} entries, over the {} receivers accept at once",
+                limits.max_entries
+            ));
+        }
+        let max_size = limits.max_frame_size;
+        if introduction.encoded_len() > max_size {
+            return Err(anyhow!(crate::errors::AppError::IntroductionTooLarge {
+                size: introduction.encoded_len(),
+                max: max_size,
+            }));
+        }
+
         self.send_encrypted_frame(&introduction).await?;
+        self.update_state(
+            |e| {
+                e.state = State::SentIntroduction;
+            },
+            true,
+        )
+        .await;
 
         Ok(())
     }
 
-    async fn process_consent(
+    // The receiver only wants some of the introduced files
+    async fn skip_unaccepted_files(&mut self, accepted: &[i64]) {
+        self.update_state(
+            |e| {
+                let skipped: Vec<i64> = e
+                    .transferred_files
+                    .keys()
+                    .filter(|id| !accepted.contains(id))
+                    .cloned()
+                    .collect();
+
+                let mut skipped_names = vec![];
+                let mut skipped_bytes = 0;
+                for id in skipped {
+                    if let Some(fi) = e.transferred_files.remove(&id) {
+                        skipped_bytes += fi.total_size as u64;
+                        if let Some(name) = fi.file_url.file_name() {
+                            skipped_names.push(name.to_string_lossy().into_owned());
+                        }
+                    }
+                }
+
+                info!("The receiver skipped: {:?}", skipped_names);
+                if let Some(tmd) = e.transfer_metadata.as_mut() {
+                    tmd.total_bytes = tmd.total_bytes.saturating_sub(skipped_bytes);
+                    tmd.skipped_files = Some(skipped_names);
+                }
+            },
+            false,
+        )
+        .await;
+    }
+
+    async fn tune_chunk_size(&mut self, bytes_sent: usize) {
+        let (Some(tuner), Some(started)) = (self.chunk_tuner.as_mut(), self.state.transfer_started)
+        else {
+            return;
+        };
+
+        if let Some(tuning) = tuner.record(bytes_sent, started.elapsed()) {
+            info!(
+                "Warm-up done at {} bytes/s, going on with {} bytes chunks",
+                tuning.throughput, tuning.chunk_size
+            );
+            self.update_state(
+                |e| {
+                    e.chunk_tuning = Some(tuning);
+                },
+                false,
+            )
+            .await;
+        }
+    }
+
+    // Files the receiver partly got during an interrupted transfer are sent
+    // from where it left off
+    async fn resume_files(&mut self, offsets: &HashMap<i64, i64>) {
+        let mut resumed_bytes = 0;
+        for (id, offset) in offsets {
+            let Some(fi) = self.state.transferred_files.get_mut(id) else {
+                continue;
+            };
+            if *offset <= 0 || *offset >= fi.total_size {
+                warn!("Ignoring the resume offset {offset} of {:?}", fi.file_url);
+                continue;
+            }
+
+            info!("Resuming {:?} from {offset}", fi.file_url);
+            fi.bytes_transferred = *offset;
+            resumed_bytes += *offset as u64;
+            if let Some(journal) = self.journal.as_mut() {
+                journal.record_resumed(&fi.file_url, *offset as u64);
+            }
+        }
+
+        self.update_state(
+            |e| {
+                if let Some(tmd) = e.transfer_metadata.as_mut() {
+                    tmd.ack_bytes += resumed_bytes;
+                }
+            },
+            false,
+        )
+        .await;
+    }
+
+    // The body of a text payload, under the id it was introduced with
+    async fn send_text(&mut self) -> Result<(), anyhow::Error> {
+        let (Some(info), OutboundPayload::Text { body, .. }) =
+            (&self.state.text_payload, &self.payload)
+        else {
+            return Ok(());
+        };
+
+        let (payload_id, body) = (info.get_i64_value(), body.clone().into_bytes());
+        let size = body.len() as u64;
+        self.send_bytes(payload_id, body).await?;
+        self.update_state(
+            |e| {
+                if let Some(tmd) = e.transfer_metadata.as_mut() {
+                    tmd.ack_bytes += size;
+                }
+            },
+            true,
+        )
+        .await;
+
+        Ok(())
+    }
+
+    async fn process_transfer_complete(
         &mut self,
         v1_frame: &sharing_nearby::V1Frame,
     ) -> Result<(), anyhow::Error> {
-        if v1_frame.r#type() != sharing_nearby::v1_frame::FrameType::Response
-            || v1_frame.connection_response.is_none()
-        {
-            return Err(anyhow!("Missing required fields"));
-        }
+        let Some(complete) = v1_frame.transfer_complete.as_ref() else {
+            return Ok(());
+        };
 
-        match v1_frame.connection_response.as_ref().unwrap().status() {
-            sharing_nearby::connection_response_frame::Status::Accept => {
-                info!("State is now State::SendingFiles");
-                self.update_state(
-                    |e| {
-                        e.state = State::SendingFiles;
-                    },
-                    true,
-                )
-                .await;
+        if self.state.state == State::Cancelled {
+            return Ok(());
+        }
 
-                // TODO - Handle sending Text
-                let ids: Vec<i64> = self.state.transferred_files.keys().cloned().collect();
-                info!("We are sending: {:?}", ids);
-                let mut ids_iter = ids.into_iter();
-                // Loop through all files
-                loop {
-                    let current = match ids_iter.next() {
-                        Some(i) => i,
-                        None => {
-                            info!("All files have been transferred");
-                            self.update_state(
-                                |e| {
-                                    e.state = State::Finished;
-                                },
-                                true,
-                            )
-                            .await;
-                            self.disconnection().await?;
-                            // Breaking instead of NotAnError to allow peacefull termination
-                            break;
-                        }
-                    };
+        if complete.digest_mismatch() {
+            let name = complete
+                .payload_id
+                .and_then(|id| self.unconfirmed_files.remove(&id));
+            tlog!(
+                &self.state.id,
+                Level::Warn,
+                "The receiver discarded {:?}, it didn't match its digest",
+                name.as_deref().unwrap_or("an unknown payload")
+            );
+            return Ok(());
+        }
 
-                    // Loop until we reached end of file
-                    loop {
-                        // Workaround to limit scope of the immutable borrow on self
-                        let (curr_state, buffer, bytes_read) = {
-                            let curr_state = match self.state.transferred_files.get(&current) {
-                                Some(s) => s,
-                                None => break,
-                            };
-
-                            info!("> Currently sending {:?}", curr_state.file_url);
-                            if curr_state.bytes_transferred == curr_state.total_size {
-                                debug!("File {current} finished");
-                                self.update_state(
-                                    |e| {
-                                        e.transferred_files.remove(&current);
-                                    },
-                                    false,
-                                )
-                                .await;
-                                break;
-                            }
+        if let Some(name) = complete
+            .payload_id
+            .and_then(|id| self.unconfirmed_files.remove(&id))
+        {
+            tlog!(
+                &self.state.id,
+                Level::Debug,
+                "The receiver confirmed {name}"
+            );
+            self.update_state(
+                |e| {
+                    if let Some(tmd) = e.transfer_metadata.as_mut() {
+                        tmd.confirmed_files.get_or_insert_with(Vec::new).push(name);
+                    }
+                },
+                true,
+            )
+            .await;
+        }
 
-                            if curr_state.file.is_none() {
-                                warn!("File {current} is none");
-                                break;
-                            }
+        if complete.session_complete() {
+            info!("The receiver confirmed the transfer");
+            self.finish_transfer().await?;
+        }
 
-                            let mut buffer = vec![0u8; 512 * 1024];
-                            let bytes_read = curr_state.file.as_ref().unwrap().read(&mut buffer)?;
-
-                            (
-                                InternalFileInfo {
-                                    payload_id: curr_state.payload_id,
-                                    file_url: curr_state.file_url.clone(),
-                                    bytes_transferred: curr_state.bytes_transferred,
-                                    total_size: curr_state.total_size,
-                                    file: None,
-                                },
-                                buffer,
-                                bytes_read,
-                            )
-                        };
-
-                        let sending_buffer = buffer[..bytes_read].to_vec();
-                        info!(
-                            "> File ready: {bytes_read} bytes && {} && left to send: {} with current offset: {}",
-                            sending_buffer.len(),
-                            curr_state.total_size - curr_state.bytes_transferred,
-							curr_state.bytes_transferred
-                        );
+        Ok(())
+    }
 
-                        let payload_header = PayloadHeader {
-                            id: Some(current),
-                            r#type: Some(payload_header::PayloadType::File.into()),
-                            total_size: Some(curr_state.total_size),
-                            is_sensitive: Some(false),
-                            file_name: curr_state
-                                .file_url
-                                .file_name()
-                                .map(|name| name.to_string_lossy().into_owned()),
-                            ..Default::default()
-                        };
+    // Everything was sent and, as far as we know, received
+    async fn finish_transfer(&mut self) -> Result<(), anyhow::Error> {
+        self.completion_deadline = None;
+        self.update_state(
+            |e| {
+                e.state = State::Finished;
+            },
+            true,
+        )
+        .await;
+        self.drop_journal();
+        self.disconnection().await
+    }
 
-                        let wrapper = location_nearby_connections::OfflineFrame {
-							version: Some(location_nearby_connections::offline_frame::Version::V1.into()),
-							v1: Some(location_nearby_connections::V1Frame {
-								r#type: Some(
-									location_nearby_connections::v1_frame::FrameType::PayloadTransfer.into(),
-								),
-								payload_transfer: Some(PayloadTransferFrame {
-									packet_type: Some(PacketType::Data.into()),
-									payload_chunk: Some(PayloadChunk {
-										offset: Some(curr_state.bytes_transferred),
-										flags: Some(0),
-										body: Some(buffer[..bytes_read].to_vec()),
-									}),
-									payload_header: Some(payload_header.clone()),
-									..Default::default()
-								}),
-								..Default::default()
-							}),
-						};
+    async fn process_consent(
+        &mut self,
+        v1_frame: &sharing_nearby::V1Frame,
+    ) -> Result<(), anyhow::Error> {
+        if v1_frame.r#type() != sharing_nearby::v1_frame::FrameType::Response
+            || v1_frame.connection_response.is_none()
+        {
+            return Err(missing_fields("Response"));
+        }
 
-                        self.encrypt_and_send(&wrapper).await?;
-                        self.update_state(
-                            |e| {
-                                if let Some(mu) = e.transferred_files.get_mut(&current) {
-                                    mu.bytes_transferred += bytes_read as i64;
-                                }
+        let response = v1_frame.connection_response.as_ref().unwrap();
+        match response.status() {
+            sharing_nearby::connection_response_frame::Status::Accept => {
+                self.state.peer_capabilities =
+                    PeerCapabilities::from_advertised(response.capabilities());
+                tlog!(
+                    &self.state.id,
+                    Level::Debug,
+                    "Peer capabilities: {:?}",
+                    self.state.peer_capabilities
+                );
 
-                                if let Some(tmd) = e.transfer_metadata.as_mut() {
-                                    tmd.ack_bytes += bytes_read as u64;
-                                }
-                            },
-                            true,
-                        )
+                if !response.accepted_payload_ids.is_empty() {
+                    self.skip_unaccepted_files(&response.accepted_payload_ids)
                         .await;
+                }
 
-                        // If we just sent the last bytes of the file, mark it as finished
-                        if curr_state.bytes_transferred + bytes_read as i64 == curr_state.total_size
-                        {
-                            debug!(
-                                "File {current} finished, curr offset: {} over total: {}",
-                                curr_state.bytes_transferred + bytes_read as i64,
-                                curr_state.total_size
-                            );
-
-                            let wrapper = location_nearby_connections::OfflineFrame {
-								version: Some(location_nearby_connections::offline_frame::Version::V1.into()),
-								v1: Some(location_nearby_connections::V1Frame {
-									r#type: Some(
-										location_nearby_connections::v1_frame::FrameType::PayloadTransfer.into(),
-									),
-									payload_transfer: Some(PayloadTransferFrame {
-										packet_type: Some(PacketType::Data.into()),
-										payload_chunk: Some(PayloadChunk {
-											offset: Some(curr_state.total_size),
-											flags: Some(1), // lastChunk
-											body: Some(vec![]),
-										}),
-										payload_header: Some(payload_header),
-										..Default::default()
-									}),
-									..Default::default()
-								}),
-							};
+                if !response.resume_offsets.is_empty() {
+                    self.resume_files(&response.resume_offsets).await;
+                }
 
-                            self.encrypt_and_send(&wrapper).await?;
-                            break;
+                // Only rqs receivers say who they are when accepting
+                let accepted_by = match response.endpoint_info.as_deref() {
+                    Some(endpoint_info) => match RemoteDeviceInfo::deserialize(endpoint_info) {
+                        Ok(rdi) => Some(rdi),
+                        Err(e) => {
+                            warn!("Ignoring the accepting device's info: {}", e);
+                            None
                         }
-                    }
+                    },
+                    None => None,
+                };
+                info!("Accepted by: {:?}", accepted_by);
+
+                if self.introduction_only {
+                    info!("Holding the payloads until told to proceed");
+                    self.update_state(
+                        |e| {
+                            e.state = State::AwaitingProceed;
+                            if let Some(tmd) = e.transfer_metadata.as_mut() {
+                                tmd.accepted_by = accepted_by;
+                            }
+                        },
+                        true,
+                    )
+                    .await;
+                    return Ok(());
                 }
+
+                self.send_payloads(accepted_by).await?;
             }
             sharing_nearby::connection_response_frame::Status::Reject
             | sharing_nearby::connection_response_frame::Status::NotEnoughSpace
@@ -915,13 +1786,32 @@ impl OutboundRequest {
                     "Cannot process: consent denied: {:?}",
                     v1_frame.connection_response.as_ref().unwrap().status()
                 );
+                let error = {
+                    use sharing_nearby::connection_response_frame::Status;
+
+                    match response.status() {
+                        Status::NotEnoughSpace => OutboundError::NotEnoughSpace(
+                            self.state
+                                .transfer_metadata
+                                .as_ref()
+                                .map_or(0, |m| m.total_bytes),
+                        ),
+                        Status::UnsupportedAttachmentType => OutboundError::UnsupportedAttachment,
+                        Status::TimedOut => OutboundError::ConsentTimedOut,
+                        _ => OutboundError::Declined,
+                    }
+                };
                 self.update_state(
                     |e| {
                         e.state = State::Disconnected;
+                        if let Some(tmd) = e.transfer_metadata.as_mut() {
+                            tmd.error = Some(error.into());
+                        }
                     },
                     true,
                 )
                 .await;
+                self.drop_journal();
                 self.disconnection().await?;
                 return Err(anyhow!(crate::errors::AppError::NotAnError));
             }
@@ -934,6 +1824,7 @@ impl OutboundRequest {
                     true,
                 )
                 .await;
+                self.drop_journal();
                 self.disconnection().await?;
                 return Err(anyhow!(crate::errors::AppError::NotAnError));
             }
@@ -942,156 +1833,672 @@ impl OutboundRequest {
         Ok(())
     }
 
-    async fn disconnection(&mut self) -> Result<(), anyhow::Error> {
-        let frame = location_nearby_connections::OfflineFrame {
-            version: Some(location_nearby_connections::offline_frame::Version::V1.into()),
-            v1: Some(location_nearby_connections::V1Frame {
-                r#type: Some(
-                    location_nearby_connections::v1_frame::FrameType::Disconnection.into(),
-                ),
-                disconnection: Some(location_nearby_connections::DisconnectionFrame {
-                    ..Default::default()
-                }),
-                ..Default::default()
-            }),
-        };
-
-        if self.state.encryption_done {
-            self.encrypt_and_send(&frame).await
-        } else {
-            self.send_frame(frame.encode_to_vec()).await
-        }
-    }
-
-    async fn finalize_key_exchange(
+    // Once accepted, the text or files in the order they were introduced
+    async fn send_payloads(
         &mut self,
-        raw_peer_key: GenericPublicKey,
+        accepted_by: Option<RemoteDeviceInfo>,
     ) -> Result<(), anyhow::Error> {
-        let peer_p256_key = raw_peer_key
-            .ec_p256_public_key
-            .ok_or_else(|| anyhow!("Missing required fields"))?;
-
-        let mut bytes = vec![0x04];
-        // Ensure no more than 32 bytes for the keys
-        if peer_p256_key.x.len() > 32 {
-            bytes.extend_from_slice(&peer_p256_key.x[peer_p256_key.x.len() - 32..]);
-        } else {
-            bytes.extend_from_slice(&peer_p256_key.x);
-        }
-        if peer_p256_key.y.len() > 32 {
-            bytes.extend_from_slice(&peer_p256_key.y[peer_p256_key.y.len() - 32..]);
-        } else {
-            bytes.extend_from_slice(&peer_p256_key.y);
-        }
-
-        let encoded_point = EncodedPoint::from_bytes(bytes)?;
-        let peer_key = PublicKey::from_encoded_point(&encoded_point).unwrap();
-        let priv_key = self.state.private_key.as_ref().unwrap();
-
-        let dhs = diffie_hellman(priv_key.to_nonzero_scalar(), peer_key.as_affine());
-        let derived_secret = Sha256::digest(dhs.raw_secret_bytes());
-
-        let mut ukey_info: Vec<u8> = vec![];
-        ukey_info.extend_from_slice(self.state.client_init_msg_data.as_ref().unwrap());
-        ukey_info.extend_from_slice(self.state.server_init_data.as_ref().unwrap());
-
-        let auth_label = "UKEY2 v1 auth".as_bytes();
-        let next_label = "UKEY2 v1 next".as_bytes();
-
-        let auth_string = hkdf_extract_expand(auth_label, &derived_secret, &ukey_info, 32)?;
-        let next_secret = hkdf_extract_expand(next_label, &derived_secret, &ukey_info, 32)?;
-
-        let salt_hex = "82AA55A0D397F88346CA1CEE8D3909B95F13FA7DEB1D4AB38376B8256DA85510";
-        let salt =
-            hex::decode(salt_hex).map_err(|e| anyhow!("Failed to decode salt_hex: {}", e))?;
-
-        let d2d_client = hkdf_extract_expand(&salt, &next_secret, "client".as_bytes(), 32)?;
-        let d2d_server = hkdf_extract_expand(&salt, &next_secret, "server".as_bytes(), 32)?;
-
-        let key_salt_hex = "BF9D2A53C63616D75DB0A7165B91C1EF73E537F2427405FA23610A4BE657642E";
-        let key_salt = hex::decode(key_salt_hex)
-            .map_err(|e| anyhow!("Failed to decode key_salt_hex: {}", e))?;
-
-        let client_key = hkdf_extract_expand(&key_salt, &d2d_client, "ENC:2".as_bytes(), 32)?;
-        let client_hmac_key = hkdf_extract_expand(&key_salt, &d2d_client, "SIG:1".as_bytes(), 32)?;
-        let server_key = hkdf_extract_expand(&key_salt, &d2d_server, "ENC:2".as_bytes(), 32)?;
-        let server_hmac_key = hkdf_extract_expand(&key_salt, &d2d_server, "SIG:1".as_bytes(), 32)?;
-
+        info!("State is now State::SendingFiles");
         self.update_state(
             |e| {
-                e.decrypt_key = Some(server_key);
-                e.recv_hmac_key = Some(server_hmac_key);
-                e.encrypt_key = Some(client_key);
-                e.send_hmac_key = Some(client_hmac_key);
-                e.pin_code = Some(to_four_digit_string(&auth_string));
-                e.encryption_done = true;
-
-                if let Some(ref mut tm) = e.transfer_metadata {
-                    tm.pin_code = Some(to_four_digit_string(&auth_string));
+                e.state = State::SendingFiles;
+                e.transfer_started = Some(Instant::now());
+                if let Some(tmd) = e.transfer_metadata.as_mut() {
+                    tmd.accepted_by = accepted_by;
                 }
             },
             true,
         )
         .await;
 
-        info!("Pin code: {:?}", self.state.pin_code);
+        self.send_text().await?;
+
+        // In the order they were introduced, which is also the order
+        // a resumed transfer finds them in
+        let ids: Vec<i64> = self
+            .state
+            .file_order
+            .iter()
+            .filter(|id| self.state.transferred_files.contains_key(id))
+            .cloned()
+            .collect();
+        info!("We are sending: {:?}", ids);
+        let mut ids_iter = ids.into_iter();
+        // Shared with the other transfers, held from reading a chunk until it's sent
+        let chunk_memory = get_chunk_memory();
+        // Loop through all files
+        loop {
+            let current = match ids_iter.next() {
+                Some(i) => i,
+                None => {
+                    info!("All files have been transferred");
+                    if !self.state.peer_capabilities.transfer_complete
+                        || self.state.text_payload.is_some()
+                    {
+                        // Nothing will confirm them, so done as far as we can tell.
+                        // Nor a text, the receiver hangs up once it has it.
+                        self.finish_transfer().await?;
+                        break;
+                    }
 
-        Ok(())
-    }
+                    // The receiver confirms every file once written and verified,
+                    // but only wait so long for it.
+                    self.completion_deadline = Some(Instant::now() + COMPLETION_TIMEOUT);
+                    // Breaking instead of NotAnError to allow peacefull termination
+                    break;
+                }
+            };
+
+            // Hashed along the way so that the file is only read once
+            let mut hasher = Sha256::new();
+
+            // Bound how many files are read at once, across all transfers. With
+            // a per-chunk policy the slot is given back after every chunk.
+            let read_slots = get_read_slots();
+            let bytes_left = self
+                .state
+                .transferred_files
+                .get(&current)
+                .map_or(0, |fi| (fi.total_size - fi.bytes_transferred) as u64);
+            let mut read_slot = Some(read_slots.acquire(self.scheduling.rank(bytes_left)).await);
+            if let Some(fi) = self.state.transferred_files.get_mut(&current) {
+                // A resumed file still needs what the receiver already has
+                // hashed, which also gets the reads to the right offset
+                match File::open(&fi.file_url).and_then(|mut f| {
+                    hash_prefix(&mut f, fi.bytes_transferred as u64, &mut hasher).map(|_| f)
+                }) {
+                    Ok(f) => fi.file = Some(f),
+                    Err(e) => {
+                        error!("Failed to open file: {:?}: {:?}", fi.file_url, e);
+                        let path = fi.file_url.clone();
+                        return self.fail_unreadable(path).await;
+                    }
+                }
+            }
 
-    async fn send_ukey2_alert(&mut self, atype: AlertType) -> Result<(), anyhow::Error> {
-        let alert = Ukey2Alert {
-            r#type: Some(atype.into()),
-            error_message: None,
-        };
+            // Small files go out whole, in a single frame flagged as the last chunk
+            let single_frame = self
+                .state
+                .transferred_files
+                .get(&current)
+                .is_some_and(|fi| {
+                    fi.bytes_transferred == 0
+                        && fi.total_size as u64
+                            <= get_single_frame_threshold().min(self.chunk_size as u64)
+                        && fi.total_size as usize <= chunk_memory.limit()
+                });
+
+            // Loop until we reached end of file
+            loop {
+                self.poll_actions().await?;
+
+                // Workaround to limit scope of the immutable borrow on self
+                let (curr_state, buffer, bytes_read, _reservation) = {
+                    let curr_state = match self.state.transferred_files.get(&current) {
+                        Some(s) => s,
+                        None => break,
+                    };
 
-        let data = Ukey2Message {
-            message_type: Some(atype.into()),
-            message_data: Some(alert.encode_to_vec()),
-        };
+                    info!("> Currently sending {:?}", curr_state.file_url);
+                    if curr_state.bytes_transferred == curr_state.total_size {
+                        tlog!(&self.state.id, Level::Debug, "File {current} finished");
+                        self.update_state(
+                            |e| {
+                                e.transferred_files.remove(&current);
+                            },
+                            false,
+                        )
+                        .await;
+                        break;
+                    }
 
-        self.send_frame(data.encode_to_vec()).await
-    }
+                    if curr_state.file.is_none() {
+                        warn!("File {current} is none");
+                        break;
+                    }
 
-    async fn send_encrypted_frame(
-        &mut self,
-        frame: &sharing_nearby::Frame,
+                    if read_slot.is_none() {
+                        let bytes_left =
+                            (curr_state.total_size - curr_state.bytes_transferred) as u64;
+                        read_slot =
+                            Some(read_slots.acquire(self.scheduling.rank(bytes_left)).await);
+                    }
+
+                    let (buffer, bytes_read, reservation) = if single_frame {
+                        let (reservation, size) =
+                            chunk_memory.reserve(curr_state.total_size as usize).await?;
+                        let mut buffer = vec![0u8; size];
+                        curr_state.file.as_ref().unwrap().read_exact(&mut buffer)?;
+                        let bytes_read = buffer.len();
+                        (buffer, bytes_read, reservation)
+                    } else {
+                        let chunk_size = self
+                            .chunk_tuner
+                            .as_ref()
+                            .map_or(self.chunk_size, |tuner| tuner.chunk_size());
+                        // Possibly less than asked for with a small budget
+                        let (reservation, chunk_size) = chunk_memory.reserve(chunk_size).await?;
+                        let mut buffer = vec![0u8; chunk_size];
+                        let bytes_read = curr_state.file.as_ref().unwrap().read(&mut buffer)?;
+                        (buffer, bytes_read, reservation)
+                    };
+
+                    (
+                        InternalFileInfo {
+                            payload_id: curr_state.payload_id,
+                            file_url: curr_state.file_url.clone(),
+                            bytes_transferred: curr_state.bytes_transferred,
+                            total_size: curr_state.total_size,
+                            file: None,
+                            temp_url: None,
+                            digest: None,
+                        },
+                        buffer,
+                        bytes_read,
+                        reservation,
+                    )
+                };
+
+                // Others get to read while this chunk goes out
+                if self.scheduling.per_chunk() {
+                    read_slot = None;
+                }
+
+                hasher.update(&buffer[..bytes_read]);
+                let sending_buffer = buffer[..bytes_read].to_vec();
+                info!(
+                    "> File ready: {bytes_read} bytes && {} && left to send: {} with current offset: {}",
+                    sending_buffer.len(),
+                    curr_state.total_size - curr_state.bytes_transferred,
+							curr_state.bytes_transferred
+                );
+
+                let payload_header = PayloadHeader {
+                    id: Some(current),
+                    r#type: Some(payload_header::PayloadType::File.into()),
+                    total_size: Some(curr_state.total_size),
+                    is_sensitive: Some(false),
+                    file_name: curr_state
+                        .file_url
+                        .file_name()
+                        .map(|name| name.to_string_lossy().into_owned()),
+                    ..Default::default()
+                };
+
+                let wrapper = location_nearby_connections::OfflineFrame {
+                    version: Some(location_nearby_connections::offline_frame::Version::V1.into()),
+                    v1: Some(location_nearby_connections::V1Frame {
+                        r#type: Some(
+                            location_nearby_connections::v1_frame::FrameType::PayloadTransfer
+                                .into(),
+                        ),
+                        payload_transfer: Some(PayloadTransferFrame {
+                            packet_type: Some(PacketType::Data.into()),
+                            payload_chunk: Some(PayloadChunk {
+                                offset: Some(curr_state.bytes_transferred),
+                                flags: Some(single_frame as i32),
+                                body: Some(buffer[..bytes_read].to_vec()),
+                                sha256_digest: single_frame
+                                    .then(|| std::mem::take(&mut hasher).finalize().to_vec()),
+                            }),
+                            payload_header: Some(payload_header.clone()),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }),
+                };
+
+                self.encrypt_and_send(&wrapper).await?;
+                let file_progress = FileProgress {
+                    payload_id: current,
+                    bytes_transferred: (curr_state.bytes_transferred + bytes_read as i64) as u64,
+                    total_bytes: curr_state.total_size as u64,
+                };
+                let inform = self
+                    .progress
+                    .should_report(file_progress.bytes_transferred == file_progress.total_bytes);
+                self.update_state(
+                    |e| {
+                        if let Some(mu) = e.transferred_files.get_mut(&current) {
+                            mu.bytes_transferred += bytes_read as i64;
+                        }
+
+                        if let Some(tmd) = e.transfer_metadata.as_mut() {
+                            tmd.ack_bytes += bytes_read as u64;
+                            tmd.file_progress = Some(file_progress);
+                        }
+                    },
+                    inform,
+                )
+                .await;
+                if let Some(journal) = self.journal.as_mut() {
+                    journal.record_progress(&curr_state.file_url, bytes_read as u64);
+                }
+                self.tune_chunk_size(bytes_read).await;
+
+                // If we just sent the last bytes of the file, mark it as finished
+                if curr_state.bytes_transferred + bytes_read as i64 == curr_state.total_size {
+                    tlog!(
+                        &self.state.id,
+                        Level::Debug,
+                        "File {current} finished, curr offset: {} over total: {}",
+                        curr_state.bytes_transferred + bytes_read as i64,
+                        curr_state.total_size
+                    );
+
+                    if !single_frame {
+                        let wrapper = location_nearby_connections::OfflineFrame {
+									version: Some(location_nearby_connections::offline_frame::Version::V1.into()),
+									v1: Some(location_nearby_connections::V1Frame {
+										r#type: Some(
+											location_nearby_connections::v1_frame::FrameType::PayloadTransfer.into(),
+										),
+										payload_transfer: Some(PayloadTransferFrame {
+											packet_type: Some(PacketType::Data.into()),
+											payload_chunk: Some(PayloadChunk {
+												offset: Some(curr_state.total_size),
+												flags: Some(1), // lastChunk
+												body: Some(vec![]),
+												sha256_digest: Some(std::mem::take(&mut hasher).finalize().to_vec()),
+											}),
+											payload_header: Some(payload_header),
+											..Default::default()
+										}),
+										..Default::default()
+									}),
+								};
+
+                        self.encrypt_and_send(&wrapper).await?;
+                    }
+                    if let Some(journal) = self.journal.as_mut() {
+                        journal.record_done(&curr_state.file_url);
+                    }
+                    let name = curr_state
+                        .file_url
+                        .file_name()
+                        .map(|n| n.to_string_lossy().into_owned())
+                        .unwrap_or_default();
+                    self.unconfirmed_files.insert(current, name);
+                    break;
+                }
+            }
+
+            // Release the handle along with the read slot
+            if let Some(fi) = self.state.transferred_files.get_mut(&current) {
+                fi.file = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn process_bandwidth_upgrade(
+        &mut self,
+        v1_frame: &location_nearby_connections::V1Frame,
     ) -> Result<(), anyhow::Error> {
-        let frame_data = frame.encode_to_vec();
-        let body_size = frame_data.len();
+        let negotiation = v1_frame
+            .bandwidth_upgrade_negotiation
+            .as_ref()
+            .ok_or_else(|| missing_fields("BandwidthUpgradeNegotiation"))?;
 
-        let payload_header = PayloadHeader {
-            id: Some(rand::thread_rng().gen_range(i64::MIN..i64::MAX)),
-            r#type: Some(payload_header::PayloadType::Bytes.into()),
-            total_size: Some(body_size as i64),
-            is_sensitive: Some(false),
-            ..Default::default()
-        };
+        match negotiation.event_type() {
+            bandwidth_upgrade_negotiation_frame::EventType::UpgradePathAvailable => {
+                let medium = negotiation
+                    .upgrade_path_info
+                    .as_ref()
+                    .map(|i| i.medium())
+                    .unwrap_or(Medium::UnknownMedium);
 
-        let transfer = PayloadTransferFrame {
-            packet_type: Some(PacketType::Data.into()),
-            payload_chunk: Some(PayloadChunk {
-                offset: Some(0),
-                flags: Some(0),
-                body: Some(frame_data),
-            }),
-            payload_header: Some(payload_header.clone()),
-            ..Default::default()
+                let target = negotiation
+                    .upgrade_path_info
+                    .as_ref()
+                    .and_then(upgrade_address)
+                    .filter(|_| get_upgrade_policy().accepts(medium) && self.upgrade.is_none());
+                if let Some(addr) = target {
+                    info!("Accepting bandwidth upgrade to {:?} ({})", medium, addr);
+                    let await_ack = negotiation
+                        .upgrade_path_info
+                        .as_ref()
+                        .is_some_and(|i| i.supports_client_introduction_ack());
+                    match self.connect_upgrade(addr, await_ack).await {
+                        Ok(socket) => {
+                            // Nothing goes on the prior connection after this
+                            self.encrypt_and_send(&build_upgrade_event(
+                                bandwidth_upgrade_negotiation_frame::EventType::LastWriteToPriorChannel,
+                            ))
+                            .await?;
+                            self.upgrade = Some(socket);
+                            return Ok(());
+                        }
+                        Err(e) => {
+                            warn!("Couldn't upgrade to {}, staying put: {}", addr, e);
+                        }
+                    }
+                } else {
+                    info!("Declining bandwidth upgrade to {:?}", medium);
+                }
+
+                // Tell the peer right away so it doesn't wait for an upgrade that
+                // will never happen, we keep going on the current medium.
+                self.encrypt_and_send(&build_upgrade_failure(medium))
+                    .await?;
+            }
+            // The peer is done writing on the prior connection
+            bandwidth_upgrade_negotiation_frame::EventType::LastWriteToPriorChannel
+                if self.upgrade.is_some() =>
+            {
+                let upgrade = self.upgrade.take();
+                let sent = self
+                    .encrypt_and_send(&build_upgrade_event(
+                        bandwidth_upgrade_negotiation_frame::EventType::SafeToClosePriorChannel,
+                    ))
+                    .await;
+                self.upgrade = upgrade;
+                sent?;
+            }
+            // And done reading it, whatever comes next is on the upgraded one
+            bandwidth_upgrade_negotiation_frame::EventType::SafeToClosePriorChannel
+                if self.upgrade.is_some() =>
+            {
+                if let Some(socket) = self.upgrade.take() {
+                    let mut prior = std::mem::replace(&mut self.socket, socket);
+                    let _ = prior.shutdown().await;
+                    tlog!(
+                        &self.state.id,
+                        Level::Info,
+                        "Moved onto the upgraded connection"
+                    );
+                }
+            }
+            _ => {
+                tlog!(
+                    &self.state.id,
+                    Level::Debug,
+                    "Ignoring bandwidth upgrade event: {:?}",
+                    negotiation.event_type()
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    // Connect to where the peer offered to move the session and introduce
+    // ourselves there, in the clear like the very first frames
+    async fn connect_upgrade(&self, addr: SocketAddr, await_ack: bool) -> Result<S, anyhow::Error> {
+        let stream = tokio::time::timeout(UPGRADE_TIMEOUT, TcpStream::connect(addr)).await??;
+        let mut socket = S::from_upgrade(stream)
+            .ok_or_else(|| anyhow!("This session can't move onto a TCP connection"))?;
+
+        let introduction = build_client_introduction(&self.endpoint_id).encode_to_vec();
+        socket.write_u32(introduction.len() as u32).await?;
+        socket.write_all(&introduction).await?;
+        socket.flush().await?;
+
+        if await_ack {
+            let ack = tokio::time::timeout(UPGRADE_TIMEOUT, async {
+                let length = socket.read_u32().await? as usize;
+                if length > self.max_frame_length {
+                    return Err(reject_frame(
+                        None,
+                        RejectReason::Oversized,
+                        Some(length.to_string()),
+                    ));
+                }
+                let mut data = vec![0u8; length];
+                stream_read_exact(&mut socket, &mut data).await?;
+                Ok(OfflineFrame::decode(data.as_slice())?)
+            })
+            .await??;
+
+            let event = ack
+                .v1
+                .and_then(|v1| v1.bandwidth_upgrade_negotiation)
+                .map(|n| n.event_type());
+            if event != Some(bandwidth_upgrade_negotiation_frame::EventType::ClientIntroductionAck)
+            {
+                return Err(reject_frame(
+                    Some("BandwidthUpgradeNegotiation"),
+                    RejectReason::Unexpected,
+                    Some(format!("{:?}", event)),
+                ));
+            }
+        }
+
+        Ok(socket)
+    }
+
+    async fn cancel_with_grace(&mut self) -> CancellationKind {
+        if let Err(e) = self.disconnection().await {
+            warn!("Couldn't send the disconnection frame: {}", e);
+            let _ = self.socket.shutdown().await;
+            return CancellationKind::Forced;
+        }
+
+        // Give the peer a chance to close its side (or answer) before tearing
+        // the socket down ourselves, so that an unresponsive peer can't hold us.
+        let mut buf = [0u8; 1];
+        let kind = match tokio::time::timeout(CANCEL_GRACE_PERIOD, self.socket.read(&mut buf)).await
+        {
+            Ok(Ok(_)) => CancellationKind::Clean,
+            _ => CancellationKind::Forced,
         };
+        tlog!(
+            &self.state.id,
+            Level::Debug,
+            "Cancellation completed: {:?}",
+            kind
+        );
+
+        let _ = self.socket.shutdown().await;
+        kind
+    }
 
-        let wrapper = location_nearby_connections::OfflineFrame {
+    // The transfer ended for good, there's nothing left to resume
+    fn drop_journal(&mut self) {
+        if let Some(journal) = self.journal.take() {
+            journal.remove();
+        }
+    }
+
+    async fn disconnection(&mut self) -> Result<(), anyhow::Error> {
+        let frame = location_nearby_connections::OfflineFrame {
             version: Some(location_nearby_connections::offline_frame::Version::V1.into()),
             v1: Some(location_nearby_connections::V1Frame {
                 r#type: Some(
-                    location_nearby_connections::v1_frame::FrameType::PayloadTransfer.into(),
+                    location_nearby_connections::v1_frame::FrameType::Disconnection.into(),
                 ),
-                payload_transfer: Some(transfer),
+                disconnection: Some(location_nearby_connections::DisconnectionFrame {
+                    ..Default::default()
+                }),
                 ..Default::default()
             }),
         };
 
-        // Encrypt and send offline
-        self.encrypt_and_send(&wrapper).await?;
+        let sent = if self.state.encryption_done {
+            self.encrypt_and_send(&frame).await
+        } else {
+            self.send_frame(frame.encode_to_vec()).await
+        };
+        self.disconnected |= sent.is_ok();
+        sent
+    }
+
+    // Closes the session whichever way it ended, the peer getting a
+    // disconnection frame rather than a reset socket if none was exchanged
+    // yet. There's no async drop: call it once done with handle(), but not
+    // after handle() was itself dropped halfway through a frame.
+    pub async fn shutdown(&mut self) {
+        if !self.disconnected {
+            match tokio::time::timeout(CANCEL_GRACE_PERIOD, self.disconnection()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => debug!("Couldn't send the disconnection frame: {}", e),
+                Err(_) => debug!("Timed out sending the disconnection frame"),
+            }
+        }
+
+        if let Some(upgrade) = self.upgrade.as_mut() {
+            let _ = upgrade.shutdown().await;
+        }
+        let _ = self.socket.shutdown().await;
+    }
+
+    async fn finalize_key_exchange(
+        &mut self,
+        raw_peer_key: GenericPublicKey,
+    ) -> Result<(), anyhow::Error> {
+        let peer_p256_key = raw_peer_key
+            .ec_p256_public_key
+            .ok_or_else(|| missing_fields("Ukey2ServerInit"))?;
+
+        let (Some(x), Some(y)) = (
+            decode_point(&peer_p256_key.x),
+            decode_point(&peer_p256_key.y),
+        ) else {
+            self.send_ukey2_alert(AlertType::BadPublicKey).await?;
+            return Err(reject_frame(
+                Some("Ukey2ServerInit"),
+                RejectReason::Malformed,
+                Some(format!(
+                    "coordinates of {} and {} bytes",
+                    peer_p256_key.x.len(),
+                    peer_p256_key.y.len()
+                )),
+            ));
+        };
+        let bytes = [&[0x04][..], &x, &y].concat();
+
+        // Off the curve (the identity included) it can't be used, and
+        // from_encoded_point is the one place checking it
+        let peer_key = EncodedPoint::from_bytes(&bytes)
+            .ok()
+            .and_then(|point| Option::from(PublicKey::from_encoded_point(&point)));
+        let Some(peer_key) = peer_key else {
+            self.send_ukey2_alert(AlertType::BadPublicKey).await?;
+            return Err(reject_frame(
+                Some("Ukey2ServerInit"),
+                RejectReason::Malformed,
+                Some(String::from("public key not on P-256")),
+            ));
+        };
+        let priv_key = self.state.private_key.as_ref().unwrap();
+
+        let dhs = diffie_hellman(priv_key.to_nonzero_scalar(), peer_key.as_affine());
+        let derived_secret = Sha256::digest(dhs.raw_secret_bytes());
+
+        let mut ukey_info: Vec<u8> = vec![];
+        ukey_info.extend_from_slice(self.state.client_init_msg_data.as_ref().unwrap());
+        ukey_info.extend_from_slice(self.state.server_init_data.as_ref().unwrap());
+
+        let auth_label = "UKEY2 v1 auth".as_bytes();
+        let next_label = "UKEY2 v1 next".as_bytes();
+
+        let auth_string = hkdf_extract_expand(auth_label, &derived_secret, &ukey_info, 32)?;
+        let next_secret = hkdf_extract_expand(next_label, &derived_secret, &ukey_info, 32)?;
+
+        let salt_hex = "82AA55A0D397F88346CA1CEE8D3909B95F13FA7DEB1D4AB38376B8256DA85510";
+        let salt =
+            hex::decode(salt_hex).map_err(|e| anyhow!("Failed to decode salt_hex: {}", e))?;
+
+        let d2d_client = hkdf_extract_expand(&salt, &next_secret, "client".as_bytes(), 32)?;
+        let d2d_server = hkdf_extract_expand(&salt, &next_secret, "server".as_bytes(), 32)?;
+
+        let key_salt_hex = "BF9D2A53C63616D75DB0A7165B91C1EF73E537F2427405FA23610A4BE657642E";
+        let key_salt = hex::decode(key_salt_hex)
+            .map_err(|e| anyhow!("Failed to decode key_salt_hex: {}", e))?;
+
+        let client_key = hkdf_extract_expand(&key_salt, &d2d_client, "ENC:2".as_bytes(), 32)?;
+        let client_hmac_key = hkdf_extract_expand(&key_salt, &d2d_client, "SIG:1".as_bytes(), 32)?;
+        let server_key = hkdf_extract_expand(&key_salt, &d2d_server, "ENC:2".as_bytes(), 32)?;
+        let server_hmac_key = hkdf_extract_expand(&key_salt, &d2d_server, "SIG:1".as_bytes(), 32)?;
+
+        self.update_state(
+            |e| {
+                e.decrypt_key = Some(server_key);
+                e.recv_hmac_key = Some(server_hmac_key);
+                e.encrypt_key = Some(client_key);
+                e.send_hmac_key = Some(client_hmac_key);
+                e.pin_code = Some(to_four_digit_string(&auth_string));
+                e.encryption_done = true;
+                e.handshake_duration = e.handshake_started.map(|s| s.elapsed());
+
+                if let Some(ref mut tm) = e.transfer_metadata {
+                    tm.pin_code = Some(to_four_digit_string(&auth_string));
+                }
+            },
+            true,
+        )
+        .await;
+
+        info!("Pin code: {:?}", self.state.pin_code);
+
+        Ok(())
+    }
+
+    async fn send_ukey2_alert(&mut self, atype: AlertType) -> Result<(), anyhow::Error> {
+        let alert = Ukey2Alert {
+            r#type: Some(atype.into()),
+            error_message: None,
+        };
+
+        let data = Ukey2Message {
+            message_type: Some(ukey2_message::Type::Alert.into()),
+            message_data: Some(alert.encode_to_vec()),
+        };
+
+        self.send_frame(data.encode_to_vec()).await
+    }
+
+    async fn send_encrypted_frame(
+        &mut self,
+        frame: &sharing_nearby::Frame,
+    ) -> Result<(), anyhow::Error> {
+        let payload_id = rand::thread_rng().gen_range(i64::MIN..i64::MAX);
+        self.send_bytes(payload_id, frame.encode_to_vec()).await
+    }
+
+    // Send data as a Bytes payload, in chunks of up to chunk_size followed by
+    // an empty last one
+    async fn send_bytes(&mut self, payload_id: i64, data: Vec<u8>) -> Result<(), anyhow::Error> {
+        let body_size = data.len();
+
+        let payload_header = PayloadHeader {
+            id: Some(payload_id),
+            r#type: Some(payload_header::PayloadType::Bytes.into()),
+            total_size: Some(body_size as i64),
+            is_sensitive: Some(false),
+            ..Default::default()
+        };
+
+        let mut offset = 0;
+        for chunk in data.chunks(self.chunk_size) {
+            let transfer = PayloadTransferFrame {
+                packet_type: Some(PacketType::Data.into()),
+                payload_chunk: Some(PayloadChunk {
+                    offset: Some(offset as i64),
+                    flags: Some(0),
+                    body: Some(chunk.to_vec()),
+                    ..Default::default()
+                }),
+                payload_header: Some(payload_header.clone()),
+                ..Default::default()
+            };
+
+            let wrapper = location_nearby_connections::OfflineFrame {
+                version: Some(location_nearby_connections::offline_frame::Version::V1.into()),
+                v1: Some(location_nearby_connections::V1Frame {
+                    r#type: Some(
+                        location_nearby_connections::v1_frame::FrameType::PayloadTransfer.into(),
+                    ),
+                    payload_transfer: Some(transfer),
+                    ..Default::default()
+                }),
+            };
+
+            // Encrypt and send offline
+            self.encrypt_and_send(&wrapper).await?;
+            offset += chunk.len();
+        }
 
         // Send lastChunk
         let transfer = PayloadTransferFrame {
@@ -1100,6 +2507,7 @@ impl OutboundRequest {
                 offset: Some(body_size as i64),
                 flags: Some(1), // lastChunk
                 body: Some(vec![]),
+                ..Default::default()
             }),
             payload_header: Some(payload_header),
             ..Default::default()
@@ -1123,46 +2531,17 @@ impl OutboundRequest {
     }
 
     async fn encrypt_and_send(&mut self, frame: &OfflineFrame) -> Result<(), anyhow::Error> {
-        let d2d_msg = DeviceToDeviceMessage {
-            sequence_number: Some(self.get_server_seq_inc().await),
-            message: Some(frame.encode_to_vec()),
-        };
-
-        let key = self.state.encrypt_key.as_ref().unwrap();
-        let msg_data = d2d_msg.encode_to_vec();
-        let iv = gen_random(16);
-
-        let mut cipher = Cipher::new_256(&key[..AES_256_KEY_LEN].try_into().unwrap());
-        cipher.set_auto_padding(true);
-        let encrypted = cipher.cbc_encrypt(&iv, &msg_data);
-
-        let hb = HeaderAndBody {
-            body: encrypted,
-            header: Header {
-                encryption_scheme: EncScheme::Aes256Cbc.into(),
-                signature_scheme: SigScheme::HmacSha256.into(),
-                iv: Some(iv),
-                public_metadata: Some(
-                    GcmMetadata {
-                        r#type: Type::DeviceToDeviceMessage.into(),
-                        version: Some(1),
-                    }
-                    .encode_to_vec(),
-                ),
-                ..Default::default()
-            },
-        };
+        self.state.ensure_encrypted()?;
 
-        let mut hmac = HmacSha256::new_from_slice(self.state.send_hmac_key.as_ref().unwrap())?;
-        hmac.update(&hb.encode_to_vec());
-        let result = hmac.finalize();
-
-        let smsg = SecureMessage {
-            header_and_body: hb.encode_to_vec(),
-            signature: result.into_bytes().to_vec(),
-        };
+        let seq = self.get_server_seq_inc().await;
+        let smsg = seal_frame(
+            self.state.encrypt_key.as_ref().unwrap(),
+            self.state.send_hmac_key.as_ref().unwrap(),
+            seq,
+            frame,
+        )?;
 
-        self.send_frame(smsg.encode_to_vec()).await?;
+        self.send_frame(smsg).await?;
 
         Ok(())
     }
@@ -1199,8 +2578,11 @@ impl OutboundRequest {
         prefixed_length.extend_from_slice(&length_bytes);
         prefixed_length.extend_from_slice(&data);
 
-        self.socket.write_all(&prefixed_length).await?;
-        self.socket.flush().await?;
+        let socket = self.upgrade.as_mut().unwrap_or(&mut self.socket);
+        stream_write_all(socket, &prefixed_length, get_write_stall_timeout()).await?;
+        socket.flush().await?;
+        self.state.wire_bytes += prefixed_length.len() as u64;
+        self.last_sent = Instant::now();
 
         Ok(())
     }
@@ -1229,6 +2611,21 @@ impl OutboundRequest {
         self.state.client_seq
     }
 
+    // Sent right before the session ends because of the frame, update_state
+    // would hold it until the next state change which won't come
+    fn report_rejection(&self, rejection: FrameRejection) {
+        warn!("Rejected a frame from the peer: {:?}", rejection);
+        let _ = self.sender.send(ChannelMessage {
+            id: self.state.id.clone(),
+            direction: ChannelDirection::LibToFront,
+            rtype: Some(crate::channel::TransferType::Outbound),
+            state: Some(self.state.state.clone()),
+            meta: self.state.transfer_metadata.clone(),
+            rejection: Some(rejection),
+            ..Default::default()
+        });
+    }
+
     async fn update_state<F>(&mut self, f: F, inform: bool)
     where
         F: FnOnce(&mut InnerState),
@@ -1239,6 +2636,17 @@ impl OutboundRequest {
             return;
         }
 
+        if let Some(tmd) = self.state.transfer_metadata.as_mut() {
+            tmd.wire_bytes = self.state.wire_bytes;
+            tmd.paused = self.state.paused;
+            tmd.handshake_ms = self.state.handshake_duration.map(|d| d.as_millis() as u64);
+            if let Some(started) = self.state.transfer_started {
+                let elapsed = started.elapsed();
+                tmd.goodput = bytes_per_second(tmd.ack_bytes, elapsed);
+                tmd.duration_ms = Some(elapsed.as_millis() as u64);
+            }
+        }
+
         let _ = self.sender.send(ChannelMessage {
             id: self.state.id.clone(),
             direction: ChannelDirection::LibToFront,
@@ -1253,3 +2661,2210 @@ impl OutboundRequest {
         tokio::time::sleep(SANITY_DURATION).await;
     }
 }
+
+/// Resolve a payload into the files it offers, exactly as they'd be
+/// introduced but without any connection. Anything that isn't a readable
+/// file is left out. Payload ids come from payload_id_of when it knows the
+/// path (a resumed transfer), or are drawn at random.
+pub fn build_introduction(
+    payload: &OutboundPayload,
+    payload_id_of: impl Fn(&Path) -> Option<i64>,
+) -> Result<IntroductionInfo, anyhow::Error> {
+    let mut files = vec![];
+    let mut empty_folders = vec![];
+    match payload {
+        OutboundPayload::Files(paths) => {
+            for f in paths {
+                files.extend(offer_file(Path::new(f), None, &payload_id_of)?);
+            }
+        }
+        OutboundPayload::Directory(dir) => {
+            let tree = walk_directory(Path::new(dir), get_follow_symlinks())?;
+            for (path, folder) in tree.files {
+                files.extend(offer_file(&path, Some(folder), &payload_id_of)?);
+            }
+            empty_folders = tree.empty_folders;
+        }
+        OutboundPayload::DirectoryPart {
+            files: entries,
+            empty_folders: folders,
+        } => {
+            for (path, folder) in entries {
+                files.extend(offer_file(
+                    Path::new(path),
+                    Some(folder.clone()),
+                    &payload_id_of,
+                )?);
+            }
+            empty_folders.clone_from(folders);
+        }
+        // Not a file, introduced as TextMetadata by send_introduction
+        OutboundPayload::Text { .. } => {}
+    }
+
+    let total_bytes = files.iter().map(|f| f.size).sum();
+    Ok(IntroductionInfo {
+        files,
+        total_bytes,
+        empty_folders,
+    })
+}
+
+// None when it isn't a readable file
+fn offer_file(
+    path: &Path,
+    parent_folder: Option<String>,
+    payload_id_of: impl Fn(&Path) -> Option<i64>,
+) -> Result<Option<OfferedFile>, anyhow::Error> {
+    let f = path.to_string_lossy();
+    if !path.is_file() {
+        warn!("Path is not a file: {}", f);
+        return Ok(None);
+    }
+
+    // Only opened when its turn comes, see process_consent
+    let fmetadata = match std::fs::metadata(path) {
+        Ok(_fm) => _fm,
+        Err(e) => {
+            error!("Failed to get metadata for: {f}: {:?}", e);
+            return Ok(None);
+        }
+    };
+
+    let mime_type = mime_type_of(path);
+    info!("File type to send: {}", mime_type);
+
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Failed to get file_name for {f}"))?;
+    Ok(Some(OfferedFile {
+        payload_id: payload_id_of(path).unwrap_or_else(|| rand::thread_rng().gen::<i64>()),
+        path: f.into_owned(),
+        name: name.to_string_lossy().into_owned(),
+        parent_folder,
+        size: fmetadata.size(),
+        mime_type,
+    }))
+}
+
+/// A directory, in parts that each fit in the introduction of a transfer,
+/// to be sent one after the other. The empty folders come last.
+pub(crate) fn split_directory(
+    dir: &Path,
+    max_entries: usize,
+) -> Result<Vec<OutboundPayload>, anyhow::Error> {
+    let tree = walk_directory(dir, get_follow_symlinks())?;
+    let mut remaining_files = tree
+        .files
+        .into_iter()
+        .map(|(path, folder)| (path.to_string_lossy().into_owned(), folder))
+        .peekable();
+    let mut remaining_folders = tree.empty_folders.into_iter().peekable();
+
+    let mut parts = vec![];
+    while remaining_files.peek().is_some() || remaining_folders.peek().is_some() {
+        let files: Vec<_> = remaining_files.by_ref().take(max_entries).collect();
+        let empty_folders = remaining_folders
+            .by_ref()
+            .take(max_entries - files.len())
+            .collect();
+        parts.push(OutboundPayload::DirectoryPart {
+            files,
+            empty_folders,
+        });
+    }
+
+    Ok(parts)
+}
+
+// What's under a directory sent as a whole, see walk_directory
+#[derive(Debug, Default)]
+struct DirectoryTree {
+    // Along with the folder each one is in
+    files: Vec<(PathBuf, String)>,
+    // Those holding neither a file nor a subdirectory
+    empty_folders: Vec<String>,
+}
+
+// Every file under root, along with the folder it's in relative to root's
+// parent ("Photos/2024" for Photos/2024/a.jpg). A directory's files come
+// before its subdirectories, each in name order. Symlinks are skipped unless
+// followed, and a directory reached again through one isn't walked twice.
+// Empty directories are listed apart, named like the folders.
+fn walk_directory(root: &Path, follow_symlinks: bool) -> Result<DirectoryTree, anyhow::Error> {
+    let top = root
+        .file_name()
+        .ok_or_else(|| anyhow!("Failed to get the name of {}", root.display()))?
+        .to_string_lossy()
+        .into_owned();
+
+    let mut tree = DirectoryTree::default();
+    let mut visited = HashSet::new();
+    // Rather than recursing, however deep the tree
+    let mut pending = vec![(root.to_path_buf(), top)];
+    while let Some((dir, folder)) = pending.pop() {
+        if let Ok(canonical) = dir.canonicalize() {
+            if !visited.insert(canonical) {
+                warn!("Already walked {}, skipping it", dir.display());
+                continue;
+            }
+        }
+
+        let mut entries: Vec<_> = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries.filter_map(Result::ok).collect(),
+            Err(e) => {
+                warn!("Failed to read {}: {:?}", dir.display(), e);
+                continue;
+            }
+        };
+        entries.sort_by_key(|entry| entry.file_name());
+
+        let mut subdirs = vec![];
+        let mut has_files = false;
+        for entry in entries {
+            let path = entry.path();
+            let is_symlink = entry.file_type().is_ok_and(|t| t.is_symlink());
+            if is_symlink && !follow_symlinks {
+                debug!("Skipping the symlink {}", path.display());
+                continue;
+            }
+
+            // Through the link, if it is one
+            if path.is_dir() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                subdirs.push((path, format!("{folder}/{name}")));
+            } else {
+                tree.files.push((path, folder.clone()));
+                has_files = true;
+            }
+        }
+        if !has_files && subdirs.is_empty() {
+            tree.empty_folders.push(folder);
+        }
+        // Popped in name order
+        pending.extend(subdirs.into_iter().rev());
+    }
+
+    Ok(tree)
+}
+
+// By extension, or by the first bytes when that says nothing (none, or an
+// unknown one), application/octet-stream when neither does
+fn mime_type_of(path: &Path) -> String {
+    if let Some(mime) = mime_guess::from_path(path).first() {
+        return mime.to_string();
+    }
+
+    let mut head = [0u8; 16];
+    let sniffed = File::open(path)
+        .and_then(|mut f| f.read(&mut head))
+        .ok()
+        .and_then(|n| sniff_mime_type(&head[..n]));
+    sniffed.unwrap_or("application/octet-stream").to_owned()
+}
+
+// Only the formats phones commonly share
+fn sniff_mime_type(head: &[u8]) -> Option<&'static str> {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\xFF\xD8\xFF", "image/jpeg"),
+        (b"\x89PNG\r\n\x1A\n", "image/png"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"ID3", "audio/mpeg"),
+        (b"OggS", "audio/ogg"),
+        (b"PK\x03\x04", "application/zip"),
+    ];
+    if let Some((_, mime)) = SIGNATURES.iter().find(|(sig, _)| head.starts_with(sig)) {
+        return Some(*mime);
+    }
+
+    if head.len() >= 12 && head.starts_with(b"RIFF") && &head[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+
+    // ISO base media files (HEIC, AVIF, MP4, ...), told apart by their brand
+    if head.len() >= 12 && &head[4..8] == b"ftyp" {
+        return match &head[8..12] {
+            b"heic" | b"heix" | b"heim" | b"heis" | b"mif1" | b"msf1" => Some("image/heic"),
+            b"avif" => Some("image/avif"),
+            b"qt  " => Some("video/quicktime"),
+            b"M4A " => Some("audio/mp4"),
+            _ => Some("video/mp4"),
+        };
+    }
+
+    None
+}
+
+fn file_type(mime_type: &str, path: &Path) -> file_metadata::Type {
+    if mime_type.starts_with("image/") {
+        file_metadata::Type::Image
+    } else if mime_type.starts_with("video/") {
+        file_metadata::Type::Video
+    } else if mime_type.starts_with("audio/") {
+        file_metadata::Type::Audio
+    } else if path.extension().unwrap_or_default() == "apk" {
+        file_metadata::Type::App
+    } else {
+        file_metadata::Type::Unknown
+    }
+}
+
+/// Drives the send path of an OutboundRequest over an in-memory stream whose
+/// other end is drained, for the benchmarks to measure the CPU cost alone
+/// (see benches/crypto.rs). Must be created within a tokio runtime.
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub struct BenchSender {
+    request: OutboundRequest<DuplexStream>,
+    peer_key: GenericPublicKey,
+}
+
+#[cfg(feature = "bench")]
+impl BenchSender {
+    pub fn new(chunk_size: usize) -> Result<Self, anyhow::Error> {
+        let (ours, mut theirs) = tokio::io::duplex(2 * chunk_size);
+        tokio::spawn(async move {
+            let _ = tokio::io::copy(&mut theirs, &mut tokio::io::sink()).await;
+        });
+
+        let (sender, _) = tokio::sync::broadcast::channel(100);
+        let mut request = OutboundRequest::new(
+            *b"BNCH",
+            ours,
+            String::from("bench"),
+            sender,
+            OutboundPayload::Files(vec![]),
+            RemoteDeviceInfo {
+                name: String::from("bench"),
+                device_type: crate::utils::DeviceType::Unknown,
+            },
+            None,
+        );
+        request.set_chunk_size(chunk_size)?;
+        // As if the handshake had happened, with fixed keys and transcript
+        request.state.encrypt_key = Some(vec![0x42; 32]);
+        request.state.send_hmac_key = Some(vec![0x24; 32]);
+        request.state.private_key = Some(p256::SecretKey::from_slice(&[0x11; 32])?);
+        request.state.client_init_msg_data = Some(vec![0x22; 128]);
+        request.state.server_init_data = Some(vec![0x33; 128]);
+
+        let point = p256::SecretKey::from_slice(&[0x44; 32])?
+            .public_key()
+            .to_encoded_point(false);
+        let peer_key = GenericPublicKey {
+            r#type: PublicKeyType::EcP256.into(),
+            ec_p256_public_key: Some(EcP256PublicKey {
+                x: point.x().map(|x| x.to_vec()).unwrap_or_default(),
+                y: point.y().map(|y| y.to_vec()).unwrap_or_default(),
+            }),
+            ..Default::default()
+        };
+
+        Ok(Self { request, peer_key })
+    }
+
+    pub async fn encrypt_and_send(&mut self, frame: &OfflineFrame) -> Result<(), anyhow::Error> {
+        self.request.encrypt_and_send(frame).await
+    }
+
+    // From the peer's key to the session keys and the PIN
+    pub async fn finalize_key_exchange(&mut self) -> Result<(), anyhow::Error> {
+        self.request
+            .finalize_key_exchange(self.peer_key.clone())
+            .await
+    }
+
+    // The whole chunk loop for a single file, from the receiver accepting it
+    // to the disconnection. Returns what was written, framing included.
+    pub async fn send_file(&mut self, path: &Path) -> Result<u64, anyhow::Error> {
+        let total_size = std::fs::metadata(path)?.len() as i64;
+        let wire_bytes = self.request.state.wire_bytes;
+
+        self.request.state.state = State::SentIntroduction;
+        self.request.state.file_order = vec![1];
+        self.request.state.transferred_files = HashMap::from([(
+            1,
+            InternalFileInfo {
+                payload_id: 1,
+                file_url: path.to_path_buf(),
+                bytes_transferred: 0,
+                total_size,
+                file: None,
+                temp_url: None,
+                digest: None,
+            },
+        )]);
+        let accept = sharing_nearby::V1Frame {
+            r#type: Some(sharing_nearby::v1_frame::FrameType::Response.into()),
+            connection_response: Some(sharing_nearby::ConnectionResponseFrame {
+                status: Some(sharing_nearby::connection_response_frame::Status::Accept.into()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        self.request.process_consent(&accept).await?;
+
+        Ok(self.request.state.wire_bytes - wire_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use tokio::sync::broadcast;
+
+    use super::*;
+    use crate::utils::DeviceType;
+
+    fn new_request(socket: TcpStream) -> OutboundRequest {
+        let (sender, _) = broadcast::channel(10);
+        let mut or = OutboundRequest::new(
+            *b"ABCD",
+            socket,
+            String::from("127.0.0.1"),
+            sender,
+            OutboundPayload::Files(vec![]),
+            RemoteDeviceInfo {
+                name: String::from("peer"),
+                device_type: DeviceType::Unknown,
+            },
+            None,
+        );
+        // No handshake happened, send the disconnection in the clear
+        or.state.encryption_done = false;
+        or
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_redacts_secrets() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut or = new_request(socket);
+
+        let key = vec![0xAB; 32];
+        or.state.decrypt_key = Some(key.clone());
+        or.state.encrypt_key = Some(key.clone());
+        or.state.recv_hmac_key = Some(key.clone());
+        or.state.send_hmac_key = Some(key.clone());
+        or.state.pin_code = Some(String::from("4242"));
+        or.state.server_seq = 3;
+
+        let snapshot = or.snapshot();
+        assert_eq!(snapshot.server_seq, 3);
+
+        let dump = format!("{:?}", snapshot);
+        assert!(!dump.contains("171"));
+        assert!(!dump.contains("4242"));
+
+        // Nor does the state give the PIN away while it's being confirmed
+        or.state.state = State::AwaitingPinConfirmation {
+            pin: String::from("4242"),
+        };
+        let snapshot = or.snapshot();
+        assert_eq!(
+            snapshot.state,
+            State::AwaitingPinConfirmation { pin: String::new() }
+        );
+        assert!(!format!("{:?}", snapshot).contains("4242"));
+        assert!(!serde_json::to_string(&snapshot).unwrap().contains("4242"));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_duration() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (peer, _) = listener.accept().await.unwrap();
+
+        // A real inbound handler on the other side of the loopback
+        let (peer_sender, _) = broadcast::channel(10);
+        let mut ir =
+            crate::hdl::InboundRequest::new(*b"WXYZ", peer, String::from("127.0.0.1"), peer_sender);
+        let inbound = tokio::spawn(async move { while ir.handle().await.is_ok() {} });
+
+        let mut or = new_request(socket);
+        or.send_connection_request().await.unwrap();
+        or.send_ukey2_client_init().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while or.state.handshake_duration.is_none() {
+                or.handle().await.unwrap();
+            }
+        })
+        .await
+        .unwrap();
+        inbound.abort();
+
+        assert!(or.state.handshake_duration.unwrap() > Duration::ZERO);
+        assert!(or.state.transfer_metadata.unwrap().handshake_ms.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_probe_liveness() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (peer, _) = listener.accept().await.unwrap();
+
+        let (peer_sender, _) = broadcast::channel(10);
+        let mut ir =
+            crate::hdl::InboundRequest::new(*b"WXYZ", peer, String::from("127.0.0.1"), peer_sender);
+        let inbound = tokio::spawn(async move { while ir.handle().await.is_ok() {} });
+
+        let mut or = new_request(socket);
+        or.send_connection_request().await.unwrap();
+        or.send_ukey2_client_init().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while or.state.handshake_duration.is_none() {
+                or.handle().await.unwrap();
+            }
+        })
+        .await
+        .unwrap();
+
+        let rtt = or.probe_liveness(Duration::from_secs(5)).await.unwrap();
+        assert!(rtt < Duration::from_secs(5));
+        assert!(or.keepalive_sent.is_none());
+        inbound.abort();
+
+        // Connected, but never answering
+        let socket = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (_silent, _) = listener.accept().await.unwrap();
+        let mut or = new_request(socket);
+        let e = or
+            .probe_liveness(Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(crate::errors::AppError::PeerUnresponsive)
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_handshake_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        // Takes the connection request and the ClientInit, never answers
+        let (mut peer, _) = listener.accept().await.unwrap();
+
+        let mut or = new_request(socket);
+        or.handshake_timeout = Duration::from_secs(10);
+        or.send_connection_request().await.unwrap();
+        or.send_ukey2_client_init().await.unwrap();
+        let started = Instant::now();
+        let e = or.handle().await.unwrap_err();
+        assert!(started.elapsed() >= Duration::from_secs(10));
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(crate::errors::AppError::HandshakeTimedOut(_))
+        ));
+
+        let mut frames = vec![];
+        for _ in 0..3 {
+            let len = peer.read_u32().await.unwrap() as usize;
+            let mut data = vec![0u8; len];
+            peer.read_exact(&mut data).await.unwrap();
+            frames.push(data);
+        }
+        let alert = Ukey2Message::decode(frames[2].as_slice()).unwrap();
+        assert_eq!(alert.message_type(), ukey2_message::Type::Alert);
+        let alert = Ukey2Alert::decode(alert.message_data()).unwrap();
+        assert_eq!(alert.r#type(), AlertType::InternalError);
+
+        // Nothing to wait for once it's done
+        or.state.state = State::SentPairedKeyEncryption;
+        assert!(or.handshake_deadline().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keepalive_interval() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut peer, _) = listener.accept().await.unwrap();
+
+        let mut or = new_request(socket);
+        or.keepalive_interval = Some(Duration::from_secs(5));
+        or.state.state = State::SentIntroduction;
+        // Nothing before the handshake is done
+        assert!(keepalive_at(or.keepalive_interval, or.last_sent, &or.state).is_none());
+
+        or.state.handshake_duration = Some(Duration::ZERO);
+        let idle_since = or.last_sent;
+        or.handle().await.unwrap();
+        assert!(idle_since.elapsed() >= Duration::from_secs(5));
+
+        let len = peer.read_u32().await.unwrap() as usize;
+        let mut data = vec![0u8; len];
+        peer.read_exact(&mut data).await.unwrap();
+        let v1 = OfflineFrame::decode(data.as_slice()).unwrap().v1.unwrap();
+        assert_eq!(
+            v1.r#type(),
+            location_nearby_connections::v1_frame::FrameType::KeepAlive
+        );
+        assert!(!v1.keep_alive.unwrap().ack());
+
+        // Pushed back by whatever went out, gone once the transfer ended
+        assert_eq!(
+            keepalive_at(or.keepalive_interval, or.last_sent, &or.state),
+            Some(or.last_sent + Duration::from_secs(5))
+        );
+        assert!(or.last_sent > idle_since);
+        or.state.state = State::Finished;
+        assert!(keepalive_at(or.keepalive_interval, or.last_sent, &or.state).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_bandwidth_upgrade() {
+        use bandwidth_upgrade_negotiation_frame::upgrade_path_info::WifiLanSocket;
+        use bandwidth_upgrade_negotiation_frame::{EventType, UpgradePathInfo};
+
+        async fn read_frame(peer: &mut TcpStream) -> OfflineFrame {
+            let len = peer.read_u32().await.unwrap() as usize;
+            let mut data = vec![0u8; len];
+            peer.read_exact(&mut data).await.unwrap();
+            OfflineFrame::decode(data.as_slice()).unwrap()
+        }
+        let event_of = |frame: OfflineFrame| {
+            frame
+                .v1
+                .unwrap()
+                .bandwidth_upgrade_negotiation
+                .unwrap()
+                .event_type()
+        };
+        let event = |event_type| build_upgrade_event(event_type).v1.unwrap();
+        let path_available = |port: u16| location_nearby_connections::V1Frame {
+            r#type: Some(
+                location_nearby_connections::v1_frame::FrameType::BandwidthUpgradeNegotiation
+                    .into(),
+            ),
+            bandwidth_upgrade_negotiation: Some(
+                location_nearby_connections::BandwidthUpgradeNegotiationFrame {
+                    event_type: Some(EventType::UpgradePathAvailable.into()),
+                    upgrade_path_info: Some(UpgradePathInfo {
+                        medium: Some(Medium::WifiLan.into()),
+                        wifi_lan_socket: Some(WifiLanSocket {
+                            ip_address: Some(vec![127, 0, 0, 1]),
+                            wifi_port: Some(port.into()),
+                        }),
+                        supports_client_introduction_ack: Some(true),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            ),
+            ..Default::default()
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut prior, _) = listener.accept().await.unwrap();
+        let upgraded = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upgraded_addr = upgraded.local_addr().unwrap();
+        let peer = tokio::spawn(async move {
+            let (mut socket, _) = upgraded.accept().await.unwrap();
+            let negotiation = read_frame(&mut socket)
+                .await
+                .v1
+                .unwrap()
+                .bandwidth_upgrade_negotiation
+                .unwrap();
+            assert_eq!(negotiation.event_type(), EventType::ClientIntroduction);
+            assert_eq!(
+                negotiation.client_introduction.unwrap().endpoint_id(),
+                "ABCD"
+            );
+
+            let ack = build_upgrade_event(EventType::ClientIntroductionAck).encode_to_vec();
+            socket.write_u32(ack.len() as u32).await.unwrap();
+            socket.write_all(&ack).await.unwrap();
+            socket
+        });
+
+        let mut or = new_request(socket);
+        or.process_bandwidth_upgrade(&path_available(upgraded_addr.port()))
+            .await
+            .unwrap();
+        let mut upgraded = peer.await.unwrap();
+        assert_eq!(
+            event_of(read_frame(&mut prior).await),
+            EventType::LastWriteToPriorChannel
+        );
+
+        // Whatever comes after our last write goes over the upgraded one
+        or.send_keepalive(false).await.unwrap();
+        assert_eq!(
+            read_frame(&mut upgraded).await.v1.unwrap().r#type(),
+            location_nearby_connections::v1_frame::FrameType::KeepAlive
+        );
+
+        or.process_bandwidth_upgrade(&event(EventType::LastWriteToPriorChannel))
+            .await
+            .unwrap();
+        assert_eq!(
+            event_of(read_frame(&mut prior).await),
+            EventType::SafeToClosePriorChannel
+        );
+        or.process_bandwidth_upgrade(&event(EventType::SafeToClosePriorChannel))
+            .await
+            .unwrap();
+        assert!(or.upgrade.is_none());
+        assert_eq!(or.socket.peer_addr().unwrap(), upgraded_addr);
+        let mut buf = [0u8; 1];
+        assert_eq!(prior.read(&mut buf).await.unwrap(), 0);
+
+        // Nobody listening there, we stay put and tell the peer
+        let gone = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        or.process_bandwidth_upgrade(&path_available(gone.port()))
+            .await
+            .unwrap();
+        assert_eq!(
+            event_of(read_frame(&mut upgraded).await),
+            EventType::UpgradeFailure
+        );
+        assert_eq!(or.socket.peer_addr().unwrap(), upgraded_addr);
+    }
+
+    #[tokio::test]
+    async fn test_server_init_with_extra_fields() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut peer, _) = listener.accept().await.unwrap();
+
+        let mut or = new_request(socket);
+        or.send_ukey2_client_init().await.unwrap();
+
+        let server_init = |random_len| {
+            let point = gen_ecdsa_keypair().1.to_encoded_point(false);
+            let public_key = GenericPublicKey {
+                r#type: PublicKeyType::EcP256.into(),
+                ec_p256_public_key: Some(EcP256PublicKey {
+                    x: point.x().unwrap().to_vec(),
+                    y: point.y().unwrap().to_vec(),
+                }),
+                ..Default::default()
+            };
+            let mut data = Ukey2ServerInit {
+                version: Some(1),
+                random: Some(gen_random(random_len)),
+                handshake_cipher: Some(Ukey2HandshakeCipher::P256Sha512.into()),
+                public_key: Some(public_key.encode_to_vec()),
+            }
+            .encode_to_vec();
+            // Fields 5 (varint) and 100 (bytes), unknown to us
+            data.extend_from_slice(&[0x28, 0x01, 0xA2, 0x06, 0x03, b'a', b'b', b'c']);
+
+            Ukey2Message {
+                message_type: Some(ukey2_message::Type::ServerInit.into()),
+                message_data: Some(data),
+            }
+        };
+
+        // The essential invariants still hold
+        let e = or
+            .process_ukey2_server_init(&server_init(16))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            frame_rejection(&e, &or.state.state),
+            Some(FrameRejection {
+                frame_type: Some(String::from("Ukey2ServerInit")),
+                reason: RejectReason::Malformed,
+                value: Some(String::from("random of 16 bytes")),
+            })
+        );
+        assert!(or.state.encrypt_key.is_none());
+
+        // A ClientFinish that doesn't match the commitment is never sent
+        let finish = or.state.ukey_client_finish_msg_data.clone();
+        or.state.ukey_client_finish_msg_data = Some(vec![0x42; 8]);
+        let e = or
+            .process_ukey2_server_init(&server_init(32))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(crate::errors::AppError::CommitmentMismatch)
+        ));
+        assert!(or.state.encrypt_key.is_none());
+        or.state.ukey_client_finish_msg_data = finish;
+
+        // ClientInit, then the BadRandom and BadMessageData alerts
+        let mut frames = vec![];
+        for _ in 0..3 {
+            let len = peer.read_u32().await.unwrap() as usize;
+            let mut data = vec![0u8; len];
+            peer.read_exact(&mut data).await.unwrap();
+            frames.push(data);
+        }
+        let alert = Ukey2Message::decode(frames[2].as_slice()).unwrap();
+        let alert = Ukey2Alert::decode(alert.message_data()).unwrap();
+        assert_eq!(alert.r#type(), AlertType::BadMessageData);
+
+        or.state.server_init_data = Some(server_init(32).encode_to_vec());
+        or.process_ukey2_server_init(&server_init(32))
+            .await
+            .unwrap();
+        assert!(or.state.encrypt_key.is_some());
+        assert!(or.state.pin_code.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_over_duplex() {
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let (sender, _) = broadcast::channel(10);
+        let mut ir = crate::hdl::InboundRequest::new(
+            *b"WXYZ",
+            theirs,
+            String::from("duplex"),
+            sender.clone(),
+        );
+        let mut or = OutboundRequest::new(
+            *b"ABCD",
+            ours,
+            String::from("duplex"),
+            sender,
+            OutboundPayload::Files(vec![]),
+            RemoteDeviceInfo {
+                name: String::from("peer"),
+                device_type: DeviceType::Unknown,
+            },
+            None,
+        );
+
+        // Every step is a whole frame already buffered on the other side
+        or.send_connection_request().await.unwrap();
+        or.send_ukey2_client_init().await.unwrap();
+        ir.handle().await.unwrap();
+        ir.handle().await.unwrap();
+        assert_eq!(ir.state.state, State::SentUkeyServerInit);
+        or.handle().await.unwrap();
+        assert_eq!(or.state.state, State::SentUkeyClientFinish);
+        ir.handle().await.unwrap();
+        assert_eq!(ir.state.state, State::ReceivedUkeyClientFinish);
+
+        // Both ends derived the same secrets, each key matching its counterpart
+        assert!(or.state.pin_code.is_some());
+        assert_eq!(or.state.pin_code, ir.state.pin_code);
+        assert!(or.state.encrypt_key.is_some());
+        assert_eq!(or.state.encrypt_key, ir.state.decrypt_key);
+        assert_eq!(or.state.decrypt_key, ir.state.encrypt_key);
+        assert_eq!(or.state.send_hmac_key, ir.state.recv_hmac_key);
+        assert_eq!(or.state.recv_hmac_key, ir.state.send_hmac_key);
+    }
+
+    #[tokio::test]
+    async fn test_key_derivation_vectors() {
+        // Nothing goes out, the peer's end is only kept open
+        let (socket, _peer) = tokio::io::duplex(64 * 1024);
+        let (sender, _) = broadcast::channel(10);
+        let mut or = OutboundRequest::new(
+            *b"ABCD",
+            socket,
+            String::from("duplex"),
+            sender,
+            OutboundPayload::Files(vec![]),
+            RemoteDeviceInfo {
+                name: String::from("peer"),
+                device_type: DeviceType::Unknown,
+            },
+            None,
+        );
+        or.state.private_key = Some(p256::SecretKey::from_slice(&[0x11; 32]).unwrap());
+        or.state.client_init_msg_data = Some(b"client init".to_vec());
+        or.state.server_init_data = Some(b"server init".to_vec());
+
+        // Computed independently, from the same scalars and transcript, with
+        // the ECDH and HKDF of Python's cryptography package
+        let peer_key = GenericPublicKey {
+            r#type: PublicKeyType::EcP256.into(),
+            ec_p256_public_key: Some(EcP256PublicKey {
+                x: hex::decode("d65a93977caa3d1b081852ff57a79e465f1660577304baead505dd3a48589cf3")
+                    .unwrap(),
+                y: hex::decode("50185e895372df6221ea3a137557e473fddb6755f05bd507c3c533fce9c91285")
+                    .unwrap(),
+            }),
+            ..Default::default()
+        };
+        let client_key = "d0c3c694763361cfb4b9292e182328c355a6b76e3c4f128c895034115a834906";
+        let client_hmac_key = "88beb21ef45cdf73b434cb350b3533514b4ca9efc60065ee6438acb7a25c7085";
+        let server_key = "6ff43cfb4241b9f392ab3aa9024f6e350b668e4315ef0dfb6fdb467b06ceeb88";
+        let server_hmac_key = "ecbb4ab3631a001555e9cdb903084f8c380726d8770c9911d723a17e4f64bea1";
+
+        or.finalize_key_exchange(peer_key).await.unwrap();
+        let hex_of = |key: &Option<Vec<u8>>| hex::encode(key.as_ref().unwrap());
+        assert_eq!(hex_of(&or.state.encrypt_key), client_key);
+        assert_eq!(hex_of(&or.state.send_hmac_key), client_hmac_key);
+        assert_eq!(hex_of(&or.state.decrypt_key), server_key);
+        assert_eq!(hex_of(&or.state.recv_hmac_key), server_hmac_key);
+        assert_eq!(or.state.pin_code.as_deref(), Some("0398"));
+    }
+
+    #[tokio::test]
+    async fn test_peer_alert() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut peer, _) = listener.accept().await.unwrap();
+
+        let mut or = new_request(socket);
+        or.send_ukey2_client_init().await.unwrap();
+
+        let alert = Ukey2Message {
+            message_type: Some(ukey2_message::Type::Alert.into()),
+            message_data: Some(
+                Ukey2Alert {
+                    r#type: Some(AlertType::BadHandshakeCipher.into()),
+                    error_message: Some(String::from("no cipher in common")),
+                }
+                .encode_to_vec(),
+            ),
+        };
+        let e = or.process_ukey2_server_init(&alert).await.unwrap_err();
+        match e.downcast_ref() {
+            Some(crate::errors::AppError::PeerAlert { alert, message }) => {
+                assert_eq!(*alert, AlertType::BadHandshakeCipher);
+                assert_eq!(message.as_deref(), Some("no cipher in common"));
+            }
+            other => panic!("unexpected error: {other:?}"),
+        }
+        // Not a frame of ours to reject
+        assert_eq!(frame_rejection(&e, &or.state.state), None);
+
+        // Only our ClientInit, the alert isn't answered with another one
+        drop(or);
+        let len = peer.read_u32().await.unwrap() as usize;
+        let mut data = vec![0u8; len];
+        peer.read_exact(&mut data).await.unwrap();
+        let mut rest = vec![];
+        peer.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_missing_fields() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (_peer, _) = listener.accept().await.unwrap();
+        let mut or = new_request(socket);
+
+        let negotiation = location_nearby_connections::V1Frame {
+            r#type: Some(
+                location_nearby_connections::v1_frame::FrameType::BandwidthUpgradeNegotiation
+                    .into(),
+            ),
+            ..Default::default()
+        };
+        let e = or
+            .process_bandwidth_upgrade(&negotiation)
+            .await
+            .unwrap_err();
+        let rejection = frame_rejection(&e, &or.state.state).unwrap();
+        assert_eq!(
+            rejection.frame_type.as_deref(),
+            Some("BandwidthUpgradeNegotiation")
+        );
+        assert_eq!(rejection.reason, RejectReason::Malformed);
+
+        let e = or
+            .process_transfer_setup(&sharing_nearby::Frame::default())
+            .await
+            .unwrap_err();
+        let rejection = frame_rejection(&e, &or.state.state).unwrap();
+        assert_eq!(rejection.frame_type.as_deref(), Some("SharingFrame"));
+
+        let response = |connection_response| location_nearby_connections::OfflineFrame {
+            version: Some(location_nearby_connections::offline_frame::Version::V1.into()),
+            v1: Some(location_nearby_connections::V1Frame {
+                r#type: Some(
+                    location_nearby_connections::v1_frame::FrameType::ConnectionResponse.into(),
+                ),
+                connection_response,
+                ..Default::default()
+            }),
+        };
+        let e = or
+            .process_connection_response(&response(None))
+            .await
+            .unwrap_err();
+        let rejection = frame_rejection(&e, &or.state.state).unwrap();
+        assert_eq!(rejection.frame_type.as_deref(), Some("ConnectionResponse"));
+        assert_eq!(rejection.reason, RejectReason::Malformed);
+
+        // The peer turning the connection down
+        let e = or
+            .process_connection_response(&response(Some(
+                location_nearby_connections::ConnectionResponseFrame {
+                    response: Some(ResponseStatus::Reject.into()),
+                    ..Default::default()
+                },
+            )))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            frame_rejection(&e, &or.state.state),
+            Some(FrameRejection {
+                frame_type: Some(String::from("ConnectionResponse")),
+                reason: RejectReason::Unexpected,
+                value: Some(String::from("Reject")),
+            })
+        );
+    }
+
+    // Past the handshake, right before the PIN would need to be confirmed
+    async fn paired_request(peer_name: &str) -> (OutboundRequest, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (peer, _) = listener.accept().await.unwrap();
+
+        let mut or = new_request(socket);
+        or.state.encryption_done = true;
+        or.state.encrypt_key = Some(vec![0x42; 32]);
+        or.state.send_hmac_key = Some(vec![0x24; 32]);
+        or.state.pin_code = Some(String::from("1234"));
+        or.state.remote_device_info = Some(RemoteDeviceInfo {
+            name: String::from(peer_name),
+            device_type: DeviceType::Unknown,
+        });
+        or.state.state = State::SentPairedKeyResult;
+        or.require_pin_confirmation = true;
+
+        (or, peer)
+    }
+
+    async fn receive_paired_key_result(or: &mut OutboundRequest) {
+        let frame = sharing_nearby::Frame {
+            version: Some(sharing_nearby::frame::Version::V1.into()),
+            v1: Some(sharing_nearby::V1Frame {
+                r#type: Some(sharing_nearby::v1_frame::FrameType::PairedKeyResult.into()),
+                paired_key_result: Some(sharing_nearby::PairedKeyResultFrame {
+                    status: Some(sharing_nearby::paired_key_result_frame::Status::Unable.into()),
+                }),
+                ..Default::default()
+            }),
+        };
+        or.process_transfer_setup(&frame).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pin_required() {
+        let (mut or, _peer) = paired_request("careful-receiver").await;
+        let mut events = or.sender.subscribe();
+
+        receive_paired_key_result(&mut or).await;
+        let awaiting = State::AwaitingPinConfirmation {
+            pin: String::from("1234"),
+        };
+        assert_eq!(or.state.state, awaiting);
+        assert_eq!(events.recv().await.unwrap().state, Some(awaiting));
+
+        // Nothing moves until the PIN is confirmed
+        or.sender
+            .send(ChannelMessage {
+                id: or.state.id.clone(),
+                direction: ChannelDirection::FrontToLib,
+                action: Some(ChannelAction::ConfirmPin),
+                ..Default::default()
+            })
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while or.state.state != State::SentIntroduction {
+                or.handle().await.unwrap();
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pin_confirmation_timeout() {
+        let (mut or, _peer) = paired_request("forgetful-receiver").await;
+        or.pin_confirmation_timeout = Duration::from_millis(100);
+
+        receive_paired_key_result(&mut or).await;
+        assert!(matches!(
+            or.state.state,
+            State::AwaitingPinConfirmation { .. }
+        ));
+
+        // The timeout, then the grace period given to the silent peer
+        let e = tokio::time::timeout(CANCEL_GRACE_PERIOD * 2, async {
+            loop {
+                if let Err(e) = or.handle().await {
+                    return e;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(crate::errors::AppError::NotAnError)
+        ));
+
+        assert_eq!(or.state.state, State::Cancelled);
+        let tmd = or.state.transfer_metadata.unwrap();
+        assert_eq!(
+            tmd.cancel_reason,
+            Some(CancelReason::PinConfirmationTimeout)
+        );
+        assert_eq!(tmd.cancellation, Some(CancellationKind::Forced));
+    }
+
+    #[tokio::test]
+    async fn test_pin_rejected() {
+        let (mut or, _peer) = paired_request("impostor-receiver").await;
+        receive_paired_key_result(&mut or).await;
+
+        or.sender
+            .send(ChannelMessage {
+                id: or.state.id.clone(),
+                direction: ChannelDirection::FrontToLib,
+                action: Some(ChannelAction::RejectPin),
+                ..Default::default()
+            })
+            .unwrap();
+        let e = tokio::time::timeout(CANCEL_GRACE_PERIOD * 2, async {
+            loop {
+                if let Err(e) = or.handle().await {
+                    return e;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(crate::errors::AppError::NotAnError)
+        ));
+
+        // Never got to the introduction
+        assert_eq!(or.state.state, State::Cancelled);
+        let tmd = or.state.transfer_metadata.unwrap();
+        assert_eq!(tmd.cancel_reason, Some(CancelReason::PinMismatch));
+        assert_eq!(tmd.total_bytes, 0);
+    }
+
+    #[tokio::test]
+    async fn test_pin_not_required() {
+        let (mut or, _peer) = paired_request("trusted-receiver").await;
+        or.require_pin_confirmation = false;
+        let mut events = or.sender.subscribe();
+
+        receive_paired_key_result(&mut or).await;
+        assert_eq!(or.state.state, State::SentIntroduction);
+        while let Ok(msg) = events.try_recv() {
+            assert!(!matches!(
+                msg.state,
+                Some(State::AwaitingPinConfirmation { .. })
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_no_payload_before_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let mut or = new_request(socket);
+        // As it comes out of new(), claiming encryption without any key
+        or.state.encryption_done = true;
+
+        let frame = sharing_nearby::Frame {
+            version: Some(sharing_nearby::frame::Version::V1.into()),
+            ..Default::default()
+        };
+        let e = or.send_encrypted_frame(&frame).await.unwrap_err();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(crate::errors::AppError::EncryptionNotEstablished)
+        ));
+        assert_eq!(or.state.wire_bytes, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_progress_throttle() {
+        let mut progress = ProgressThrottle::default();
+        assert!(progress.should_report(false));
+
+        // A fast link, chunks every 10ms
+        let mut reported = 0;
+        for _ in 0..50 {
+            tokio::time::advance(Duration::from_millis(10)).await;
+            if progress.should_report(false) {
+                reported += 1;
+            }
+        }
+        assert_eq!(reported, 5);
+
+        // The end of a file never waits
+        assert!(progress.should_report(true));
+        assert!(progress.should_report(true));
+        assert!(!progress.should_report(false));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_goodput_over_time() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let mut or = new_request(socket);
+        or.state.transfer_started = Some(Instant::now());
+        tokio::time::advance(Duration::from_secs(2)).await;
+        or.update_state(
+            |e| {
+                if let Some(tmd) = e.transfer_metadata.as_mut() {
+                    tmd.ack_bytes = 2000;
+                }
+            },
+            true,
+        )
+        .await;
+
+        let tmd = or.state.transfer_metadata.unwrap();
+        assert_eq!(tmd.goodput, 1000);
+        assert_eq!(tmd.duration_ms, Some(2000));
+    }
+
+    #[tokio::test]
+    async fn test_goodput_against_wire_bytes() {
+        let path = std::env::temp_dir().join(format!("rqs_goodput_{}", std::process::id()));
+        let size = 200_000;
+        std::fs::write(&path, vec![0x42u8; size]).unwrap();
+
+        let (ours, mut theirs) = tokio::io::duplex(8 * 1024 * 1024);
+        let (sender, mut events) = broadcast::channel(1000);
+        let mut or = OutboundRequest::new(
+            *b"ABCD",
+            ours,
+            String::from("duplex"),
+            sender,
+            OutboundPayload::Files(vec![]),
+            RemoteDeviceInfo {
+                name: String::from("peer"),
+                device_type: DeviceType::Unknown,
+            },
+            None,
+        );
+        or.set_chunk_size(64 * 1024).unwrap();
+        or.state.encrypt_key = Some(vec![0x42; 32]);
+        or.state.send_hmac_key = Some(vec![0x24; 32]);
+        or.state.state = State::SentIntroduction;
+        or.state.file_order.push(1);
+        or.state.transferred_files.insert(
+            1,
+            InternalFileInfo {
+                payload_id: 1,
+                file_url: path.clone(),
+                bytes_transferred: 0,
+                total_size: size as i64,
+                file: None,
+                temp_url: None,
+                digest: None,
+            },
+        );
+
+        let accept = sharing_nearby::V1Frame {
+            r#type: Some(sharing_nearby::v1_frame::FrameType::Response.into()),
+            connection_response: Some(sharing_nearby::ConnectionResponseFrame {
+                status: Some(sharing_nearby::connection_response_frame::Status::Accept.into()),
+                capabilities: our_capabilities(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        or.process_consent(&accept).await.unwrap();
+        or.process_transfer_complete(&session_complete())
+            .await
+            .unwrap();
+        drop(or);
+
+        // Everything that went out: the chunks, then the disconnection which
+        // comes after the final update
+        let mut sent = vec![];
+        theirs.read_to_end(&mut sent).await.unwrap();
+        let mut frames = vec![];
+        let mut rest = sent.as_slice();
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            frames.push(4 + len);
+            rest = &rest[4 + len..];
+        }
+        let wire_bytes: usize = frames[..frames.len() - 1].iter().sum();
+
+        let mut summary = None;
+        while let Ok(msg) = events.try_recv() {
+            if msg.state == Some(State::Finished) {
+                summary = msg.meta;
+            }
+        }
+        let summary = summary.unwrap();
+        assert_eq!(summary.ack_bytes, size as u64);
+        assert_eq!(summary.wire_bytes, wire_bytes as u64);
+        assert!(summary.ack_bytes <= summary.wire_bytes);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_file_gone_before_send() {
+        let dir = std::env::temp_dir().join(format!("rqs_gone_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let kept = dir.join("kept.txt");
+        let gone = dir.join("gone.txt");
+        std::fs::write(&kept, b"still here").unwrap();
+        std::fs::write(&gone, b"deleted after the introduction").unwrap();
+
+        let (ours, mut theirs) = tokio::io::duplex(1024 * 1024);
+        let (sender, mut events) = broadcast::channel(1000);
+        let mut or = OutboundRequest::new(
+            *b"ABCD",
+            ours,
+            String::from("duplex"),
+            sender,
+            OutboundPayload::Files(vec![
+                kept.to_string_lossy().into_owned(),
+                gone.to_string_lossy().into_owned(),
+            ]),
+            RemoteDeviceInfo {
+                name: String::from("peer"),
+                device_type: DeviceType::Unknown,
+            },
+            None,
+        );
+        or.state.encrypt_key = Some(vec![0x42; 32]);
+        or.state.send_hmac_key = Some(vec![0x24; 32]);
+        or.send_introduction().await.unwrap();
+        std::fs::remove_file(&gone).unwrap();
+
+        let accept = sharing_nearby::V1Frame {
+            r#type: Some(sharing_nearby::v1_frame::FrameType::Response.into()),
+            connection_response: Some(sharing_nearby::ConnectionResponseFrame {
+                status: Some(sharing_nearby::connection_response_frame::Status::Accept.into()),
+                capabilities: our_capabilities(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let err = or.process_consent(&accept).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(crate::errors::AppError::FileUnreadable(path)) if *path == gone
+        ));
+        assert_eq!(or.state.state, State::Disconnected);
+        let error = or.state.transfer_metadata.as_ref().unwrap().error.clone();
+        assert_eq!(error.unwrap().code, "file_unreadable");
+        drop(or);
+
+        // Never Finished, and the receiver is told to stop waiting
+        while let Ok(msg) = events.try_recv() {
+            assert_ne!(msg.state, Some(State::Finished));
+        }
+        let mut sent = vec![];
+        theirs.read_to_end(&mut sent).await.unwrap();
+        let mut last = &sent[..];
+        loop {
+            let len = u32::from_be_bytes(last[..4].try_into().unwrap()) as usize;
+            if last.len() == 4 + len {
+                break;
+            }
+            last = &last[4 + len..];
+        }
+        let frame = OfflineFrame::decode(&last[4..]).unwrap();
+        assert_eq!(
+            frame.v1.unwrap().r#type(),
+            location_nearby_connections::v1_frame::FrameType::Disconnection
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cancel_responsive_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut peer, _) = listener.accept().await.unwrap();
+
+        // Close our side as soon as the disconnection frame shows up
+        tokio::spawn(async move {
+            let mut length_buf = [0u8; 4];
+            peer.read_exact(&mut length_buf).await.unwrap();
+            let mut frame_data = vec![0u8; u32::from_be_bytes(length_buf) as usize];
+            peer.read_exact(&mut frame_data).await.unwrap();
+        });
+
+        let mut or = new_request(socket);
+        assert_eq!(or.cancel_with_grace().await, CancellationKind::Clean);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_on_peer_offline() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut peer, _) = listener.accept().await.unwrap();
+
+        tokio::spawn(async move {
+            let mut length_buf = [0u8; 4];
+            peer.read_exact(&mut length_buf).await.unwrap();
+            let mut frame_data = vec![0u8; u32::from_be_bytes(length_buf) as usize];
+            peer.read_exact(&mut frame_data).await.unwrap();
+        });
+
+        let mut or = new_request(socket);
+        // What the discovery sends once the service got removed
+        or.sender
+            .send(crate::hdl::peer_offline_message(or.state.id.clone()))
+            .unwrap();
+
+        let e = or.handle().await.unwrap_err();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(crate::errors::AppError::NotAnError)
+        ));
+        assert_eq!(or.state.state, State::Cancelled);
+        assert_eq!(
+            or.state.transfer_metadata.unwrap().cancel_reason,
+            Some(CancelReason::PeerOffline)
+        );
+    }
+
+    // Everything was sent, only the receiver's confirmation is missing
+    async fn awaiting_confirmation() -> OutboundRequest {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut peer, _) = listener.accept().await.unwrap();
+        tokio::spawn(async move {
+            let mut length_buf = [0u8; 4];
+            while peer.read_exact(&mut length_buf).await.is_ok() {
+                let mut frame_data = vec![0u8; u32::from_be_bytes(length_buf) as usize];
+                peer.read_exact(&mut frame_data).await.unwrap();
+            }
+        });
+
+        let mut or = new_request(socket);
+        or.state.state = State::SendingFiles;
+        or.state.transfer_metadata = Some(TransferMetadata::default());
+        or.state.peer_capabilities.transfer_complete = true;
+        or.unconfirmed_files.insert(1, String::from("a.txt"));
+        or.completion_deadline = Some(Instant::now() + COMPLETION_TIMEOUT);
+        or
+    }
+
+    fn session_complete() -> sharing_nearby::V1Frame {
+        sharing_nearby::V1Frame {
+            r#type: Some(sharing_nearby::v1_frame::FrameType::TransferComplete.into()),
+            transfer_complete: Some(sharing_nearby::TransferCompleteFrame {
+                payload_id: Some(1),
+                session_complete: Some(true),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn cancel_message(id: &str) -> ChannelMessage {
+        ChannelMessage {
+            id: id.to_owned(),
+            direction: ChannelDirection::FrontToLib,
+            action: Some(ChannelAction::CancelTransfer),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cancel_before_confirmation() {
+        let mut or = awaiting_confirmation().await;
+        or.sender.send(cancel_message(&or.state.id)).unwrap();
+
+        let e = or.handle().await.unwrap_err();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(crate::errors::AppError::NotAnError)
+        ));
+        assert_eq!(or.state.state, State::Cancelled);
+
+        // The confirmation showing up right after changes nothing
+        or.process_transfer_complete(&session_complete())
+            .await
+            .unwrap();
+        assert_eq!(or.state.state, State::Cancelled);
+        assert!(or.completion_deadline.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pause_and_resume() {
+        let mut or = awaiting_confirmation().await;
+        or.state.handshake_duration = Some(Duration::from_millis(10));
+        or.keepalive_interval = Some(Duration::from_secs(5));
+        let action = |id: &str, action| ChannelMessage {
+            id: id.to_owned(),
+            direction: ChannelDirection::FrontToLib,
+            action: Some(action),
+            ..Default::default()
+        };
+
+        // Nothing asked for, the next chunk goes out right away
+        or.poll_actions().await.unwrap();
+        assert!(!or.state.paused);
+
+        or.sender
+            .send(action(&or.state.id, ChannelAction::Pause))
+            .unwrap();
+        let sender = or.sender.clone();
+        let resume = action(&or.state.id, ChannelAction::Resume);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(12)).await;
+            sender.send(resume).unwrap();
+        });
+        let paused_at = Instant::now();
+        or.poll_actions().await.unwrap();
+        assert!(paused_at.elapsed() >= Duration::from_secs(12));
+        // Kept alive meanwhile
+        assert!(or.last_sent > paused_at);
+        assert!(!or.state.paused);
+        assert!(!or.state.transfer_metadata.as_ref().unwrap().paused);
+
+        // Cancelling a paused transfer needs no resume
+        or.sender
+            .send(action(&or.state.id, ChannelAction::Pause))
+            .unwrap();
+        or.sender.send(cancel_message(&or.state.id)).unwrap();
+        let e = or.poll_actions().await.unwrap_err();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(crate::errors::AppError::NotAnError)
+        ));
+        assert_eq!(or.state.state, State::Cancelled);
+    }
+
+    #[tokio::test]
+    async fn test_payload_control_and_disconnection() {
+        let mut or = awaiting_confirmation().await;
+        or.state.decrypt_key = Some(vec![1; 32]);
+        or.state.recv_hmac_key = Some(vec![2; 32]);
+        let v1 = |v1_frame| OfflineFrame {
+            version: Some(location_nearby_connections::offline_frame::Version::V1.into()),
+            v1: Some(v1_frame),
+        };
+        let control = |event: control_message::EventType| {
+            v1(location_nearby_connections::V1Frame {
+                r#type: Some(
+                    location_nearby_connections::v1_frame::FrameType::PayloadTransfer.into(),
+                ),
+                payload_transfer: Some(PayloadTransferFrame {
+                    packet_type: Some(PacketType::Control.into()),
+                    payload_header: Some(PayloadHeader {
+                        id: Some(1),
+                        ..Default::default()
+                    }),
+                    control_message: Some(ControlMessage {
+                        event: Some(event.into()),
+                        offset: Some(10),
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+        };
+        let mut seq = 0;
+        let mut receive = |frame: OfflineFrame| {
+            seq += 1;
+            let data = seal_frame(&[1; 32], &[2; 32], seq, &frame).unwrap();
+            SecureMessage::decode(data.as_slice()).unwrap()
+        };
+
+        // No chunk in there, yet nothing wrong with it
+        let ack = receive(control(control_message::EventType::PayloadReceivedAck));
+        or.decrypt_and_process_secure_message(&ack).await.unwrap();
+        assert_eq!(or.state.state, State::SendingFiles);
+        assert_eq!(or.unconfirmed_files.len(), 1);
+
+        let canceled = receive(control(control_message::EventType::PayloadCanceled));
+        or.decrypt_and_process_secure_message(&canceled)
+            .await
+            .unwrap();
+        assert!(or.unconfirmed_files.is_empty());
+
+        let disconnection = receive(v1(location_nearby_connections::V1Frame {
+            r#type: Some(location_nearby_connections::v1_frame::FrameType::Disconnection.into()),
+            disconnection: Some(location_nearby_connections::DisconnectionFrame::default()),
+            ..Default::default()
+        }));
+        let e = or
+            .decrypt_and_process_secure_message(&disconnection)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(crate::errors::AppError::NotAnError)
+        ));
+        assert_eq!(or.state.state, State::Disconnected);
+        assert!(or.completion_deadline.is_none());
+        let tmd = or.state.transfer_metadata.as_ref().unwrap();
+        assert_eq!(
+            tmd.error.as_ref().map(|e| e.code.as_str()),
+            Some("connection_lost")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut peer, _) = listener.accept().await.unwrap();
+
+        // Ended by an error, the peer still gets told
+        let mut or = new_request(socket);
+        or.shutdown().await;
+        let len = peer.read_u32().await.unwrap() as usize;
+        let mut data = vec![0u8; len];
+        peer.read_exact(&mut data).await.unwrap();
+        let frame = OfflineFrame::decode(data.as_slice()).unwrap();
+        assert_eq!(
+            frame.v1.unwrap().r#type(),
+            location_nearby_connections::v1_frame::FrameType::Disconnection
+        );
+        assert_eq!(peer.read(&mut [0u8; 1]).await.unwrap(), 0);
+
+        // The peer disconnected first, nothing left to send
+        let socket = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut peer, _) = listener.accept().await.unwrap();
+        let mut or = new_request(socket);
+        assert!(or.process_disconnection().await.is_err());
+        or.shutdown().await;
+        assert_eq!(peer.read(&mut [0u8; 1]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_digest_mismatch() {
+        let mut or = awaiting_confirmation().await;
+        let mismatch = sharing_nearby::V1Frame {
+            r#type: Some(sharing_nearby::v1_frame::FrameType::TransferComplete.into()),
+            transfer_complete: Some(sharing_nearby::TransferCompleteFrame {
+                payload_id: Some(1),
+                digest_mismatch: Some(true),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        or.process_transfer_complete(&mismatch).await.unwrap();
+
+        // Never counted as confirmed
+        assert!(or.unconfirmed_files.is_empty());
+        let tmd = or.state.transfer_metadata.as_ref().unwrap();
+        assert!(tmd.confirmed_files.is_none());
+        assert_eq!(or.state.state, State::SendingFiles);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_after_confirmation() {
+        let mut or = awaiting_confirmation().await;
+        or.process_transfer_complete(&session_complete())
+            .await
+            .unwrap();
+        assert_eq!(or.state.state, State::Finished);
+
+        or.sender.send(cancel_message(&or.state.id)).unwrap();
+        // Our own state updates are queued before it
+        while or.handle().await.is_ok() {}
+        assert_eq!(or.state.state, State::Finished);
+        assert!(or.state.transfer_metadata.unwrap().cancel_reason.is_none());
+    }
+
+    // Paused clock, so a cancel honored only once the wait times out would
+    // show as the whole COMPLETION_TIMEOUT having elapsed
+    #[tokio::test(start_paused = true)]
+    async fn test_cancel_during_confirmation_wait() {
+        let mut or = awaiting_confirmation().await;
+        let started = Instant::now();
+
+        let (sender, id) = (or.sender.clone(), or.state.id.clone());
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            sender.send(cancel_message(&id)).unwrap();
+        });
+
+        while or.handle().await.is_ok() {}
+        assert_eq!(or.state.state, State::Cancelled);
+        // The cancel, then the grace period for the silent peer
+        assert!(started.elapsed() < COMPLETION_TIMEOUT);
+    }
+
+    // The clock is paused, the grace period elapses as soon as we're idle
+    #[tokio::test(start_paused = true)]
+    async fn test_cancel_unresponsive_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        // Keep the peer alive but never read nor answer
+        let (_peer, _) = listener.accept().await.unwrap();
+
+        let mut or = new_request(socket);
+        assert_eq!(or.cancel_with_grace().await, CancellationKind::Forced);
+    }
+
+    // Sends a file of the given size once accepted by a receiver advertising
+    // the given capabilities, counting the frames that went out.
+    async fn send_file(size: usize, capabilities: Vec<i32>) -> (State, usize) {
+        send_file_in_chunks(size, capabilities, CHUNK_SIZE).await
+    }
+
+    async fn send_file_in_chunks(
+        size: usize,
+        capabilities: Vec<i32>,
+        chunk_size: usize,
+    ) -> (State, usize) {
+        let path = std::env::temp_dir().join(format!(
+            "rqs_frames_{}_{size}_{}_{chunk_size}",
+            std::process::id(),
+            capabilities.len()
+        ));
+        std::fs::write(&path, vec![0x42u8; size]).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut peer, _) = listener.accept().await.unwrap();
+        let counter = tokio::spawn(async move {
+            let mut frames = 0;
+            let mut length_buf = [0u8; 4];
+            while stream_read_exact(&mut peer, &mut length_buf).await.is_ok() {
+                let mut frame = vec![0u8; u32::from_be_bytes(length_buf) as usize];
+                stream_read_exact(&mut peer, &mut frame).await.unwrap();
+                frames += 1;
+            }
+            frames
+        });
+
+        let mut or = new_request(socket);
+        or.set_chunk_size(chunk_size).unwrap();
+        or.state.encryption_done = true;
+        or.state.encrypt_key = Some(vec![0x42; 32]);
+        or.state.send_hmac_key = Some(vec![0x24; 32]);
+        or.state.state = State::SentIntroduction;
+        or.state.file_order.push(1);
+        or.state.transferred_files.insert(
+            1,
+            InternalFileInfo {
+                payload_id: 1,
+                file_url: path.clone(),
+                bytes_transferred: 0,
+                total_size: size as i64,
+                file: None,
+                temp_url: None,
+                digest: None,
+            },
+        );
+
+        let accept = sharing_nearby::V1Frame {
+            r#type: Some(sharing_nearby::v1_frame::FrameType::Response.into()),
+            connection_response: Some(sharing_nearby::ConnectionResponseFrame {
+                status: Some(sharing_nearby::connection_response_frame::Status::Accept.into()),
+                capabilities,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        or.process_consent(&accept).await.unwrap();
+        let state = or.state.state.clone();
+        // Closing the socket ends the count
+        drop(or);
+
+        std::fs::remove_file(&path).unwrap();
+        (state, counter.await.unwrap())
+    }
+
+    // Streams a file to an actual receiver, its digest primed with the given
+    // bytes, returning how the receiver ended and what it wrote to disk
+    async fn send_file_to_receiver(primed: &[u8]) -> (anyhow::Error, Vec<u8>, Vec<u8>) {
+        let dir = std::env::temp_dir().join(format!(
+            "rqs_digest_{}_{}",
+            std::process::id(),
+            primed.len()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (path, dest) = (dir.join("source"), dir.join("dest"));
+        // Several chunks, none alike
+        let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+
+        let (ours, theirs) = tokio::io::duplex(8 * 1024 * 1024);
+        let (sender, _) = broadcast::channel(10);
+        let mut or = OutboundRequest::new(
+            *b"ABCD",
+            ours,
+            String::from("duplex"),
+            sender,
+            OutboundPayload::Files(vec![]),
+            RemoteDeviceInfo {
+                name: String::from("peer"),
+                device_type: DeviceType::Unknown,
+            },
+            None,
+        );
+        or.set_chunk_size(64 * 1024).unwrap();
+        or.state.encrypt_key = Some(vec![0x42; 32]);
+        or.state.send_hmac_key = Some(vec![0x24; 32]);
+        or.state.state = State::SentIntroduction;
+        or.state.file_order.push(1);
+        or.state.transferred_files.insert(
+            1,
+            InternalFileInfo {
+                payload_id: 1,
+                file_url: path.clone(),
+                bytes_transferred: 0,
+                total_size: data.len() as i64,
+                file: None,
+                temp_url: None,
+                digest: None,
+            },
+        );
+
+        let (sender, _) = broadcast::channel(10);
+        let mut ir =
+            crate::hdl::InboundRequest::new(*b"WXYZ", theirs, String::from("duplex"), sender);
+        ir.state.decrypt_key = Some(vec![0x42; 32]);
+        ir.state.recv_hmac_key = Some(vec![0x24; 32]);
+        ir.state.encrypt_key = Some(vec![0x43; 32]);
+        ir.state.send_hmac_key = Some(vec![0x25; 32]);
+        ir.state.state = State::ReceivingFiles;
+        let mut digest = Sha256::new();
+        digest.update(primed);
+        ir.state.transferred_files.insert(
+            1,
+            InternalFileInfo {
+                payload_id: 1,
+                file_url: dest.clone(),
+                bytes_transferred: 0,
+                total_size: data.len() as i64,
+                file: Some(File::create(&dest).unwrap()),
+                temp_url: None,
+                digest: Some(digest),
+            },
+        );
+
+        let accept = sharing_nearby::V1Frame {
+            r#type: Some(sharing_nearby::v1_frame::FrameType::Response.into()),
+            connection_response: Some(sharing_nearby::ConnectionResponseFrame {
+                status: Some(sharing_nearby::connection_response_frame::Status::Accept.into()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        // The whole file fits in the duplex buffer, the receiver reads it after
+        or.process_consent(&accept).await.unwrap();
+        let e = loop {
+            if let Err(e) = ir.handle().await {
+                break e;
+            }
+        };
+
+        let received = std::fs::read(&dest).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        (e, data, received)
+    }
+
+    #[tokio::test]
+    async fn test_digest_sender_to_receiver() {
+        let (e, sent, received) = send_file_to_receiver(&[]).await;
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(crate::errors::AppError::NotAnError)
+        ));
+        assert_eq!(received, sent);
+
+        // Only caught if the sender streamed a digest along the last chunk
+        let (e, _, _) = send_file_to_receiver(&[0]).await;
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(crate::errors::AppError::DigestMismatch(1))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_introduction_only() {
+        let path = std::env::temp_dir().join(format!("rqs_held_{}", std::process::id()));
+        std::fs::write(&path, vec![0x42u8; 4096]).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut peer, _) = listener.accept().await.unwrap();
+        tokio::spawn(async move {
+            let mut length_buf = [0u8; 4];
+            while peer.read_exact(&mut length_buf).await.is_ok() {
+                let mut frame_data = vec![0u8; u32::from_be_bytes(length_buf) as usize];
+                peer.read_exact(&mut frame_data).await.unwrap();
+            }
+        });
+
+        let (sender, _) = broadcast::channel(10);
+        let mut or = OutboundRequestBuilder::new(
+            *b"ABCD",
+            String::from("127.0.0.1"),
+            sender,
+            OutboundPayload::Files(vec![]),
+        )
+        .introduction_only(true)
+        .build(socket)
+        .unwrap();
+        or.state.encrypt_key = Some(vec![0x42; 32]);
+        or.state.send_hmac_key = Some(vec![0x24; 32]);
+        or.state.state = State::SentIntroduction;
+        or.state.file_order.push(1);
+        or.state.transferred_files.insert(
+            1,
+            InternalFileInfo {
+                payload_id: 1,
+                file_url: path.clone(),
+                bytes_transferred: 0,
+                total_size: 4096,
+                file: None,
+                temp_url: None,
+                digest: None,
+            },
+        );
+
+        let accept = sharing_nearby::V1Frame {
+            r#type: Some(sharing_nearby::v1_frame::FrameType::Response.into()),
+            connection_response: Some(sharing_nearby::ConnectionResponseFrame {
+                status: Some(sharing_nearby::connection_response_frame::Status::Accept.into()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        or.process_consent(&accept).await.unwrap();
+        // Accepted, but not a byte read nor sent
+        assert_eq!(or.state.state, State::AwaitingProceed);
+        assert_eq!(or.state.transferred_files[&1].bytes_transferred, 0);
+        assert!(or.state.transfer_started.is_none());
+
+        or.sender
+            .send(ChannelMessage {
+                id: or.state.id.clone(),
+                direction: ChannelDirection::FrontToLib,
+                action: Some(ChannelAction::Proceed),
+                ..Default::default()
+            })
+            .unwrap();
+        or.handle().await.unwrap();
+        assert_eq!(or.state.transferred_files[&1].bytes_transferred, 4096);
+        assert_eq!(or.state.state, State::Finished);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_build_introduction() {
+        let dir = std::env::temp_dir().join(format!("rqs_preview_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (photo, notes) = (dir.join("photo.jpg"), dir.join("notes.txt"));
+        std::fs::write(&photo, vec![0xFFu8; 2048]).unwrap();
+        std::fs::write(&notes, b"hello").unwrap();
+
+        let paths = [&photo, &notes, &dir, &dir.join("missing.bin")]
+            .map(|p| p.to_string_lossy().into_owned());
+        let payload = OutboundPayload::Files(paths.to_vec());
+        // As for a resumed transfer that knows the photo
+        let info = build_introduction(&payload, |p| (p == photo).then_some(42)).unwrap();
+
+        // The directory and the missing file are left out
+        assert_eq!(info.files.len(), 2);
+        assert_eq!(info.total_bytes, 2053);
+        assert_eq!(
+            info.files[0],
+            OfferedFile {
+                payload_id: 42,
+                path: paths[0].clone(),
+                name: String::from("photo.jpg"),
+                parent_folder: None,
+                size: 2048,
+                mime_type: String::from("image/jpeg"),
+            }
+        );
+        assert_eq!(info.files[1].name, "notes.txt");
+        assert_eq!(info.files[1].size, 5);
+        assert_eq!(info.files[1].mime_type, "text/plain");
+        assert_ne!(info.files[1].payload_id, 42);
+        assert!(info.empty_folders.is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_build_directory_introduction() {
+        let base = std::env::temp_dir().join(format!("rqs_directory_{}", std::process::id()));
+        let dir = base.join("Photos");
+        std::fs::create_dir_all(dir.join("2024/summer")).unwrap();
+        std::fs::create_dir_all(dir.join("empty")).unwrap();
+        std::fs::write(dir.join("cover.jpg"), b"cover").unwrap();
+        std::fs::write(dir.join("2024/b.jpg"), b"b").unwrap();
+        std::fs::write(dir.join("2024/a.jpg"), b"a").unwrap();
+        std::fs::write(dir.join("2024/summer/beach.jpg"), b"beach").unwrap();
+        std::fs::write(base.join("outside.txt"), b"outside").unwrap();
+        std::os::unix::fs::symlink(base.join("outside.txt"), dir.join("link.txt")).unwrap();
+        // Would never end if followed blindly
+        std::os::unix::fs::symlink(&dir, dir.join("2024/loop")).unwrap();
+
+        let payload = OutboundPayload::Directory(dir.to_string_lossy().into_owned());
+        let offered = |follow| {
+            walk_directory(&dir, follow)
+                .unwrap()
+                .files
+                .into_iter()
+                .map(|(path, folder)| {
+                    let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                    format!("{folder}/{name}")
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Files before subdirectories, the links left out
+        assert_eq!(
+            offered(false),
+            [
+                "Photos/cover.jpg",
+                "Photos/2024/a.jpg",
+                "Photos/2024/b.jpg",
+                "Photos/2024/summer/beach.jpg",
+            ]
+        );
+        // The loop leads back to the top, walked already
+        assert_eq!(
+            offered(true),
+            [
+                "Photos/cover.jpg",
+                "Photos/link.txt",
+                "Photos/2024/a.jpg",
+                "Photos/2024/b.jpg",
+                "Photos/2024/summer/beach.jpg",
+            ]
+        );
+
+        // Listed apart, whether the links are followed or not
+        for follow in [false, true] {
+            let tree = walk_directory(&dir, follow).unwrap();
+            assert_eq!(tree.empty_folders, ["Photos/empty"]);
+        }
+
+        let info = build_introduction(&payload, |_| None).unwrap();
+        assert_eq!(info.empty_folders, ["Photos/empty"]);
+        assert_eq!(info.files.len(), 4);
+        assert_eq!(info.total_bytes, 12);
+        assert_eq!(info.files[3].name, "beach.jpg");
+        assert_eq!(
+            info.files[3].parent_folder.as_deref(),
+            Some("Photos/2024/summer")
+        );
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_split_directory() {
+        let base = std::env::temp_dir().join(format!("rqs_split_{}", std::process::id()));
+        let dir = base.join("Album");
+        std::fs::create_dir_all(dir.join("empty_1")).unwrap();
+        std::fs::create_dir_all(dir.join("empty_2")).unwrap();
+        for name in ["a.jpg", "b.jpg", "c.jpg"] {
+            std::fs::write(dir.join(name), name).unwrap();
+        }
+
+        let parts = split_directory(&dir, 2).unwrap();
+        let entries: Vec<_> = parts
+            .iter()
+            .map(|part| {
+                let info = build_introduction(part, |_| None).unwrap();
+                let names: Vec<_> = info.files.into_iter().map(|f| f.name).collect();
+                (names, info.empty_folders)
+            })
+            .collect();
+        // Files first, the folders filling up what's left
+        assert_eq!(
+            entries,
+            [
+                (vec!["a.jpg", "b.jpg"], vec![]),
+                (vec!["c.jpg"], vec!["Album/empty_1"]),
+                (vec![], vec!["Album/empty_2"]),
+            ]
+            .map(|(files, folders)| (
+                files.into_iter().map(str::to_owned).collect::<Vec<_>>(),
+                folders.into_iter().map(str::to_owned).collect::<Vec<_>>(),
+            ))
+        );
+
+        // Small enough to go as one
+        assert_eq!(split_directory(&dir, 5).unwrap().len(), 1);
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_mime_type() {
+        let dir = std::env::temp_dir().join(format!("rqs_mime_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mime_of = |name: &str, content: &[u8]| {
+            let path = dir.join(name);
+            std::fs::write(&path, content).unwrap();
+            mime_type_of(&path)
+        };
+
+        // The extension wins, whatever the content
+        assert_eq!(mime_of("photo.webp", b"whatever"), "image/webp");
+        assert_eq!(mime_of("notes.txt", b"\xFF\xD8\xFF\xE0"), "text/plain");
+
+        // Without one, the first bytes tell
+        assert_eq!(
+            mime_of("IMG_0001", b"\xFF\xD8\xFF\xE0\x00\x10JFIF"),
+            "image/jpeg"
+        );
+        assert_eq!(
+            mime_of("IMG_0002", b"\x00\x00\x00\x18ftypheic\x00\x00\x00\x00"),
+            "image/heic"
+        );
+        assert_eq!(
+            mime_of("IMG_0003", b"RIFF\x24\x00\x00\x00WEBPVP8 "),
+            "image/webp"
+        );
+        assert_eq!(
+            mime_of(
+                "VID_0001.unknownext",
+                b"\x00\x00\x00\x20ftypisom\x00\x00\x02\x00"
+            ),
+            "video/mp4"
+        );
+
+        // Nothing to go by
+        assert_eq!(mime_of("blob", b"\x00\x01\x02"), "application/octet-stream");
+        assert_eq!(mime_of("empty", b""), "application/octet-stream");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_single_frame_threshold() {
+        assert_eq!(send_file(1024, our_capabilities()).await.1, 1);
+
+        // Four full chunks, then the last chunk flag on its own
+        assert_eq!(send_file(4 * CHUNK_SIZE, our_capabilities()).await.1, 5);
+    }
+
+    #[tokio::test]
+    async fn test_chunk_size() {
+        let chunk_size = 64 * 1024;
+        assert_eq!(
+            send_file_in_chunks(4 * chunk_size, our_capabilities(), chunk_size)
+                .await
+                .1,
+            5
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut or = new_request(socket);
+        assert!(or.set_chunk_size(0).is_err());
+        assert!(or.set_chunk_size(DEFAULT_MAX_FRAME_LENGTH).is_err());
+        assert!(or.set_chunk_size(1024 * 1024).is_ok());
+        assert_eq!(or.chunk_size, 1024 * 1024);
+    }
+
+    #[tokio::test]
+    async fn test_builder() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, _) = broadcast::channel(10);
+        let builder = || {
+            OutboundRequestBuilder::new(
+                *b"ABCD",
+                String::from("brave-otter-42"),
+                sender.clone(),
+                OutboundPayload::Files(vec![]),
+            )
+        };
+
+        // Nothing set, same as new()
+        let or = builder().connect(&[addr]).await.unwrap();
+        assert_eq!(or.chunk_size, CHUNK_SIZE);
+        assert_eq!(or.handshake_timeout, get_handshake_timeout());
+        assert_eq!(
+            or.state.remote_device_info.as_ref().unwrap().name,
+            "brave-otter-42"
+        );
+
+        let or = builder()
+            .note(Some(String::from("for you")))
+            .chunk_size(1024 * 1024)
+            .handshake_timeout(Duration::from_secs(3))
+            .keepalive_interval(None)
+            .require_pin_confirmation(true)
+            .connect(&[addr])
+            .await
+            .unwrap();
+        assert_eq!(or.note.as_deref(), Some("for you"));
+        assert_eq!(or.chunk_size, 1024 * 1024);
+        assert_eq!(or.handshake_timeout, Duration::from_secs(3));
+        assert!(or.keepalive_interval.is_none());
+        assert!(or.require_pin_confirmation);
+
+        // Same checks as the setters of the request
+        let socket = TcpStream::connect(addr).await.unwrap();
+        assert!(builder().chunk_size(0).build(socket).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_minimal_receiver() {
+        // An rquickshare receiver gets to confirm the files
+        assert_eq!(
+            send_file(2048, our_capabilities()).await.0,
+            State::SendingFiles
+        );
+
+        // Others won't, so there's no point in waiting: the file, then the
+        // disconnection right away
+        assert_eq!(send_file(1024, vec![]).await, (State::Finished, 2));
+    }
+}
@@ -0,0 +1,240 @@
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::channel::TransferType;
+use crate::hdl::info::{CancelReason, TransferError};
+use crate::hdl::{InnerState, State, TextPayloadType};
+use crate::utils::{bytes_per_second, get_transfer_history, RemoteDeviceInfo};
+
+/// One line of the transfer history, written once a transfer ended. Keys,
+/// the PIN and the content of texts never make it there.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TransferRecord {
+    // When the transfer ended, in seconds since the Unix epoch
+    pub timestamp: u64,
+    pub id: String,
+    pub peer: Option<RemoteDeviceInfo>,
+    pub direction: TransferType,
+    pub files: Vec<RecordedFile>,
+    // Only the kind of a text, not the text itself
+    pub text_type: Option<TextPayloadType>,
+    pub total_bytes: u64,
+    pub ack_bytes: u64,
+    // From the first payload byte to the end, None if none ever went through
+    pub duration_ms: Option<u64>,
+    // Average goodput over that duration, in bytes/s
+    pub throughput: u64,
+    // Always one of Finished, Cancelled, Rejected or Disconnected
+    pub state: State,
+    pub error: Option<TransferError>,
+    pub cancel_reason: Option<CancelReason>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RecordedFile {
+    pub name: String,
+    pub size: u64,
+    pub result: FileResult,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum FileResult {
+    // Sent or received in full
+    Done,
+    // Left out by the receiver
+    Skipped,
+    Incomplete,
+}
+
+impl TransferRecord {
+    pub(crate) fn of(state: &InnerState, direction: TransferType) -> Self {
+        let metadata = state.transfer_metadata.clone().unwrap_or_default();
+        let skipped = metadata.skipped_files.unwrap_or_default();
+
+        let files = state
+            .file_order
+            .iter()
+            .filter_map(|id| {
+                let (name, size) = state.introduced_files.get(id)?;
+                let result = if skipped.contains(name) {
+                    FileResult::Skipped
+                } else if state.transferred_files.contains_key(id) {
+                    FileResult::Incomplete
+                } else {
+                    // Dropped from transferred_files once done with
+                    FileResult::Done
+                };
+
+                Some(RecordedFile {
+                    name: name.clone(),
+                    size: *size,
+                    result,
+                })
+            })
+            .collect();
+
+        let duration = state.transfer_started.map(|started| started.elapsed());
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+
+        Self {
+            timestamp,
+            id: state.id.clone(),
+            peer: metadata.accepted_by.or(state.remote_device_info.clone()),
+            direction,
+            files,
+            text_type: metadata.text_type,
+            total_bytes: metadata.total_bytes,
+            ack_bytes: metadata.ack_bytes,
+            duration_ms: duration.map(|d| d.as_millis() as u64),
+            throughput: duration.map_or(0, |d| bytes_per_second(metadata.ack_bytes, d)),
+            state: match &state.state {
+                s
+                @ (State::Finished | State::Cancelled | State::Rejected | State::Disconnected) => {
+                    s.clone()
+                }
+                // The session ended halfway
+                _ => State::Disconnected,
+            },
+            error: metadata.error,
+            cancel_reason: metadata.cancel_reason,
+        }
+    }
+}
+
+// Record a session that just ended, when the history is enabled. Sessions
+// that never got to introduce anything weren't transfers.
+pub(crate) fn record_transfer(state: &InnerState, direction: TransferType) {
+    let Some(path) = get_transfer_history() else {
+        return;
+    };
+
+    if state.file_order.is_empty() && state.text_payload.is_none() {
+        return;
+    }
+
+    if let Err(e) = append(&path, &TransferRecord::of(state, direction)) {
+        warn!("Failed to record the transfer in {:?}: {}", path, e);
+    }
+}
+
+// Only ever appended to, a line at a time in a single write so that
+// concurrent sessions don't interleave. A line cut short by a crash is left
+// for readers to skip, the next record still starts on its own line.
+pub(crate) fn append(path: &Path, record: &TransferRecord) -> Result<(), anyhow::Error> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)?;
+    if file.metadata()?.len() > 0 {
+        let mut last = [0u8];
+        file.seek(SeekFrom::End(-1))?;
+        file.read_exact(&mut last)?;
+        if last[0] != b'\n' {
+            line.insert(0, b'\n');
+        }
+    }
+
+    file.write_all(&line)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::*;
+    use crate::hdl::info::{InternalFileInfo, TransferMetadata};
+    use crate::utils::DeviceType;
+
+    #[test]
+    fn test_transfer_record() {
+        let path = std::env::temp_dir().join(format!("rqs_history_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut state = InnerState {
+            id: String::from("history"),
+            state: State::Finished,
+            remote_device_info: Some(RemoteDeviceInfo {
+                name: String::from("peer"),
+                device_type: DeviceType::Phone,
+            }),
+            pin_code: Some(String::from("1234")),
+            encrypt_key: Some(vec![0x42; 32]),
+            transfer_started: Some(Instant::now() - Duration::from_secs(2)),
+            transfer_metadata: Some(TransferMetadata {
+                files: Some(vec![String::from("a.txt"), String::from("b.txt")]),
+                skipped_files: Some(vec![String::from("b.txt")]),
+                pin_code: Some(String::from("1234")),
+                total_bytes: 2048,
+                ack_bytes: 1024,
+                ..Default::default()
+            }),
+            file_order: vec![1, 2],
+            ..Default::default()
+        };
+        state
+            .introduced_files
+            .insert(1, (String::from("a.txt"), 1024));
+        state
+            .introduced_files
+            .insert(2, (String::from("b.txt"), 1024));
+
+        append(&path, &TransferRecord::of(&state, TransferType::Inbound)).unwrap();
+
+        // A crash in the middle of the next record, then one more
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"timestamp":17"#).unwrap();
+        state.state = State::ReceivingFiles;
+        state.transferred_files.insert(
+            1,
+            InternalFileInfo {
+                payload_id: 1,
+                file_url: "a.txt".into(),
+                bytes_transferred: 512,
+                total_size: 1024,
+                file: None,
+                temp_url: None,
+                digest: None,
+            },
+        );
+        append(&path, &TransferRecord::of(&state, TransferType::Inbound)).unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(serde_json::from_str::<TransferRecord>(lines[1]).is_err());
+
+        let record: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(record["id"], "history");
+        assert_eq!(record["direction"], "Inbound");
+        assert_eq!(record["state"], "Finished");
+        assert_eq!(record["peer"]["name"], "peer");
+        assert_eq!(record["total_bytes"], 2048);
+        assert_eq!(record["files"][0]["name"], "a.txt");
+        assert_eq!(record["files"][0]["size"], 1024);
+        assert_eq!(record["files"][0]["result"], "Done");
+        assert_eq!(record["files"][1]["result"], "Skipped");
+        assert!(record["duration_ms"].as_u64().unwrap() >= 2000);
+        assert!(record["throughput"].as_u64().unwrap() > 0);
+        assert!(!lines[0].contains("pin"));
+        assert!(!lines[0].contains("key"));
+
+        let interrupted: TransferRecord = serde_json::from_str(lines[2]).unwrap();
+        assert_eq!(interrupted.state, State::Disconnected);
+        assert_eq!(interrupted.files[0].result, FileResult::Incomplete);
+    }
+}
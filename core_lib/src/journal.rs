@@ -0,0 +1,437 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use anyhow::anyhow;
+use rand::Rng;
+
+use crate::hdl::OutboundPayload;
+use crate::manager::SendInfo;
+
+const JOURNAL_EXTENSION: &str = "journal";
+// Entries that couldn't be decoded are renamed to this, out of the way but
+// still there to look at
+const CORRUPT_EXTENSION: &str = "corrupt";
+// Entries without a version in their header predate payload ids
+const JOURNAL_VERSION: u32 = 2;
+// Don't hit the disk for every chunk, a crash only costs us this much
+const FLUSH_INTERVAL: u64 = 16 * 1024 * 1024;
+
+/// Progress of a single file being sent, as persisted in the journal.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct JournalFile {
+    pub path: PathBuf,
+    // Offered again under the same id on resume, so that the receiver can
+    // match it with what it already got
+    pub payload_id: i64,
+    pub size: u64,
+    // Modification time of the source (ms since epoch) when the transfer started
+    pub modified: u64,
+    // Bytes of this file that went through the socket
+    pub confirmed: u64,
+    // Set once the last chunk went out, digest included
+    pub done: bool,
+}
+
+impl JournalFile {
+    fn new(path: &Path) -> Result<Self, anyhow::Error> {
+        let (size, modified) = file_fingerprint(path)?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            payload_id: rand::thread_rng().gen(),
+            size,
+            modified,
+            confirmed: 0,
+            done: false,
+        })
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.done
+    }
+
+    // The source must still be the exact same file for a resume to make sense
+    pub fn is_unchanged(&self) -> bool {
+        match file_fingerprint(&self.path) {
+            Ok((size, modified)) => size == self.size && modified == self.modified,
+            Err(_) => false,
+        }
+    }
+}
+
+/// Minimal state needed to restart an outbound transfer after the app died.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct JournalEntry {
+    pub id: String,
+    pub addr: String,
+    pub name: String,
+    pub files: Vec<JournalFile>,
+}
+
+impl JournalEntry {
+    pub fn new(id: &str, addr: &str, name: &str, files: &[String]) -> Result<Self, anyhow::Error> {
+        for field in [id, addr, name] {
+            if field.contains(['\t', '\n']) {
+                return Err(anyhow!("Can't journal a field containing a tab or newline"));
+            }
+        }
+
+        let files = files
+            .iter()
+            .map(|f| {
+                if f.contains('\n') {
+                    return Err(anyhow!("Can't journal a path containing a newline"));
+                }
+
+                JournalFile::new(Path::new(f))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            id: id.to_owned(),
+            addr: addr.to_owned(),
+            name: name.to_owned(),
+            files,
+        })
+    }
+
+    pub fn is_unchanged(&self) -> bool {
+        self.files.iter().all(|f| f.is_unchanged())
+    }
+
+    // Whether the given files are (what's left of) this transfer
+    pub fn covers(&self, files: &[String]) -> bool {
+        files
+            .iter()
+            .all(|f| self.files.iter().any(|jf| jf.path == Path::new(f)))
+    }
+
+    // Files already sent are left out. Partially sent ones keep their payload
+    // id, the receiver tells how much of them it has when accepting.
+    pub fn to_send_info(&self) -> SendInfo {
+        let files = self
+            .files
+            .iter()
+            .filter(|f| !f.is_complete())
+            .map(|f| f.path.to_string_lossy().into_owned())
+            .collect();
+
+        SendInfo {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            addr: self.addr.clone(),
+            ob: OutboundPayload::Files(files),
+            note: None,
+            chunk_size: None,
+            introduction_only: false,
+        }
+    }
+
+    // First line is the transfer and the version, then one line per file:
+    // done, payload id, confirmed, size, modified and the path last so that
+    // it may contain tabs.
+    fn encode(&self) -> String {
+        let mut out = format!(
+            "{}\t{}\t{}\t{}\n",
+            self.id, self.addr, self.name, JOURNAL_VERSION
+        );
+        for f in &self.files {
+            out.push_str(&format!(
+                "{}\t{}\t{}\t{}\t{}\t{}\n",
+                f.done as u8,
+                f.payload_id,
+                f.confirmed,
+                f.size,
+                f.modified,
+                f.path.display()
+            ));
+        }
+
+        out
+    }
+
+    fn decode(data: &str) -> Result<Self, anyhow::Error> {
+        // Every line is terminated, anything else was cut short mid-write
+        if !data.ends_with('\n') {
+            return Err(anyhow!("Truncated journal entry"));
+        }
+
+        let mut lines = data.lines();
+        let header: Vec<&str> = lines
+            .next()
+            .ok_or_else(|| anyhow!("Empty journal entry"))?
+            .split('\t')
+            .collect();
+        let (id, addr, name, version) = match header[..] {
+            [id, addr, name] => (id, addr, name, 1),
+            [id, addr, name, version] => (id, addr, name, version.parse()?),
+            _ => return Err(anyhow!("Malformed journal header")),
+        };
+
+        let files = lines
+            .map(|line| {
+                if version < JOURNAL_VERSION {
+                    let fields: Vec<&str> = line.splitn(4, '\t').collect();
+                    let [confirmed, size, modified, path] = fields[..] else {
+                        return Err(anyhow!("Malformed journal line: {line}"));
+                    };
+                    let (confirmed, size) = (confirmed.parse()?, size.parse()?);
+
+                    // The receiver can't have kept anything under a new id
+                    return Ok(JournalFile {
+                        path: PathBuf::from(path),
+                        payload_id: rand::thread_rng().gen(),
+                        size,
+                        modified: modified.parse()?,
+                        confirmed,
+                        done: confirmed >= size,
+                    });
+                }
+
+                let fields: Vec<&str> = line.splitn(6, '\t').collect();
+                let [done, payload_id, confirmed, size, modified, path] = fields[..] else {
+                    return Err(anyhow!("Malformed journal line: {line}"));
+                };
+
+                Ok(JournalFile {
+                    path: PathBuf::from(path),
+                    payload_id: payload_id.parse()?,
+                    size: size.parse()?,
+                    modified: modified.parse()?,
+                    confirmed: confirmed.parse()?,
+                    done: done == "1",
+                })
+            })
+            .collect::<Result<Vec<_>, anyhow::Error>>()?;
+
+        Ok(Self {
+            id: id.to_owned(),
+            addr: addr.to_owned(),
+            name: name.to_owned(),
+            files,
+        })
+    }
+}
+
+/// Keeps the on-disk entry of a running transfer up to date.
+#[derive(Debug)]
+pub(crate) struct Journal {
+    dir: PathBuf,
+    entry: JournalEntry,
+    unflushed: u64,
+}
+
+impl Journal {
+    pub fn open(dir: PathBuf, entry: JournalEntry) -> Self {
+        let journal = Self {
+            dir,
+            entry,
+            unflushed: 0,
+        };
+        journal.flush();
+
+        journal
+    }
+
+    pub fn payload_id(&self, path: &Path) -> Option<i64> {
+        self.entry
+            .files
+            .iter()
+            .find(|f| f.path == path)
+            .map(|f| f.payload_id)
+    }
+
+    // Picking up a file from the receiver's offset rather than from zero
+    pub fn record_resumed(&mut self, path: &Path, offset: u64) {
+        if let Some(file) = self.entry.files.iter_mut().find(|f| f.path == path) {
+            file.confirmed = offset;
+        }
+    }
+
+    pub fn record_progress(&mut self, path: &Path, bytes: u64) {
+        let Some(file) = self.entry.files.iter_mut().find(|f| f.path == path) else {
+            return;
+        };
+
+        file.confirmed += bytes;
+        self.unflushed += bytes;
+        if self.unflushed >= FLUSH_INTERVAL {
+            self.flush();
+        }
+    }
+
+    pub fn record_done(&mut self, path: &Path) {
+        if let Some(file) = self.entry.files.iter_mut().find(|f| f.path == path) {
+            file.done = true;
+            self.flush();
+        }
+    }
+
+    // Called once the transfer ended for good (finished, cancelled, rejected)
+    pub fn remove(self) {
+        remove_entry(&self.dir, &self.entry.id);
+    }
+
+    fn flush(&self) {
+        if let Err(e) = write_entry(&self.dir, &self.entry) {
+            warn!("Couldn't write the resume journal: {}", e);
+        }
+    }
+}
+
+// Written aside then renamed over the entry, so that a crash mid-write
+// leaves the previous version in place rather than half of the new one
+pub(crate) fn write_entry(dir: &Path, entry: &JournalEntry) -> Result<(), anyhow::Error> {
+    fs::create_dir_all(dir)?;
+    let path = entry_path(dir, &entry.id);
+    let temp = path.with_extension(format!("{JOURNAL_EXTENSION}.tmp"));
+    fs::write(&temp, entry.encode())?;
+    fs::rename(&temp, &path)?;
+    Ok(())
+}
+
+pub(crate) fn read_entry(dir: &Path, id: &str) -> Option<JournalEntry> {
+    let data = fs::read_to_string(entry_path(dir, id)).ok()?;
+    match JournalEntry::decode(&data) {
+        Ok(entry) => Some(entry),
+        Err(e) => {
+            warn!("Ignoring the malformed journal entry of {id}: {}", e);
+            None
+        }
+    }
+}
+
+pub(crate) fn remove_entry(dir: &Path, id: &str) {
+    let path = entry_path(dir, id);
+    if path.exists() {
+        if let Err(e) = fs::remove_file(&path) {
+            warn!("Couldn't remove the journal entry {:?}: {}", path, e);
+        }
+    }
+}
+
+pub(crate) fn read_entries(dir: &Path) -> Result<Vec<JournalEntry>, anyhow::Error> {
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut entries = vec![];
+    for dir_entry in fs::read_dir(dir)? {
+        let path = dir_entry?.path();
+        if path.extension().unwrap_or_default() != JOURNAL_EXTENSION {
+            continue;
+        }
+
+        // A single bad entry mustn't keep the others from being resumed
+        let decoded = fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|data| JournalEntry::decode(&data));
+        match decoded {
+            Ok(entry) => entries.push(entry),
+            Err(e) => {
+                warn!("Setting the corrupt journal entry {:?} aside: {}", path, e);
+                if let Err(e) = fs::rename(&path, path.with_extension(CORRUPT_EXTENSION)) {
+                    warn!("Couldn't set {:?} aside: {}", path, e);
+                }
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+// The id comes from the frontend, hex it to get a safe file name
+fn entry_path(dir: &Path, id: &str) -> PathBuf {
+    let name: String = id.bytes().map(|b| format!("{:02x}", b)).collect();
+    dir.join(format!("{name}.{JOURNAL_EXTENSION}"))
+}
+
+fn file_fingerprint(path: &Path) -> Result<(u64, u64), anyhow::Error> {
+    let metadata = fs::metadata(path)?;
+    let modified = metadata.modified()?.duration_since(UNIX_EPOCH)?.as_millis() as u64;
+
+    Ok((metadata.len(), modified))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_journal_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("rqs_journal_{}", std::process::id()));
+        let source = dir.join("source\twith tab.bin");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&source, vec![0u8; 128]).unwrap();
+
+        let files = vec![source.to_string_lossy().into_owned()];
+        let entry = JournalEntry::new("ABCD", "127.0.0.1:4242", "peer", &files).unwrap();
+        let mut journal = Journal::open(dir.clone(), entry);
+        journal.record_progress(&source, 64);
+        journal.flush();
+
+        let entries = read_entries(&dir).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].files[0].confirmed, 64);
+        assert_eq!(entries[0].files[0].path, source);
+        assert_eq!(
+            Some(entries[0].files[0].payload_id),
+            journal.payload_id(&source)
+        );
+        assert!(!entries[0].files[0].is_complete());
+        assert!(entries[0].is_unchanged());
+
+        // Every byte went through, but not the last chunk
+        journal.record_progress(&source, 64);
+        journal.flush();
+        assert!(!read_entry(&dir, "ABCD").unwrap().files[0].is_complete());
+        journal.record_done(&source);
+        assert!(read_entry(&dir, "ABCD").unwrap().files[0].is_complete());
+
+        // The source changed after the entry got written, it can't be resumed
+        fs::write(&source, vec![0u8; 256]).unwrap();
+        assert!(!entries[0].is_unchanged());
+
+        journal.remove();
+        assert!(read_entries(&dir).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_entry() {
+        let dir = std::env::temp_dir().join(format!("rqs_journal_corrupt_{}", std::process::id()));
+        let source = dir.join("source.bin");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&source, vec![0u8; 128]).unwrap();
+
+        let files = vec![source.to_string_lossy().into_owned()];
+        let entry = JournalEntry::new("ABCD", "127.0.0.1:4242", "peer", &files).unwrap();
+        write_entry(&dir, &entry).unwrap();
+        // What a crash halfway through writing an entry would leave behind
+        let corrupt = entry_path(&dir, "EFGH");
+        fs::write(&corrupt, "EFGH\t127.0.0.1:4242\tpeer\t2\n0\t42\t6").unwrap();
+
+        assert_eq!(read_entries(&dir).unwrap(), vec![entry]);
+        assert!(!corrupt.exists());
+        assert!(corrupt.with_extension(CORRUPT_EXTENSION).exists());
+        // Not picked up again
+        assert_eq!(read_entries(&dir).unwrap().len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_journal_v1() {
+        let entry = JournalEntry::decode(
+            "ABCD\t127.0.0.1:4242\tpeer\n128\t128\t42\t/done.bin\n64\t128\t42\t/half.bin\n",
+        )
+        .unwrap();
+
+        assert_eq!(entry.files.len(), 2);
+        assert!(entry.files[0].is_complete());
+        assert!(!entry.files[1].is_complete());
+        assert_eq!(entry.files[1].confirmed, 64);
+        assert_eq!(entry.files[1].path, PathBuf::from("/half.bin"));
+    }
+}
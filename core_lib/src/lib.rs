@@ -1,33 +1,71 @@
 #[macro_use]
 extern crate log;
 
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
 use anyhow::anyhow;
 use channel::ChannelMessage;
 #[cfg(all(feature = "experimental", target_os = "linux"))]
 use hdl::BleAdvertiser;
 use hdl::MDnsDiscovery;
+use log::LevelFilter;
 use once_cell::sync::Lazy;
-use rand::{distributions, Rng};
 use tokio::net::TcpListener;
 use tokio::sync::{broadcast, mpsc, watch};
 use tokio_util::sync::CancellationToken;
 use tokio_util::task::TaskTracker;
 
-use crate::hdl::{BleListener, MDnsServer};
+use crate::hdl::{
+    build_introduction, BleListener, ChunkMemory, FilenameRewriter, MDnsServer, ReadSlots,
+};
 use crate::manager::TcpServer;
+use crate::utils::{
+    gen_endpoint_id, get_resume_journal_dir, DEFAULT_CHUNK_MEMORY, DEFAULT_CONCURRENT_READS,
+    DEFAULT_CONNECT_RETRIES, DEFAULT_CONSENT_TIMEOUT, DEFAULT_FALLBACK_NAME,
+    DEFAULT_HANDSHAKE_TIMEOUT, DEFAULT_KEEPALIVE_INTERVAL, DEFAULT_MAX_FRAME_LENGTH,
+    DEFAULT_PIN_CONFIRMATION_TIMEOUT, DEFAULT_SINGLE_FRAME_THRESHOLD, DEFAULT_WRITE_STALL_TIMEOUT,
+};
 
 pub mod channel;
 mod errors;
+mod frame;
 mod hdl;
+mod history;
+mod journal;
 mod manager;
 mod utils;
 
-pub use hdl::{EndpointInfo, OutboundPayload, State, Visibility};
-pub use manager::SendInfo;
-pub use utils::DeviceType;
+pub use frame::{try_parse_frame, ParsedFrame};
+pub use hdl::{
+    AddressFamily, AutoAcceptPolicy, EndpointInfo, FilenameRewriter, IntroductionInfo,
+    IntroductionLimits, OfferedFile, OutboundPayload, OutboundRequest, OutboundRequestBuilder,
+    PayloadKind, SchedulingPolicy, State, StateSnapshot, TextKind, UnixOutboundRequest,
+    UpgradableSocket, UpgradePolicy, Visibility, TRANSFER_LOG_TARGET,
+};
+pub use history::{FileResult, RecordedFile, TransferRecord};
+pub use manager::{SendInfo, TransferHandle};
+pub use utils::{
+    connect_unix, gen_transfer_id, is_valid_transfer_id, DeviceType, RemoteDeviceInfo,
+};
+
+/// Internals only exposed for the benchmarks, not a stable API.
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench {
+    pub use crate::hdl::BenchSender;
+}
+
+/// DANGEROUS, see SessionKeys. Drive a session by hand and read its keys
+/// through `request.state.session_keys()`.
+#[cfg(feature = "dangerous-debug-keys")]
+#[doc(hidden)]
+pub mod debug_keys {
+    pub use crate::hdl::{InboundRequest, InnerState, OutboundRequest, SessionKeys};
+}
 
 pub mod sharing_nearby {
     include!(concat!(env!("OUT_DIR"), "/sharing.nearby.rs"));
@@ -46,6 +84,49 @@ pub mod location_nearby_connections {
 }
 
 static CUSTOM_DOWNLOAD: Lazy<RwLock<Option<PathBuf>>> = Lazy::new(|| RwLock::new(None));
+static CUSTOM_TEMP: Lazy<RwLock<Option<PathBuf>>> = Lazy::new(|| RwLock::new(None));
+static RESUME_JOURNAL: Lazy<RwLock<Option<PathBuf>>> = Lazy::new(|| RwLock::new(None));
+static TRANSFER_HISTORY: Lazy<RwLock<Option<PathBuf>>> = Lazy::new(|| RwLock::new(None));
+static INTRODUCTION_LIMITS: Lazy<RwLock<IntroductionLimits>> =
+    Lazy::new(|| RwLock::new(IntroductionLimits::default()));
+static AUTO_ACCEPT_POLICY: Lazy<RwLock<AutoAcceptPolicy>> =
+    Lazy::new(|| RwLock::new(AutoAcceptPolicy::default()));
+static UPGRADE_POLICY: Lazy<RwLock<UpgradePolicy>> =
+    Lazy::new(|| RwLock::new(UpgradePolicy::default()));
+static ADDRESS_FAMILY: Lazy<RwLock<AddressFamily>> =
+    Lazy::new(|| RwLock::new(AddressFamily::default()));
+static CANCEL_ON_PEER_OFFLINE: Lazy<RwLock<bool>> = Lazy::new(|| RwLock::new(false));
+static FALLBACK_NAME: Lazy<RwLock<String>> =
+    Lazy::new(|| RwLock::new(String::from(DEFAULT_FALLBACK_NAME)));
+static DEVICE_NAME: Lazy<RwLock<Option<String>>> = Lazy::new(|| RwLock::new(None));
+static DEVICE_TYPE: Lazy<RwLock<DeviceType>> = Lazy::new(|| RwLock::new(DeviceType::Laptop));
+static SINGLE_FRAME_THRESHOLD: Lazy<RwLock<u64>> =
+    Lazy::new(|| RwLock::new(DEFAULT_SINGLE_FRAME_THRESHOLD));
+static WRITE_STALL_TIMEOUT: Lazy<RwLock<Duration>> =
+    Lazy::new(|| RwLock::new(DEFAULT_WRITE_STALL_TIMEOUT));
+static KEEPALIVE_INTERVAL: Lazy<RwLock<Option<Duration>>> =
+    Lazy::new(|| RwLock::new(Some(DEFAULT_KEEPALIVE_INTERVAL)));
+static SAMPLE_COMPRESSIBILITY: Lazy<RwLock<bool>> = Lazy::new(|| RwLock::new(false));
+static CHUNK_WARMUP: Lazy<RwLock<bool>> = Lazy::new(|| RwLock::new(false));
+static FOLLOW_SYMLINKS: Lazy<RwLock<bool>> = Lazy::new(|| RwLock::new(false));
+static TRANSFER_LOG_LEVELS: Lazy<RwLock<HashMap<String, LevelFilter>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+static REQUIRE_PIN_CONFIRMATION: Lazy<RwLock<bool>> = Lazy::new(|| RwLock::new(false));
+static PIN_CONFIRMATION_TIMEOUT: Lazy<RwLock<Duration>> =
+    Lazy::new(|| RwLock::new(DEFAULT_PIN_CONFIRMATION_TIMEOUT));
+static MAX_FRAME_LENGTH: Lazy<RwLock<usize>> = Lazy::new(|| RwLock::new(DEFAULT_MAX_FRAME_LENGTH));
+static HANDSHAKE_TIMEOUT: Lazy<RwLock<Duration>> =
+    Lazy::new(|| RwLock::new(DEFAULT_HANDSHAKE_TIMEOUT));
+static CONNECT_RETRIES: Lazy<RwLock<u32>> = Lazy::new(|| RwLock::new(DEFAULT_CONNECT_RETRIES));
+static CONSENT_TIMEOUT: Lazy<RwLock<Duration>> = Lazy::new(|| RwLock::new(DEFAULT_CONSENT_TIMEOUT));
+static FRAME_RATE_LIMIT: Lazy<RwLock<Option<u32>>> = Lazy::new(|| RwLock::new(None));
+static FILENAME_REWRITER: Lazy<RwLock<Option<FilenameRewriter>>> = Lazy::new(|| RwLock::new(None));
+static FILE_READ_SLOTS: Lazy<RwLock<ReadSlots>> =
+    Lazy::new(|| RwLock::new(ReadSlots::new(DEFAULT_CONCURRENT_READS)));
+static SCHEDULING_POLICY: Lazy<RwLock<SchedulingPolicy>> =
+    Lazy::new(|| RwLock::new(SchedulingPolicy::default()));
+static CHUNK_MEMORY: Lazy<RwLock<ChunkMemory>> =
+    Lazy::new(|| RwLock::new(ChunkMemory::new(DEFAULT_CHUNK_MEMORY)));
 
 #[derive(Debug)]
 pub struct RQS {
@@ -63,6 +144,8 @@ pub struct RQS {
     ble_sender: broadcast::Sender<()>,
 
     port_number: Option<u32>,
+    // Only known once the service is running
+    endpoint_id: Option<[u8; 4]>,
 
     pub message_sender: broadcast::Sender<ChannelMessage>,
 }
@@ -97,6 +180,7 @@ impl RQS {
             visibility_receiver,
             ble_sender,
             port_number,
+            endpoint_id: None,
             message_sender,
         }
     }
@@ -109,11 +193,7 @@ impl RQS {
         self.tracker = Some(tracker.clone());
         self.ctoken = Some(ctoken.clone());
 
-        let endpoint_id: Vec<u8> = rand::thread_rng()
-            .sample_iter(distributions::Alphanumeric)
-            .take(4)
-            .map(u8::from)
-            .collect();
+        self.endpoint_id = Some(gen_endpoint_id());
         let tcp_listener =
             TcpListener::bind(format!("0.0.0.0:{}", self.port_number.unwrap_or(0))).await?;
         let binded_addr = tcp_listener.local_addr()?;
@@ -183,12 +263,67 @@ impl RQS {
             });
         }
 
-        let discovery = MDnsDiscovery::new(sender)?;
+        let discovery = MDnsDiscovery::new(sender, self.message_sender.clone())?;
         tracker.spawn(async move { discovery.run(ctk.clone()).await });
 
         Ok(())
     }
 
+    /// Send the same files to several peers at once, see
+    /// manager::send_to_many. Returns the state each session ended in.
+    pub async fn send_to_many(
+        &self,
+        peers: Vec<SocketAddr>,
+        ob: OutboundPayload,
+    ) -> Result<Vec<(SocketAddr, Result<State, anyhow::Error>)>, anyhow::Error> {
+        let (endpoint_id, ctoken) = match (self.endpoint_id, &self.ctoken) {
+            (Some(eid), Some(ctk)) => (eid, ctk.clone()),
+            _ => return Err(anyhow!("The service wasn't first started")),
+        };
+
+        Ok(
+            manager::send_to_many(endpoint_id, self.message_sender.clone(), ctoken, peers, ob)
+                .await,
+        )
+    }
+
+    /// Start sending to a single peer, progress going through the usual
+    /// messages. The handle resolves with the record of the transfer.
+    pub fn send(&self, si: SendInfo) -> Result<TransferHandle, anyhow::Error> {
+        let (endpoint_id, tracker, ctoken) = match (self.endpoint_id, &self.tracker, &self.ctoken) {
+            (Some(eid), Some(tracker), Some(ctk)) => (eid, tracker, ctk.clone()),
+            _ => return Err(anyhow!("The service wasn't first started")),
+        };
+
+        Ok(manager::spawn_transfer(
+            tracker,
+            endpoint_id,
+            self.message_sender.clone(),
+            ctoken,
+            si,
+        ))
+    }
+
+    /// The files sending ob as the transfer id would offer, with their sizes,
+    /// MIME types and payload ids, without connecting to anything. The ids
+    /// are those of the actual transfer only when it's a resumed one, they're
+    /// otherwise drawn anew for every transfer.
+    pub fn preview_introduction(
+        &self,
+        id: &str,
+        ob: &OutboundPayload,
+    ) -> Result<IntroductionInfo, anyhow::Error> {
+        let entry = get_resume_journal_dir().and_then(|dir| journal::read_entry(&dir, id));
+        build_introduction(ob, |path| {
+            entry
+                .as_ref()?
+                .files
+                .iter()
+                .find(|f| f.path == path)
+                .map(|f| f.payload_id)
+        })
+    }
+
     pub fn stop_discovery(&mut self) {
         if let Some(discovert_ctk) = &self.discovery_ctk {
             discovert_ctk.cancel();
@@ -224,4 +359,302 @@ impl RQS {
         let mut guard = CUSTOM_DOWNLOAD.write().unwrap();
         *guard = p;
     }
+
+    // Where in-progress (.part) files are written before being moved into
+    // the download directory. Setting None writes them next to the download.
+    pub fn set_temp_dir(&self, p: Option<PathBuf>) -> Result<(), anyhow::Error> {
+        debug!("Setting the temp dir to {:?}", p);
+        if let Some(dir) = &p {
+            std::fs::create_dir_all(dir)?;
+            // Ensure we'll be able to write there before accepting anything
+            let probe = dir.join(".rqs_write_probe");
+            std::fs::File::create(&probe)
+                .map_err(|e| anyhow!("Temp dir {:?} isn't writable: {}", dir, e))?;
+            std::fs::remove_file(&probe)?;
+        }
+
+        let mut guard = CUSTOM_TEMP.write().unwrap();
+        *guard = p;
+        Ok(())
+    }
+
+    // Directory holding the state of outbound transfers, so that they can be
+    // picked up again by resume_pending() after a restart. None disables it.
+    pub fn set_resume_journal_dir(&self, p: Option<PathBuf>) -> Result<(), anyhow::Error> {
+        debug!("Setting the resume journal dir to {:?}", p);
+        if let Some(dir) = &p {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let mut guard = RESUME_JOURNAL.write().unwrap();
+        *guard = p;
+        Ok(())
+    }
+
+    // File every transfer gets recorded in once it ended, one JSON object per
+    // line (see TransferRecord). None disables it.
+    pub fn set_transfer_history(&self, p: Option<PathBuf>) -> Result<(), anyhow::Error> {
+        debug!("Setting the transfer history to {:?}", p);
+        if let Some(dir) = p.as_ref().and_then(|p| p.parent()) {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let mut guard = TRANSFER_HISTORY.write().unwrap();
+        *guard = p;
+        Ok(())
+    }
+
+    /// Restart the outbound transfers that were interrupted by a crash or a
+    /// restart. Files already sent are skipped, a partially sent one picks
+    /// up from what the receiver already has (rqs receivers only, others get
+    /// it again from the start). Entries whose sources changed are dropped,
+    /// corrupt ones are set aside without holding up the others.
+    /// Returns the number of transfers that were queued.
+    pub async fn resume_pending(
+        &self,
+        sender: &mpsc::Sender<SendInfo>,
+    ) -> Result<usize, anyhow::Error> {
+        let dir = match get_resume_journal_dir() {
+            Some(d) => d,
+            None => return Ok(0),
+        };
+
+        let mut resumed = 0;
+        for entry in journal::read_entries(&dir)? {
+            if !entry.is_unchanged() {
+                warn!(
+                    "Sources of {} changed since it was interrupted, not resuming",
+                    entry.id
+                );
+                journal::remove_entry(&dir, &entry.id);
+                continue;
+            }
+
+            let si = entry.to_send_info();
+            // Journals only ever hold files
+            if matches!(&si.ob, OutboundPayload::Files(files) if files.is_empty()) {
+                journal::remove_entry(&dir, &entry.id);
+                continue;
+            }
+
+            info!("Resuming the transfer {} to {}", si.id, si.addr);
+            sender.send(si).await?;
+            resumed += 1;
+        }
+
+        Ok(resumed)
+    }
+
+    pub fn set_introduction_limits(&self, limits: IntroductionLimits) {
+        debug!("Setting the introduction limits to {:?}", limits);
+        let mut guard = INTRODUCTION_LIMITS.write().unwrap();
+        *guard = limits;
+    }
+
+    pub fn set_auto_accept_policy(&self, policy: AutoAcceptPolicy) {
+        debug!("Setting the auto-accept policy to {:?}", policy);
+        let mut guard = AUTO_ACCEPT_POLICY.write().unwrap();
+        *guard = policy;
+    }
+
+    pub fn set_upgrade_policy(&self, policy: UpgradePolicy) {
+        debug!("Setting the bandwidth upgrade policy to {:?}", policy);
+        let mut guard = UPGRADE_POLICY.write().unwrap();
+        *guard = policy;
+    }
+
+    // Cancel the outbound transfers to a peer as soon as it vanishes from
+    // the discovery, instead of waiting for the socket to time out.
+    pub fn set_cancel_on_peer_offline(&self, enabled: bool) {
+        debug!("Setting cancel on peer offline to {}", enabled);
+        let mut guard = CANCEL_ON_PEER_OFFLINE.write().unwrap();
+        *guard = enabled;
+    }
+
+    // How many files may be read from at the same time, across every
+    // outbound transfer. Only applies to the files opened afterwards.
+    pub fn set_max_concurrent_reads(&self, limit: usize) {
+        debug!("Setting the max concurrent file reads to {}", limit);
+        let mut guard = FILE_READ_SLOTS.write().unwrap();
+        *guard = ReadSlots::new(limit);
+    }
+
+    // How those reads are shared between the files of every outbound
+    // transfer, see SchedulingPolicy. Only applies to the transfers started
+    // afterwards.
+    pub fn set_scheduling_policy(&self, policy: SchedulingPolicy) {
+        debug!("Setting the scheduling policy to {:?}", policy);
+        let mut guard = SCHEDULING_POLICY.write().unwrap();
+        *guard = policy;
+    }
+
+    // How many bytes the chunks being read and sent may take up together,
+    // across every outbound transfer. Reading the next chunk waits for room.
+    // Only applies to the transfers started afterwards.
+    pub fn set_chunk_memory_limit(&self, bytes: usize) {
+        debug!("Setting the chunk memory limit to {}", bytes);
+        let mut guard = CHUNK_MEMORY.write().unwrap();
+        *guard = ChunkMemory::new(bytes);
+    }
+
+    // Name announced to peers when the hostname can't be queried
+    pub fn set_fallback_name(&self, name: String) {
+        debug!("Setting the fallback name to {}", name);
+        let mut guard = FALLBACK_NAME.write().unwrap();
+        *guard = name;
+    }
+
+    // Name announced to peers instead of the hostname, None to go back to it.
+    // Applies to the mDNS service from the next start on.
+    pub fn set_device_name(&self, name: Option<String>) {
+        debug!("Setting the device name to {:?}", name);
+        let mut guard = DEVICE_NAME.write().unwrap();
+        *guard = name;
+    }
+
+    // Which icon peers show for us, Laptop by default
+    pub fn set_device_type(&self, device_type: DeviceType) {
+        debug!("Setting the device type to {:?}", device_type);
+        let mut guard = DEVICE_TYPE.write().unwrap();
+        *guard = device_type;
+    }
+
+    // Files up to this size are sent whole in a single frame, which saves a
+    // round of tiny writes. Capped to the chunk size, above that it's chunked.
+    pub fn set_single_frame_threshold(&self, bytes: u64) {
+        debug!("Setting the single frame threshold to {}", bytes);
+        let mut guard = SINGLE_FRAME_THRESHOLD.write().unwrap();
+        *guard = bytes;
+    }
+
+    // Outbound transfers are aborted once the receiver didn't read anything
+    // for that long, rather than hanging on a peer that's gone.
+    pub fn set_write_stall_timeout(&self, timeout: Duration) {
+        debug!("Setting the write stall timeout to {:?}", timeout);
+        let mut guard = WRITE_STALL_TIMEOUT.write().unwrap();
+        *guard = timeout;
+    }
+
+    // Once the handshake is done, a keepalive goes out whenever nothing else
+    // was sent for that long, until the transfer ended. None disables them.
+    pub fn set_keepalive_interval(&self, interval: Option<Duration>) {
+        debug!("Setting the keepalive interval to {:?}", interval);
+        let mut guard = KEEPALIVE_INTERVAL.write().unwrap();
+        *guard = interval;
+    }
+
+    pub fn set_address_family(&self, family: AddressFamily) {
+        debug!("Setting the preferred address family to {:?}", family);
+        let mut guard = ADDRESS_FAMILY.write().unwrap();
+        *guard = family;
+    }
+
+    // Estimate how well each outbound file would compress from its first
+    // bytes, logged and reported in the StateSnapshot
+    pub fn set_sample_compressibility(&self, enabled: bool) {
+        debug!("Setting compressibility sampling to {}", enabled);
+        let mut guard = SAMPLE_COMPRESSIBILITY.write().unwrap();
+        *guard = enabled;
+    }
+
+    // Time the first seconds of each outbound transfer and size the chunks
+    // of the rest after the measured throughput, reported in the StateSnapshot
+    pub fn set_chunk_warmup(&self, enabled: bool) {
+        debug!("Setting the chunk size warm-up to {}", enabled);
+        let mut guard = CHUNK_WARMUP.write().unwrap();
+        *guard = enabled;
+    }
+
+    // Whether sending a Directory goes through the symlinks found in it,
+    // rather than leaving them out
+    pub fn set_follow_symlinks(&self, follow: bool) {
+        debug!("Setting symlink following to {}", follow);
+        let mut guard = FOLLOW_SYMLINKS.write().unwrap();
+        *guard = follow;
+    }
+
+    // Log the given outbound transfer up to that level (None to go back to
+    // the global filter), its lines being logged under TRANSFER_LOG_TARGET
+    pub fn set_transfer_log_level(&self, id: String, level: Option<LevelFilter>) {
+        debug!("Setting the log level of {} to {:?}", id, level);
+        let mut guard = TRANSFER_LOG_LEVELS.write().unwrap();
+        match level {
+            Some(level) => guard.insert(id, level),
+            None => guard.remove(&id),
+        };
+    }
+
+    // Have every outbound transfer wait in State::AwaitingPinConfirmation
+    // until ChannelAction::ConfirmPin, or ChannelAction::RejectPin to cancel
+    // it. None is skipped: without certificates to check a peer against,
+    // no peer is known to be trusted.
+    pub fn set_require_pin_confirmation(&self, enabled: bool) {
+        debug!("Setting the PIN confirmation requirement to {}", enabled);
+        let mut guard = REQUIRE_PIN_CONFIRMATION.write().unwrap();
+        *guard = enabled;
+    }
+
+    // Transfers left in State::AwaitingPinConfirmation for longer are
+    // cancelled with CancelReason::PinConfirmationTimeout.
+    pub fn set_pin_confirmation_timeout(&self, timeout: Duration) {
+        debug!("Setting the PIN confirmation timeout to {:?}", timeout);
+        let mut guard = PIN_CONFIRMATION_TIMEOUT.write().unwrap();
+        *guard = timeout;
+    }
+
+    // Frames, and texts held in memory, larger than that are rejected, 5 MB
+    // by default. Introductions of hundreds of files can go over it, but
+    // every byte of it may be allocated at a peer's request: raising it
+    // trades headroom against memory exhaustion for those large batches.
+    pub fn set_max_frame_length(&self, bytes: usize) -> Result<(), anyhow::Error> {
+        debug!("Setting the max frame length to {}", bytes);
+        if bytes == 0 {
+            return Err(anyhow!("The max frame length must be positive"));
+        }
+
+        let mut guard = MAX_FRAME_LENGTH.write().unwrap();
+        *guard = bytes;
+        Ok(())
+    }
+
+    // Outbound transfers whose peer didn't get through the UKEY2 handshake
+    // within that long, from the connection request on, are dropped.
+    pub fn set_handshake_timeout(&self, timeout: Duration) {
+        debug!("Setting the handshake timeout to {:?}", timeout);
+        let mut guard = HANDSHAKE_TIMEOUT.write().unwrap();
+        *guard = timeout;
+    }
+
+    // How many more times an outbound transfer tries to connect to a peer
+    // refusing the connection, waiting twice as long before each retry.
+    pub fn set_connect_retries(&self, retries: u32) {
+        debug!("Setting the connect retries to {}", retries);
+        let mut guard = CONNECT_RETRIES.write().unwrap();
+        *guard = retries;
+    }
+
+    // Inbound transfers neither accepted nor rejected for longer are declined,
+    // ending in State::Rejected with CancelReason::ConsentTimeout.
+    pub fn set_consent_timeout(&self, timeout: Duration) {
+        debug!("Setting the consent timeout to {:?}", timeout);
+        let mut guard = CONSENT_TIMEOUT.write().unwrap();
+        *guard = timeout;
+    }
+
+    // Drop inbound connections whose peer sends more secure messages per
+    // second than that, each costing an HMAC check and a decryption. Off by
+    // default, a transfer of small chunks can legitimately go over a few
+    // hundred a second.
+    pub fn set_frame_rate_limit(&self, frames_per_second: Option<u32>) {
+        debug!("Setting the frame rate limit to {:?}", frames_per_second);
+        let mut guard = FRAME_RATE_LIMIT.write().unwrap();
+        *guard = frames_per_second;
+    }
+
+    // Decide where incoming files are saved, None to keep their own name
+    pub fn set_filename_rewriter(&self, rewriter: Option<FilenameRewriter>) {
+        debug!("Setting the filename rewriter to {:?}", rewriter);
+        let mut guard = FILENAME_REWRITER.write().unwrap();
+        *guard = rewriter;
+    }
 }
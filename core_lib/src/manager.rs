@@ -1,24 +1,74 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use futures::Stream;
 use serde::{Deserialize, Serialize};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::broadcast::Sender;
+use tokio::net::{lookup_host, TcpListener};
+use tokio::sync::broadcast::{self, Sender};
 use tokio::sync::mpsc::Receiver;
+use tokio::sync::oneshot;
 use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
 use ts_rs::TS;
 
-use crate::channel::{ChannelDirection, ChannelMessage};
-use crate::errors::AppError;
-use crate::hdl::{InboundRequest, OutboundPayload, OutboundRequest, State};
-use crate::utils::RemoteDeviceInfo;
+use crate::channel::{
+    transfer_events, ChannelDirection, ChannelMessage, TransferEvent, TransferType,
+};
+use crate::errors::{AppError, OutboundError};
+use crate::hdl::{
+    split_directory, InboundRequest, IntroductionLimits, OutboundPayload, OutboundRequestBuilder,
+    State,
+};
+use crate::history::{self, TransferRecord};
+use crate::journal::{self, Journal, JournalEntry};
+use crate::utils::{get_address_family, get_resume_journal_dir, sort_by_family, RemoteDeviceInfo};
 
 const INNER_NAME: &str = "TcpServer";
 
-#[derive(Debug, Deserialize, Serialize, TS)]
+#[derive(Debug, Clone, Deserialize, Serialize, TS)]
 #[ts(export)]
 pub struct SendInfo {
     pub id: String,
     pub name: String,
     pub addr: String,
     pub ob: OutboundPayload,
+    // Optional free-text note shown to the receiver
+    pub note: Option<String>,
+    // Payload bytes per chunk, the default when unset
+    pub chunk_size: Option<usize>,
+    // Wait for ChannelAction::Proceed once accepted, see State::AwaitingProceed
+    pub introduction_only: bool,
+}
+
+/// Resolves once the transfer it was returned for ended, see RQS::send.
+#[derive(Debug)]
+pub struct TransferHandle {
+    id: String,
+    result: oneshot::Receiver<Result<TransferRecord, anyhow::Error>>,
+    // Subscribed before the transfer started, so that no event is missed
+    receiver: broadcast::Receiver<ChannelMessage>,
+}
+
+impl TransferHandle {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    // What the history would record of the transfer, whichever way it ended.
+    // An error for one that never got going, eg: the peer was unreachable.
+    pub async fn finished(self) -> Result<TransferRecord, anyhow::Error> {
+        self.result
+            .await
+            .map_err(|_| anyhow!("Transfer {} stopped before it ended", self.id))?
+    }
+
+    // The transfer's messages, already filtered and typed. The first stream
+    // gets every event from the start, any later one only those to come.
+    pub fn events(&mut self) -> impl Stream<Item = TransferEvent> {
+        let receiver = std::mem::replace(&mut self.receiver, self.receiver.resubscribe());
+        transfer_events(receiver, self.id.clone())
+    }
 }
 
 pub struct TcpServer {
@@ -66,16 +116,21 @@ impl TcpServer {
                             trace!("{INNER_NAME}: new client: {remote_addr}");
                             let esender = self.sender.clone();
                             let csender = self.sender.clone();
+                            let endpoint_id = self.endpoint_id;
 
                             tokio::spawn(async move {
-                                let mut ir = InboundRequest::new(socket, remote_addr.to_string(), csender);
+                                let mut ir = InboundRequest::new(endpoint_id, socket, remote_addr.to_string(), csender);
 
                                 loop {
                                     match ir.handle().await {
                                         Ok(_) => {},
                                         Err(e) => match e.downcast_ref() {
                                             Some(AppError::NotAnError) => break,
-                                            None => {
+                                            Some(AppError::SelfConnection) => {
+                                                warn!("{INNER_NAME}: dropping a connection to ourselves ({remote_addr})");
+                                                break;
+                                            }
+                                            _ => {
                                                 if ir.state.state == State::Initial {
                                                     break;
                                                 }
@@ -94,6 +149,8 @@ impl TcpServer {
                                         },
                                     }
                                 }
+
+                                history::record_transfer(&ir.state, TransferType::Inbound);
                             });
                         },
                         Err(err) => {
@@ -110,58 +167,856 @@ impl TcpServer {
 
     /// To be called inside a separate task if we want to handle concurrency
     pub async fn connect(&self, ctk: CancellationToken, si: SendInfo) -> Result<(), anyhow::Error> {
-        debug!("{INNER_NAME}: Connecting to: {}", si.addr);
-        let socket = TcpStream::connect(si.addr.clone()).await?;
-
-        let mut or = OutboundRequest::new(
+        connect(
             self.endpoint_id,
-            socket,
-            si.id,
             self.sender.clone(),
-            si.ob,
-            RemoteDeviceInfo {
-                device_type: crate::DeviceType::Unknown,
-                name: si.name,
-            },
-        );
+            ctk,
+            si,
+            get_resume_journal_dir(),
+        )
+        .await?;
+        Ok(())
+    }
+}
 
-        // Send connection request
-        or.send_connection_request().await?;
-        // Send UKEY init
-        or.send_ukey2_client_init().await?;
+// Run a transfer in its own task, progress still going through the channel
+pub(crate) fn spawn_transfer(
+    tracker: &TaskTracker,
+    endpoint_id: [u8; 4],
+    sender: Sender<ChannelMessage>,
+    ctk: CancellationToken,
+    si: SendInfo,
+) -> TransferHandle {
+    let (tx, rx) = oneshot::channel();
+    let id = si.id.clone();
+    let receiver = sender.subscribe();
+    tracker.spawn(async move {
+        let _ = tx.send(connect(endpoint_id, sender, ctk, si, get_resume_journal_dir()).await);
+    });
 
-        loop {
-            tokio::select! {
-                _ = ctk.cancelled() => {
-                    info!("{INNER_NAME}: tracker cancelled, breaking");
-                    break;
-                },
-                r = or.handle() => {
-                    if let Err(e) = r {
-                        match e.downcast_ref() {
-                            Some(AppError::NotAnError) => break,
-                            None => {
-                                if or.state.state == State::Initial {
-                                    break;
-                                }
+    TransferHandle {
+        id,
+        result: rx,
+        receiver,
+    }
+}
 
-                                if or.state.state != State::Finished && or.state.state != State::Cancelled {
-                                    let _ = self.sender.clone().send(ChannelMessage {
-                                        id: si.addr,
-                                        direction: ChannelDirection::LibToFront,
-                                        state: Some(State::Disconnected),
-                                        ..Default::default()
-                                    });
-                                }
-                                error!("{INNER_NAME}: error while handling client: {e} ({:?})", or.state.state);
+// Returns how the session ended. Journaled in journal_dir when set, callers
+// other than the tests pass get_resume_journal_dir(). A directory too large
+// for a single introduction goes over several sessions, see connect_parts.
+async fn connect(
+    endpoint_id: [u8; 4],
+    sender: Sender<ChannelMessage>,
+    ctk: CancellationToken,
+    si: SendInfo,
+    journal_dir: Option<PathBuf>,
+) -> Result<TransferRecord, anyhow::Error> {
+    if let OutboundPayload::Directory(dir) = &si.ob {
+        let max_entries = IntroductionLimits::default().max_entries;
+        let parts = split_directory(Path::new(dir), max_entries)?;
+        if parts.len() > 1 {
+            return connect_parts(endpoint_id, sender, ctk, si, parts).await;
+        }
+    }
+
+    connect_session(endpoint_id, sender, ctk, si, journal_dir).await
+}
+
+// One session per part, in a row and under the same id, until one doesn't
+// finish. Returns how the last one ended.
+async fn connect_parts(
+    endpoint_id: [u8; 4],
+    sender: Sender<ChannelMessage>,
+    ctk: CancellationToken,
+    si: SendInfo,
+    parts: Vec<OutboundPayload>,
+) -> Result<TransferRecord, anyhow::Error> {
+    let count = parts.len();
+    let mut record = None;
+    for (i, ob) in parts.into_iter().enumerate() {
+        info!("{INNER_NAME}: sending part {}/{count} of {}", i + 1, si.id);
+        let part = SendInfo { ob, ..si.clone() };
+        let ended = connect_session(endpoint_id, sender.clone(), ctk.clone(), part, None).await?;
+        let finished = ended.state == State::Finished;
+        record = Some(ended);
+        if !finished {
+            break;
+        }
+    }
+
+    record.ok_or_else(|| anyhow!("Nothing to send in {}", si.id))
+}
+
+async fn connect_session(
+    endpoint_id: [u8; 4],
+    sender: Sender<ChannelMessage>,
+    ctk: CancellationToken,
+    si: SendInfo,
+    journal_dir: Option<PathBuf>,
+) -> Result<TransferRecord, anyhow::Error> {
+    debug!("{INNER_NAME}: Connecting to: {}", si.addr);
+    let mut addrs: Vec<SocketAddr> = lookup_host(&si.addr).await?.collect();
+    sort_by_family(&mut addrs, get_address_family());
+
+    let journal = journal_dir.and_then(|dir| {
+        // Nothing to resume about a text, nor about a directory whose
+        // content may well have changed in between
+        let OutboundPayload::Files(files) = &si.ob else {
+            return None;
+        };
+        // A resumed transfer keeps its entry, and with it the payload ids
+        let entry = match journal::read_entry(&dir, &si.id) {
+            Some(mut entry) if entry.covers(files) => {
+                entry.addr.clone_from(&si.addr);
+                Ok(entry)
+            }
+            _ => JournalEntry::new(&si.id, &si.addr, &si.name, files),
+        };
+        match entry {
+            Ok(entry) => Some(Journal::open(dir, entry)),
+            Err(e) => {
+                warn!("{INNER_NAME}: transfer won't be resumable: {}", e);
+                None
+            }
+        }
+    });
+
+    let mut builder = OutboundRequestBuilder::new(endpoint_id, si.id, sender.clone(), si.ob)
+        .remote_device(RemoteDeviceInfo {
+            device_type: crate::DeviceType::Unknown,
+            name: si.name,
+        })
+        .note(si.note)
+        .introduction_only(si.introduction_only)
+        .journal(journal);
+    if let Some(chunk_size) = si.chunk_size {
+        builder = builder.chunk_size(chunk_size);
+    }
+    let mut or = builder.connect(&addrs).await?;
+
+    // Send connection request
+    or.send_connection_request().await?;
+    // Send UKEY init
+    or.send_ukey2_client_init().await?;
+
+    loop {
+        tokio::select! {
+            _ = ctk.cancelled() => {
+                info!("{INNER_NAME}: tracker cancelled, breaking");
+                break;
+            },
+            r = or.handle() => {
+                if let Err(e) = r {
+                    // Whether it went well or not, rather than dropping the socket
+                    or.shutdown().await;
+                    match e.downcast_ref() {
+                        Some(AppError::NotAnError) => break,
+                        _ => {
+                            if or.state.state == State::Initial {
                                 break;
                             }
+
+                            if or.state.state != State::Finished && or.state.state != State::Cancelled {
+                                let error = match e.downcast_ref() {
+                                    Some(AppError::PeerAlert { alert, .. }) => {
+                                        OutboundError::HandshakeRejected(*alert)
+                                    }
+                                    Some(AppError::FileUnreadable(_)) => OutboundError::FileUnreadable,
+                                    _ => OutboundError::interrupted_in(&or.state.state),
+                                };
+                                let meta = or.state.transfer_metadata.clone().map(|mut m| {
+                                    m.error = Some(error.into());
+                                    m
+                                });
+                                let _ = sender.send(ChannelMessage {
+                                    id: or.state.id.clone(),
+                                    direction: ChannelDirection::LibToFront,
+                                    state: Some(State::Disconnected),
+                                    meta,
+                                    ..Default::default()
+                                });
+                            }
+                            error!("{INNER_NAME}: error while handling client: {e} ({:?})", or.state.state);
+                            break;
                         }
                     }
                 }
             }
         }
+    }
 
-        Ok(())
+    history::record_transfer(&or.state, TransferType::Outbound);
+    Ok(TransferRecord::of(&or.state, TransferType::Outbound))
+}
+
+/// Send the same payload to several peers at once, each one over its own
+/// session (the sources are read once per peer). One of them failing or
+/// rejecting doesn't affect the others. Progress goes through the usual
+/// channel, with the peer address as id.
+pub async fn send_to_many(
+    endpoint_id: [u8; 4],
+    sender: Sender<ChannelMessage>,
+    ctk: CancellationToken,
+    peers: Vec<SocketAddr>,
+    ob: OutboundPayload,
+) -> Vec<(SocketAddr, Result<State, anyhow::Error>)> {
+    let sessions = peers.into_iter().map(|addr| {
+        let si = SendInfo {
+            id: addr.to_string(),
+            name: addr.to_string(),
+            addr: addr.to_string(),
+            ob: ob.clone(),
+            note: None,
+            chunk_size: None,
+            introduction_only: false,
+        };
+        let (sender, ctk) = (sender.clone(), ctk.clone());
+
+        async move {
+            let result = connect(endpoint_id, sender, ctk, si, get_resume_journal_dir()).await;
+            (addr, result.map(|record| record.state))
+        }
+    });
+
+    futures::future::join_all(sessions).await
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use tokio::net::{TcpSocket, UnixListener};
+    use tokio::sync::broadcast;
+    use tokio::task::JoinHandle;
+
+    use super::*;
+    use crate::channel::ChannelAction;
+    use crate::hdl::{TextKind, TextPayloadType, UnixOutboundRequest};
+    use crate::utils::connect_unix;
+
+    // Shared by the end-to-end tests, they run concurrently so each one must
+    // use its own file names. Handed to each receiver, not set globally.
+    fn download_dir() -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("rqs_download_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_send_to_many() {
+        let source = std::env::temp_dir().join(format!("rqs_fanout_{}.txt", std::process::id()));
+        std::fs::write(&source, b"hello").unwrap();
+
+        // The first peer is there, and turns the transfer down
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let reachable = listener.local_addr().unwrap();
+        let (peer_sender, mut peer_receiver) = broadcast::channel(50);
+        let inbound_sender = peer_sender.clone();
+        tokio::spawn(async move {
+            let (socket, addr) = listener.accept().await.unwrap();
+            let mut ir = InboundRequest::new(*b"WXYZ", socket, addr.to_string(), inbound_sender);
+            while ir.handle().await.is_ok() {}
+        });
+        tokio::spawn(async move {
+            while let Ok(msg) = peer_receiver.recv().await {
+                if msg.state == Some(State::WaitingForUserConsent) {
+                    let _ = peer_sender.send(ChannelMessage {
+                        id: msg.id,
+                        direction: ChannelDirection::FrontToLib,
+                        action: Some(ChannelAction::RejectTransfer),
+                        ..Default::default()
+                    });
+                    break;
+                }
+            }
+        });
+
+        // The second one is gone already
+        let gone = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let (sender, _) = broadcast::channel(50);
+        let results = send_to_many(
+            *b"ABCD",
+            sender,
+            CancellationToken::new(),
+            vec![reachable, gone],
+            OutboundPayload::Files(vec![source.to_string_lossy().into_owned()]),
+        )
+        .await;
+        std::fs::remove_file(&source).unwrap();
+
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].0, reachable);
+        assert_eq!(results[0].1.as_ref().unwrap(), &State::Disconnected);
+        assert_eq!(results[1].0, gone);
+        assert!(results[1].1.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_partial_acceptance() {
+        let dir = std::env::temp_dir().join(format!("rqs_partial_{}", std::process::id()));
+        let (source, download) = (dir.join("source"), download_dir());
+        std::fs::create_dir_all(&source).unwrap();
+        std::fs::write(source.join("a.txt"), b"wanted").unwrap();
+        std::fs::write(source.join("b.txt"), b"not wanted").unwrap();
+
+        // The receiver only takes the first of the two files
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (peer_sender, mut peer_receiver) = broadcast::channel(100);
+        let inbound_sender = peer_sender.clone();
+        let inbound = tokio::spawn(async move {
+            let (socket, addr) = listener.accept().await.unwrap();
+            let mut ir = InboundRequest::new(*b"WXYZ", socket, addr.to_string(), inbound_sender);
+            ir.set_download_dir(download_dir());
+            while ir.handle().await.is_ok() {}
+            ir
+        });
+        tokio::spawn(async move {
+            while let Ok(msg) = peer_receiver.recv().await {
+                if msg.state == Some(State::WaitingForUserConsent) {
+                    let files = msg.meta.and_then(|m| m.files).unwrap();
+                    let wanted = files.iter().position(|f| f == "a.txt").unwrap();
+                    let _ = peer_sender.send(ChannelMessage {
+                        id: msg.id,
+                        direction: ChannelDirection::FrontToLib,
+                        action: Some(ChannelAction::AcceptTransfer),
+                        accepted_files: Some(vec![wanted]),
+                        ..Default::default()
+                    });
+                    break;
+                }
+            }
+        });
+
+        let (sender, mut receiver) = broadcast::channel(100);
+        let files = ["a.txt", "b.txt"]
+            .iter()
+            .map(|f| source.join(f).to_string_lossy().into_owned())
+            .collect();
+        let si = SendInfo {
+            id: String::from("partial"),
+            name: String::from("peer"),
+            addr: addr.to_string(),
+            ob: OutboundPayload::Files(files),
+            note: None,
+            chunk_size: None,
+            introduction_only: false,
+        };
+        connect(*b"ABCD", sender, CancellationToken::new(), si, None)
+            .await
+            .unwrap();
+        let ir = inbound.await.unwrap();
+
+        let mut skipped = None;
+        while let Ok(msg) = receiver.try_recv() {
+            if let Some(files) = msg.meta.and_then(|m| m.skipped_files) {
+                skipped = Some(files);
+            }
+        }
+        assert_eq!(skipped, Some(vec![String::from("b.txt")]));
+        assert_eq!(ir.state.state, State::Finished);
+        assert_eq!(std::fs::read(download.join("a.txt")).unwrap(), b"wanted");
+        assert!(!download.join("b.txt").exists());
+
+        std::fs::remove_file(download.join("a.txt")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_directory_in_parts() {
+        let dir = std::env::temp_dir().join(format!("rqs_parts_{}", std::process::id()));
+        let (album, download) = (dir.join("source/Album"), dir.join("download"));
+        std::fs::create_dir_all(album.join("empty")).unwrap();
+        std::fs::create_dir_all(&download).unwrap();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            std::fs::write(album.join(name), name).unwrap();
+        }
+
+        // One session per part, each one accepted
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (peer_sender, mut peer_receiver) = broadcast::channel(100);
+        let inbound_sender = peer_sender.clone();
+        let inbound_download = download.clone();
+        let inbound = tokio::spawn(async move {
+            let mut states = vec![];
+            for _ in 0..2 {
+                let (socket, addr) = listener.accept().await.unwrap();
+                let mut ir =
+                    InboundRequest::new(*b"WXYZ", socket, addr.to_string(), inbound_sender.clone());
+                ir.set_download_dir(inbound_download.clone());
+                while ir.handle().await.is_ok() {}
+                states.push(ir.state.state);
+            }
+            states
+        });
+        tokio::spawn(async move {
+            while let Ok(msg) = peer_receiver.recv().await {
+                if msg.state == Some(State::WaitingForUserConsent) {
+                    let _ = peer_sender.send(ChannelMessage {
+                        id: msg.id,
+                        direction: ChannelDirection::FrontToLib,
+                        action: Some(ChannelAction::AcceptTransfer),
+                        ..Default::default()
+                    });
+                }
+            }
+        });
+
+        let parts = split_directory(&album, 2).unwrap();
+        assert_eq!(parts.len(), 2);
+        let (sender, _) = broadcast::channel(100);
+        let si = SendInfo {
+            id: String::from("parts"),
+            name: String::from("peer"),
+            addr: addr.to_string(),
+            ob: OutboundPayload::Directory(album.to_string_lossy().into_owned()),
+            note: None,
+            chunk_size: None,
+            introduction_only: false,
+        };
+        let record = connect_parts(*b"ABCD", sender, CancellationToken::new(), si, parts)
+            .await
+            .unwrap();
+
+        assert_eq!(record.state, State::Finished);
+        assert_eq!(inbound.await.unwrap(), [State::Finished, State::Finished]);
+        // The tree is whole again, the empty folder included
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            assert_eq!(
+                std::fs::read(download.join("Album").join(name)).unwrap(),
+                name.as_bytes()
+            );
+        }
+        assert!(download.join("Album/empty").is_dir());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_completion_confirmed_by_receiver() {
+        let source = std::env::temp_dir().join(format!("rqs_confirm_{}", std::process::id()));
+        let download = download_dir();
+        std::fs::create_dir_all(&source).unwrap();
+        let names = ["confirm_1.bin", "confirm_2.bin"];
+        for name in names {
+            std::fs::write(source.join(name), vec![0x42u8; 1024 * 1024]).unwrap();
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (peer_sender, mut peer_receiver) = broadcast::channel(100);
+        let inbound_sender = peer_sender.clone();
+        tokio::spawn(async move {
+            let (socket, addr) = listener.accept().await.unwrap();
+            let mut ir = InboundRequest::new(*b"WXYZ", socket, addr.to_string(), inbound_sender);
+            ir.set_download_dir(download_dir());
+            while ir.handle().await.is_ok() {}
+        });
+        tokio::spawn(async move {
+            while let Ok(msg) = peer_receiver.recv().await {
+                if msg.state == Some(State::WaitingForUserConsent) {
+                    let _ = peer_sender.send(ChannelMessage {
+                        id: msg.id,
+                        direction: ChannelDirection::FrontToLib,
+                        action: Some(ChannelAction::AcceptTransfer),
+                        ..Default::default()
+                    });
+                    break;
+                }
+            }
+        });
+
+        // A file may only be reported as confirmed once it's in place on the
+        // receiver's side, which is the point of the confirmation.
+        let (sender, mut receiver) = broadcast::channel(1000);
+        let watched = download.clone();
+        let watcher = tokio::spawn(async move {
+            let mut confirmed = vec![];
+            while let Ok(msg) = receiver.recv().await {
+                let Some(meta) = msg.meta else {
+                    continue;
+                };
+
+                for name in meta.confirmed_files.unwrap_or_default() {
+                    if !confirmed.contains(&name) {
+                        assert!(watched.join(&name).exists(), "{name} confirmed too early");
+                        confirmed.push(name);
+                    }
+                }
+
+                if msg.state == Some(State::Finished) {
+                    break;
+                }
+            }
+            confirmed
+        });
+
+        let si = SendInfo {
+            id: String::from("confirm"),
+            name: String::from("peer"),
+            addr: addr.to_string(),
+            ob: OutboundPayload::Files(
+                names
+                    .iter()
+                    .map(|f| source.join(f).to_string_lossy().into_owned())
+                    .collect(),
+            ),
+            note: None,
+            chunk_size: None,
+            introduction_only: false,
+        };
+        let state = connect(*b"ABCD", sender, CancellationToken::new(), si, None)
+            .await
+            .unwrap()
+            .state;
+
+        let mut confirmed = watcher.await.unwrap();
+        confirmed.sort();
+        assert_eq!(state, State::Finished);
+        assert_eq!(confirmed, names);
+
+        for name in names {
+            std::fs::remove_file(download.join(name)).unwrap();
+        }
+        std::fs::remove_dir_all(&source).unwrap();
+    }
+
+    // Accepts everything. When interrupting, goes away as soon as the second
+    // file started coming, with only its first chunk on disk.
+    async fn resume_receiver(interrupt: bool) -> (SocketAddr, JoinHandle<State>) {
+        let listen_socket = TcpSocket::new_v4().unwrap();
+        // Keeps what's in flight small next to the second file
+        listen_socket.set_recv_buffer_size(4096).unwrap();
+        listen_socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let listener = listen_socket.listen(1).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (peer_sender, mut peer_receiver) = broadcast::channel(100);
+        let inbound_sender = peer_sender.clone();
+        let inbound = tokio::spawn(async move {
+            let (socket, addr) = listener.accept().await.unwrap();
+            let mut ir = InboundRequest::new(*b"WXYZ", socket, addr.to_string(), inbound_sender);
+            ir.set_download_dir(download_dir());
+            while ir.handle().await.is_ok() {
+                let second_started =
+                    ir.state.transferred_files.values().any(|fi| {
+                        fi.file_url.ends_with("resume_2.bin") && fi.bytes_transferred > 0
+                    });
+                if interrupt && second_started {
+                    break;
+                }
+            }
+            ir.state.state
+        });
+        tokio::spawn(async move {
+            while let Ok(msg) = peer_receiver.recv().await {
+                if msg.state == Some(State::WaitingForUserConsent) {
+                    let _ = peer_sender.send(ChannelMessage {
+                        id: msg.id,
+                        direction: ChannelDirection::FrontToLib,
+                        action: Some(ChannelAction::AcceptTransfer),
+                        ..Default::default()
+                    });
+                    break;
+                }
+            }
+        });
+
+        (addr, inbound)
+    }
+
+    #[tokio::test]
+    async fn test_resume_interrupted() {
+        let source = std::env::temp_dir().join(format!("rqs_resume_{}", std::process::id()));
+        let journal_dir = source.join("journal");
+        let download = download_dir();
+        std::fs::create_dir_all(&journal_dir).unwrap();
+
+        // The second one is way more than the socket buffers can hold
+        let names = ["resume_1.bin", "resume_2.bin", "resume_3.bin"];
+        let sizes = [1024, 16 * 1024 * 1024, 1024];
+        for (name, size) in names.iter().zip(sizes) {
+            let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            std::fs::write(source.join(name), data).unwrap();
+        }
+
+        let (addr, inbound) = resume_receiver(true).await;
+        let (sender, _) = broadcast::channel(1000);
+        let si = SendInfo {
+            id: String::from("resume"),
+            name: String::from("peer"),
+            addr: addr.to_string(),
+            ob: OutboundPayload::Files(
+                names
+                    .iter()
+                    .map(|f| source.join(f).to_string_lossy().into_owned())
+                    .collect(),
+            ),
+            note: None,
+            chunk_size: None,
+            introduction_only: false,
+        };
+        let outbound = tokio::spawn(connect(
+            *b"ABCD",
+            sender,
+            CancellationToken::new(),
+            si,
+            Some(journal_dir.clone()),
+        ));
+        assert_eq!(inbound.await.unwrap(), State::ReceivingFiles);
+        let _ = outbound.await.unwrap();
+
+        let entry = journal::read_entry(&journal_dir, "resume").unwrap();
+        let done: Vec<bool> = entry.files.iter().map(|f| f.is_complete()).collect();
+        assert_eq!(done, [true, false, false]);
+
+        // Only what's left is offered again, from where the receiver stopped
+        let (addr, inbound) = resume_receiver(false).await;
+        let mut si = entry.to_send_info();
+        si.addr = addr.to_string();
+        let OutboundPayload::Files(files) = &si.ob else {
+            unreachable!("journals only hold files");
+        };
+        assert_eq!(files.len(), 2);
+
+        let (sender, mut receiver) = broadcast::channel(1000);
+        let state = connect(
+            *b"ABCD",
+            sender,
+            CancellationToken::new(),
+            si,
+            Some(journal_dir.clone()),
+        )
+        .await
+        .unwrap()
+        .state;
+        assert_eq!(state, State::Finished);
+        assert_eq!(inbound.await.unwrap(), State::Finished);
+
+        let mut wire_bytes = 0;
+        while let Ok(msg) = receiver.try_recv() {
+            if let Some(meta) = msg.meta {
+                wire_bytes = meta.wire_bytes;
+            }
+        }
+        assert!(wire_bytes < sizes[1] as u64);
+
+        for name in names {
+            assert_eq!(
+                std::fs::read(download.join(name)).unwrap(),
+                std::fs::read(source.join(name)).unwrap()
+            );
+            assert!(!download.join(format!("1_{name}")).exists());
+            std::fs::remove_file(download.join(name)).unwrap();
+        }
+        assert!(journal::read_entry(&journal_dir, "resume").is_none());
+
+        std::fs::remove_dir_all(&source).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unix_transfer() {
+        let dir = std::env::temp_dir().join(format!("rqs_unix_{}", std::process::id()));
+        let download = download_dir();
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("unix.txt");
+        std::fs::write(&source, b"no network involved").unwrap();
+
+        let path = dir.join("rqs.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let (peer_sender, mut peer_receiver) = broadcast::channel(100);
+        let inbound_sender = peer_sender.clone();
+        let inbound = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut ir =
+                InboundRequest::new(*b"WXYZ", socket, String::from("unix"), inbound_sender);
+            ir.set_download_dir(download_dir());
+            while ir.handle().await.is_ok() {}
+            ir
+        });
+        tokio::spawn(async move {
+            while let Ok(msg) = peer_receiver.recv().await {
+                if msg.state == Some(State::WaitingForUserConsent) {
+                    let _ = peer_sender.send(ChannelMessage {
+                        id: msg.id,
+                        direction: ChannelDirection::FrontToLib,
+                        action: Some(ChannelAction::AcceptTransfer),
+                        ..Default::default()
+                    });
+                    break;
+                }
+            }
+        });
+
+        let (sender, _) = broadcast::channel(100);
+        let mut or = UnixOutboundRequest::new(
+            *b"ABCD",
+            connect_unix(&path).await.unwrap(),
+            String::from("unix"),
+            sender,
+            OutboundPayload::Files(vec![source.to_string_lossy().into_owned()]),
+            RemoteDeviceInfo {
+                device_type: crate::DeviceType::Unknown,
+                name: String::from("peer"),
+            },
+            None,
+        );
+        or.send_connection_request().await.unwrap();
+        or.send_ukey2_client_init().await.unwrap();
+        // Ends once the receiver hung up
+        while or.handle().await.is_ok() {}
+        let ir = inbound.await.unwrap();
+
+        assert_eq!(or.state.state, State::Finished);
+        assert_eq!(ir.state.state, State::Finished);
+        assert_eq!(
+            std::fs::read(download.join("unix.txt")).unwrap(),
+            b"no network involved"
+        );
+
+        std::fs::remove_file(download.join("unix.txt")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_text_transfer() {
+        let url = "https://github.com/Martichou/rquickshare";
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (peer_sender, mut peer_receiver) = broadcast::channel(100);
+        let inbound_sender = peer_sender.clone();
+        let inbound = tokio::spawn(async move {
+            let (socket, addr) = listener.accept().await.unwrap();
+            let mut ir = InboundRequest::new(*b"WXYZ", socket, addr.to_string(), inbound_sender);
+            while ir.handle().await.is_ok() {}
+            ir
+        });
+        let consent = tokio::spawn(async move {
+            while let Ok(msg) = peer_receiver.recv().await {
+                if msg.state == Some(State::WaitingForUserConsent) {
+                    let _ = peer_sender.send(ChannelMessage {
+                        id: msg.id,
+                        direction: ChannelDirection::FrontToLib,
+                        action: Some(ChannelAction::AcceptTransfer),
+                        ..Default::default()
+                    });
+                    return msg.meta.and_then(|m| m.text_description);
+                }
+            }
+            None
+        });
+
+        let (sender, _) = broadcast::channel(100);
+        let si = SendInfo {
+            id: String::from("text"),
+            name: String::from("peer"),
+            addr: addr.to_string(),
+            ob: OutboundPayload::Text {
+                kind: TextKind::Url,
+                body: String::from(url),
+            },
+            note: None,
+            chunk_size: None,
+            introduction_only: false,
+        };
+        let state = connect(*b"ABCD", sender, CancellationToken::new(), si, None)
+            .await
+            .unwrap()
+            .state;
+        let ir = inbound.await.unwrap();
+
+        // Shown when asking for consent, before the body itself came
+        assert_eq!(consent.await.unwrap().as_deref(), Some(url));
+        assert_eq!(state, State::Finished);
+        assert_eq!(ir.state.state, State::Finished);
+        let meta = ir.state.transfer_metadata.unwrap();
+        assert_eq!(meta.text_payload.as_deref(), Some(url));
+        assert!(matches!(meta.text_type, Some(TextPayloadType::Url)));
+    }
+
+    #[tokio::test]
+    async fn test_transfer_handle() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (peer_sender, mut peer_receiver) = broadcast::channel(100);
+        let inbound_sender = peer_sender.clone();
+        tokio::spawn(async move {
+            let (socket, addr) = listener.accept().await.unwrap();
+            let mut ir = InboundRequest::new(*b"WXYZ", socket, addr.to_string(), inbound_sender);
+            while ir.handle().await.is_ok() {}
+        });
+        tokio::spawn(async move {
+            while let Ok(msg) = peer_receiver.recv().await {
+                if msg.state == Some(State::WaitingForUserConsent) {
+                    let _ = peer_sender.send(ChannelMessage {
+                        id: msg.id,
+                        direction: ChannelDirection::FrontToLib,
+                        action: Some(ChannelAction::AcceptTransfer),
+                        ..Default::default()
+                    });
+                }
+            }
+        });
+
+        let si = |addr: String| SendInfo {
+            id: String::from("handle"),
+            name: String::from("peer"),
+            addr,
+            ob: OutboundPayload::Text {
+                kind: TextKind::Text,
+                body: String::from("awaited"),
+            },
+            note: None,
+            chunk_size: None,
+            introduction_only: false,
+        };
+        let (sender, _) = broadcast::channel(100);
+        let tracker = TaskTracker::new();
+        let mut handle = spawn_transfer(
+            &tracker,
+            *b"ABCD",
+            sender.clone(),
+            CancellationToken::new(),
+            si(addr.to_string()),
+        );
+        assert_eq!(handle.id(), "handle");
+        let events = handle.events();
+
+        let record = handle.finished().await.unwrap();
+        assert_eq!(record.id, "handle");
+        assert_eq!(record.state, State::Finished);
+        assert_eq!(record.direction, TransferType::Outbound);
+        assert!(matches!(record.text_type, Some(TextPayloadType::Text)));
+        assert_eq!(record.ack_bytes, 7);
+
+        // Ends with the transfer, nothing but its own events in there
+        let events: Vec<TransferEvent> = events.collect().await;
+        assert!(matches!(events.first(), Some(TransferEvent::PinReady(pin)) if pin.len() == 4));
+        assert!(events.iter().any(|e| matches!(e, TransferEvent::Accepted)));
+        assert!(matches!(
+            events.last(),
+            Some(TransferEvent::Completed(meta)) if meta.ack_bytes == 7
+        ));
+
+        // Nobody listening there anymore
+        let gone = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let handle = spawn_transfer(
+            &tracker,
+            *b"ABCD",
+            sender,
+            CancellationToken::new(),
+            si(gone.to_string()),
+        );
+        assert!(handle.finished().await.is_err());
     }
 }
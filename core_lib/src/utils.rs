@@ -1,5 +1,9 @@
-use std::net::Ipv4Addr;
+use std::fs::File;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
 
 use anyhow::anyhow;
 use base64::engine::general_purpose::URL_SAFE_NO_PAD;
@@ -7,16 +11,61 @@ use base64::Engine;
 use bytes::Bytes;
 use get_if_addrs::get_if_addrs;
 use hkdf::Hkdf;
+use log::LevelFilter;
 use num_bigint::{BigUint, ToBigInt};
+use once_cell::sync::Lazy;
 use p256::{PublicKey, SecretKey};
 use rand::{thread_rng, Rng, RngCore};
 use serde::{Deserialize, Serialize};
 use sha2::Sha256;
-use tokio::io::AsyncReadExt;
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
 use ts_rs::TS;
 
-use crate::CUSTOM_DOWNLOAD;
+use crate::errors::AppError;
+use crate::hdl::{
+    AddressFamily, AutoAcceptPolicy, ChunkMemory, FilenameRewriter, IntroductionLimits, ReadSlots,
+    SchedulingPolicy, UpgradePolicy,
+};
+use crate::{
+    ADDRESS_FAMILY, AUTO_ACCEPT_POLICY, CANCEL_ON_PEER_OFFLINE, CHUNK_MEMORY, CHUNK_WARMUP,
+    CONNECT_RETRIES, CONSENT_TIMEOUT, CUSTOM_DOWNLOAD, CUSTOM_TEMP, DEVICE_NAME, DEVICE_TYPE,
+    FALLBACK_NAME, FILENAME_REWRITER, FILE_READ_SLOTS, FOLLOW_SYMLINKS, FRAME_RATE_LIMIT,
+    HANDSHAKE_TIMEOUT, INTRODUCTION_LIMITS, KEEPALIVE_INTERVAL, MAX_FRAME_LENGTH,
+    PIN_CONFIRMATION_TIMEOUT, REQUIRE_PIN_CONFIRMATION, RESUME_JOURNAL, SAMPLE_COMPRESSIBILITY,
+    SCHEDULING_POLICY, SINGLE_FRAME_THRESHOLD, TRANSFER_HISTORY, TRANSFER_LOG_LEVELS,
+    UPGRADE_POLICY, WRITE_STALL_TIMEOUT,
+};
+
+// Keeps a spinning disk from seeking back and forth between files
+pub const DEFAULT_CONCURRENT_READS: usize = 2;
+// Chunk buffers of all outbound transfers together, 128 chunks by default
+pub const DEFAULT_CHUNK_MEMORY: usize = 64 * 1024 * 1024;
+pub const DEFAULT_FALLBACK_NAME: &str = "rquickshare device";
+// Files up to this size are sent in a single frame rather than chunked
+pub const DEFAULT_SINGLE_FRAME_THRESHOLD: u64 = 64 * 1024;
+// A peer not reading anything for this long is considered gone
+pub const DEFAULT_WRITE_STALL_TIMEOUT: Duration = Duration::from_secs(30);
+// How long the user gets to compare the PIN before the transfer is dropped
+pub const DEFAULT_PIN_CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(60);
+// How long an inbound transfer waits to be accepted before being declined
+pub const DEFAULT_CONSENT_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+// Largest frame, or text payload kept in memory, accepted from a peer
+pub const DEFAULT_MAX_FRAME_LENGTH: usize = 5 * 1024 * 1024;
+// From sending the connection request to the handshake being done
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// Android drops connections quiet for about 10s
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+// Phones waking up tend to refuse the first connection or two
+pub const DEFAULT_CONNECT_RETRIES: u32 = 2;
+// Wait before the first retry, doubled before each following one
+const CONNECT_BACKOFF: Duration = Duration::from_millis(250);
+
+// First byte of an endpoint_info, from the most significant bit:
+// Version(3 bits)|Visibility(1 bit)|Device Type(3 bits)|Reserved(1 bit).
+// We always advertise version 0 and visible, the peers' are ignored.
+const DEVICE_TYPE_SHIFT: u8 = 1;
+const DEVICE_TYPE_MASK: u8 = 0b111;
 
 #[derive(Debug, Clone, Deserialize, PartialEq, Serialize, TS)]
 #[ts(export)]
@@ -39,6 +88,18 @@ impl DeviceType {
             _ => DeviceType::Unknown,
         }
     }
+
+    // The first byte of an endpoint_info for this device type, see
+    // DEVICE_TYPE_SHIFT for the layout
+    pub fn to_bitfield(&self) -> u8 {
+        (self.clone() as u8 & DEVICE_TYPE_MASK) << DEVICE_TYPE_SHIFT
+    }
+
+    // All three bits are kept, types we don't know of (like 5) must not be
+    // mistaken for one we do
+    pub fn from_bitfield(byte: u8) -> Self {
+        Self::from_raw_value((byte >> DEVICE_TYPE_SHIFT) & DEVICE_TYPE_MASK)
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, TS)]
@@ -51,7 +112,7 @@ pub struct RemoteDeviceInfo {
 impl RemoteDeviceInfo {
     pub fn serialize(&self) -> Vec<u8> {
         // 1 byte: Version(3 bits)|Visibility(1 bit)|Device Type(3 bits)|Reserved(1 bit)
-        let mut endpoint_info: Vec<u8> = vec![((self.device_type.clone() as u8) << 1) & 0b111];
+        let mut endpoint_info: Vec<u8> = vec![self.device_type.to_bitfield()];
 
         // 16 bytes: unknown random bytes
         endpoint_info.extend((0..16).map(|_| rand::thread_rng().gen_range(0..=255)));
@@ -66,6 +127,30 @@ impl RemoteDeviceInfo {
 
         endpoint_info
     }
+
+    pub fn deserialize(endpoint_info: &[u8]) -> Result<Self, anyhow::Error> {
+        // Check if endpoint info length is greater than 17
+        if endpoint_info.len() <= 17 {
+            return Err(anyhow!("Endpoint info too short"));
+        }
+
+        let device_name_length = endpoint_info[17] as usize;
+        // Validate length including device name
+        if endpoint_info.len() < device_name_length + 18 {
+            return Err(anyhow!(
+                "Endpoint info too short to contain the device name"
+            ));
+        }
+
+        // Extract and validate device name based on length
+        let device_name = std::str::from_utf8(&endpoint_info[18..(18 + device_name_length)])
+            .map_err(|_| anyhow!("Device name is not valid UTF-8"))?;
+
+        Ok(RemoteDeviceInfo {
+            name: device_name.to_string(),
+            device_type: DeviceType::from_bitfield(endpoint_info[0]),
+        })
+    }
 }
 
 pub fn gen_mdns_name(endpoint_id: [u8; 4]) -> String {
@@ -90,7 +175,7 @@ pub fn gen_mdns_endpoint_info(device_type: u8, device_name: &str) -> String {
 
     // 1 byte: Version(3 bits)|Visibility(1 bit)|Device Type(3 bits)|Reserved(1 bits)
     // Device types: unknown=0, phone=1, tablet=2, laptop=3
-    record.push(device_type << 1);
+    record.push((device_type & DEVICE_TYPE_MASK) << DEVICE_TYPE_SHIFT);
 
     let unknown_bytes = rand::thread_rng().gen::<[u8; 16]>();
     record.extend_from_slice(&unknown_bytes);
@@ -109,7 +194,7 @@ pub fn parse_mdns_endpoint_info(encoded_str: &str) -> Result<(DeviceType, String
         return Err(anyhow!("Invalid data length"));
     }
 
-    let device_type = (decoded_bytes[0] >> 1) & 0x7;
+    let device_type = DeviceType::from_bitfield(decoded_bytes[0]);
     let name_length = decoded_bytes[17] as usize;
     if 18 + name_length > decoded_bytes.len() {
         return Err(anyhow!("Invalid name length"));
@@ -118,11 +203,11 @@ pub fn parse_mdns_endpoint_info(encoded_str: &str) -> Result<(DeviceType, String
     let device_name_bytes = &decoded_bytes[18..18 + name_length];
     let device_name = String::from_utf8(device_name_bytes.to_vec())?;
 
-    Ok((DeviceType::from_raw_value(device_type), device_name))
+    Ok((device_type, device_name))
 }
 
-pub async fn stream_read_exact(
-    socket: &mut TcpStream,
+pub async fn stream_read_exact<S: AsyncRead + Unpin>(
+    socket: &mut S,
     buf: &mut [u8],
 ) -> Result<(), anyhow::Error> {
     match socket.read_exact(buf).await {
@@ -131,6 +216,27 @@ pub async fn stream_read_exact(
     }
 }
 
+// Same as write_all, except that it gives up with WriteStalled once the
+// peer hasn't read a single byte for stall_timeout. A slow peer is fine as
+// long as it keeps making progress.
+pub async fn stream_write_all<S: AsyncWrite + Unpin>(
+    socket: &mut S,
+    buf: &[u8],
+    stall_timeout: Duration,
+) -> Result<(), anyhow::Error> {
+    let mut written = 0;
+    while written < buf.len() {
+        match tokio::time::timeout(stall_timeout, socket.write(&buf[written..])).await {
+            Ok(Ok(0)) => return Err(std::io::Error::from(std::io::ErrorKind::WriteZero).into()),
+            Ok(Ok(n)) => written += n,
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) => return Err(anyhow!(AppError::WriteStalled)),
+        }
+    }
+
+    Ok(())
+}
+
 pub fn gen_ecdsa_keypair() -> (SecretKey, PublicKey) {
     let secret_key = SecretKey::random(&mut thread_rng());
     let public_key = secret_key.public_key();
@@ -146,6 +252,21 @@ pub fn encode_point(unsigned: Bytes) -> Result<Vec<u8>, anyhow::Error> {
     Ok(big_int.to_signed_bytes_be())
 }
 
+// Reverse of encode_point for a P-256 coordinate, as Java's BigInteger
+// encodes it: a leading zero byte when the top bit is set, none of the
+// leading zeros of a smaller value. Anything longer isn't truncated.
+pub fn decode_point(signed: &[u8]) -> Option<[u8; 32]> {
+    let unsigned = match signed {
+        [0, rest @ ..] if rest.len() == 32 => rest,
+        _ if signed.is_empty() || signed.len() > 32 => return None,
+        _ => signed,
+    };
+
+    let mut coordinate = [0u8; 32];
+    coordinate[32 - unsigned.len()..].copy_from_slice(unsigned);
+    Some(coordinate)
+}
+
 pub fn hkdf_extract_expand(
     salt: &[u8],
     input: &[u8],
@@ -174,6 +295,25 @@ pub fn to_four_digit_string(bytes: &Vec<u8>) -> String {
     format!("{:04}", hash.abs())
 }
 
+// Maximum number of characters kept from a sender's note
+const MAX_NOTE_LENGTH: usize = 256;
+
+pub fn sanitize_note(note: &str) -> Option<String> {
+    let note = note.trim();
+    if note.is_empty() {
+        return None;
+    }
+
+    Some(note.chars().take(MAX_NOTE_LENGTH).collect())
+}
+
+/// Four ASCII alphanumeric characters, as Nearby endpoint ids are. Always
+/// valid UTF-8, the connection request carries it as a string.
+pub fn gen_endpoint_id() -> [u8; 4] {
+    let mut rng = thread_rng();
+    std::array::from_fn(|_| rng.sample(rand::distributions::Alphanumeric))
+}
+
 pub fn gen_random(size: usize) -> Vec<u8> {
     let mut data = vec![0; size];
     rand::thread_rng().fill_bytes(&mut data);
@@ -181,6 +321,51 @@ pub fn gen_random(size: usize) -> Vec<u8> {
     data
 }
 
+const ID_ADJECTIVES: [&str; 16] = [
+    "brave", "calm", "clever", "eager", "fancy", "gentle", "happy", "jolly", "kind", "lively",
+    "lucky", "proud", "quick", "shiny", "swift", "witty",
+];
+const ID_ANIMALS: [&str; 16] = [
+    "badger", "bison", "crane", "falcon", "fox", "heron", "koala", "lynx", "marten", "otter",
+    "panda", "puffin", "raven", "seal", "tiger", "wombat",
+];
+// Words are only there to be readable, the counter keeps ids unique
+static TRANSFER_ID_COUNTER: Lazy<AtomicU32> =
+    Lazy::new(|| AtomicU32::new(thread_rng().gen_range(0..1000)));
+
+/// Short and readable transfer id (eg: "brave-otter-42"), easier to spot
+/// across logs than an uuid. Unique for the lifetime of the process.
+pub fn gen_transfer_id() -> String {
+    let mut rng = thread_rng();
+    let adjective = ID_ADJECTIVES[rng.gen_range(0..ID_ADJECTIVES.len())];
+    let animal = ID_ANIMALS[rng.gen_range(0..ID_ANIMALS.len())];
+    let counter = TRANSFER_ID_COUNTER.fetch_add(1, Ordering::Relaxed);
+
+    format!("{adjective}-{animal}-{counter}")
+}
+
+// Whether the id looks like one from gen_transfer_id
+pub fn is_valid_transfer_id(id: &str) -> bool {
+    let parts: Vec<&str> = id.split('-').collect();
+    let [adjective, animal, counter] = parts[..] else {
+        return false;
+    };
+
+    ID_ADJECTIVES.contains(&adjective)
+        && ID_ANIMALS.contains(&animal)
+        && !counter.is_empty()
+        && counter.chars().all(|c| c.is_ascii_digit())
+}
+
+pub fn bytes_per_second(bytes: u64, elapsed: Duration) -> u64 {
+    let millis = elapsed.as_millis();
+    if millis == 0 {
+        return 0;
+    }
+
+    (bytes as u128 * 1000 / millis) as u64
+}
+
 pub fn get_download_dir() -> PathBuf {
     let cdown = CUSTOM_DOWNLOAD.read();
     match cdown {
@@ -205,7 +390,400 @@ pub fn get_download_dir() -> PathBuf {
     Path::new("/").to_path_buf()
 }
 
-pub fn is_not_self_ip(ip_address: &Ipv4Addr) -> bool {
+pub fn get_temp_dir() -> Option<PathBuf> {
+    match CUSTOM_TEMP.read() {
+        Ok(p) => p.clone(),
+        Err(_) => None,
+    }
+}
+
+/// The path itself if it's free, else the first free one with a counter
+/// prefixed to the file name (eg: 1_photo.jpg, 2_photo.jpg...).
+pub fn unique_path(path: PathBuf) -> PathBuf {
+    if !path.exists() {
+        return path;
+    }
+
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return path;
+    };
+    let name = name.to_string_lossy();
+
+    let mut counter = 1;
+    loop {
+        let candidate = parent.join(format!("{}_{}", counter, name));
+        if !candidate.exists() {
+            return candidate;
+        }
+        counter += 1;
+    }
+}
+
+// Names from the peer are meant to be bare file names, anything that would
+// point elsewhere (separators, "..") is dropped
+pub fn sanitize_file_name(name: &str) -> String {
+    Path::new(name)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .map_or_else(|| String::from("unnamed"), str::to_owned)
+}
+
+// Only plain components, so that it can't leave the directory it's joined to
+pub fn is_safe_relative_path(path: &str) -> bool {
+    !path.is_empty()
+        && Path::new(path)
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)))
+}
+
+pub fn move_file(from: &Path, to: &Path) -> Result<(), std::io::Error> {
+    if std::fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+
+    // Renaming doesn't work across devices (eg: local temp to a network mount)
+    std::fs::copy(from, to)?;
+    std::fs::remove_file(from)
+}
+
+/// Reserve the whole size of a file about to be written at offsets, to limit
+/// fragmentation and to hit a full disk now rather than midway. Only a lack
+/// of space is an error, if the filesystem can't do it the file just grows
+/// as the chunks arrive. Either way its size only counts what was written,
+/// which is what an interrupted transfer resumes from.
+pub fn preallocate(file: &File, size: u64) -> Result<(), std::io::Error> {
+    if size == 0 {
+        return Ok(());
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        // SAFETY: the descriptor is owned by file and stays open for the call
+        let ret = unsafe {
+            libc::fallocate(
+                file.as_raw_fd(),
+                libc::FALLOC_FL_KEEP_SIZE,
+                0,
+                size as libc::off_t,
+            )
+        };
+        if ret != 0 {
+            let e = std::io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::ENOSPC) {
+                return Err(e);
+            }
+
+            debug!("Couldn't preallocate {size} bytes, falling back to append: {e}");
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    let _ = file;
+
+    Ok(())
+}
+
+// Feed the first len bytes of a file to a digest, leaving the file right after
+// them. Used to pick up a streamed digest where an interrupted transfer left it.
+pub fn hash_prefix(file: &mut File, len: u64, digest: &mut Sha256) -> Result<(), std::io::Error> {
+    let copied = std::io::copy(&mut file.take(len), digest)?;
+    if copied != len {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!("only {copied} of {len} bytes to hash"),
+        ));
+    }
+
+    Ok(())
+}
+
+pub fn get_resume_journal_dir() -> Option<PathBuf> {
+    match RESUME_JOURNAL.read() {
+        Ok(dir) => dir.clone(),
+        Err(_) => None,
+    }
+}
+
+pub fn get_transfer_history() -> Option<PathBuf> {
+    match TRANSFER_HISTORY.read() {
+        Ok(path) => path.clone(),
+        Err(_) => None,
+    }
+}
+
+pub fn get_introduction_limits() -> IntroductionLimits {
+    match INTRODUCTION_LIMITS.read() {
+        Ok(limits) => *limits,
+        Err(_) => IntroductionLimits::default(),
+    }
+}
+
+pub fn get_auto_accept_policy() -> AutoAcceptPolicy {
+    match AUTO_ACCEPT_POLICY.read() {
+        Ok(policy) => policy.clone(),
+        Err(_) => AutoAcceptPolicy::default(),
+    }
+}
+
+pub fn get_upgrade_policy() -> UpgradePolicy {
+    match UPGRADE_POLICY.read() {
+        Ok(policy) => policy.clone(),
+        Err(_) => UpgradePolicy::default(),
+    }
+}
+
+pub fn get_filename_rewriter() -> Option<FilenameRewriter> {
+    match FILENAME_REWRITER.read() {
+        Ok(rewriter) => rewriter.clone(),
+        Err(_) => None,
+    }
+}
+
+pub fn get_read_slots() -> ReadSlots {
+    match FILE_READ_SLOTS.read() {
+        Ok(slots) => slots.clone(),
+        Err(_) => ReadSlots::new(DEFAULT_CONCURRENT_READS),
+    }
+}
+
+pub fn get_scheduling_policy() -> SchedulingPolicy {
+    match SCHEDULING_POLICY.read() {
+        Ok(policy) => *policy,
+        Err(_) => SchedulingPolicy::default(),
+    }
+}
+
+pub fn get_fallback_name() -> String {
+    match FALLBACK_NAME.read() {
+        Ok(name) => name.clone(),
+        Err(_) => String::from(DEFAULT_FALLBACK_NAME),
+    }
+}
+
+// Not knowing our own name isn't worth failing a transfer over
+pub fn hostname_or_fallback<F, E>(provider: F) -> String
+where
+    F: FnOnce() -> Result<String, E>,
+    E: std::fmt::Display,
+{
+    match provider() {
+        Ok(hostname) => hostname,
+        Err(e) => {
+            let fallback = get_fallback_name();
+            warn!("Couldn't get the hostname ({}), using {:?}", e, fallback);
+            fallback
+        }
+    }
+}
+
+// How we describe ourselves to peers, both when sending and when accepting
+pub fn local_device_info() -> RemoteDeviceInfo {
+    let name = match DEVICE_NAME.read() {
+        Ok(name) => name.clone(),
+        Err(_) => None,
+    };
+    let device_type = match DEVICE_TYPE.read() {
+        Ok(device_type) => device_type.clone(),
+        Err(_) => DeviceType::Laptop,
+    };
+
+    RemoteDeviceInfo {
+        name: name.unwrap_or_else(|| hostname_or_fallback(sys_metrics::host::get_hostname)),
+        device_type,
+    }
+}
+
+pub fn get_cancel_on_peer_offline() -> bool {
+    match CANCEL_ON_PEER_OFFLINE.read() {
+        Ok(enabled) => *enabled,
+        Err(_) => false,
+    }
+}
+
+pub fn get_single_frame_threshold() -> u64 {
+    match SINGLE_FRAME_THRESHOLD.read() {
+        Ok(threshold) => *threshold,
+        Err(_) => DEFAULT_SINGLE_FRAME_THRESHOLD,
+    }
+}
+
+pub fn get_transfer_log_level(id: &str) -> Option<LevelFilter> {
+    match TRANSFER_LOG_LEVELS.read() {
+        Ok(levels) => levels.get(id).cloned(),
+        Err(_) => None,
+    }
+}
+
+pub fn get_require_pin_confirmation() -> bool {
+    match REQUIRE_PIN_CONFIRMATION.read() {
+        Ok(enabled) => *enabled,
+        Err(_) => false,
+    }
+}
+
+pub fn get_consent_timeout() -> Duration {
+    match CONSENT_TIMEOUT.read() {
+        Ok(timeout) => *timeout,
+        Err(_) => DEFAULT_CONSENT_TIMEOUT,
+    }
+}
+
+pub fn get_frame_rate_limit() -> Option<u32> {
+    match FRAME_RATE_LIMIT.read() {
+        Ok(limit) => *limit,
+        Err(_) => None,
+    }
+}
+
+pub fn get_max_frame_length() -> usize {
+    match MAX_FRAME_LENGTH.read() {
+        Ok(length) => *length,
+        Err(_) => DEFAULT_MAX_FRAME_LENGTH,
+    }
+}
+
+pub fn get_connect_retries() -> u32 {
+    match CONNECT_RETRIES.read() {
+        Ok(retries) => *retries,
+        Err(_) => DEFAULT_CONNECT_RETRIES,
+    }
+}
+
+pub fn get_handshake_timeout() -> Duration {
+    match HANDSHAKE_TIMEOUT.read() {
+        Ok(timeout) => *timeout,
+        Err(_) => DEFAULT_HANDSHAKE_TIMEOUT,
+    }
+}
+
+pub fn get_pin_confirmation_timeout() -> Duration {
+    match PIN_CONFIRMATION_TIMEOUT.read() {
+        Ok(timeout) => *timeout,
+        Err(_) => DEFAULT_PIN_CONFIRMATION_TIMEOUT,
+    }
+}
+
+pub fn get_sample_compressibility() -> bool {
+    match SAMPLE_COMPRESSIBILITY.read() {
+        Ok(enabled) => *enabled,
+        Err(_) => false,
+    }
+}
+
+pub fn get_follow_symlinks() -> bool {
+    match FOLLOW_SYMLINKS.read() {
+        Ok(follow) => *follow,
+        Err(_) => false,
+    }
+}
+
+pub fn get_chunk_memory() -> ChunkMemory {
+    match CHUNK_MEMORY.read() {
+        Ok(memory) => memory.clone(),
+        Err(_) => ChunkMemory::new(DEFAULT_CHUNK_MEMORY),
+    }
+}
+
+pub fn get_chunk_warmup() -> bool {
+    match CHUNK_WARMUP.read() {
+        Ok(enabled) => *enabled,
+        Err(_) => false,
+    }
+}
+
+pub fn get_keepalive_interval() -> Option<Duration> {
+    match KEEPALIVE_INTERVAL.read() {
+        Ok(interval) => *interval,
+        Err(_) => Some(DEFAULT_KEEPALIVE_INTERVAL),
+    }
+}
+
+pub fn get_write_stall_timeout() -> Duration {
+    match WRITE_STALL_TIMEOUT.read() {
+        Ok(timeout) => *timeout,
+        Err(_) => DEFAULT_WRITE_STALL_TIMEOUT,
+    }
+}
+
+pub fn get_address_family() -> AddressFamily {
+    match ADDRESS_FAMILY.read() {
+        Ok(family) => *family,
+        Err(_) => AddressFamily::default(),
+    }
+}
+
+// Put the addresses of the preferred family first, keeping the order otherwise
+pub fn sort_by_family(addrs: &mut [SocketAddr], family: AddressFamily) {
+    let preferred: fn(&SocketAddr) -> bool = match family {
+        AddressFamily::System => return,
+        AddressFamily::Ipv4 => SocketAddr::is_ipv4,
+        AddressFamily::Ipv6 => SocketAddr::is_ipv6,
+    };
+
+    if !addrs.iter().any(preferred) {
+        warn!(
+            "No {:?} candidate in {:?}, using what's there",
+            family, addrs
+        );
+    }
+
+    addrs.sort_by_key(|a| !preferred(a));
+}
+
+// Try each address in order, returning the first one that answered
+pub async fn connect_first(addrs: &[SocketAddr]) -> Result<(TcpStream, SocketAddr), anyhow::Error> {
+    let mut last_err = None;
+    for addr in addrs {
+        match TcpStream::connect(addr).await {
+            Ok(socket) => return Ok((socket, *addr)),
+            Err(e) => {
+                debug!("Couldn't connect to {}: {}", addr, e);
+                last_err = Some(e);
+            }
+        }
+    }
+
+    match last_err {
+        Some(e) => Err(e.into()),
+        None => Err(anyhow!("No address to connect to")),
+    }
+}
+
+// connect_first, tried again up to `retries` times with an exponential backoff
+pub async fn connect_with_retries(
+    addrs: &[SocketAddr],
+    retries: u32,
+) -> Result<(TcpStream, SocketAddr), anyhow::Error> {
+    let mut backoff = CONNECT_BACKOFF;
+    let mut attempt = 0;
+    loop {
+        match connect_first(addrs).await {
+            Ok(connected) => return Ok(connected),
+            Err(e) if attempt < retries && !addrs.is_empty() => {
+                attempt += 1;
+                debug!(
+                    "Connection attempt {} failed ({}), retrying in {:?}",
+                    attempt, e, backoff
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Local counterpart of connect_first, for a UnixOutboundRequest
+pub async fn connect_unix(path: impl AsRef<Path>) -> Result<UnixStream, anyhow::Error> {
+    let path = path.as_ref();
+    UnixStream::connect(path)
+        .await
+        .map_err(|e| anyhow!("Couldn't connect to {}: {}", path.display(), e))
+}
+
+pub fn is_not_self_ip(ip_address: &IpAddr) -> bool {
     if let Ok(if_addrs) = get_if_addrs() {
         for if_addr in if_addrs {
             if if_addr.ip() == *ip_address {
@@ -219,8 +797,127 @@ pub fn is_not_self_ip(ip_address: &Ipv4Addr) -> bool {
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+
     use super::*;
 
+    #[tokio::test(start_paused = true)]
+    async fn test_connect_with_retries() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let started = tokio::time::Instant::now();
+        assert!(connect_with_retries(&[addr], 2).await.is_err());
+        // Waited 250ms, then 500ms
+        assert!(started.elapsed() >= Duration::from_millis(750));
+        assert!(started.elapsed() < Duration::from_millis(1750));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_, connected) = connect_with_retries(&[addr], 0).await.unwrap();
+        assert_eq!(connected, addr);
+
+        assert!(connect_with_retries(&[], 2).await.is_err());
+    }
+
+    #[test]
+    fn test_decode_point() {
+        for coordinate in [[0xAB; 32], [0x12; 32], {
+            let mut small = [0u8; 32];
+            small[2..].fill(0xCD);
+            small
+        }] {
+            let encoded = encode_point(Bytes::copy_from_slice(&coordinate)).unwrap();
+            assert_eq!(decode_point(&encoded), Some(coordinate));
+        }
+        // Sign byte included, and a smaller value
+        assert_eq!(encode_point(Bytes::from(vec![0xAB; 32])).unwrap().len(), 33);
+        assert_eq!(decode_point(&[0x01]).map(|c| c[31]), Some(0x01));
+
+        assert_eq!(decode_point(&[]), None);
+        assert_eq!(decode_point(&[0x01; 33]), None);
+        assert_eq!(decode_point(&[0x00; 34]), None);
+    }
+
+    #[test]
+    fn test_endpoint_ids() {
+        for _ in 0..1000 {
+            let id = gen_endpoint_id();
+            assert!(id.iter().all(u8::is_ascii_alphanumeric));
+            assert!(std::str::from_utf8(&id).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_transfer_ids() {
+        let ids: std::collections::HashSet<String> =
+            (0..10_000).map(|_| gen_transfer_id()).collect();
+        assert_eq!(ids.len(), 10_000);
+        assert!(ids.iter().all(|id| is_valid_transfer_id(id)));
+
+        assert!(is_valid_transfer_id("brave-otter-42"));
+        assert!(!is_valid_transfer_id("brave-otter-"));
+        assert!(!is_valid_transfer_id("brave-otter-42-1"));
+        assert!(!is_valid_transfer_id("127.0.0.1:4242"));
+    }
+
+    #[test]
+    fn test_hostname_fallback() {
+        assert_eq!(
+            hostname_or_fallback(|| Ok::<_, std::io::Error>(String::from("laptop"))),
+            "laptop"
+        );
+        assert_eq!(
+            hostname_or_fallback(|| Err(std::io::Error::other("no hostname"))),
+            DEFAULT_FALLBACK_NAME
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bounded_file_reads() {
+        use std::sync::atomic::AtomicUsize;
+
+        let open = Arc::new(AtomicUsize::new(0));
+        let max_open = Arc::new(AtomicUsize::new(0));
+
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let (open, max_open) = (open.clone(), max_open.clone());
+                tokio::spawn(async move {
+                    let _slot = get_read_slots().acquire(0).await;
+                    let now = open.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_open.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    open.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        for t in tasks {
+            t.await.unwrap();
+        }
+
+        assert!((1..=DEFAULT_CONCURRENT_READS).contains(&max_open.load(Ordering::SeqCst)));
+    }
+
+    #[tokio::test]
+    async fn test_prefer_address_family() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let v4 = listener.local_addr().unwrap();
+        // Nothing listens there, the v6 attempt has to fail
+        let v6: SocketAddr = "[::1]:1".parse().unwrap();
+
+        let mut addrs = vec![v4, v6];
+        sort_by_family(&mut addrs, AddressFamily::Ipv6);
+        assert_eq!(addrs, vec![v6, v4]);
+
+        let (_, connected) = connect_first(&addrs).await.unwrap();
+        assert_eq!(connected, v4);
+
+        sort_by_family(&mut addrs, AddressFamily::Ipv4);
+        assert_eq!(addrs, vec![v4, v6]);
+    }
+
     #[test]
     fn test_gen_and_parse_mdns_info() {
         let device_name = "a_device_name";
@@ -235,4 +932,195 @@ mod tests {
         assert_eq!(parse_info.1, device_name);
         assert_eq!(parse_info.0, device_type);
     }
+
+    #[test]
+    fn test_endpoint_info_roundtrip() {
+        let info = RemoteDeviceInfo {
+            name: String::from("a_device_name"),
+            device_type: DeviceType::Phone,
+        };
+        let parsed = RemoteDeviceInfo::deserialize(&info.serialize()).unwrap();
+
+        assert_eq!(parsed.name, info.name);
+        assert_eq!(parsed.device_type, info.device_type);
+        assert!(RemoteDeviceInfo::deserialize(&info.serialize()[..17]).is_err());
+    }
+
+    #[test]
+    fn test_endpoint_info_layout() {
+        // First byte of the endpoint_info, worked out from the documented
+        // layout (version, visibility, device type) rather than captured
+        let golden = [
+            (0x00, DeviceType::Unknown),
+            (0x02, DeviceType::Phone),
+            (0x04, DeviceType::Tablet),
+            (0x06, DeviceType::Laptop),
+            // Version and visibility bits set, a hidden phone
+            (0x32, DeviceType::Phone),
+            // Reserved bit set
+            (0x07, DeviceType::Laptop),
+            // Types we don't know of, the third bit must not be dropped
+            (0x08, DeviceType::Unknown),
+            (0x0A, DeviceType::Unknown),
+            (0x0E, DeviceType::Unknown),
+        ];
+        for (byte, device_type) in &golden {
+            assert_eq!(
+                DeviceType::from_bitfield(*byte),
+                *device_type,
+                "{byte:#04x}"
+            );
+
+            let mut endpoint_info = vec![*byte];
+            endpoint_info.extend([0xAB; 16]);
+            endpoint_info.push(5);
+            endpoint_info.extend(b"Pixel");
+            let parsed = RemoteDeviceInfo::deserialize(&endpoint_info).unwrap();
+            assert_eq!(parsed.device_type, *device_type);
+            assert_eq!(parsed.name, "Pixel");
+            let (mdns_type, _) =
+                parse_mdns_endpoint_info(&URL_SAFE_NO_PAD.encode(&endpoint_info)).unwrap();
+            assert_eq!(mdns_type, *device_type);
+        }
+
+        // What we send: the type bits only, then 16 random bytes and the
+        // length-prefixed name
+        for (byte, device_type) in golden.iter().take(4) {
+            assert_eq!(device_type.to_bitfield(), *byte);
+
+            let info = RemoteDeviceInfo {
+                name: String::from("rqs laptop"),
+                device_type: device_type.clone(),
+            };
+            let endpoint_info = info.serialize();
+            assert_eq!(endpoint_info.len(), 1 + 16 + 1 + 10);
+            assert_eq!(endpoint_info[0], *byte);
+            assert_eq!(endpoint_info[17], 10);
+            assert_eq!(&endpoint_info[18..], b"rqs laptop");
+
+            let mdns = URL_SAFE_NO_PAD
+                .decode(gen_mdns_endpoint_info(
+                    device_type.clone() as u8,
+                    "rqs laptop",
+                ))
+                .unwrap();
+            assert_eq!(mdns[0], *byte);
+        }
+    }
+
+    #[test]
+    fn test_note_roundtrip() {
+        use prost::Message;
+
+        use crate::sharing_nearby::IntroductionFrame;
+
+        let introduction = IntroductionFrame {
+            note: sanitize_note("  here are the vacation photos "),
+            ..Default::default()
+        };
+        let decoded = IntroductionFrame::decode(&*introduction.encode_to_vec()).unwrap();
+        assert_eq!(decoded.note(), "here are the vacation photos");
+
+        assert_eq!(sanitize_note("   "), None);
+        let long_note = "a".repeat(MAX_NOTE_LENGTH + 10);
+        assert_eq!(
+            sanitize_note(&long_note).unwrap().chars().count(),
+            MAX_NOTE_LENGTH
+        );
+    }
+
+    #[test]
+    fn test_move_file_to_download_dir() {
+        let root = std::env::temp_dir().join(format!("rqs_test_{}", hex::encode(gen_random(4))));
+        let temp_dir = root.join("temp");
+        let download_dir = root.join("download");
+        std::fs::create_dir_all(&temp_dir).unwrap();
+        std::fs::create_dir_all(&download_dir).unwrap();
+
+        let from = temp_dir.join("42.photo.jpg.part");
+        let to = download_dir.join("photo.jpg");
+        std::fs::write(&from, b"some content").unwrap();
+
+        move_file(&from, &to).unwrap();
+        assert!(!from.exists());
+        assert_eq!(std::fs::read(&to).unwrap(), b"some content");
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_preallocate() {
+        use std::os::unix::fs::MetadataExt;
+
+        let path =
+            std::env::temp_dir().join(format!("rqs_prealloc_{}", hex::encode(gen_random(4))));
+        let file = File::create(&path).unwrap();
+        preallocate(&file, 4 * 1024 * 1024).unwrap();
+
+        let metadata = file.metadata().unwrap();
+        // Nothing was written yet
+        assert_eq!(metadata.len(), 0);
+        // Yet actually reserved
+        assert!(metadata.blocks() * 512 >= 4 * 1024 * 1024);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_write_stalled() {
+        use tokio::net::TcpSocket;
+
+        // Small buffers on both ends, so that they fill up after a few KB
+        async fn slow_pair() -> (TcpStream, TcpStream) {
+            let listen_socket = TcpSocket::new_v4().unwrap();
+            listen_socket.set_recv_buffer_size(4096).unwrap();
+            listen_socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+            let listener = listen_socket.listen(1).unwrap();
+
+            let socket = TcpSocket::new_v4().unwrap();
+            socket.set_send_buffer_size(4096).unwrap();
+            let stream = socket
+                .connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (peer, _) = listener.accept().await.unwrap();
+            (stream, peer)
+        }
+
+        fn drain(mut peer: TcpStream, every: Duration) -> tokio::task::JoinHandle<()> {
+            tokio::spawn(async move {
+                let mut buf = [0u8; 4096];
+                while peer.read(&mut buf).await.is_ok_and(|n| n > 0) {
+                    tokio::time::sleep(every).await;
+                }
+            })
+        }
+
+        let data = vec![0x42u8; 1024 * 1024];
+
+        // Slow, but draining
+        let (mut stream, peer) = slow_pair().await;
+        let reader = drain(peer, Duration::from_millis(1));
+        stream_write_all(&mut stream, &data, Duration::from_millis(500))
+            .await
+            .unwrap();
+        reader.abort();
+
+        // Barely draining at all
+        let (mut stream, peer) = slow_pair().await;
+        let reader = drain(peer, Duration::from_secs(60));
+        let e = stream_write_all(&mut stream, &data, Duration::from_millis(200))
+            .await
+            .unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(AppError::WriteStalled)));
+        reader.abort();
+    }
+
+    #[test]
+    fn test_bytes_per_second() {
+        assert_eq!(bytes_per_second(1000, Duration::from_secs(2)), 500);
+        assert_eq!(bytes_per_second(1000, Duration::from_millis(500)), 2000);
+        assert_eq!(bytes_per_second(1000, Duration::ZERO), 0);
+    }
 }